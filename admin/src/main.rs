@@ -0,0 +1,176 @@
+use colored::*;
+use core::{
+    audit,
+    codec::{command::Command, message::Message, msg_codec::MsgCodec},
+    config::{AdminConfig, Config, ServerConfig},
+    error::{ClientError, GlobalError, GlobalResult},
+};
+use futures::SinkExt;
+use std::{env, error::Error, path::PathBuf};
+use tokio::{
+    io::{self, AsyncBufReadExt},
+    net::TcpStream,
+};
+use tokio_stream::StreamExt;
+use tokio_util::codec::Framed;
+
+type Console = Framed<TcpStream, MsgCodec>;
+
+const USAGE: &str = "\
+usage: jhchat-admin [--addr <host:port>] [--token <token>] [command ...]
+       jhchat-admin verify-audit [file] [--head '<seq> <hash>']
+
+without a command, commands are read from stdin one per line
+the token defaults to $JHCHAT_ADMIN_TOKEN, then to admin.token in the server's config.toml
+verify-audit checks the audit log offline, audit.file of the server's config.toml by default
+its hashes are unkeyed, keep a copy of <file>.head off the server and pass it as --head
+to also catch a log rewritten together with its head";
+
+struct Args {
+    admin: AdminConfig,
+    command: Vec<String>,
+    // the expected head of `verify-audit`
+    head: Option<String>,
+}
+
+/// flags override the environment, which overrides the server's config.toml
+fn args() -> Option<Args> {
+    // never `init`, which writes a default config where there is none
+    let mut admin = ServerConfig::read()
+        .ok()
+        .flatten()
+        .map(|c| c.admin)
+        .unwrap_or_default();
+    if let Ok(token) = env::var("JHCHAT_ADMIN_TOKEN") {
+        admin.token = token;
+    }
+    let mut command = Vec::new();
+    let mut head = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--addr" => admin.addr = args.next()?,
+            "--token" => admin.token = args.next()?,
+            "--head" => head = Some(args.next()?),
+            "-h" | "--help" => return None,
+            _ => command.push(arg),
+        }
+    }
+    Some(Args {
+        admin,
+        command,
+        head,
+    })
+}
+
+async fn connect(admin: &AdminConfig) -> GlobalResult<Console> {
+    let stream = TcpStream::connect(&admin.addr).await.map_err(|e| {
+        ClientError::CannotEstablishConnection.info(&format!("{}: {}", admin.addr, e))
+    })?;
+    let mut console = Framed::new(stream, MsgCodec::new());
+    console
+        .send(Message::login("admin").set_content(admin.token.as_bytes()))
+        .await?;
+    reply(&mut console).await?;
+    Ok(console)
+}
+
+/// wait for the server's answer to the last frame
+async fn reply(console: &mut Console) -> GlobalResult<String> {
+    match console.next().await {
+        Some(Ok(msg)) if msg.command == Command::Admin => {
+            Ok(String::from_utf8_lossy(&msg.content).to_string())
+        }
+        Some(Ok(msg)) if msg.command == Command::RemoteError => Err(GlobalError::from(
+            String::from_utf8_lossy(&msg.content).to_string(),
+        )),
+        _ => Err(ClientError::ServerDisconnected.into()),
+    }
+}
+
+/// send `line` and print the answer, an error the server answers with is returned
+async fn execute(console: &mut Console, line: &str) -> GlobalResult<()> {
+    console.send(Message::admin(line)).await?;
+    println!("{}", reply(console).await?);
+    Ok(())
+}
+
+/// walk the chain of the audit log, needs no running server
+/// with `head`, the chain must also still contain that entry
+fn verify_audit(file: Option<&String>, head: Option<&str>) -> GlobalResult<()> {
+    let path = match file {
+        Some(file) => PathBuf::from(file),
+        None => {
+            let config = ServerConfig::read()?.unwrap_or_default();
+            config.audit.path().ok_or_else(|| {
+                ClientError::InvalidCommand.info("audit.file is empty, the audit log is disabled")
+            })?
+        }
+    };
+    let head = match head {
+        Some(anchor) => audit::verify_anchored(&path, &anchor.parse()?)?,
+        None => audit::verify(&path)?,
+    };
+    println!(
+        "{} is intact, {} entries, the last is {}",
+        path.display(),
+        head.seq,
+        head.hash
+    );
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let Some(Args {
+        admin,
+        command,
+        head,
+    }) = args()
+    else {
+        println!("{}", USAGE);
+        return Ok(());
+    };
+    if command.first().map(String::as_str) == Some("verify-audit") {
+        if let Err(e) = verify_audit(command.get(1), head.as_deref()) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if admin.token.is_empty() {
+        eprintln!("{}", "no admin token, see --help".red());
+        std::process::exit(2);
+    }
+
+    let mut console = match connect(&admin).await {
+        Ok(console) => console,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // a single command exits non-zero when it fails, so scripts can tell
+    if !command.is_empty() {
+        if let Err(e) = execute(&mut console, &command.join(" ")).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let mut lines = io::BufReader::new(io::stdin()).lines();
+    while let Some(line) = lines.next_line().await? {
+        match line.trim() {
+            "" => continue,
+            "exit" | "quit" => break,
+            line => {
+                if let Err(e) = execute(&mut console, line).await {
+                    eprintln!("{}", e);
+                }
+            }
+        }
+    }
+    Ok(())
+}
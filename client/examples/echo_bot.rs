@@ -0,0 +1,25 @@
+// reply to every message with the same text, using config.toml next to the executable
+// cargo run -p client --example echo_bot
+use std::error::Error;
+
+use client::{init, ChatClient, Event};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let (client, mut events) = ChatClient::connect(init::config()?).await?;
+    println!("online: {}", client.list_online().await?.join(", "));
+
+    while let Some(event) = events.recv().await {
+        match event {
+            // answer the device that wrote, not every device of its uid
+            Event::Received { sender, text } => match client.send(&sender, &text).await {
+                Ok((id, _)) => println!("#{} echoed to {}", id, sender),
+                Err(e) => println!("cannot echo to {}: {}", sender, e),
+            },
+            Event::Error(e) | Event::RemoteError { error: e, .. } => println!("{}", e),
+            Event::Disconnected => break,
+            _ => (),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,201 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use core::{
+    codec::{command::Command, message::Message},
+    config::ClientConfig,
+    device::DeviceInfo,
+    error::{ClientError, GlobalResult},
+    presence::PresenceState,
+};
+use tokio::{
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    task::JoinHandle,
+};
+
+use crate::{
+    history::SharedHistory,
+    init,
+    outbox::{Delivery, Outbox, SharedOutbox},
+    peers::Peers,
+    worker::{self, Event},
+};
+
+/// everything the connection reports, ends after `Event::Disconnected`
+pub type Events = UnboundedReceiver<Event>;
+
+/// a logged in device, messages to and from it are encrypted and decrypted on the way
+/// share it behind an `Arc`, the connection closes when it is dropped
+pub struct ChatClient {
+    config: Arc<ClientConfig>,
+    tx: UnboundedSender<Message>,
+    peers: Peers,
+    history: SharedHistory,
+    outbox: SharedOutbox,
+    // asks the write task to tell once what is queued so far has been written
+    flushed: UnboundedSender<oneshot::Sender<()>>,
+    read_task: JoinHandle<GlobalResult<()>>,
+    write_task: JoinHandle<GlobalResult<()>>,
+}
+
+impl ChatClient {
+    /// create the directories and key pair of `config` if missing, open its history,
+    /// then connect to `server_host` and log in as `uid/device`
+    pub async fn connect(config: ClientConfig) -> GlobalResult<(Self, Events)> {
+        let (addr, config) = init::directory(config)
+            .and_then(init::encrypt_key)
+            .and_then(init::socket_addr)?;
+        let (mut rd, mut wt) = worker::connect(addr).await?;
+        worker::authenticate(&mut rd, &mut wt, &config).await?;
+
+        let history = Arc::new(Mutex::new(init::history(&config)?));
+        let config = Arc::new(config);
+        let peers: Peers = Arc::default();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (events_tx, events) = mpsc::unbounded_channel();
+
+        let (flushed, flush_rx) = mpsc::unbounded_channel();
+        let write_task = worker::write_stream(wt, rx, flush_rx);
+        let outbox = Outbox::new(
+            tx.clone(),
+            events_tx.clone(),
+            Arc::clone(&config),
+            Arc::clone(&peers),
+            Arc::clone(&history),
+        );
+        let read_task = worker::read_stream(
+            rd,
+            events_tx,
+            Arc::clone(&config),
+            Arc::clone(&peers),
+            Arc::clone(&history),
+        );
+
+        let client = Self {
+            config,
+            tx,
+            peers,
+            history,
+            outbox,
+            flushed,
+            read_task,
+            write_task,
+        };
+        Ok((client, events))
+    }
+
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    pub fn history(&self) -> &SharedHistory {
+        &self.history
+    }
+
+    pub fn peers(&self) -> &Peers {
+        &self.peers
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.config.fetch.timeout_secs)
+    }
+
+    /// send a frame as it is, answers arrive as events
+    pub fn send_frame(&self, msg: Message) -> GlobalResult<()> {
+        self.tx
+            .send(msg)
+            .map_err(|_| ClientError::ServerDisconnected.info("the connection is closed"))
+    }
+
+    /// send `text` to every online device of `uid`, or to one if `uid/device` is given,
+    /// and wait until it is handed to the server
+    /// returns the request id, echoed by `Event::RemoteError` if delivery fails later,
+    /// and the number of devices
+    pub async fn send(&self, receiver: &str, text: &str) -> Delivery {
+        self.outbox
+            .push(receiver, text)
+            .1
+            .await
+            .map_err(|_| ClientError::ServerDisconnected.info("the outbox has stopped"))?
+    }
+
+    /// like `send`, but reports the outcome only as `Event::Sent` or `Event::Undelivered`
+    /// returns the request id these events carry
+    pub fn queue(&self, receiver: &str, text: &str) -> u64 {
+        // nobody waits for the outcome
+        self.outbox.push(receiver, text).0
+    }
+
+    /// wait until every queued message is sent or given up on, and written to the connection
+    /// those waiting for an offline uid give up, messages queued afterwards wait as usual
+    pub async fn flush(&self) {
+        self.outbox.flush().await;
+        let (done, written) = oneshot::channel();
+        if self.flushed.send(done).is_ok() {
+            let _ = written.await;
+        }
+    }
+
+    /// uids that are online and not hidden
+    pub async fn list_online(&self) -> GlobalResult<Vec<String>> {
+        let request = Message::online_list("").set_id(worker::next_id());
+        let answer = worker::request(&self.tx, &self.peers, request, self.timeout()).await?;
+        Ok(String::from_utf8_lossy(&answer.content)
+            .lines()
+            .filter(|uid| !uid.is_empty())
+            .map(String::from)
+            .collect())
+    }
+
+    /// devices of `uid` with their state
+    pub async fn devices(&self, uid: &str) -> GlobalResult<Vec<DeviceInfo>> {
+        let request = Message::device_list(uid).set_id(worker::next_id());
+        let answer = worker::request(&self.tx, &self.peers, request, self.timeout()).await?;
+        Ok(DeviceInfo::from_content(&answer.content))
+    }
+
+    /// revoke a device of this uid, returns the devices left
+    pub async fn revoke(&self, device: &str) -> GlobalResult<Vec<DeviceInfo>> {
+        let request = Message::revoke_device(device).set_id(worker::next_id());
+        let answer = worker::request(&self.tx, &self.peers, request, self.timeout()).await?;
+        Ok(DeviceInfo::from_content(&answer.content))
+    }
+
+    /// let a pending device of this uid log in, `fingerprint` as listed by `devices`
+    /// returns the devices of this uid
+    pub async fn approve(&self, device: &str, fingerprint: &str) -> GlobalResult<Vec<DeviceInfo>> {
+        let request = Message::approve_device(device, fingerprint).set_id(worker::next_id());
+        let answer = worker::request(&self.tx, &self.peers, request, self.timeout()).await?;
+        Ok(DeviceInfo::from_content(&answer.content))
+    }
+
+    /// receive the presence of `contacts` instead of the configured ones
+    pub fn subscribe(&self, contacts: &[String]) -> GlobalResult<()> {
+        self.send_frame(Message::subscribe(contacts).set_id(worker::next_id()))
+    }
+
+    pub fn set_status(&self, state: PresenceState, text: &str) -> GlobalResult<()> {
+        self.send_frame(Message::set_status(state, text).set_id(worker::next_id()))
+    }
+
+    pub fn set_hidden(&self, hidden: bool) -> GlobalResult<()> {
+        self.send_frame(Message::set_hidden(hidden).set_id(worker::next_id()))
+    }
+
+    /// `Typing`, `StoppedTyping` or `Viewing` to `to`, dropped by the server when rate limited
+    pub fn signal(&self, to: &str, command: Command) -> GlobalResult<()> {
+        self.send_frame(Message::signal(to, command))
+    }
+}
+
+impl Drop for ChatClient {
+    fn drop(&mut self) {
+        self.read_task.abort();
+        self.write_task.abort();
+    }
+}
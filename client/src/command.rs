@@ -0,0 +1,506 @@
+use core::{device, presence::PresenceState};
+
+/// every command of line mode: name, arguments, description
+pub const COMMANDS: &[(&str, &str, &str)] = &[
+    (
+        "send",
+        "<uid[/device]> <message>",
+        "send the rest of the line, to every online device of uid unless one is named",
+    ),
+    (
+        "chat",
+        "[uid[/device]]",
+        "plain lines go to the peer until /chat without a peer, who sees you viewing \
+         the conversation but not typing, which line mode cannot tell",
+    ),
+    ("list", "", "list online users"),
+    (
+        "history",
+        "[uid] [n]",
+        "last n messages with uid, 20 by default, the current peer in a chat",
+    ),
+    ("search", "<text>", "messages containing text"),
+    (
+        "export",
+        "<path>",
+        "write the history as plain JSON, one message per line",
+    ),
+    ("devices", "[uid]", "devices of uid, your own by default"),
+    (
+        "revoke",
+        "<device>",
+        "revoke one of your devices, or turn down a pending one",
+    ),
+    (
+        "approve",
+        "<device> <fingerprint>",
+        "let a pending device of yours log in, check the fingerprint it was told",
+    ),
+    (
+        "contacts",
+        "[uid ...]",
+        "follow these uids instead of the configured contacts, \
+         their presence shows once they follow you too",
+    ),
+    (
+        "status",
+        "online|away [text]",
+        "set your presence with an optional status text",
+    ),
+    (
+        "hide",
+        "on|off",
+        "leave the online list and appear offline to your contacts",
+    ),
+    ("help", "[command]", "show this help"),
+    ("exit", "", "quit the client"),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Send { receiver: String, text: String },
+    // `None` leaves conversation mode
+    Chat(Option<String>),
+    List,
+    History { peer: Option<String>, n: usize },
+    Search(String),
+    Export(String),
+    Devices(Option<String>),
+    Revoke(String),
+    Approve { device: String, fingerprint: String },
+    Contacts(Vec<String>),
+    Status { state: PresenceState, text: String },
+    Hide(bool),
+    Help(Option<String>),
+    Exit,
+}
+
+/// usage of `command`, or of every command
+pub fn help(command: Option<&str>) -> String {
+    COMMANDS
+        .iter()
+        .filter(|(name, ..)| match command {
+            Some(command) => command == *name,
+            None => true,
+        })
+        .map(|(name, args, description)| {
+            let usage = format!("/{} {}", name, args);
+            format!("{}\n    {}", usage.trim_end(), description)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn usage(command: &str) -> String {
+    match COMMANDS.iter().find(|(name, ..)| *name == command) {
+        Some((name, args, _)) => format!("usage: /{} {}", name, args).trim_end().into(),
+        None => format!("unknown command {}, try /help", command),
+    }
+}
+
+/// words of a line, read one at a time so the rest can be taken as it was typed
+/// single or double quotes keep spaces in a word, a backslash escapes the next character
+/// except inside single quotes
+struct Words<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Words<'a> {
+    fn new(line: &'a str) -> Self {
+        Self { rest: line }
+    }
+
+    fn next(&mut self) -> Result<Option<String>, String> {
+        let line = self.rest.trim_start();
+        if line.is_empty() {
+            self.rest = line;
+            return Ok(None);
+        }
+        let mut word = String::new();
+        let mut quote = None;
+        let mut chars = line.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match (quote, c) {
+                (None, c) if c.is_whitespace() => {
+                    self.rest = &line[i..];
+                    return Ok(Some(word));
+                }
+                (None, '\'' | '"') => quote = Some(c),
+                (Some(q), c) if c == q => quote = None,
+                (None | Some('"'), '\\') => match chars.next() {
+                    Some((_, c)) => word.push(c),
+                    None => return Err("nothing to escape at the end of the line".into()),
+                },
+                (_, c) => word.push(c),
+            }
+        }
+        if let Some(q) = quote {
+            return Err(format!("missing closing {}", q));
+        }
+        self.rest = "";
+        Ok(Some(word))
+    }
+
+    /// everything left as typed, unquoted if it is a single quoted word
+    fn rest(&mut self) -> Option<String> {
+        let rest = self.rest.trim();
+        self.rest = "";
+        if rest.is_empty() {
+            return None;
+        }
+        if rest.starts_with(['\'', '"']) {
+            let mut words = Words::new(rest);
+            if let Ok(Some(word)) = words.next() {
+                if words.rest.trim().is_empty() {
+                    return Some(word);
+                }
+            }
+        }
+        Some(rest.into())
+    }
+
+    /// usage of `command` if any word is left
+    fn end(&mut self, command: &str) -> Result<(), String> {
+        match self.next()? {
+            Some(_) => Err(usage(command)),
+            None => Ok(()),
+        }
+    }
+
+    fn all(&mut self) -> Result<Vec<String>, String> {
+        let mut words = Vec::new();
+        while let Some(word) = self.next()? {
+            words.push(word);
+        }
+        Ok(words)
+    }
+}
+
+fn address(word: Option<String>, command: &str) -> Result<String, String> {
+    let word = word.ok_or_else(|| usage(command))?;
+    let (uid, device) = device::parse_address(&word);
+    let valid = match device {
+        Some(device) => device::valid_name(uid) && device::valid_name(device),
+        None => device::valid_name(uid),
+    };
+    match valid {
+        true => Ok(word),
+        false => Err(format!("{} is not a valid uid or uid/device", word)),
+    }
+}
+
+/// parse a line of line mode, `Ok(None)` for a blank line
+/// in a chat with `peer`, a line not starting with `/` is a message to the peer,
+/// otherwise the leading `/` of a command is optional
+pub fn parse(line: &str, peer: Option<&str>) -> Result<Option<Request>, String> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    let line = match (line.strip_prefix('/'), peer) {
+        (Some(command), _) => command,
+        (None, Some(peer)) => {
+            return Ok(Some(Request::Send {
+                receiver: peer.into(),
+                text: line.into(),
+            }))
+        }
+        (None, None) => line,
+    };
+    let mut words = Words::new(line);
+    let Some(command) = words.next()? else {
+        return Err(usage("help"));
+    };
+    let request = match command.as_str() {
+        "send" => {
+            let receiver = address(words.next()?, "send")?;
+            let text = words.rest().ok_or_else(|| usage("send"))?;
+            Request::Send { receiver, text }
+        }
+        "chat" => match words.next()? {
+            Some(word) => {
+                let peer = address(Some(word), "chat")?;
+                words.end("chat")?;
+                Request::Chat(Some(peer))
+            }
+            None => Request::Chat(None),
+        },
+        "list" => {
+            words.end("list")?;
+            Request::List
+        }
+        "history" => {
+            let mut peer = None;
+            let mut n = None;
+            for word in words.all()? {
+                match word.parse() {
+                    Ok(count) if n.is_none() => n = Some(count),
+                    Err(_) if peer.is_none() => peer = Some(address(Some(word), "history")?),
+                    _ => return Err(usage("history")),
+                }
+            }
+            Request::History {
+                peer,
+                n: n.unwrap_or(20),
+            }
+        }
+        "search" => Request::Search(words.rest().ok_or_else(|| usage("search"))?),
+        "export" => {
+            let path = words.next()?.ok_or_else(|| usage("export"))?;
+            words.end("export")?;
+            Request::Export(path)
+        }
+        "devices" => match words.next()? {
+            Some(uid) => {
+                let uid = address(Some(uid), "devices")?;
+                words.end("devices")?;
+                Request::Devices(Some(uid))
+            }
+            None => Request::Devices(None),
+        },
+        "revoke" => {
+            let device = address(words.next()?, "revoke")?;
+            words.end("revoke")?;
+            Request::Revoke(device)
+        }
+        "approve" => {
+            let device = address(words.next()?, "approve")?;
+            let fingerprint = words.next()?.ok_or_else(|| usage("approve"))?;
+            words.end("approve")?;
+            Request::Approve {
+                device,
+                fingerprint,
+            }
+        }
+        "contacts" => {
+            let contacts = words.all()?;
+            if let Some(invalid) = contacts.iter().find(|uid| !device::valid_name(uid)) {
+                return Err(format!("{} is not a valid uid", invalid));
+            }
+            Request::Contacts(contacts)
+        }
+        "status" => {
+            let state = words
+                .next()?
+                .and_then(|state| state.parse::<PresenceState>().ok());
+            match state {
+                Some(state @ (PresenceState::Online | PresenceState::Away)) => Request::Status {
+                    state,
+                    text: words.rest().unwrap_or_default(),
+                },
+                _ => return Err(usage("status")),
+            }
+        }
+        "hide" => {
+            let hidden = match words.next()?.as_deref() {
+                Some("on") => true,
+                Some("off") => false,
+                _ => return Err(usage("hide")),
+            };
+            words.end("hide")?;
+            Request::Hide(hidden)
+        }
+        "help" => {
+            let command = words.next()?.map(|c| c.trim_start_matches('/').to_string());
+            words.end("help")?;
+            Request::Help(command)
+        }
+        "exit" | "quit" => {
+            words.end("exit")?;
+            Request::Exit
+        }
+        other => return Err(usage(other)),
+    };
+    Ok(Some(request))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ok(line: &str) -> Request {
+        parse(line, None).unwrap().unwrap()
+    }
+
+    fn usage_of(line: &str, command: &str) {
+        assert_eq!(parse(line, None), Err(usage(command)), "{}", line);
+    }
+
+    #[test]
+    fn blank_lines_are_nothing() {
+        assert_eq!(parse("   ", None), Ok(None));
+        assert_eq!(parse("", Some("bob")), Ok(None));
+    }
+
+    #[test]
+    fn send() {
+        assert_eq!(
+            ok("/send bob/laptop  hello   there "),
+            Request::Send {
+                receiver: "bob/laptop".into(),
+                text: "hello   there".into(),
+            }
+        );
+        assert_eq!(
+            ok("send bob 'hi there'"),
+            Request::Send {
+                receiver: "bob".into(),
+                text: "hi there".into(),
+            }
+        );
+        usage_of("/send", "send");
+        usage_of("/send bob", "send");
+        assert!(parse("/send bob/ hi", None).is_err());
+    }
+
+    #[test]
+    fn chat() {
+        assert_eq!(ok("/chat bob"), Request::Chat(Some("bob".into())));
+        assert_eq!(ok("/chat"), Request::Chat(None));
+        usage_of("/chat bob carol", "chat");
+        // plain lines go to the peer, commands still work
+        assert_eq!(
+            parse("hi /there", Some("bob")),
+            Ok(Some(Request::Send {
+                receiver: "bob".into(),
+                text: "hi /there".into(),
+            }))
+        );
+        assert_eq!(parse("/list", Some("bob")), Ok(Some(Request::List)));
+    }
+
+    #[test]
+    fn list() {
+        assert_eq!(ok("/list"), Request::List);
+        usage_of("/list all", "list");
+    }
+
+    #[test]
+    fn history() {
+        assert_eq!(ok("/history"), Request::History { peer: None, n: 20 });
+        assert_eq!(
+            ok("/history bob 5"),
+            Request::History {
+                peer: Some("bob".into()),
+                n: 5,
+            }
+        );
+        assert_eq!(ok("/history 5 bob"), ok("/history bob 5"));
+        usage_of("/history bob carol", "history");
+        usage_of("/history 5 6", "history");
+        usage_of("/history bob 5 6", "history");
+    }
+
+    #[test]
+    fn search() {
+        assert_eq!(ok("/search Noon at"), Request::Search("Noon at".into()));
+        usage_of("/search", "search");
+    }
+
+    #[test]
+    fn export() {
+        assert_eq!(ok("/export out.jsonl"), Request::Export("out.jsonl".into()));
+        assert_eq!(
+            ok(r#"/export "my history.jsonl""#),
+            Request::Export("my history.jsonl".into())
+        );
+        usage_of("/export", "export");
+        usage_of("/export my history.jsonl", "export");
+    }
+
+    #[test]
+    fn devices() {
+        assert_eq!(ok("/devices"), Request::Devices(None));
+        assert_eq!(ok("/devices bob"), Request::Devices(Some("bob".into())));
+        usage_of("/devices bob carol", "devices");
+    }
+
+    #[test]
+    fn revoke() {
+        assert_eq!(ok("/revoke laptop"), Request::Revoke("laptop".into()));
+        usage_of("/revoke", "revoke");
+        usage_of("/revoke laptop phone", "revoke");
+    }
+
+    #[test]
+    fn approve() {
+        assert_eq!(
+            ok("/approve laptop 0123456789abcdef"),
+            Request::Approve {
+                device: "laptop".into(),
+                fingerprint: "0123456789abcdef".into(),
+            }
+        );
+        usage_of("/approve", "approve");
+        usage_of("/approve laptop", "approve");
+        usage_of("/approve laptop 0123 4567", "approve");
+    }
+
+    #[test]
+    fn contacts() {
+        assert_eq!(
+            ok("/contacts bob carol"),
+            Request::Contacts(vec!["bob".into(), "carol".into()])
+        );
+        assert_eq!(ok("/contacts"), Request::Contacts(vec![]));
+        assert!(parse("/contacts bob/laptop", None).is_err());
+    }
+
+    #[test]
+    fn status() {
+        assert_eq!(
+            ok("/status away at lunch"),
+            Request::Status {
+                state: PresenceState::Away,
+                text: "at lunch".into(),
+            }
+        );
+        assert_eq!(
+            ok("/status online"),
+            Request::Status {
+                state: PresenceState::Online,
+                text: "".into(),
+            }
+        );
+        usage_of("/status", "status");
+        usage_of("/status offline", "status");
+    }
+
+    #[test]
+    fn hide() {
+        assert_eq!(ok("/hide on"), Request::Hide(true));
+        assert_eq!(ok("/hide off"), Request::Hide(false));
+        usage_of("/hide", "hide");
+        usage_of("/hide maybe", "hide");
+        usage_of("/hide on off", "hide");
+    }
+
+    #[test]
+    fn help() {
+        assert_eq!(ok("/help"), Request::Help(None));
+        assert_eq!(ok("/help /send"), Request::Help(Some("send".into())));
+        usage_of("/help send chat", "help");
+    }
+
+    #[test]
+    fn exit() {
+        assert_eq!(ok("/exit"), Request::Exit);
+        assert_eq!(ok("quit"), Request::Exit);
+        usage_of("/exit now", "exit");
+    }
+
+    #[test]
+    fn unknown_commands_and_quotes() {
+        assert_eq!(parse("/frobnicate", None), Err(usage("frobnicate")));
+        assert!(parse("/export 'unclosed", None).is_err());
+        assert!(parse(r"/export trailing\", None).is_err());
+        // the text of a message is taken as typed
+        assert_eq!(
+            ok("/send bob don't"),
+            Request::Send {
+                receiver: "bob".into(),
+                text: "don't".into(),
+            }
+        );
+    }
+}
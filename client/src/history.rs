@@ -0,0 +1,561 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Local;
+use core::{
+    device,
+    error::{ClientError, GlobalResult},
+};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+pub type SharedHistory = Arc<Mutex<History>>;
+
+/// first line of every history file, the rest is one encrypted `Record` per line
+const HEADER: &str = "jhchat-history v1";
+const NONCE_LEN: usize = 12;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Receipt {
+    // handed to the server
+    Sent,
+    // the server answered every copy with `RemoteError`
+    Failed,
+    Received,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    // encrypted with a key from `safe_key_dir`
+    Verified,
+    // encrypted with a key delivered by the server
+    Unverified,
+    // received messages are not signed, so the sender cannot be verified
+    Unsigned,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    // local, increasing
+    pub id: u64,
+    // uid of the other side, names the conversation
+    pub peer: String,
+    // `uid/device` the message came from, or the receiver as given for sent messages
+    pub address: String,
+    pub direction: Direction,
+    pub text: String,
+    // RFC 3339
+    pub time: String,
+    // id of the frame, matched against `RemoteError`
+    pub request_id: u64,
+    pub receipt: Receipt,
+    pub verification: Verification,
+    // devices a sent message was encrypted for, one copy each
+    #[serde(default = "one")]
+    pub copies: usize,
+}
+
+fn one() -> usize {
+    1
+}
+
+fn peer_of(address: &str) -> &str {
+    device::parse_address(address).0
+}
+
+/// a line of the history file, later lines update earlier ones
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+enum Record {
+    Message(Entry),
+    // written before copies were counted, still read
+    Receipt { id: u64, receipt: Receipt },
+    // one copy of a sent message was answered with `RemoteError`
+    CopyFailed { id: u64 },
+}
+
+/// sent and received messages of this device, kept in memory and appended to an encrypted file
+/// every record is AES-256-GCM encrypted with a key derived from the device's private key,
+/// and base64 encoded on its own line, so the file can be copied as is for a backup
+pub struct History {
+    path: PathBuf,
+    cipher: Aes256Gcm,
+    entries: Vec<Entry>,
+    // failed copies of each sent message, by entry id
+    failed_copies: HashMap<u64, usize>,
+}
+
+impl History {
+    /// open or create the history at `path`
+    /// `key_material` is the device's private key, `address` its `uid/device`
+    pub fn open(path: impl AsRef<Path>, key_material: &[u8], address: &str) -> GlobalResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(b"jhchat history"), key_material)
+            .expand(address.as_bytes(), &mut key)
+            .map_err(|_| ClientError::History.info("cannot derive the history key"))?;
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+        let mut history = Self {
+            path,
+            cipher,
+            entries: Vec::new(),
+            failed_copies: HashMap::new(),
+        };
+        history.load()?;
+        Ok(history)
+    }
+
+    /// a record torn by a crash at the end of the file is cut off, damage anywhere else is an error
+    fn load(&mut self) -> GlobalResult<()> {
+        if !self.path.is_file() {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(&self.path, format!("{}\n", HEADER))?;
+            return Ok(());
+        }
+        let content = fs::read_to_string(&self.path)?;
+        let mut lines = content.split_inclusive('\n');
+        if lines.next().map(|l| l.trim_end_matches('\n')) != Some(HEADER) {
+            return Err(
+                ClientError::History.info(&format!("{:?} is not a history file", self.path))
+            );
+        }
+        let lines: Vec<&str> = lines.collect();
+        // where the readable part of the file ends
+        let mut end = HEADER.len() + 1;
+        for (n, line) in lines.iter().enumerate() {
+            let text = line.trim_end_matches('\n');
+            if !text.is_empty() {
+                match self.decrypt(text) {
+                    Ok(record) => self.apply(record),
+                    // only a write cut short leaves an unterminated last line, a terminated one
+                    // that cannot be read is damage like anywhere else
+                    Err(_) if n + 1 == lines.len() && !line.ends_with('\n') => break,
+                    Err(_) => {
+                        return Err(ClientError::History.info(&format!(
+                            "line {} of {:?} cannot be decrypted, was the key pair replaced?",
+                            n + 2,
+                            self.path
+                        )))
+                    }
+                }
+            }
+            end += line.len();
+        }
+        if end < content.len() {
+            OpenOptions::new()
+                .write(true)
+                .open(&self.path)?
+                .set_len(end as u64)?;
+        } else if !content.ends_with('\n') {
+            writeln!(OpenOptions::new().append(true).open(&self.path)?)?;
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Message(entry) => self.entries.push(entry),
+            Record::Receipt { id, receipt } => {
+                if let Some(entry) = self.entries.iter_mut().find(|e| e.id == id) {
+                    entry.receipt = receipt;
+                }
+            }
+            Record::CopyFailed { id } => {
+                let failed = self.failed_copies.entry(id).or_default();
+                *failed += 1;
+                if let Some(entry) = self.entries.iter_mut().find(|e| e.id == id) {
+                    if *failed >= entry.copies {
+                        entry.receipt = Receipt::Failed;
+                    }
+                }
+            }
+        }
+    }
+
+    fn encrypt(&self, record: &Record) -> GlobalResult<String> {
+        let plain =
+            serde_json::to_vec(record).map_err(|e| ClientError::History.info(&e.to_string()))?;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plain.as_slice())
+            .map_err(|_| ClientError::Encryption.info("history record"))?;
+        let mut bytes = nonce.to_vec();
+        bytes.extend(ciphertext);
+        Ok(STANDARD.encode(bytes))
+    }
+
+    fn decrypt(&self, line: &str) -> GlobalResult<Record> {
+        let bytes = STANDARD
+            .decode(line)
+            .map_err(|_| ClientError::History.info("record is not base64"))?;
+        if bytes.len() < NONCE_LEN {
+            return Err(ClientError::History.info("record is truncated"));
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let plain = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| ClientError::Decryption.info("history record"))?;
+        serde_json::from_slice(&plain).map_err(|e| ClientError::History.info(&e.to_string()))
+    }
+
+    fn append(&mut self, record: Record) -> GlobalResult<()> {
+        let line = self.encrypt(&record)?;
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+        writeln!(file, "{}", line)?;
+        self.apply(record);
+        Ok(())
+    }
+
+    fn next_id(&self) -> u64 {
+        self.entries.last().map_or(1, |e| e.id + 1)
+    }
+
+    /// `address` is the receiver, either `uid` or `uid/device`
+    /// `copies` is the number of devices the message was encrypted for
+    pub fn record_sent(
+        &mut self,
+        address: &str,
+        text: &str,
+        request_id: u64,
+        copies: usize,
+        verification: Verification,
+    ) -> GlobalResult<()> {
+        let entry = Entry {
+            id: self.next_id(),
+            peer: peer_of(address).into(),
+            address: address.into(),
+            direction: Direction::Sent,
+            text: text.into(),
+            time: Local::now().to_rfc3339(),
+            request_id,
+            receipt: Receipt::Sent,
+            verification,
+            copies,
+        };
+        self.append(Record::Message(entry))
+    }
+
+    /// `address` is the `uid/device` of the sender
+    pub fn record_received(
+        &mut self,
+        address: &str,
+        text: &str,
+        request_id: u64,
+    ) -> GlobalResult<()> {
+        let entry = Entry {
+            id: self.next_id(),
+            peer: peer_of(address).into(),
+            address: address.into(),
+            direction: Direction::Received,
+            text: text.into(),
+            time: Local::now().to_rfc3339(),
+            request_id,
+            receipt: Receipt::Received,
+            verification: Verification::Unsigned,
+            copies: 1,
+        };
+        self.append(Record::Message(entry))
+    }
+
+    /// count a failed copy of the sent message of `request_id`, if there is one
+    /// the message is marked `Failed` once every copy has failed
+    pub fn mark_failed(&mut self, request_id: u64) -> GlobalResult<()> {
+        let found = self
+            .entries
+            .iter()
+            .rev()
+            .find(|e| e.direction == Direction::Sent && e.request_id == request_id)
+            .map(|e| e.id);
+        match found {
+            Some(id) => self.append(Record::CopyFailed { id }),
+            None => Ok(()),
+        }
+    }
+
+    /// the last `n` messages exchanged with `peer`, oldest first
+    pub fn conversation(&self, peer: &str, n: usize) -> Vec<&Entry> {
+        self.window(peer, 0, n)
+    }
+
+    /// `n` messages exchanged with `peer` before the newest `skip`, oldest first,
+    /// fewer near its start, only as many as needed are looked at from the newest back
+    pub fn window(&self, peer: &str, skip: usize, n: usize) -> Vec<&Entry> {
+        let mut entries: Vec<&Entry> = self
+            .entries
+            .iter()
+            .rev()
+            .filter(|e| e.peer == peer)
+            .skip(skip)
+            .take(n)
+            .collect();
+        entries.reverse();
+        entries
+    }
+
+    /// uids with a conversation, most recent first
+    pub fn peers(&self) -> Vec<String> {
+        let mut peers: Vec<String> = Vec::new();
+        for entry in self.entries.iter().rev() {
+            if !peers.contains(&entry.peer) {
+                peers.push(entry.peer.clone());
+            }
+        }
+        peers
+    }
+
+    /// messages containing `text`, ignoring case, oldest first
+    pub fn search(&self, text: &str) -> Vec<&Entry> {
+        let text = text.to_lowercase();
+        self.entries
+            .iter()
+            .filter(|e| e.text.to_lowercase().contains(&text))
+            .collect()
+    }
+
+    /// write every message as plain JSON, one per line
+    /// on Unix a new file is readable by the owner only, like the history itself should be
+    /// returns the number of messages written
+    pub fn export(&self, path: impl AsRef<Path>) -> GlobalResult<usize> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path)?;
+        for entry in &self.entries {
+            let line = serde_json::to_string(entry)
+                .map_err(|e| ClientError::History.info(&e.to_string()))?;
+            writeln!(file, "{}", line)?;
+        }
+        Ok(self.entries.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"not a real private key";
+
+    fn open(dir: &tempfile::TempDir) -> History {
+        History::open(dir.path().join("history"), KEY, "alice/phone").unwrap()
+    }
+
+    #[test]
+    fn records_survive_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut history = open(&dir);
+        history
+            .record_sent("bob", "hello bob", 1, 1, Verification::Verified)
+            .unwrap();
+        history
+            .record_received("bob/laptop", "hi alice", 7)
+            .unwrap();
+        history.record_received("carol/phone", "lunch?", 8).unwrap();
+        history.mark_failed(1).unwrap();
+        drop(history);
+
+        let mut history = open(&dir);
+        let bob = history.conversation("bob", 10);
+        assert_eq!(bob.len(), 2);
+        assert_eq!(bob[0].text, "hello bob");
+        assert_eq!(bob[0].receipt, Receipt::Failed);
+        assert_eq!(bob[1].address, "bob/laptop");
+        assert_eq!(bob[1].direction, Direction::Received);
+        assert_eq!(bob[1].request_id, 7);
+        assert_eq!(history.peers(), vec!["carol", "bob"]);
+        // ids go on where the file left off
+        history.record_received("bob/laptop", "bye", 9).unwrap();
+        assert_eq!(history.conversation("bob", 1)[0].id, 4);
+    }
+
+    #[test]
+    fn file_holds_no_plaintext() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut history = open(&dir);
+        history
+            .record_received("bob/laptop", "secret plans", 1)
+            .unwrap();
+        let file = fs::read_to_string(dir.path().join("history")).unwrap();
+        assert!(file.starts_with(HEADER));
+        assert!(!file.contains("secret plans"));
+        assert!(!file.contains("bob"));
+    }
+
+    #[test]
+    fn other_keys_cannot_open_it() {
+        let dir = tempfile::tempdir().unwrap();
+        open(&dir).record_received("bob/laptop", "hi", 1).unwrap();
+        let path = dir.path().join("history");
+        assert!(History::open(&path, b"another key", "alice/phone").is_err());
+        assert!(History::open(&path, KEY, "alice/laptop").is_err());
+    }
+
+    #[test]
+    fn torn_last_record_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        let mut history = open(&dir);
+        history.record_received("bob/laptop", "hi", 1).unwrap();
+        history
+            .record_received("bob/laptop", "still there?", 2)
+            .unwrap();
+        drop(history);
+        let file = fs::read_to_string(&path).unwrap();
+        fs::write(&path, &file[..file.len() - 10]).unwrap();
+
+        let mut history = open(&dir);
+        assert_eq!(history.conversation("bob", 10).len(), 1);
+        history.record_received("bob/laptop", "bye", 3).unwrap();
+        drop(history);
+        let history = open(&dir);
+        let texts: Vec<_> = history
+            .conversation("bob", 10)
+            .iter()
+            .map(|e| e.text.as_str())
+            .collect();
+        assert_eq!(texts, vec!["hi", "bye"]);
+
+        // also when nothing before it could be read
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        open(&dir).record_received("bob/laptop", "hi", 1).unwrap();
+        let file = fs::read_to_string(&path).unwrap();
+        fs::write(&path, &file[..file.len() - 10]).unwrap();
+        assert!(open(&dir).conversation("bob", 10).is_empty());
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", HEADER));
+    }
+
+    #[test]
+    fn damage_before_the_end_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        let mut history = open(&dir);
+        history.record_received("bob/laptop", "hi", 1).unwrap();
+        history.record_received("bob/laptop", "bye", 2).unwrap();
+        drop(history);
+        let file = fs::read_to_string(&path).unwrap();
+        let damaged = file.replacen(&format!("{}\n", HEADER), &format!("{}\nxx", HEADER), 1);
+        fs::write(&path, damaged).unwrap();
+        assert!(History::open(&path, KEY, "alice/phone").is_err());
+
+        // a complete last line cannot be torn, it is damaged and kept for inspection
+        let mut lines: Vec<&str> = file.lines().collect();
+        let last = lines.pop().unwrap().replacen(|_| true, "x", 3);
+        lines.push(&last);
+        let damaged = format!("{}\n", lines.join("\n"));
+        fs::write(&path, &damaged).unwrap();
+        let e = History::open(&path, KEY, "alice/phone").err().unwrap();
+        assert_eq!(e.code(), ClientError::History as u16);
+        assert_eq!(fs::read_to_string(&path).unwrap(), damaged);
+    }
+
+    #[test]
+    fn window_skips_the_newest_messages_of_one_peer() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut history = open(&dir);
+        for n in 1..=5 {
+            history
+                .record_received("bob/laptop", &n.to_string(), n)
+                .unwrap();
+            history
+                .record_received("carol/phone", "other", 10 + n)
+                .unwrap();
+        }
+        let texts = |entries: Vec<&Entry>| -> Vec<String> {
+            entries.iter().map(|e| e.text.clone()).collect()
+        };
+        assert_eq!(texts(history.window("bob", 1, 2)), vec!["3", "4"]);
+        assert_eq!(texts(history.window("bob", 3, 10)), vec!["1", "2"]);
+        assert!(history.window("bob", 5, 10).is_empty());
+        assert_eq!(texts(history.conversation("bob", 2)), vec!["4", "5"]);
+    }
+
+    #[test]
+    fn search_ignores_case() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut history = open(&dir);
+        history
+            .record_received("bob/laptop", "Meet at NOON", 1)
+            .unwrap();
+        history
+            .record_sent("bob", "noon works", 2, 1, Verification::Unverified)
+            .unwrap();
+        history
+            .record_received("carol/phone", "morning", 3)
+            .unwrap();
+        let found: Vec<&str> = history
+            .search("noon")
+            .iter()
+            .map(|e| e.text.as_str())
+            .collect();
+        assert_eq!(found, vec!["Meet at NOON", "noon works"]);
+        assert!(history.search("evening").is_empty());
+    }
+
+    #[test]
+    fn export_writes_one_json_entry_per_message() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut history = open(&dir);
+        history.record_received("bob/laptop", "first", 1).unwrap();
+        history
+            .record_sent("bob", "second", 2, 1, Verification::Verified)
+            .unwrap();
+        let path = dir.path().join("export.jsonl");
+        assert_eq!(history.export(&path).unwrap(), 2);
+        let entries: Vec<Entry> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].text, "first");
+        assert_eq!(entries[1].text, "second");
+        assert_eq!(entries[1].verification, Verification::Verified);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn failed_only_once_every_copy_has_failed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut history = open(&dir);
+        history
+            .record_sent("bob", "to three devices", 1, 3, Verification::Verified)
+            .unwrap();
+        history.mark_failed(1).unwrap();
+        history.mark_failed(1).unwrap();
+        assert_eq!(history.conversation("bob", 1)[0].receipt, Receipt::Sent);
+        drop(history);
+
+        // the copies that failed before reopening still count
+        let mut history = open(&dir);
+        assert_eq!(history.conversation("bob", 1)[0].receipt, Receipt::Sent);
+        history.mark_failed(1).unwrap();
+        assert_eq!(history.conversation("bob", 1)[0].receipt, Receipt::Failed);
+    }
+}
@@ -1,31 +1,84 @@
 use std::{
-    fs::{create_dir_all, remove_file},
+    env,
+    fs::{create_dir_all, remove_file, rename},
     net::{SocketAddr, ToSocketAddrs},
     path::{Path, PathBuf},
 };
 
 use colored::*;
 use core::{
-    config::{ClientConfig, Config},
+    config::{self, ClientConfig, Config, Overrides},
+    device::{self, DEFAULT_DEVICE},
     encryption::rsa_impl,
-    error::{ExternalError, GlobalResult},
+    error::{ClientError, ExternalError, GlobalResult},
+    layout::Layout,
     traits::encrypt::Encrypt,
 };
 
+use crate::{history::History, profile};
+
 pub type Encryptor = rsa_impl::RsaEncryption;
 
+/// config.toml with `JHCHAT_*` variables and arguments applied, e.g. `--uid bob --device laptop`
+/// `--profile <name>` or `JHCHAT_PROFILE` reads the config of a profile instead
+/// `--tui`, `--line` and `--json` are short for `--ui Tui` and so on
 pub fn config() -> GlobalResult<ClientConfig> {
-    let config = ClientConfig::init()?;
-    Ok(config)
+    let flags = [
+        ("--tui", "ui", "Tui"),
+        ("--line", "ui", "Line"),
+        ("--json", "ui", "Json"),
+    ];
+    let (name, args) = profile_arg(env::args().skip(1))?;
+    let mut overrides = Overrides::parse(env::vars(), args, &flags)?;
+    if let Some(name) = name.or_else(|| env::var("JHCHAT_PROFILE").ok()) {
+        if overrides.path.is_some() {
+            return Err(ExternalError::Initialize.info("--profile and --config cannot be combined"));
+        }
+        // the layout tells where profiles are kept
+        overrides.layout.unwrap_or_else(Layout::detect).install();
+        overrides.path = Some(profile::open(&name)?);
+    }
+    let config = ClientConfig::load(&overrides);
+    // stderr, so the json mode keeps stdout for events
+    // an upgraded file is reported even if it turns out to be invalid
+    for notice in config::take_notices() {
+        eprintln!("{}", notice);
+    }
+    config
+}
+
+/// take `--profile <name>` out of `args`
+fn profile_arg(args: impl Iterator<Item = String>) -> GlobalResult<(Option<String>, Vec<String>)> {
+    let mut name = None;
+    let mut rest = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.strip_prefix("--profile") {
+            Some("") => {
+                let value = args.next();
+                name =
+                    Some(value.ok_or_else(|| {
+                        ExternalError::Initialize.info("--profile needs a value")
+                    })?);
+            }
+            Some(value) if value.starts_with('=') => name = Some(value[1..].to_string()),
+            _ => rest.push(arg),
+        }
+    }
+    Ok((name, rest))
 }
 
 pub fn socket_addr(config: ClientConfig) -> GlobalResult<(SocketAddr, ClientConfig)> {
+    let unresolved = || {
+        ClientError::CannotEstablishConnection
+            .info(&format!("cannot resolve {}", config.server_host))
+    };
     let addr = config
         .server_host
         .to_socket_addrs()
-        .unwrap_or_else(|_| panic!("{}", "to_socket_addrs() failed".red().bold().to_string()))
+        .map_err(|_| unresolved())?
         .next()
-        .expect("no address is resolved");
+        .ok_or_else(unresolved)?;
     Ok((addr, config))
 }
 
@@ -34,6 +87,7 @@ pub fn directory(config: ClientConfig) -> GlobalResult<ClientConfig> {
     let rsa_self = Path::new(&config.encryption.self_key_dir);
     let rsa_safe = Path::new(&config.encryption.safe_key_dir);
     let rsa_unsafe = Path::new(&config.encryption.unsafe_key_dir);
+    let history = Path::new(&config.history_dir);
     let rsa_self_pub = rsa_self.join("public");
     let rsa_self_priv = rsa_self.join("private");
 
@@ -43,6 +97,7 @@ pub fn directory(config: ClientConfig) -> GlobalResult<ClientConfig> {
         rsa_self,
         rsa_safe,
         rsa_unsafe,
+        history,
         &rsa_self_pub,
         &rsa_self_priv,
     ]
@@ -57,17 +112,46 @@ pub fn directory(config: ClientConfig) -> GlobalResult<ClientConfig> {
     Ok(config)
 }
 
+/// `dir/uid/device`, where each device of a uid keeps its own key
+/// a key stored at `dir/uid` before multi-device support becomes the key of the default device
+pub fn device_key_path(dir: impl AsRef<Path>, uid: &str, device: &str) -> GlobalResult<PathBuf> {
+    let uid_dir = dir.as_ref().join(uid);
+    if uid_dir.is_file() {
+        let legacy = dir.as_ref().join(format!("{}.legacy", uid));
+        rename(&uid_dir, &legacy)?;
+        create_dir_all(&uid_dir)?;
+        rename(&legacy, uid_dir.join(DEFAULT_DEVICE))?;
+    }
+    create_dir_all(&uid_dir)?;
+    Ok(uid_dir.join(device))
+}
+
+/// open the history of this device, encrypted with a key derived from its private key
+pub fn history(config: &ClientConfig) -> GlobalResult<History> {
+    let priv_key = config.encryption.rsa_self_priv_key.as_ref().ok_or(
+        ClientError::EncryptKeyPersistence.info("user's private key does not exist"),
+    )?;
+    let key_material = Encryptor::export_priv_key(priv_key)?;
+    let address = device::address(&config.uid, &config.device);
+    let path = Path::new(&config.history_dir).join(format!("{}.{}.history", config.uid, config.device));
+    History::open(path, &key_material, &address)
+}
+
 // ensures config has key pair, and self key files do exist
 pub fn encrypt_key(mut config: ClientConfig) -> GlobalResult<ClientConfig> {
+    if !device::valid_name(&config.uid) || !device::valid_name(&config.device) {
+        return Err(ClientError::AuthenticationFailed.info(
+            "uid and device may only contain letters, digits, '-', '_' and '.'",
+        ));
+    }
     let self_root_dir = PathBuf::from(&config.encryption.self_key_dir);
-    let priv_key_path = self_root_dir.join("private").join(&config.uid);
-    let pub_key_path = self_root_dir.join("public").join(&config.uid);
+    let priv_key_path = device_key_path(self_root_dir.join("private"), &config.uid, &config.device)?;
+    let pub_key_path = device_key_path(self_root_dir.join("public"), &config.uid, &config.device)?;
 
     // ture if both exist, false otherwise
     let exist = [&priv_key_path, &pub_key_path]
         .iter()
-        .map(|f| f.try_exists().unwrap_or(false))
-        .all(|r| r);
+        .all(|f| f.try_exists().unwrap_or(false));
 
     // if both keys exist, read and mount to config
     if exist {
@@ -85,7 +169,8 @@ pub fn encrypt_key(mut config: ClientConfig) -> GlobalResult<ClientConfig> {
             remove_file(&pub_key_path)?;
         }
 
-        println!("{}", "key pair does not exist, generating...".yellow());
+        // stderr, stdout belongs to the frontend
+        eprintln!("{}", "key pair does not exist, generating...".yellow());
 
         let mut rng = rand::thread_rng();
         let (pub_key, priv_key) =
@@ -95,7 +180,7 @@ pub fn encrypt_key(mut config: ClientConfig) -> GlobalResult<ClientConfig> {
         config.encryption.rsa_self_priv_key = Some(priv_key);
         config.encryption.rsa_self_pub_key = Some(pub_key);
 
-        println!("{}", "key pair initialization has completed".green());
+        eprintln!("{}", "key pair initialization has completed".green());
     }
     Ok(config)
 }
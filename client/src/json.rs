@@ -0,0 +1,471 @@
+use std::sync::Arc;
+
+use client::{history::Entry, worker::lock, ChatClient, Event, Events};
+use core::{
+    device::{self, DeviceInfo},
+    error::{ClientError, GlobalError, GlobalResult},
+    presence::{Presence, PresenceState},
+};
+use serde::{Deserialize, Serialize};
+use tokio::io::{self, AsyncBufReadExt};
+
+/// a line of stdin, e.g. `{"command":"send","to":"bob","text":"hi"}`
+#[derive(Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "snake_case", deny_unknown_fields)]
+enum Input {
+    // `to` is `uid` for every online device or `uid/device` for one
+    Send {
+        to: String,
+        text: String,
+    },
+    List,
+    Devices {
+        uid: Option<String>,
+    },
+    Revoke {
+        device: String,
+    },
+    Approve {
+        device: String,
+        fingerprint: String,
+    },
+    History {
+        peer: String,
+        n: Option<usize>,
+    },
+    Search {
+        text: String,
+    },
+    Contacts {
+        uids: Vec<String>,
+    },
+    // `online` or `away`
+    Status {
+        state: String,
+        #[serde(default)]
+        text: String,
+    },
+    Hide {
+        hidden: bool,
+    },
+    Exit,
+}
+
+/// `GlobalError` without colors, `code` is stable across versions
+#[derive(Serialize, Debug)]
+struct ErrorJson {
+    code: u16,
+    category: &'static str,
+    name: &'static str,
+    info: Option<String>,
+}
+
+impl From<&GlobalError> for ErrorJson {
+    fn from(e: &GlobalError) -> Self {
+        Self {
+            code: e.err.code(),
+            category: e.err.category(),
+            name: e.err.name(),
+            info: e.info.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct DeviceJson<'a> {
+    name: &'a str,
+    online: bool,
+    pending: bool,
+    registered: &'a str,
+    fingerprint: &'a str,
+}
+
+/// a line of stdout
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Output<'a> {
+    Received {
+        sender: &'a str,
+        text: &'a str,
+    },
+    KeyReceived {
+        uid: &'a str,
+    },
+    // answers `send` at once, `sent`, `waiting` and `undelivered` carry the same `id`
+    Queued {
+        id: u64,
+        receiver: &'a str,
+    },
+    Sent {
+        id: u64,
+        receiver: &'a str,
+        devices: usize,
+    },
+    Waiting {
+        id: u64,
+        receiver: &'a str,
+    },
+    Undelivered {
+        id: u64,
+        receiver: &'a str,
+        error: ErrorJson,
+    },
+    // `id` is the failed request, 0 if the server did not name one
+    RemoteError {
+        id: u64,
+        error: ErrorJson,
+    },
+    // a frame or a command that could not be handled, the client goes on
+    Error {
+        error: ErrorJson,
+    },
+    Online {
+        uids: Vec<&'a str>,
+    },
+    Devices {
+        uid: &'a str,
+        devices: Vec<DeviceJson<'a>>,
+    },
+    History {
+        entries: Vec<&'a Entry>,
+    },
+    Presence {
+        uid: &'a str,
+        state: String,
+        text: &'a str,
+    },
+    Typing {
+        sender: &'a str,
+    },
+    StoppedTyping {
+        sender: &'a str,
+    },
+    Viewing {
+        sender: &'a str,
+    },
+    Announcement {
+        text: &'a str,
+    },
+    ServerShutdown {
+        reason: &'a str,
+    },
+    Kicked {
+        reason: &'a str,
+    },
+    Unknown {
+        command: &'a str,
+        sender: &'a str,
+    },
+    Disconnected,
+}
+
+fn emit(output: Output) {
+    // every field serializes, a failure would be a bug
+    if let Ok(line) = serde_json::to_string(&output) {
+        println!("{}", line);
+    }
+}
+
+fn emit_error(e: &GlobalError) {
+    emit(Output::Error { error: e.into() });
+}
+
+fn emit_devices(uid: &str, devices: &[DeviceInfo]) {
+    let devices = devices
+        .iter()
+        .map(|d| DeviceJson {
+            name: &d.name,
+            online: d.online,
+            pending: d.pending,
+            registered: &d.registered,
+            fingerprint: &d.fingerprint,
+        })
+        .collect();
+    emit(Output::Devices { uid, devices });
+}
+
+/// false once the connection is gone
+fn emit_event(event: &Event) -> bool {
+    emit(output(event));
+    !matches!(event, Event::Disconnected)
+}
+
+fn output(event: &Event) -> Output<'_> {
+    match event {
+        Event::Received { sender, text } => Output::Received { sender, text },
+        Event::KeyReceived(uid) => Output::KeyReceived { uid },
+        Event::Error(e) => Output::Error { error: e.into() },
+        Event::OnlineList(list) => Output::Online {
+            uids: list.lines().filter(|uid| !uid.is_empty()).collect(),
+        },
+        Event::ServerShutdown(reason) => Output::ServerShutdown { reason },
+        Event::Kicked(reason) => Output::Kicked { reason },
+        Event::Announcement(text) => Output::Announcement { text },
+        Event::RemoteError { id, error } => Output::RemoteError {
+            id: *id,
+            error: error.into(),
+        },
+        Event::Presence {
+            uid,
+            presence: Presence { state, text },
+        } => Output::Presence {
+            uid,
+            state: state.as_ref().to_lowercase(),
+            text,
+        },
+        Event::Typing(sender) => Output::Typing { sender },
+        Event::StoppedTyping(sender) => Output::StoppedTyping { sender },
+        Event::Viewing(sender) => Output::Viewing { sender },
+        Event::Unknown(msg) => Output::Unknown {
+            command: msg.command.as_ref(),
+            sender: &msg.sender,
+        },
+        Event::Disconnected => Output::Disconnected,
+        Event::Sent {
+            id,
+            receiver,
+            devices,
+        } => Output::Sent {
+            id: *id,
+            receiver,
+            devices: *devices,
+        },
+        Event::Waiting { id, receiver } => Output::Waiting { id: *id, receiver },
+        Event::Undelivered {
+            id,
+            receiver,
+            error,
+        } => Output::Undelivered {
+            id: *id,
+            receiver,
+            error: error.into(),
+        },
+    }
+}
+
+fn invalid(info: &str) -> GlobalError {
+    ClientError::InvalidCommand.info(info)
+}
+
+/// run one command, its answer or failure is written as an event
+/// returns false for `exit`
+async fn handle(client: &ChatClient, input: Input) -> GlobalResult<bool> {
+    let config = client.config();
+    match input {
+        Input::Send { to, text } => {
+            let (uid, device) = device::parse_address(&to);
+            if !device::valid_name(uid) || device.is_some_and(|d| !device::valid_name(d)) {
+                return Err(invalid(&format!("{} is not a valid uid or uid/device", to)));
+            }
+            // reported as `sent` or `undelivered` once the keys are there
+            let id = client.queue(&to, &text);
+            emit(Output::Queued { id, receiver: &to });
+        }
+        Input::List => {
+            let uids = client.list_online().await?;
+            emit(Output::Online {
+                uids: uids.iter().map(String::as_str).collect(),
+            });
+        }
+        Input::Devices { uid } => {
+            let uid = uid.as_deref().unwrap_or(&config.uid);
+            emit_devices(uid, &client.devices(uid).await?);
+        }
+        Input::Revoke { device } => emit_devices(&config.uid, &client.revoke(&device).await?),
+        Input::Approve {
+            device,
+            fingerprint,
+        } => emit_devices(&config.uid, &client.approve(&device, &fingerprint).await?),
+        Input::History { peer, n } => {
+            let history = lock(client.history());
+            let uid = device::parse_address(&peer).0;
+            emit(Output::History {
+                entries: history.conversation(uid, n.unwrap_or(20)),
+            });
+        }
+        Input::Search { text } => {
+            let history = lock(client.history());
+            emit(Output::History {
+                entries: history.search(&text),
+            });
+        }
+        Input::Contacts { uids } => client.subscribe(&uids)?,
+        Input::Status { state, text } => match state.parse() {
+            Ok(state @ (PresenceState::Online | PresenceState::Away)) => {
+                client.set_status(state, &text)?
+            }
+            _ => return Err(invalid("state is online or away")),
+        },
+        Input::Hide { hidden } => client.set_hidden(hidden)?,
+        Input::Exit => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// read JSON commands from stdin and write every event as a JSON line until the input ends,
+/// `exit` is read or the server closes the connection
+pub async fn run(client: Arc<ChatClient>, mut events: Events) -> GlobalResult<()> {
+    let mut lines = io::BufReader::new(io::stdin()).lines();
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let Some(line) = line? else { break };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let result = match serde_json::from_str(&line) {
+                    Ok(input) => handle(&client, input).await,
+                    Err(e) => Err(invalid(&e.to_string())),
+                };
+                match result {
+                    Ok(true) => (),
+                    Ok(false) => break,
+                    Err(e) => emit_error(&e),
+                }
+            }
+            event = events.recv() => match event {
+                Some(event) if emit_event(&event) => (),
+                _ => return Ok(()),
+            },
+        }
+    }
+    // report the outcome of every queued message before quitting
+    client.flush().await;
+    while let Ok(event) = events.try_recv() {
+        emit_event(&event);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn input(line: &str) -> Input {
+        serde_json::from_str(line).unwrap()
+    }
+
+    fn json_of(event: &Event) -> Value {
+        serde_json::to_value(output(event)).unwrap()
+    }
+
+    #[test]
+    fn inputs_are_tagged_by_command() {
+        assert!(matches!(
+            input(r#"{"command":"send","to":"bob/phone","text":"hi"}"#),
+            Input::Send { to, text } if to == "bob/phone" && text == "hi"
+        ));
+        assert!(matches!(input(r#"{"command":"list"}"#), Input::List));
+        assert!(matches!(
+            input(r#"{"command":"devices"}"#),
+            Input::Devices { uid: None }
+        ));
+        assert!(matches!(
+            input(r#"{"command":"approve","device":"laptop","fingerprint":"ab:cd"}"#),
+            Input::Approve { device, fingerprint } if device == "laptop" && fingerprint == "ab:cd"
+        ));
+        assert!(matches!(
+            input(r#"{"command":"history","peer":"bob","n":5}"#),
+            Input::History { peer, n: Some(5) } if peer == "bob"
+        ));
+        assert!(matches!(
+            input(r#"{"command":"status","state":"away"}"#),
+            Input::Status { state, text } if state == "away" && text.is_empty()
+        ));
+        assert!(matches!(
+            input(r#"{"command":"contacts","uids":["bob","carol"]}"#),
+            Input::Contacts { uids } if uids == ["bob", "carol"]
+        ));
+        assert!(matches!(input(r#"{"command":"exit"}"#), Input::Exit));
+    }
+
+    #[test]
+    fn unknown_commands_and_fields_are_refused() {
+        for line in [
+            r#"{"command":"frobnicate"}"#,
+            r#"{"command":"send","to":"bob","text":"hi","cc":"carol"}"#,
+            r#"{"command":"send","to":"bob"}"#,
+            r#"{"to":"bob","text":"hi"}"#,
+        ] {
+            assert!(serde_json::from_str::<Input>(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn outputs_are_tagged_by_event() {
+        let received = Event::Received {
+            sender: "bob/phone".into(),
+            text: "hi".into(),
+        };
+        assert_eq!(
+            json_of(&received),
+            json!({"event": "received", "sender": "bob/phone", "text": "hi"})
+        );
+        let sent = Event::Sent {
+            id: 3,
+            receiver: "bob".into(),
+            devices: 2,
+        };
+        assert_eq!(
+            json_of(&sent),
+            json!({"event": "sent", "id": 3, "receiver": "bob", "devices": 2})
+        );
+        let waiting = Event::Waiting {
+            id: 3,
+            receiver: "bob".into(),
+        };
+        assert_eq!(
+            json_of(&waiting),
+            json!({"event": "waiting", "id": 3, "receiver": "bob"})
+        );
+        let queued = Output::Queued {
+            id: 3,
+            receiver: "bob",
+        };
+        assert_eq!(
+            serde_json::to_value(queued).unwrap(),
+            json!({"event": "queued", "id": 3, "receiver": "bob"})
+        );
+        assert_eq!(
+            json_of(&Event::OnlineList("alice\nbob\n".into())),
+            json!({"event": "online", "uids": ["alice", "bob"]})
+        );
+        assert_eq!(
+            json_of(&Event::StoppedTyping("bob/phone".into())),
+            json!({"event": "stopped_typing", "sender": "bob/phone"})
+        );
+        assert_eq!(
+            json_of(&Event::Disconnected),
+            json!({"event": "disconnected"})
+        );
+    }
+
+    #[test]
+    fn errors_carry_their_stable_code() {
+        let undelivered = Event::Undelivered {
+            id: 4,
+            receiver: "bob".into(),
+            error: ClientError::ReceiverNotExist.info("bob"),
+        };
+        assert_eq!(
+            json_of(&undelivered),
+            json!({
+                "event": "undelivered",
+                "id": 4,
+                "receiver": "bob",
+                "error": {
+                    "code": ClientError::ReceiverNotExist as u16,
+                    "category": "Client",
+                    "name": "ReceiverNotExist",
+                    "info": "bob",
+                },
+            })
+        );
+        let remote = Event::RemoteError {
+            id: 0,
+            error: GlobalError::from(ClientError::Unknown),
+        };
+        assert_eq!(json_of(&remote)["error"]["info"], Value::Null);
+    }
+}
@@ -0,0 +1,10 @@
+pub mod chat;
+pub mod history;
+pub mod init;
+pub mod outbox;
+pub mod peers;
+pub mod profile;
+pub mod worker;
+
+pub use chat::{ChatClient, Events};
+pub use worker::Event;
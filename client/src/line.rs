@@ -0,0 +1,280 @@
+use std::{collections::HashSet, sync::Arc};
+
+use client::{
+    history::{Direction, Entry, Receipt},
+    worker::lock,
+    ChatClient, Event, Events,
+};
+use colored::*;
+use core::{
+    codec::command::Command,
+    device::{self, DeviceInfo},
+    error::GlobalResult,
+    presence::{Presence, PresenceState},
+};
+use tokio::io::{self, AsyncBufReadExt};
+
+use crate::command::{self, Request};
+
+fn print_entries(entries: &[&Entry]) {
+    for Entry {
+        address,
+        direction,
+        text,
+        time,
+        receipt,
+        ..
+    } in entries
+    {
+        let time = chrono::DateTime::parse_from_rfc3339(time)
+            .map(|t| t.format("%Y/%m/%d %H:%M").to_string())
+            .unwrap_or_else(|_| time.clone());
+        let arrow = match direction {
+            Direction::Sent => format!("-> {}", address).green(),
+            Direction::Received => format!("<- {}", address).yellow(),
+        };
+        match receipt {
+            Receipt::Failed => {
+                println!("{} {} {} {}", time.dimmed(), arrow, text, "(failed)".red())
+            }
+            _ => println!("{} {} {}", time.dimmed(), arrow, text),
+        }
+    }
+}
+
+fn print_devices(uid: &str, devices: &[DeviceInfo]) {
+    println!("{} {}", "devices of".green(), uid.yellow());
+    for DeviceInfo {
+        name,
+        online,
+        pending,
+        registered,
+        fingerprint,
+    } in devices
+    {
+        let state = match (pending, online) {
+            (true, _) => "pending".yellow(),
+            (false, true) => "online".green(),
+            (false, false) => "offline".red(),
+        };
+        println!(
+            "  {}\t{}\tsince {}\tkey {}",
+            name, state, registered, fingerprint
+        );
+    }
+}
+
+/// output of line mode, ends after `Disconnected`
+pub fn print_events(mut events: Events) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        println!("{}", "polling the read stream".green());
+        // senders shown as typing, told once until they stop or their message arrives
+        let mut typing = HashSet::new();
+        while let Some(event) = events.recv().await {
+            match event {
+                Event::Received { sender, text } => {
+                    typing.remove(&sender);
+                    println!("{} {}: {}", "from".green(), sender.green(), text.green());
+                }
+                Event::KeyReceived(_) => (),
+                Event::Error(e) => println!("{} {}", "error:".red(), e),
+                Event::OnlineList(list) => println!("{}", list),
+                Event::ServerShutdown(reason) => {
+                    println!("{} {}", "server is shutting down:".red(), reason.yellow());
+                }
+                Event::Kicked(reason) => {
+                    println!("{} {}", "kicked by server:".red(), reason.yellow())
+                }
+                Event::Announcement(text) => println!("{} {}", "announcement:".yellow(), text),
+                Event::RemoteError { id: 0, error } => {
+                    println!("{} {}", "server error:".red(), error)
+                }
+                Event::RemoteError { id, error } => {
+                    println!("{} #{} {} {}", "request".red(), id, "failed:".red(), error);
+                }
+                Event::Presence {
+                    uid,
+                    presence: Presence { state, text },
+                } => {
+                    let state = match state {
+                        PresenceState::Online => "online".green(),
+                        PresenceState::Away => "away".yellow(),
+                        PresenceState::Offline => "offline".red(),
+                    };
+                    match text.is_empty() {
+                        true => println!("{} is {}", uid.yellow(), state),
+                        false => println!("{} is {}: {}", uid.yellow(), state, text),
+                    }
+                }
+                // a line per signal would flood the output, so only a change is shown
+                Event::Typing(sender) => {
+                    if typing.insert(sender.clone()) {
+                        println!("{}", format!("{} is typing…", sender).dimmed());
+                    }
+                }
+                Event::Viewing(sender) => {
+                    println!(
+                        "{}",
+                        format!("{} is viewing the conversation", sender).dimmed()
+                    );
+                }
+                Event::StoppedTyping(sender) => {
+                    typing.remove(&sender);
+                }
+                Event::Unknown(msg) => println!("{:?}", msg),
+                Event::Disconnected => {
+                    println!("{}", "disconnected from server".red());
+                    break;
+                }
+                Event::Sent {
+                    id,
+                    receiver,
+                    devices,
+                } => println!(
+                    "{} #{} {} {} ({} devices)",
+                    "message".green(),
+                    id,
+                    "sent to".green(),
+                    receiver,
+                    devices
+                ),
+                Event::Waiting { id, receiver } => {
+                    println!(
+                        "{} #{} {} {} {}",
+                        "message".yellow(),
+                        id,
+                        "to".yellow(),
+                        receiver.yellow(),
+                        "waits for it to come online".yellow()
+                    );
+                }
+                Event::Undelivered {
+                    id,
+                    receiver,
+                    error,
+                } => {
+                    println!(
+                        "{} #{} {} {} {} {}",
+                        "message".red(),
+                        id,
+                        "to".red(),
+                        receiver.yellow(),
+                        "not sent:".red(),
+                        error
+                    );
+                }
+            }
+        }
+    })
+}
+
+pub fn read_stdin(client: Arc<ChatClient>) -> tokio::task::JoinHandle<GlobalResult<()>> {
+    tokio::spawn(async move {
+        let config = client.config();
+        let history = client.history();
+        let stdin = io::stdin();
+        let mut reader = io::BufReader::new(stdin);
+        let mut line = String::new();
+        // peer of conversation mode, plain lines are sent to it
+        let mut chat: Option<String> = None;
+
+        loop {
+            line.clear();
+            // end of input quits like `exit`
+            if reader.read_line(&mut line).await? == 0 {
+                client.flush().await;
+                break Ok(());
+            }
+            let request = match command::parse(&line, chat.as_deref()) {
+                Ok(Some(request)) => request,
+                Ok(None) => continue,
+                Err(usage) => {
+                    println!("{}", usage.yellow());
+                    continue;
+                }
+            };
+            match request {
+                Request::List => match client.list_online().await {
+                    Ok(uids) => println!("{}", uids.join("\n")),
+                    Err(e) => println!("{} {}", "cannot list online users:".red(), e),
+                },
+                // reported by `print_events` once the keys are there
+                Request::Send { receiver, text } => {
+                    client.queue(&receiver, &text);
+                }
+                // typing cannot be told from stdin, so the peer only learns the chat is open
+                Request::Chat(Some(peer)) => {
+                    if let Some(left) = chat.take() {
+                        client.signal(&left, Command::StoppedTyping)?;
+                    }
+                    client.signal(&peer, Command::Viewing)?;
+                    println!(
+                        "{} {}, {}",
+                        "chatting with".green(),
+                        peer.yellow(),
+                        "/chat to leave".dimmed()
+                    );
+                    chat = Some(peer);
+                }
+                Request::Chat(None) => {
+                    if let Some(peer) = chat.take() {
+                        client.signal(&peer, Command::StoppedTyping)?;
+                        println!("{} {}", "left the chat with".green(), peer.yellow());
+                    }
+                }
+                Request::History { peer, n } => {
+                    // conversations are kept per uid, the one of the current chat when none is given
+                    match peer.as_deref().or(chat.as_deref()) {
+                        Some(peer) => {
+                            let uid = device::parse_address(peer).0;
+                            print_entries(&lock(history).conversation(uid, n));
+                        }
+                        None => println!("{}", "usage: /history <uid> [n]".yellow()),
+                    }
+                }
+                Request::Search(text) => print_entries(&lock(history).search(&text)),
+                // plain JSON, one message per line
+                Request::Export(path) => match lock(history).export(&path) {
+                    Ok(n) => println!("{} {} {}", n, "messages exported to".green(), path),
+                    Err(e) => println!("{} {}", "cannot export:".red(), e),
+                },
+                Request::Devices(uid) => {
+                    let uid = uid.as_deref().unwrap_or(&config.uid);
+                    match client.devices(uid).await {
+                        Ok(devices) => print_devices(uid, &devices),
+                        Err(e) => println!("{} {}", "cannot list devices:".red(), e),
+                    }
+                }
+                // replaces the contacts of config.toml for this session
+                Request::Contacts(contacts) => client.subscribe(&contacts)?,
+                Request::Status { state, text } => client.set_status(state, &text)?,
+                Request::Hide(hidden) => client.set_hidden(hidden)?,
+                Request::Revoke(device) => {
+                    // the server confirms with the remaining devices
+                    match client.revoke(&device).await {
+                        Ok(devices) => print_devices(&config.uid, &devices),
+                        Err(e) => println!("{} {} {}", "cannot revoke".red(), device, e),
+                    }
+                }
+                // the fingerprint is shown by `devices` and by the pending device itself
+                Request::Approve {
+                    device,
+                    fingerprint,
+                } => match client.approve(&device, &fingerprint).await {
+                    Ok(devices) => print_devices(&config.uid, &devices),
+                    Err(e) => println!("{} {} {}", "cannot approve".red(), device, e),
+                },
+                Request::Help(command) => match command::help(command.as_deref()) {
+                    help if help.is_empty() => {
+                        println!("{}", "unknown command, try /help".yellow())
+                    }
+                    help => println!("{}", help),
+                },
+                Request::Exit => {
+                    client.flush().await;
+                    break Ok(());
+                }
+            }
+        }
+    })
+}
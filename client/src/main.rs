@@ -1,31 +1,51 @@
-mod init;
-mod worker;
+mod command;
+mod json;
+mod line;
+mod tui;
 
-use std::{error::Error, sync::Arc};
-use tokio::sync::mpsc;
+use std::{env, error::Error, sync::Arc};
+
+use client::{init, profile, ChatClient};
+use colored::*;
+use core::config::UiMode;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let (addr, config) = init::config()
-        .and_then(init::directory)
-        .and_then(init::encrypt_key)
-        .and_then(init::socket_addr)
-        .unwrap();
-
-    let (rd, mut wt) = worker::connect(addr).await?;
-
-    let (tx, rx) = mpsc::unbounded_channel();
-    let config = Arc::new(config);
-
-    worker::authenticate(&mut wt, Arc::clone(&config)).await?;
-
-    let write_task = worker::write_stream(wt, rx);
-
-    let read_task = worker::read_stream(rd, tx.clone(), Arc::clone(&config));
-
-    let stdin_task = worker::read_stdin(tx.clone(), Arc::clone(&config));
-
-    let _ = tokio::join!(write_task, read_task, stdin_task);
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("profiles") {
+        return Ok(profile::run(&args[1..])?);
+    }
+    // a bad config is reported with one line per problem
+    let config = init::config().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    });
+    // stdout of json mode carries nothing but events
+    let verbose = config.ui != UiMode::Json;
+
+    if verbose {
+        println!("{} {}", "connecting to".green(), config.server_host.yellow());
+    }
+    let (client, events) = ChatClient::connect(config).await?;
+    if verbose {
+        println!("{}", "connection established".green());
+    }
+    let client = Arc::new(client);
+
+    match client.config().ui {
+        UiMode::Line => {
+            let print_task = line::print_events(events);
+            let stdin_task = line::read_stdin(Arc::clone(&client));
+            // quit once the server closes the connection or the user exits
+            tokio::select! {
+                _ = print_task => (),
+                _ = stdin_task => (),
+            }
+        }
+        // the screen stays up after a disconnect until the user quits
+        UiMode::Tui => tui::run(Arc::clone(&client), events).await??,
+        UiMode::Json => json::run(Arc::clone(&client), events).await?,
+    }
 
     Ok(())
 }
@@ -0,0 +1,530 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::Duration,
+};
+
+use core::{
+    codec::message::Message,
+    config::ClientConfig,
+    device::{self, DeviceInfo},
+    error::{ClientError, GlobalResult},
+    traits::encrypt::Encrypt,
+};
+use rsa::RsaPublicKey;
+use tokio::{
+    sync::{
+        mpsc::{self, UnboundedSender},
+        oneshot, Notify,
+    },
+    time::Instant,
+};
+
+use crate::{
+    history::SharedHistory,
+    init::{self, Encryptor},
+    peers::Peers,
+    worker::{self, Event},
+};
+
+pub type SharedOutbox = Arc<Outbox>;
+
+/// request id and number of devices of a sent message
+pub type Delivery = GlobalResult<(u64, usize)>;
+
+struct Queued {
+    // request id of the message, given when it is queued
+    id: u64,
+    receiver: String,
+    text: String,
+    // for `ChatClient::send`, which waits for the outcome
+    outcome: oneshot::Sender<Delivery>,
+    // `Outbox::flushes` when it was queued
+    generation: u64,
+}
+
+/// messages waiting for the devices and keys of their receiver
+/// each uid has its own queue, so messages to it are delivered in the order they were written
+/// while a slow or offline uid does not hold up the others
+pub struct Outbox {
+    tx: UnboundedSender<Message>,
+    events: UnboundedSender<Event>,
+    config: Arc<ClientConfig>,
+    peers: Peers,
+    history: SharedHistory,
+    queues: Mutex<HashMap<String, UnboundedSender<Queued>>>,
+    // messages queued and not yet reported
+    pending: AtomicUsize,
+    idle: Notify,
+    // calls of `flush`, messages queued before the last one give up waiting for a device
+    flushes: AtomicU64,
+    closing: Notify,
+}
+
+impl Outbox {
+    pub fn new(
+        tx: UnboundedSender<Message>,
+        events: UnboundedSender<Event>,
+        config: Arc<ClientConfig>,
+        peers: Peers,
+        history: SharedHistory,
+    ) -> SharedOutbox {
+        Arc::new(Self {
+            tx,
+            events,
+            config,
+            peers,
+            history,
+            queues: Mutex::new(HashMap::new()),
+            pending: AtomicUsize::new(0),
+            idle: Notify::new(),
+            flushes: AtomicU64::new(0),
+            closing: Notify::new(),
+        })
+    }
+
+    /// queue `text` for `receiver`, which is `uid` for every online device or `uid/device` for one
+    /// the outcome is reported as `Event::Sent` or `Event::Undelivered`, and to the returned receiver
+    /// returns the request id of the message as well, which these events carry
+    pub fn push(
+        self: &Arc<Self>,
+        receiver: &str,
+        text: &str,
+    ) -> (u64, oneshot::Receiver<Delivery>) {
+        let uid = device::parse_address(receiver).0.to_string();
+        let id = worker::next_id();
+        let (outcome, delivery) = oneshot::channel();
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        let mut queues = self.queues.lock().unwrap_or_else(PoisonError::into_inner);
+        let queue = queues.entry(uid).or_insert_with(|| self.spawn_queue());
+        let _ = queue.send(Queued {
+            id,
+            receiver: receiver.into(),
+            text: text.into(),
+            outcome,
+            generation: self.flushes.load(Ordering::SeqCst),
+        });
+        (id, delivery)
+    }
+
+    fn spawn_queue(self: &Arc<Self>) -> UnboundedSender<Queued> {
+        let (queue, mut messages) = mpsc::unbounded_channel::<Queued>();
+        let outbox = Arc::clone(self);
+        tokio::spawn(async move {
+            while let Some(Queued {
+                id,
+                receiver,
+                text,
+                outcome,
+                generation,
+            }) = messages.recv().await
+            {
+                let delivery = outbox.deliver(id, &receiver, &text, generation).await;
+                let event = match &delivery {
+                    Ok((id, devices)) => Event::Sent {
+                        id: *id,
+                        receiver,
+                        devices: *devices,
+                    },
+                    Err(error) => Event::Undelivered {
+                        id,
+                        receiver,
+                        error: error.clone(),
+                    },
+                };
+                let _ = outbox.events.send(event);
+                let _ = outcome.send(delivery);
+                if outbox.pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+                    outbox.idle.notify_waiters();
+                }
+            }
+        });
+        queue
+    }
+
+    /// wait until every queued message is sent or given up on
+    /// messages queued so far to uids without an online device are given up on at once,
+    /// those queued later wait for `offline_wait_secs` again
+    pub async fn flush(&self) {
+        self.flushes.fetch_add(1, Ordering::SeqCst);
+        self.closing.notify_waiters();
+        loop {
+            let idle = self.idle.notified();
+            if self.pending.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.config.fetch.timeout_secs)
+    }
+
+    /// online devices of `receiver`, from the cache while it is fresh
+    /// without one the message waits until the uid changes or the cache runs out and asks again,
+    /// for at most `offline_wait_secs` and not past a `flush` after `generation`
+    async fn devices(
+        &self,
+        id: u64,
+        receiver: &str,
+        generation: u64,
+    ) -> GlobalResult<Vec<DeviceInfo>> {
+        let (uid, device) = device::parse_address(receiver);
+        let max_age = Duration::from_secs(self.config.fetch.devices_cache_secs);
+        let deadline = Instant::now() + Duration::from_secs(self.config.fetch.offline_wait_secs);
+        let mut waiting = false;
+        loop {
+            let devices = match self.peers.devices(uid, max_age) {
+                Some(devices) => devices,
+                None => {
+                    let request = Message::device_list(uid).set_id(worker::next_id());
+                    let answer =
+                        worker::request(&self.tx, &self.peers, request, self.timeout()).await?;
+                    DeviceInfo::from_content(&answer.content)
+                }
+            };
+            let devices: Vec<DeviceInfo> = devices
+                .into_iter()
+                .filter(|d| match device {
+                    Some(device) => d.online && d.name == device,
+                    None => d.online,
+                })
+                .collect();
+            if !devices.is_empty() {
+                return Ok(devices);
+            }
+            // registered after the answer above, which would wake it at once
+            let changed = self.peers.expect_change(uid);
+            let closing = self.closing.notified();
+            if self.flushes.load(Ordering::SeqCst) > generation || Instant::now() >= deadline {
+                return Err(ClientError::ReceiverNotExist.info(receiver));
+            }
+            if !waiting {
+                let _ = self.events.send(Event::Waiting {
+                    id,
+                    receiver: receiver.into(),
+                });
+                waiting = true;
+            }
+            tokio::select! {
+                _ = changed => (),
+                _ = tokio::time::sleep_until(deadline.min(Instant::now() + max_age)) => (),
+                _ = closing => (),
+            }
+        }
+    }
+
+    /// key of `uid/device` from memory, then `unsafe_key_dir`, then the server
+    async fn key(&self, uid: &str, device: &str) -> GlobalResult<RsaPublicKey> {
+        let address = device::address(uid, device);
+        if let Some(key) = self.peers.key(&address) {
+            return Ok(key);
+        }
+        let path = init::device_key_path(&self.config.encryption.unsafe_key_dir, uid, device)?;
+        if path.is_file() {
+            let key = Encryptor::async_read_pub_key(path).await?;
+            self.peers.insert_key(&address, key.clone());
+            return Ok(key);
+        }
+        let key = self.peers.expect_key(&address);
+        self.tx
+            .send(Message::get_pub_key(&address).set_id(worker::next_id()))?;
+        tokio::time::timeout(self.timeout(), key)
+            .await
+            .ok()
+            .and_then(Result::ok)
+            .ok_or_else(|| {
+                ClientError::RequestTimeout.info(&format!(
+                    "public key of {} did not arrive within {}s",
+                    address,
+                    self.timeout().as_secs()
+                ))
+            })
+    }
+
+    /// encrypt once for each online device and record the message in the history
+    /// as request `id`, returns it and the number of devices
+    async fn deliver(
+        &self,
+        id: u64,
+        receiver: &str,
+        text: &str,
+        generation: u64,
+    ) -> GlobalResult<(u64, usize)> {
+        let uid = device::parse_address(receiver).0;
+        let devices = self.devices(id, receiver, generation).await?;
+        let mut keys = Vec::with_capacity(devices.len());
+        for DeviceInfo { name, .. } in &devices {
+            keys.push((name, self.key(uid, name).await?));
+        }
+        // the server routes each copy to the session of its device
+        for (name, key) in keys {
+            let ciphertext = {
+                let mut rng = rand::thread_rng();
+                Encryptor::encrypt_from_str(text, &key, &mut rng)?
+            };
+            let address = device::address(uid, name);
+            self.tx
+                .send(Message::send_text(&address, &ciphertext).set_id(id))?;
+        }
+        let verified = worker::verification(&self.config, uid, &devices);
+        worker::lock(&self.history).record_sent(receiver, text, id, devices.len(), verified)?;
+        Ok((id, devices.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{history::History, peers::PeerTable};
+    use core::{codec::command::Command, config::FetchConfig, error::ClientError};
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    struct Harness {
+        outbox: SharedOutbox,
+        peers: Peers,
+        // what the outbox hands to the write task
+        frames: UnboundedReceiver<Message>,
+        events: UnboundedReceiver<Event>,
+        _dir: tempfile::TempDir,
+    }
+
+    fn harness() -> Harness {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = ClientConfig {
+            fetch: FetchConfig {
+                timeout_secs: 5,
+                devices_cache_secs: 60,
+                offline_wait_secs: 30,
+            },
+            ..ClientConfig::default()
+        };
+        let key_dir = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+        config.encryption.unsafe_key_dir = key_dir("unsafe");
+        config.encryption.safe_key_dir = key_dir("safe");
+        let history = History::open(dir.path().join("history"), b"key", "alice/phone").unwrap();
+        let (tx, frames) = mpsc::unbounded_channel();
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let peers = Arc::new(PeerTable::default());
+        let outbox = Outbox::new(
+            tx,
+            events_tx,
+            Arc::new(config),
+            Arc::clone(&peers),
+            Arc::new(Mutex::new(history)),
+        );
+        Harness {
+            outbox,
+            peers,
+            frames,
+            events,
+            _dir: dir,
+        }
+    }
+
+    fn devices(online: bool, names: &[&str]) -> Vec<DeviceInfo> {
+        names
+            .iter()
+            .map(|name| DeviceInfo {
+                name: name.to_string(),
+                online,
+                pending: false,
+                registered: String::new(),
+                fingerprint: String::new(),
+            })
+            .collect()
+    }
+
+    fn key() -> RsaPublicKey {
+        let mut rng = rand::thread_rng();
+        Encryptor::generate_key_pair(&mut rng, 512).unwrap().0
+    }
+
+    /// answer the `DeviceList` request `request` as the server would
+    fn answer(peers: &Peers, request: &Message, devices: &[DeviceInfo]) {
+        let lines: Vec<String> = devices.iter().map(DeviceInfo::to_line).collect();
+        let reply = Message::device_list(&request.receiver)
+            .set_content(lines.join("\n").as_bytes())
+            .set_id(request.id);
+        assert!(peers.answer(reply).is_none());
+    }
+
+    /// `#[tokio::test]` cannot name std's `core` in this workspace, whose own crate takes the name
+    fn block_on<F: std::future::Future>(paused: bool, future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .start_paused(paused)
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn code(delivery: Delivery) -> u16 {
+        delivery.unwrap_err().code()
+    }
+
+    #[test]
+    fn events_carry_the_id_given_when_queued() {
+        block_on(true, async {
+            let mut h = harness();
+            h.peers.insert_key("bob/phone", key());
+            h.peers.store_devices("bob", devices(true, &["phone"]));
+            let (id, delivery) = h.outbox.push("bob", "hi");
+            assert_eq!(delivery.await.unwrap().unwrap(), (id, 1));
+            assert_eq!(h.frames.recv().await.unwrap().id, id);
+            assert!(
+                matches!(h.events.recv().await, Some(Event::Sent { id: sent, .. }) if sent == id)
+            );
+
+            h.peers.store_devices("carol", devices(false, &["phone"]));
+            let (other, delivery) = h.outbox.push("carol", "hi");
+            assert_ne!(other, id);
+            assert!(
+                matches!(h.events.recv().await, Some(Event::Waiting { id, .. }) if id == other)
+            );
+            assert!(delivery.await.unwrap().is_err());
+            assert!(matches!(
+                h.events.recv().await,
+                Some(Event::Undelivered { id, .. }) if id == other
+            ));
+        })
+    }
+
+    #[test]
+    fn unanswered_requests_time_out() {
+        block_on(true, async {
+            let mut h = harness();
+            let (_, delivery) = h.outbox.push("bob", "hi");
+            let request = h.frames.recv().await.unwrap();
+            assert_eq!(request.command, Command::DeviceList);
+            let started = Instant::now();
+            assert_eq!(
+                code(delivery.await.unwrap()),
+                ClientError::RequestTimeout as u16
+            );
+            assert_eq!(started.elapsed(), Duration::from_secs(5));
+
+            // the device list arrives, its key does not
+            let (_, delivery) = h.outbox.push("carol", "hi");
+            let request = h.frames.recv().await.unwrap();
+            answer(&h.peers, &request, &devices(true, &["phone"]));
+            let request = h.frames.recv().await.unwrap();
+            assert_eq!(request.command, Command::GetPubKey);
+            assert_eq!(request.receiver, "carol/phone");
+            assert_eq!(
+                code(delivery.await.unwrap()),
+                ClientError::RequestTimeout as u16
+            );
+        })
+    }
+
+    #[test]
+    fn a_waiting_uid_holds_up_only_its_own_messages() {
+        block_on(false, async {
+            let mut h = harness();
+            h.peers.insert_key("bob/phone", key());
+            h.peers.insert_key("carol/phone", key());
+            let (_, first) = h.outbox.push("bob", "first");
+            let (_, other) = h.outbox.push("carol", "other");
+            let (_, second) = h.outbox.push("bob", "second");
+
+            let mut requests = HashMap::new();
+            for _ in 0..2 {
+                let request = h.frames.recv().await.unwrap();
+                assert_eq!(request.command, Command::DeviceList);
+                requests.insert(request.receiver.clone(), request);
+            }
+            // carol goes ahead while bob's list is outstanding
+            answer(&h.peers, &requests["carol"], &devices(true, &["phone"]));
+            assert_eq!(other.await.unwrap().unwrap().1, 1);
+            assert_eq!(h.frames.recv().await.unwrap().receiver, "carol/phone");
+            assert!(h.frames.try_recv().is_err());
+
+            // bob's messages follow in the order they were written, the second from the cache
+            h.peers.store_devices("bob", devices(true, &["phone"]));
+            answer(&h.peers, &requests["bob"], &devices(true, &["phone"]));
+            let (first, _) = first.await.unwrap().unwrap();
+            let (second, _) = second.await.unwrap().unwrap();
+            assert_eq!(h.frames.recv().await.unwrap().id, first);
+            assert_eq!(h.frames.recv().await.unwrap().id, second);
+            let history = worker::lock(&h.outbox.history);
+            let texts: Vec<&str> = history
+                .conversation("bob", 10)
+                .iter()
+                .map(|e| e.text.as_str())
+                .collect();
+            assert_eq!(texts, vec!["first", "second"]);
+        })
+    }
+
+    #[test]
+    fn offline_receivers_are_waited_for_up_to_offline_wait_secs() {
+        block_on(true, async {
+            let mut h = harness();
+            h.peers.store_devices("bob", devices(false, &["phone"]));
+            let started = Instant::now();
+            let (_, delivery) = h.outbox.push("bob", "anyone there?");
+            assert!(
+                matches!(h.events.recv().await, Some(Event::Waiting { receiver, .. }) if receiver == "bob")
+            );
+            assert_eq!(
+                code(delivery.await.unwrap()),
+                ClientError::ReceiverNotExist as u16
+            );
+            assert_eq!(started.elapsed(), Duration::from_secs(30));
+
+            // a device coming online in time gets the message
+            let (_, delivery) = h.outbox.push("bob", "now?");
+            assert!(matches!(
+                h.events.recv().await,
+                Some(Event::Undelivered { .. })
+            ));
+            assert!(matches!(h.events.recv().await, Some(Event::Waiting { .. })));
+            h.peers.insert_key("bob/phone", key());
+            h.peers.store_devices("bob", devices(true, &["phone"]));
+            assert_eq!(delivery.await.unwrap().unwrap().1, 1);
+            assert_eq!(h.frames.recv().await.unwrap().receiver, "bob/phone");
+        })
+    }
+
+    #[test]
+    fn flush_gives_up_on_waiting_messages() {
+        block_on(false, async {
+            let h = harness();
+            h.peers.store_devices("bob", devices(false, &["phone"]));
+            let (_, delivery) = h.outbox.push("bob", "bye");
+            h.outbox.flush().await;
+            assert_eq!(
+                code(delivery.await.unwrap()),
+                ClientError::ReceiverNotExist as u16
+            );
+        })
+    }
+
+    #[test]
+    fn messages_queued_after_a_flush_wait_again() {
+        block_on(true, async {
+            let mut h = harness();
+            h.peers.store_devices("bob", devices(false, &["phone"]));
+            drop(h.outbox.push("bob", "bye").1);
+            h.outbox.flush().await;
+            // `Waiting` first unless the flush came before the queue looked for devices
+            while !matches!(h.events.recv().await, Some(Event::Undelivered { .. })) {}
+
+            let started = Instant::now();
+            let (_, delivery) = h.outbox.push("bob", "back?");
+            assert!(
+                matches!(h.events.recv().await, Some(Event::Waiting { receiver, .. }) if receiver == "bob")
+            );
+            h.peers.insert_key("bob/phone", key());
+            h.peers.store_devices("bob", devices(true, &["phone"]));
+            assert_eq!(delivery.await.unwrap().unwrap().1, 1);
+            assert_eq!(h.frames.recv().await.unwrap().receiver, "bob/phone");
+            assert!(started.elapsed() < Duration::from_secs(30));
+        })
+    }
+}
@@ -0,0 +1,205 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use core::{
+    codec::message::Message,
+    device::{self, DeviceInfo},
+    error::{GlobalError, GlobalResult},
+};
+use rsa::RsaPublicKey;
+use tokio::sync::oneshot;
+
+pub type Peers = Arc<PeerTable>;
+
+#[derive(Default)]
+struct Inner {
+    // last `DeviceList` of each uid, with the time it arrived
+    devices: HashMap<String, (Instant, Vec<DeviceInfo>)>,
+    // public key of each `uid/device` received or read from `unsafe_key_dir`
+    keys: HashMap<String, RsaPublicKey>,
+    // requests waiting for the frame or `RemoteError` with the same id
+    pending: HashMap<u64, oneshot::Sender<GlobalResult<Message>>>,
+    // tasks waiting for `SendPubKey` from an address
+    key_waiters: HashMap<String, Vec<oneshot::Sender<RsaPublicKey>>>,
+    // tasks waiting for the presence, devices or keys of a uid to change
+    change_waiters: HashMap<String, Vec<oneshot::Sender<()>>>,
+}
+
+impl Inner {
+    fn changed(&mut self, uid: &str) {
+        for tx in self.change_waiters.remove(uid).unwrap_or_default() {
+            let _ = tx.send(());
+        }
+    }
+}
+
+/// what is known about other uids, filled by `read_stream` as the server answers
+#[derive(Default)]
+pub struct PeerTable {
+    inner: Mutex<Inner>,
+}
+
+impl PeerTable {
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// devices of `uid` if they were listed less than `max_age` ago
+    pub fn devices(&self, uid: &str, max_age: Duration) -> Option<Vec<DeviceInfo>> {
+        self.lock()
+            .devices
+            .get(uid)
+            .filter(|(at, _)| at.elapsed() < max_age)
+            .map(|(_, devices)| devices.clone())
+    }
+
+    /// drop the devices of `uid`, e.g. after it went online or offline
+    pub fn forget_devices(&self, uid: &str) {
+        let mut inner = self.lock();
+        inner.devices.remove(uid);
+        inner.changed(uid);
+    }
+
+    /// resolves once the presence, device list or a key of `uid` arrives
+    pub fn expect_change(&self, uid: &str) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        let mut inner = self.lock();
+        let waiters = inner.change_waiters.entry(uid.into()).or_default();
+        waiters.retain(|tx| !tx.is_closed());
+        waiters.push(tx);
+        rx
+    }
+
+    /// store the `DeviceList` of `uid`
+    pub fn store_devices(&self, uid: &str, devices: Vec<DeviceInfo>) {
+        let mut inner = self.lock();
+        inner.devices.insert(uid.into(), (Instant::now(), devices));
+        inner.changed(uid);
+    }
+
+    /// wait for the answer to the request of `id`, register before sending the request
+    pub fn expect(&self, id: u64) -> oneshot::Receiver<GlobalResult<Message>> {
+        let (tx, rx) = oneshot::channel();
+        self.lock().pending.insert(id, tx);
+        rx
+    }
+
+    /// stop waiting for the request of `id`
+    pub fn cancel(&self, id: u64) {
+        self.lock().pending.remove(&id);
+    }
+
+    /// hand `msg` to the request it answers, or give it back if none waits for it
+    pub fn answer(&self, msg: Message) -> Option<Message> {
+        let tx = match msg.id {
+            0 => None,
+            id => self.lock().pending.remove(&id),
+        };
+        match tx {
+            Some(tx) => {
+                let _ = tx.send(Ok(msg));
+                None
+            }
+            None => Some(msg),
+        }
+    }
+
+    /// fail the request of `id` with the `RemoteError` the server answered
+    /// false if no request waits for it, so the error is someone else's to show
+    pub fn reject(&self, id: u64, error: GlobalError) -> bool {
+        match self.lock().pending.remove(&id) {
+            Some(tx) => {
+                let _ = tx.send(Err(error));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn key(&self, address: &str) -> Option<RsaPublicKey> {
+        self.lock().keys.get(address).cloned()
+    }
+
+    /// cache a key read from disk
+    pub fn insert_key(&self, address: &str, key: RsaPublicKey) {
+        self.lock().keys.insert(address.into(), key);
+    }
+
+    /// wait for the key of `address`, register before sending `GetPubKey`
+    pub fn expect_key(&self, address: &str) -> oneshot::Receiver<RsaPublicKey> {
+        let (tx, rx) = oneshot::channel();
+        let mut inner = self.lock();
+        let waiters = inner.key_waiters.entry(address.into()).or_default();
+        // waiters that timed out
+        waiters.retain(|tx| !tx.is_closed());
+        waiters.push(tx);
+        rx
+    }
+
+    /// store the key of `address` and hand it to everyone waiting for it
+    pub fn resolve_key(&self, address: &str, key: RsaPublicKey) {
+        let mut inner = self.lock();
+        for tx in inner.key_waiters.remove(address).unwrap_or_default() {
+            let _ = tx.send(key.clone());
+        }
+        inner.keys.insert(address.into(), key);
+        inner.changed(device::parse_address(address).0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn phone() -> Vec<DeviceInfo> {
+        vec![DeviceInfo {
+            name: "phone".into(),
+            online: true,
+            pending: false,
+            registered: String::new(),
+            fingerprint: String::new(),
+        }]
+    }
+
+    #[test]
+    fn device_lists_expire() {
+        let peers = PeerTable::default();
+        assert!(peers.devices("bob", Duration::from_secs(60)).is_none());
+        peers.store_devices("bob", phone());
+        assert_eq!(peers.devices("bob", Duration::from_secs(60)), Some(phone()));
+        // older than `max_age`, so asked for again
+        assert!(peers.devices("bob", Duration::ZERO).is_none());
+        peers.forget_devices("bob");
+        assert!(peers.devices("bob", Duration::from_secs(60)).is_none());
+    }
+
+    #[test]
+    fn new_device_lists_wake_waiters() {
+        let peers = PeerTable::default();
+        let mut bob = peers.expect_change("bob");
+        let mut carol = peers.expect_change("carol");
+        peers.store_devices("bob", phone());
+        assert!(bob.try_recv().is_ok());
+        assert!(carol.try_recv().is_err());
+        peers.forget_devices("carol");
+        assert!(carol.try_recv().is_ok());
+    }
+
+    #[test]
+    fn answers_go_to_their_request_only() {
+        let peers = PeerTable::default();
+        let mut answer = peers.expect(7);
+        let cancelled = peers.expect(8);
+        peers.cancel(8);
+        drop(cancelled);
+        // unknown ids and id 0 are handed back
+        assert!(peers.answer(Message::device_list("bob")).is_some());
+        assert!(peers.answer(Message::device_list("bob").set_id(8)).is_some());
+        assert!(peers.answer(Message::device_list("bob").set_id(7)).is_none());
+        assert_eq!(answer.try_recv().unwrap().unwrap().receiver, "bob");
+        assert!(!peers.reject(7, GlobalError::from("gone".to_string())));
+    }
+}
@@ -0,0 +1,465 @@
+use std::{
+    env, fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use colored::*;
+use core::{
+    config::{ClientConfig, Config, Overrides},
+    device,
+    error::{ExternalError, GlobalResult},
+    layout::Layout,
+};
+
+/// `profiles/<name>.toml` next to the client config of the layout
+pub fn config_path(name: &str) -> PathBuf {
+    Profiles::of(Layout::current()).config_path(name)
+}
+
+/// keys, trust store and history of a profile
+pub fn data_dir(name: &str) -> PathBuf {
+    Profiles::of(Layout::current()).data_dir(name)
+}
+
+/// config file of an existing profile
+pub fn open(name: &str) -> GlobalResult<PathBuf> {
+    Profiles::of(Layout::current()).open(name)
+}
+
+/// every profile by name, with its config or the reason it cannot be read
+/// only the files are read, nothing is resolved, checked or rewritten
+pub fn list() -> GlobalResult<Vec<(String, GlobalResult<ClientConfig>)>> {
+    Profiles::of(Layout::current()).list()
+}
+
+/// write the config of a new profile, the defaults with `overrides` applied
+/// its keys, trust store and history are kept apart from every other profile
+/// the key pair is generated on the first connection
+pub fn create(name: &str, overrides: &Overrides) -> GlobalResult<ClientConfig> {
+    Profiles::of(Layout::current()).create(name, overrides)
+}
+
+/// remove the config of a profile along with its keys and history
+/// returns what was removed
+pub fn delete(name: &str) -> GlobalResult<Vec<PathBuf>> {
+    Profiles::of(Layout::current()).delete(name)
+}
+
+fn check(name: &str) -> GlobalResult<()> {
+    match device::valid_name(name) {
+        true => Ok(()),
+        false => Err(ExternalError::Initialize.info(&format!(
+            "{} is not a valid profile name, use letters, digits, '-', '_' and '.'",
+            name
+        ))),
+    }
+}
+
+/// the two `profiles` directories of a layout, every path of a profile is inside one of them
+struct Profiles {
+    config: PathBuf,
+    data: PathBuf,
+}
+
+impl Profiles {
+    fn of(layout: Layout) -> Self {
+        let main = layout.config_path("client");
+        let dir = main.parent().unwrap_or(Path::new("./"));
+        Profiles {
+            config: dir.join("profiles"),
+            data: layout.data_dir().join("profiles"),
+        }
+    }
+
+    fn config_path(&self, name: &str) -> PathBuf {
+        self.config.join(format!("{}.toml", name))
+    }
+
+    fn data_dir(&self, name: &str) -> PathBuf {
+        self.data.join(name)
+    }
+
+    fn open(&self, name: &str) -> GlobalResult<PathBuf> {
+        check(name)?;
+        let path = self.config_path(name);
+        match path.is_file() {
+            true => Ok(path),
+            false => Err(ExternalError::Initialize.info(&format!(
+                "there is no profile {}, create it with `client profiles create {} --uid <uid> --server_host <host:port>`",
+                name, name
+            ))),
+        }
+    }
+
+    fn list(&self) -> GlobalResult<Vec<(String, GlobalResult<ClientConfig>)>> {
+        if !self.config.is_dir() {
+            return Ok(Vec::new());
+        }
+        let mut profiles = Vec::new();
+        for entry in fs::read_dir(&self.config)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("toml") {
+                continue;
+            }
+            let Some(name) = path.file_stem().map(|s| s.to_string_lossy().to_string()) else {
+                continue;
+            };
+            profiles.push((name, ClientConfig::read_file(&path)));
+        }
+        profiles.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(profiles)
+    }
+
+    fn create(&self, name: &str, overrides: &Overrides) -> GlobalResult<ClientConfig> {
+        check(name)?;
+        let path = self.config_path(name);
+        if path.try_exists()? {
+            return Err(ExternalError::Initialize.info(&format!("profile {} already exists", name)));
+        }
+        let dir = self.data_dir(name);
+        let dir_of = |sub: &str| dir.join(sub).to_string_lossy().to_string();
+        let mut config = ClientConfig {
+            history_dir: dir_of("history"),
+            ..Default::default()
+        };
+        config.encryption.self_key_dir = dir_of("self_key");
+        config.encryption.unsafe_key_dir = dir_of("unsafe_key");
+        config.encryption.safe_key_dir = dir_of("safe_key");
+
+        let default = toml::Value::try_from(&config)?;
+        let config: ClientConfig = overrides.apply(&default, default.clone())?.try_into()?;
+        if !device::valid_name(&config.uid) || !device::valid_name(&config.device) {
+            return Err(ExternalError::Initialize
+                .info("uid and device may only contain letters, digits, '-', '_' and '.'"));
+        }
+        fs::create_dir_all(&self.config)?;
+        fs::write(&path, toml::to_string_pretty(&config)?)?;
+        Ok(config)
+    }
+
+    fn delete(&self, name: &str) -> GlobalResult<Vec<PathBuf>> {
+        let path = self.open(name)?;
+        fs::remove_file(&path)?;
+        let mut removed = vec![path];
+        let dir = self.data_dir(name);
+        if dir.is_dir() {
+            fs::remove_dir_all(&dir)?;
+            removed.push(dir);
+        }
+        Ok(removed)
+    }
+}
+
+const USAGE: &str = "\
+usage: client profiles [list]
+       client profiles create <name> --uid <uid> --server_host <host:port> [--key value ...]
+       client profiles delete <name> [--yes]
+
+start a profile with `client --profile <name>`, every key of config.toml can be given to create";
+
+/// what `client profiles ...` was asked to do
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    List,
+    /// name and the `--key value` settings of the new profile
+    Create(String, Vec<String>),
+    /// name and whether `--yes` was given
+    Delete(String, bool),
+}
+
+/// the command in `args` and the layout that tells where the profiles are
+/// `layout` comes from `JHCHAT_LAYOUT` and is replaced by a `--layout` argument
+fn parse(args: &[String], mut layout: Option<Layout>) -> GlobalResult<(Command, Option<Layout>)> {
+    let (command, args) = match args.split_first() {
+        Some((command, args)) => (command.as_str(), args),
+        None => ("list", args),
+    };
+    let mut rest = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), arg.strip_prefix("--layout=")) {
+            ("--layout", _) => {
+                layout = Some(Layout::parse(args.next().map_or("", String::as_str))?)
+            }
+            (_, Some(value)) => layout = Some(Layout::parse(value)?),
+            _ => rest.push(arg.clone()),
+        }
+    }
+    let command = match (command, rest.as_slice()) {
+        ("list", []) => Command::List,
+        ("create", [name, settings @ ..]) => Command::Create(name.clone(), settings.to_vec()),
+        ("delete", [name]) => Command::Delete(name.clone(), false),
+        ("delete", [name, yes]) if yes == "--yes" => Command::Delete(name.clone(), true),
+        _ => return Err(ExternalError::Initialize.info(USAGE)),
+    };
+    Ok((command, layout))
+}
+
+/// `client profiles ...`, `args` follow `profiles`
+pub fn run(args: &[String]) -> GlobalResult<()> {
+    // `--layout` or `JHCHAT_LAYOUT` tells where the profiles are, it is no setting of one
+    let layout = Overrides::parse(env::vars(), std::iter::empty(), &[])?.layout;
+    let (command, layout) = parse(args, layout)?;
+    layout.unwrap_or_else(Layout::detect).install();
+
+    match command {
+        Command::List => print_list(),
+        Command::Create(name, settings) => {
+            // variables are meant for a running client, they do not end up in the profile
+            let overrides = Overrides::parse(std::iter::empty(), settings, &[])?;
+            let config = create(&name, &overrides)?;
+            println!(
+                "{} {} {} {}/{}@{}",
+                "profile".green(),
+                name.yellow(),
+                "created for".green(),
+                config.uid,
+                config.device,
+                config.server_host
+            );
+            println!("{}", config_path(&name).to_string_lossy().dimmed());
+            Ok(())
+        }
+        Command::Delete(name, yes) => confirm_delete(&name, yes),
+    }
+}
+
+fn print_list() -> GlobalResult<()> {
+    let profiles = list()?;
+    if profiles.is_empty() {
+        println!(
+            "{}",
+            "no profiles, create one with `client profiles create`".yellow()
+        );
+    }
+    for (name, config) in profiles {
+        match config {
+            Ok(config) => println!(
+                "{}\t{}/{}@{}",
+                name.yellow(),
+                config.uid,
+                config.device,
+                config.server_host
+            ),
+            Err(e) => println!("{}\t{} {}", name.yellow(), "cannot be read:".red(), e),
+        }
+    }
+    Ok(())
+}
+
+/// keys and history cannot be recovered, so ask first unless `--yes` is given
+fn confirm_delete(name: &str, yes: bool) -> GlobalResult<()> {
+    open(name)?;
+    if !yes {
+        print!(
+            "{} {} {} ",
+            "delete profile".red(),
+            name.yellow(),
+            "with its keys and history? [y/N]".red()
+        );
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        io::stdin().lock().read_line(&mut answer)?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            println!("{}", "nothing deleted".green());
+            return Ok(());
+        }
+    }
+    for path in delete(name)? {
+        println!("{} {}", "removed".green(), path.to_string_lossy());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profiles(root: &Path) -> Profiles {
+        Profiles {
+            config: root.join("config").join("profiles"),
+            data: root.join("data").join("profiles"),
+        }
+    }
+
+    fn overrides(settings: &[&str]) -> Overrides {
+        let settings = settings.iter().map(|s| s.to_string());
+        Overrides::parse(std::iter::empty(), settings, &[]).unwrap()
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn create_keeps_each_profile_apart_and_list_reads_them() {
+        let root = tempfile::tempdir().unwrap();
+        let profiles = profiles(root.path());
+        assert!(profiles.list().unwrap().is_empty());
+
+        let work = profiles
+            .create(
+                "work",
+                &overrides(&["--uid", "alice", "--server_host", "work:7000"]),
+            )
+            .unwrap();
+        assert_eq!(work.uid, "alice");
+        assert_eq!(work.server_host, "work:7000");
+        let dir = profiles.data_dir("work");
+        assert_eq!(work.history_dir, dir.join("history").to_string_lossy());
+        assert_eq!(
+            work.encryption.self_key_dir,
+            dir.join("self_key").to_string_lossy()
+        );
+        assert_eq!(
+            work.encryption.safe_key_dir,
+            dir.join("safe_key").to_string_lossy()
+        );
+        assert_eq!(
+            work.encryption.unsafe_key_dir,
+            dir.join("unsafe_key").to_string_lossy()
+        );
+        assert!(profiles.create("work", &overrides(&[])).is_err());
+
+        profiles
+            .create("home", &overrides(&["--uid", "bob"]))
+            .unwrap();
+        fs::write(profiles.config.join("broken.toml"), "uid = [").unwrap();
+        fs::write(profiles.config.join("notes.txt"), "").unwrap();
+        let list = profiles.list().unwrap();
+        let names: Vec<_> = list.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["broken", "home", "work"]);
+        assert!(list[0].1.is_err());
+        assert_eq!(list[1].1.as_ref().unwrap().uid, "bob");
+        assert_eq!(list[2].1.as_ref().unwrap().server_host, "work:7000");
+        assert_eq!(profiles.open("work").unwrap(), profiles.config_path("work"));
+        assert!(profiles.open("school").is_err());
+    }
+
+    #[test]
+    fn create_refuses_invalid_names() {
+        let root = tempfile::tempdir().unwrap();
+        let profiles = profiles(root.path());
+        for name in ["", ".", "..", "../work", "a/b", "Server"] {
+            assert!(
+                profiles.create(name, &overrides(&[])).is_err(),
+                "{:?}",
+                name
+            );
+        }
+        assert!(profiles
+            .create("work", &overrides(&["--uid", "../alice"]))
+            .is_err());
+        assert!(!profiles.config.exists());
+        assert!(!root.path().join("data").exists());
+    }
+
+    #[test]
+    fn delete_removes_only_the_profile() {
+        let root = tempfile::tempdir().unwrap();
+        let profiles = profiles(root.path());
+        profiles.create("work", &overrides(&[])).unwrap();
+        profiles.create("home", &overrides(&[])).unwrap();
+        for name in ["work", "home"] {
+            fs::create_dir_all(profiles.data_dir(name).join("history")).unwrap();
+            fs::write(profiles.data_dir(name).join("history").join("bob"), "").unwrap();
+        }
+        // a sibling of the profiles directory and the client config are no profile
+        fs::write(root.path().join("data").join("keep"), "").unwrap();
+        fs::write(root.path().join("config").join("client.toml"), "").unwrap();
+
+        for name in ["", ".", "..", "../data", "work/..", "school"] {
+            assert!(profiles.delete(name).is_err(), "{:?}", name);
+        }
+        let removed = profiles.delete("work").unwrap();
+        assert_eq!(
+            removed,
+            [profiles.config_path("work"), profiles.data_dir("work")]
+        );
+        assert!(!profiles.config_path("work").exists());
+        assert!(!profiles.data_dir("work").exists());
+
+        assert!(profiles.config_path("home").is_file());
+        assert!(profiles
+            .data_dir("home")
+            .join("history")
+            .join("bob")
+            .is_file());
+        assert!(root.path().join("data").join("keep").is_file());
+        assert!(root.path().join("config").join("client.toml").is_file());
+        assert!(profiles.delete("work").is_err());
+    }
+
+    #[test]
+    fn delete_without_data_removes_the_config() {
+        let root = tempfile::tempdir().unwrap();
+        let profiles = profiles(root.path());
+        profiles.create("work", &overrides(&[])).unwrap();
+        let removed = profiles.delete("work").unwrap();
+        assert_eq!(removed, [profiles.config_path("work")]);
+    }
+
+    #[test]
+    fn parse_reads_the_command_and_the_layout() {
+        assert_eq!(parse(&[], None).unwrap(), (Command::List, None));
+        assert_eq!(
+            parse(&args(&["list"]), None).unwrap(),
+            (Command::List, None)
+        );
+        assert_eq!(
+            parse(&args(&["create", "work", "--uid", "alice"]), None).unwrap(),
+            (
+                Command::Create("work".into(), args(&["--uid", "alice"])),
+                None
+            )
+        );
+        assert_eq!(
+            parse(&args(&["delete", "work"]), None).unwrap(),
+            (Command::Delete("work".into(), false), None)
+        );
+        assert_eq!(
+            parse(&args(&["delete", "work", "--yes"]), None).unwrap(),
+            (Command::Delete("work".into(), true), None)
+        );
+
+        // an argument replaces the variable, wherever it is given
+        let env = Some(Layout::Portable);
+        assert_eq!(parse(&args(&["list"]), env).unwrap(), (Command::List, env));
+        assert_eq!(
+            parse(&args(&["list", "--layout", "xdg"]), env).unwrap(),
+            (Command::List, Some(Layout::Xdg))
+        );
+        assert_eq!(
+            parse(&args(&["delete", "--layout=xdg", "work", "--yes"]), env).unwrap(),
+            (Command::Delete("work".into(), true), Some(Layout::Xdg))
+        );
+        assert_eq!(
+            parse(
+                &args(&["create", "work", "--layout", "xdg", "--uid", "alice"]),
+                None
+            )
+            .unwrap(),
+            (
+                Command::Create("work".into(), args(&["--uid", "alice"])),
+                Some(Layout::Xdg)
+            )
+        );
+    }
+
+    #[test]
+    fn parse_refuses_anything_else() {
+        for wrong in [
+            &["list", "work"][..],
+            &["create"],
+            &["delete"],
+            &["delete", "work", "--force"],
+            &["delete", "work", "--yes", "now"],
+            &["rename", "work"],
+            &["list", "--layout"],
+            &["list", "--layout=flat"],
+        ] {
+            assert!(parse(&args(wrong), None).is_err(), "{:?}", wrong);
+        }
+    }
+}
@@ -0,0 +1,813 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Stdout},
+    sync::Arc,
+    time::Duration,
+};
+
+use client::{
+    history::{Direction, Entry, Receipt, Verification},
+    worker::{self, Event},
+    ChatClient, Events,
+};
+use core::{
+    codec::{command::Command, message::Message},
+    device,
+    error::GlobalResult,
+    presence::{Presence, PresenceState},
+};
+use crossterm::{
+    event::{Event as TermEvent, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    execute,
+    terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+    Frame, Terminal,
+};
+use tokio_stream::StreamExt;
+
+use crate::command::{self, Request};
+
+const USAGE: &str = "/chat <uid> opens a conversation, /help lists the commands, /exit quits";
+
+/// raw mode and the alternate screen, restored when dropped so a panic leaves a usable terminal
+struct Screen(Terminal<CrosstermBackend<Stdout>>);
+
+impl Screen {
+    fn enter() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        execute!(io::stdout(), EnterAlternateScreen)?;
+        Ok(Self(Terminal::new(CrosstermBackend::new(io::stdout()))?))
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+        let _ = execute!(io::stdout(), LeaveAlternateScreen);
+        let _ = self.0.show_cursor();
+    }
+}
+
+/// line being edited, with the lines entered before it
+#[derive(Default)]
+struct Input {
+    chars: Vec<char>,
+    cursor: usize,
+    entered: Vec<String>,
+    // position in `entered` while recalling with up and down
+    recall: Option<usize>,
+}
+
+impl Input {
+    fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    fn set(&mut self, text: &str) {
+        self.chars = text.chars().collect();
+        self.cursor = self.chars.len();
+    }
+
+    fn insert(&mut self, c: char) {
+        self.chars.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.cursor < self.chars.len() {
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    /// the entered line, remembered for recall
+    fn take(&mut self) -> String {
+        let text = self.text();
+        if !text.trim().is_empty() && self.entered.last() != Some(&text) {
+            self.entered.push(text.clone());
+        }
+        self.set("");
+        self.recall = None;
+        text
+    }
+
+    fn older(&mut self) {
+        let recall = match self.recall {
+            Some(0) => return,
+            Some(n) => n - 1,
+            None if self.entered.is_empty() => return,
+            None => self.entered.len() - 1,
+        };
+        self.recall = Some(recall);
+        let text = self.entered[recall].clone();
+        self.set(&text);
+    }
+
+    fn newer(&mut self) {
+        match self.recall {
+            Some(n) if n + 1 < self.entered.len() => {
+                self.recall = Some(n + 1);
+                let text = self.entered[n + 1].clone();
+                self.set(&text);
+            }
+            Some(_) => {
+                self.recall = None;
+                self.set("");
+            }
+            None => (),
+        }
+    }
+
+    /// move the cursor, edit or recall as `key` says, other keys are ignored
+    fn edit(&mut self, key: &KeyEvent) {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('a') if ctrl => self.cursor = 0,
+            KeyCode::Char('e') if ctrl => self.cursor = self.chars.len(),
+            KeyCode::Char('u') if ctrl => self.set(""),
+            KeyCode::Char(c) => self.insert(c),
+            KeyCode::Backspace => self.backspace(),
+            KeyCode::Delete => self.delete(),
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.chars.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.chars.len(),
+            KeyCode::Up => self.older(),
+            KeyCode::Down => self.newer(),
+            _ => (),
+        }
+    }
+}
+
+/// uids with a conversation, contacts first, and the one shown
+#[derive(Default)]
+struct Conversations {
+    uids: Vec<String>,
+    selected: usize,
+}
+
+impl Conversations {
+    fn current(&self) -> Option<&String> {
+        self.uids.get(self.selected)
+    }
+
+    /// add `uid` at the end unless it has a conversation, returns where it is
+    fn add(&mut self, uid: &str) -> usize {
+        match self.uids.iter().position(|c| c == uid) {
+            Some(n) => n,
+            None => {
+                self.uids.push(uid.into());
+                self.uids.len() - 1
+            }
+        }
+    }
+
+    /// `step` places after the selected conversation, wrapping around, `None` if there is none
+    fn step(&self, step: isize) -> Option<usize> {
+        let n = self.uids.len() as isize;
+        (n > 0).then(|| (self.selected as isize + step).rem_euclid(n) as usize)
+    }
+
+    /// remove the selected conversation, the next one takes its place, or the one before it
+    /// if it was the last
+    fn remove_selected(&mut self) -> Option<String> {
+        if self.selected >= self.uids.len() {
+            return None;
+        }
+        let uid = self.uids.remove(self.selected);
+        self.selected = self.selected.min(self.uids.len().saturating_sub(1));
+        Some(uid)
+    }
+}
+
+struct App {
+    client: Arc<ChatClient>,
+    conversations: Conversations,
+    unread: HashMap<String, usize>,
+    presence: HashMap<String, Presence>,
+    // whether the keys of each uid verify, worked out again when its keys or devices arrive
+    trust: HashMap<String, Verification>,
+    // uids with a device typing to this one
+    typing: HashSet<String>,
+    // uid whose devices were asked for, shown once its device list arrives
+    listing: Option<String>,
+    // `Typing` has been sent to the selected conversation
+    typing_sent: bool,
+    input: Input,
+    // messages scrolled up from the newest one
+    scroll: usize,
+    notice: String,
+    connected: bool,
+    quit: bool,
+}
+
+impl App {
+    fn new(client: Arc<ChatClient>) -> Self {
+        let mut conversations = Conversations {
+            uids: client.config().contacts.clone(),
+            selected: 0,
+        };
+        for peer in worker::lock(client.history()).peers() {
+            conversations.add(&peer);
+        }
+        Self {
+            client,
+            conversations,
+            unread: HashMap::new(),
+            presence: HashMap::new(),
+            trust: HashMap::new(),
+            typing: HashSet::new(),
+            listing: None,
+            typing_sent: false,
+            input: Input::default(),
+            scroll: 0,
+            notice: USAGE.into(),
+            connected: true,
+            quit: false,
+        }
+    }
+
+    fn current(&self) -> Option<&String> {
+        self.conversations.current()
+    }
+
+    fn signal(&self, command: Command) {
+        if let Some(peer) = self.current() {
+            let _ = self.client.signal(peer, command);
+        }
+    }
+
+    /// send `Typing` when the input starts holding a message, `StoppedTyping` when it no longer does
+    fn update_typing(&mut self) {
+        let text = self.input.text();
+        let typing = !text.trim().is_empty() && !text.starts_with('/');
+        if typing != self.typing_sent {
+            match typing {
+                true => self.signal(Command::Typing),
+                false => self.signal(Command::StoppedTyping),
+            }
+            self.typing_sent = typing;
+        }
+    }
+
+    fn select(&mut self, selected: usize) {
+        if self.typing_sent {
+            self.signal(Command::StoppedTyping);
+            self.typing_sent = false;
+        }
+        self.conversations.selected = selected;
+        self.scroll = 0;
+        if let Some(peer) = self.current().cloned() {
+            self.unread.remove(&peer);
+            self.signal(Command::Viewing);
+        }
+        self.update_typing();
+    }
+
+    fn open(&mut self, uid: &str) {
+        let selected = self.conversations.add(uid);
+        self.select(selected);
+    }
+
+    fn on_event(&mut self, event: Event) {
+        match event {
+            Event::Received { sender, .. } => {
+                let uid = device::parse_address(&sender).0.to_string();
+                self.typing.remove(&uid);
+                self.conversations.add(&uid);
+                if self.current() != Some(&uid) {
+                    *self.unread.entry(uid).or_default() += 1;
+                }
+            }
+            Event::Error(e) => self.notice = format!("error: {}", e),
+            Event::KeyReceived(uid) => {
+                // the last known devices, however old
+                let devices = self.client.peers().devices(&uid, Duration::MAX);
+                if let Some(devices) = devices {
+                    if self.listing.as_ref() == Some(&uid) {
+                        let names: Vec<_> = devices
+                            .iter()
+                            .map(|d| match (d.pending, d.online) {
+                                (true, _) => format!("{} pending {}", d.name, d.fingerprint),
+                                (false, true) => format!("{} online", d.name),
+                                (false, false) => format!("{} offline", d.name),
+                            })
+                            .collect();
+                        self.notice = format!("devices of {}: {}", uid, names.join(", "));
+                        self.listing = None;
+                    }
+                    let verification = worker::verification(self.client.config(), &uid, &devices);
+                    self.trust.insert(uid, verification);
+                }
+            }
+            Event::OnlineList(list) => {
+                self.notice = format!("online: {}", list.lines().collect::<Vec<_>>().join(", "));
+            }
+            Event::ServerShutdown(reason) => {
+                self.notice = format!("server is shutting down: {}", reason)
+            }
+            Event::Kicked(reason) => self.notice = format!("kicked by server: {}", reason),
+            Event::Announcement(text) => self.notice = format!("announcement: {}", text),
+            Event::RemoteError { id: 0, error } => self.notice = format!("server error: {}", error),
+            Event::RemoteError { id, error } => {
+                self.notice = format!("request #{} failed: {}", id, error);
+            }
+            Event::Presence { uid, presence } => {
+                if presence.state == PresenceState::Offline {
+                    self.typing.remove(&uid);
+                }
+                self.presence.insert(uid, presence);
+            }
+            Event::Typing(sender) => {
+                self.typing.insert(device::parse_address(&sender).0.into());
+            }
+            Event::StoppedTyping(sender) => {
+                self.typing.remove(device::parse_address(&sender).0);
+            }
+            Event::Viewing(sender) => {
+                self.notice = format!("{} is viewing the conversation", sender)
+            }
+            Event::Unknown(msg) => self.notice = format!("{:?}", msg),
+            Event::Sent {
+                id,
+                receiver,
+                devices,
+            } => {
+                self.notice = format!("message #{} sent to {} ({} devices)", id, receiver, devices)
+            }
+            Event::Waiting { id, receiver } => {
+                self.notice = format!("message #{} to {} waits for it to come online", id, receiver);
+            }
+            Event::Undelivered {
+                id,
+                receiver,
+                error,
+            } => {
+                self.notice = format!("message #{} to {} not sent: {}", id, receiver, error);
+            }
+            Event::Disconnected => {
+                self.connected = false;
+                self.typing.clear();
+                self.notice = "disconnected from server, ctrl-c to quit".into();
+            }
+        }
+    }
+
+    fn on_key(&mut self, key: KeyEvent) {
+        if key.kind != KeyEventKind::Press {
+            return;
+        }
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('c') if ctrl => self.quit = true,
+            KeyCode::PageUp => self.scroll += 10,
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+            KeyCode::Tab => {
+                if let Some(next) = self.conversations.step(1) {
+                    self.select(next);
+                }
+            }
+            KeyCode::BackTab => {
+                if let Some(previous) = self.conversations.step(-1) {
+                    self.select(previous);
+                }
+            }
+            KeyCode::Enter => {
+                let line = self.input.take();
+                self.submit(&line);
+            }
+            _ => self.input.edit(&key),
+        }
+        self.update_typing();
+    }
+
+    fn close(&mut self) {
+        // told while it is still the selected one, not the one that takes its place
+        if self.typing_sent {
+            self.signal(Command::StoppedTyping);
+            self.typing_sent = false;
+        }
+        if let Some(peer) = self.conversations.remove_selected() {
+            self.unread.remove(&peer);
+            self.select(self.conversations.selected);
+        }
+    }
+
+    fn request(&self, msg: Message) {
+        let _ = self.client.send_frame(msg.set_id(worker::next_id()));
+    }
+
+    /// the commands of line mode, plain lines go to the open conversation
+    fn submit(&mut self, line: &str) {
+        let peer = self.current().cloned();
+        let text = line.trim();
+        if peer.is_none() && !text.is_empty() && !text.starts_with('/') {
+            self.notice = "no conversation is open, /chat <uid> first".into();
+            return;
+        }
+        let request = match command::parse(line, peer.as_deref()) {
+            Ok(Some(request)) => request,
+            Ok(None) => return,
+            Err(usage) => {
+                self.notice = usage;
+                return;
+            }
+        };
+        match request {
+            Request::Send { receiver, text } => self.send(receiver, &text),
+            // conversations are kept per uid
+            Request::Chat(Some(peer)) => self.open(device::parse_address(&peer).0),
+            Request::Chat(None) => self.close(),
+            Request::List => self.request(Message::online_list("")),
+            // the message pane shows the history of the open conversation
+            Request::History { peer, .. } => {
+                if let Some(peer) = peer {
+                    self.open(device::parse_address(&peer).0);
+                }
+            }
+            Request::Search(text) => {
+                let history = worker::lock(self.client.history());
+                let found = history.search(&text);
+                self.notice = match found.last() {
+                    Some(latest) => format!(
+                        "{} messages contain {:?}, the latest with {}: {}",
+                        found.len(),
+                        text,
+                        latest.address,
+                        latest.text
+                    ),
+                    None => format!("no message contains {:?}", text),
+                };
+            }
+            Request::Export(path) => {
+                self.notice = match worker::lock(self.client.history()).export(&path) {
+                    Ok(n) => format!("{} messages exported to {}", n, path),
+                    Err(e) => format!("cannot export: {}", e),
+                };
+            }
+            Request::Devices(uid) => {
+                let uid = uid.unwrap_or_else(|| self.client.config().uid.clone());
+                self.request(Message::device_list(&uid));
+                self.listing = Some(uid);
+            }
+            // the server confirms with the remaining devices
+            Request::Revoke(device) => {
+                self.request(Message::revoke_device(&device));
+                self.listing = Some(self.client.config().uid.clone());
+            }
+            Request::Approve {
+                device,
+                fingerprint,
+            } => {
+                self.request(Message::approve_device(&device, &fingerprint));
+                self.listing = Some(self.client.config().uid.clone());
+            }
+            Request::Contacts(contacts) => self.request(Message::subscribe(&contacts)),
+            Request::Status { state, text } => self.request(Message::set_status(state, &text)),
+            Request::Hide(hidden) => self.request(Message::set_hidden(hidden)),
+            Request::Help(None) => {
+                let names: Vec<_> = command::COMMANDS
+                    .iter()
+                    .map(|(name, ..)| format!("/{}", name))
+                    .collect();
+                self.notice = format!("commands: {}, /help <command> for one", names.join(" "));
+            }
+            Request::Help(Some(command)) => {
+                self.notice = match command::help(Some(&command)) {
+                    help if help.is_empty() => "unknown command, try /help".into(),
+                    help => help.split_whitespace().collect::<Vec<_>>().join(" "),
+                };
+            }
+            Request::Exit => self.quit = true,
+        }
+    }
+
+    /// queue in the outbox, the outcome comes back as `Sent` or `Undelivered`
+    fn send(&mut self, peer: String, text: &str) {
+        if !self.connected {
+            self.notice = "disconnected from server".into();
+            return;
+        }
+        self.client.queue(&peer, text);
+        self.scroll = 0;
+    }
+
+    fn draw(&mut self, f: &mut Frame) {
+        let [body, input, status] = Layout::vertical([
+            Constraint::Min(1),
+            Constraint::Length(3),
+            Constraint::Length(1),
+        ])
+        .areas(f.size());
+        let [list, messages] =
+            Layout::horizontal([Constraint::Length(24), Constraint::Min(1)]).areas(body);
+        self.draw_conversations(f, list);
+        self.draw_messages(f, messages);
+        self.draw_input(f, input);
+        self.draw_status(f, status);
+    }
+
+    fn draw_conversations(&self, f: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self
+            .conversations
+            .uids
+            .iter()
+            .map(|uid| {
+                let color = match self.presence.get(uid).map(|p| p.state) {
+                    Some(PresenceState::Online) => Color::Green,
+                    Some(PresenceState::Away) => Color::Yellow,
+                    _ => Color::DarkGray,
+                };
+                let mut spans = vec![
+                    Span::styled("● ", Style::default().fg(color)),
+                    Span::raw(uid.as_str()),
+                ];
+                if let Some(n) = self.unread.get(uid) {
+                    spans.push(Span::styled(
+                        format!(" ({})", n),
+                        Style::default()
+                            .fg(Color::Yellow)
+                            .add_modifier(Modifier::BOLD),
+                    ));
+                }
+                ListItem::new(Line::from(spans))
+            })
+            .collect();
+        let list = List::new(items)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title("conversations"),
+            )
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        let mut state =
+            ListState::default().with_selected(self.current().map(|_| self.conversations.selected));
+        f.render_stateful_widget(list, area, &mut state);
+    }
+
+    fn draw_messages(&mut self, f: &mut Frame, area: Rect) {
+        let Some(peer) = self.current().cloned() else {
+            let help = Paragraph::new(format!("no conversation is open\n\n{}", USAGE))
+                .block(Block::default().borders(Borders::ALL));
+            f.render_widget(help, area);
+            return;
+        };
+        let mut title = peer.clone();
+        if let Some(Presence { text, .. }) = self.presence.get(&peer) {
+            if !text.is_empty() {
+                title = format!("{} - {}", title, text);
+            }
+        }
+        if self.typing.contains(&peer) {
+            title = format!("{} (typing…)", title);
+        }
+
+        let history = worker::lock(self.client.history());
+        let height = area.height.saturating_sub(2) as usize;
+        let mut entries = history.window(&peer, self.scroll, height);
+        // scrolled past the first message, which then goes on top
+        if entries.len() < height && self.scroll > 0 {
+            // the whole conversation, shorter than what was skipped and shown
+            let all = history.window(&peer, 0, self.scroll + height);
+            self.scroll = all.len().saturating_sub(height);
+            entries = all[..all.len().min(height)].to_vec();
+        }
+        let lines: Vec<Line> = entries.iter().map(|entry| entry_line(entry)).collect();
+        let messages =
+            Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(title));
+        f.render_widget(messages, area);
+    }
+
+    fn draw_input(&self, f: &mut Frame, area: Rect) {
+        let title = match self.current() {
+            Some(peer) => format!("message to {}", peer),
+            None => "command".into(),
+        };
+        // keep the cursor in view on long lines
+        let width = area.width.saturating_sub(2) as usize;
+        let offset = self.input.cursor.saturating_sub(width.saturating_sub(1));
+        let visible: String = self.input.chars[offset..].iter().collect();
+        let input =
+            Paragraph::new(visible).block(Block::default().borders(Borders::ALL).title(title));
+        f.render_widget(input, area);
+        f.set_cursor(area.x + 1 + (self.input.cursor - offset) as u16, area.y + 1);
+    }
+
+    fn draw_status(&self, f: &mut Frame, area: Rect) {
+        let address = device::address(&self.client.config().uid, &self.client.config().device);
+        let mut spans = match self.connected {
+            true => vec![Span::styled(
+                format!("● {}", address),
+                Style::default().fg(Color::Green),
+            )],
+            false => vec![Span::styled(
+                format!("○ {} disconnected", address),
+                Style::default().fg(Color::Red),
+            )],
+        };
+        if let Some(peer) = self.current() {
+            let trust = match self.trust.get(peer) {
+                Some(Verification::Verified) => {
+                    Span::styled("keys verified", Style::default().fg(Color::Green))
+                }
+                Some(_) => Span::styled("keys unverified", Style::default().fg(Color::Yellow)),
+                None => Span::styled("keys not fetched", Style::default().fg(Color::DarkGray)),
+            };
+            spans.push(Span::raw(" | "));
+            spans.push(trust);
+        }
+        spans.push(Span::raw(" | "));
+        spans.push(Span::raw(self.notice.as_str()));
+        f.render_widget(Paragraph::new(Line::from(spans)), area);
+    }
+}
+
+fn entry_line(entry: &Entry) -> Line<'_> {
+    let time = chrono::DateTime::parse_from_rfc3339(&entry.time)
+        .map(|t| t.format("%H:%M").to_string())
+        .unwrap_or_default();
+    let arrow = match entry.direction {
+        Direction::Sent => Span::styled(
+            format!("-> {} ", entry.address),
+            Style::default().fg(Color::Green),
+        ),
+        Direction::Received => Span::styled(
+            format!("<- {} ", entry.address),
+            Style::default().fg(Color::Yellow),
+        ),
+    };
+    let mut spans = vec![
+        Span::styled(format!("{} ", time), Style::default().fg(Color::DarkGray)),
+        arrow,
+        Span::raw(entry.text.as_str()),
+    ];
+    if entry.receipt == Receipt::Failed {
+        spans.push(Span::styled(" (failed)", Style::default().fg(Color::Red)));
+    }
+    Line::from(spans)
+}
+
+/// full-screen frontend of `client`, ends when the user quits
+pub fn run(
+    client: Arc<ChatClient>,
+    mut events: Events,
+) -> tokio::task::JoinHandle<GlobalResult<()>> {
+    tokio::spawn(async move {
+        let mut screen = Screen::enter()?;
+        let mut app = App::new(client);
+        let mut keys = EventStream::new();
+        while !app.quit {
+            screen.0.draw(|f| app.draw(f))?;
+            tokio::select! {
+                event = events.recv(), if app.connected => {
+                    app.on_event(event.unwrap_or(Event::Disconnected));
+                }
+                key = keys.next() => match key {
+                    Some(Ok(TermEvent::Key(key))) => app.on_key(key),
+                    // resized, redraw
+                    Some(Ok(_)) => (),
+                    _ => break,
+                },
+            }
+        }
+        if app.typing_sent {
+            app.signal(Command::StoppedTyping);
+        }
+        app.notice = "sending queued messages…".into();
+        screen.0.draw(|f| app.draw(f))?;
+        app.client.flush().await;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    fn ctrl(c: char) -> KeyEvent {
+        KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL)
+    }
+
+    fn typed(text: &str) -> Input {
+        let mut input = Input::default();
+        for c in text.chars() {
+            input.edit(&key(KeyCode::Char(c)));
+        }
+        input
+    }
+
+    #[test]
+    fn editing_at_the_cursor_stays_within_the_line() {
+        let mut input = typed("héllo");
+        assert_eq!(input.cursor, 5);
+        // nothing after the end to delete
+        input.edit(&key(KeyCode::Delete));
+        input.edit(&key(KeyCode::Right));
+        assert_eq!((input.text().as_str(), input.cursor), ("héllo", 5));
+
+        input.edit(&ctrl('a'));
+        assert_eq!(input.cursor, 0);
+        // nothing before the start to erase
+        input.edit(&key(KeyCode::Backspace));
+        input.edit(&key(KeyCode::Left));
+        assert_eq!((input.text().as_str(), input.cursor), ("héllo", 0));
+        input.edit(&key(KeyCode::Delete));
+        assert_eq!((input.text().as_str(), input.cursor), ("éllo", 0));
+        input.edit(&key(KeyCode::Char('y')));
+        input.edit(&key(KeyCode::Right));
+        input.edit(&key(KeyCode::Backspace));
+        assert_eq!((input.text().as_str(), input.cursor), ("yllo", 1));
+
+        input.edit(&ctrl('e'));
+        assert_eq!(input.cursor, 4);
+        input.edit(&key(KeyCode::Backspace));
+        assert_eq!(input.text(), "yll");
+        input.edit(&key(KeyCode::Home));
+        input.edit(&key(KeyCode::Char('s')));
+        input.edit(&key(KeyCode::End));
+        input.edit(&key(KeyCode::Char('!')));
+        assert_eq!(input.text(), "syll!");
+        input.edit(&ctrl('u'));
+        assert_eq!((input.text().as_str(), input.cursor), ("", 0));
+    }
+
+    #[test]
+    fn entered_lines_are_recalled_oldest_last() {
+        let mut input = Input::default();
+        // nothing to recall yet
+        input.edit(&key(KeyCode::Up));
+        assert_eq!(input.text(), "");
+        for line in ["first", "second", "second", "  "] {
+            input.set(line);
+            input.take();
+        }
+        // blank and repeated lines are not remembered
+        assert_eq!(input.entered, vec!["first", "second"]);
+
+        input.edit(&key(KeyCode::Up));
+        assert_eq!(input.text(), "second");
+        input.edit(&key(KeyCode::Up));
+        input.edit(&key(KeyCode::Up));
+        assert_eq!((input.text().as_str(), input.cursor), ("first", 5));
+        input.edit(&key(KeyCode::Down));
+        assert_eq!(input.text(), "second");
+        // past the newest is an empty line again
+        input.edit(&key(KeyCode::Down));
+        assert_eq!(input.text(), "");
+        input.edit(&key(KeyCode::Down));
+        assert_eq!(input.text(), "");
+
+        input.edit(&key(KeyCode::Up));
+        assert_eq!(input.take(), "second");
+        assert_eq!(input.recall, None);
+    }
+
+    fn conversations(uids: &[&str], selected: usize) -> Conversations {
+        Conversations {
+            uids: uids.iter().map(|uid| uid.to_string()).collect(),
+            selected,
+        }
+    }
+
+    #[test]
+    fn closing_selects_the_next_or_else_the_one_before() {
+        let mut c = conversations(&["alice", "bob", "carol"], 1);
+        assert_eq!(c.remove_selected().as_deref(), Some("bob"));
+        assert_eq!(c.current().map(String::as_str), Some("carol"));
+        // the last one closed, the one before it is shown
+        assert_eq!(c.remove_selected().as_deref(), Some("carol"));
+        assert_eq!(c.current().map(String::as_str), Some("alice"));
+        assert_eq!(c.remove_selected().as_deref(), Some("alice"));
+        assert_eq!((c.current(), c.selected), (None, 0));
+        assert_eq!(c.remove_selected(), None);
+        assert_eq!(c.step(1), None);
+    }
+
+    #[test]
+    fn stepping_wraps_around_and_adding_keeps_one_per_uid() {
+        let mut c = conversations(&["alice", "bob", "carol"], 2);
+        assert_eq!(c.step(1), Some(0));
+        assert_eq!(c.step(-1), Some(1));
+        c.selected = 0;
+        assert_eq!(c.step(-1), Some(2));
+        assert_eq!(c.add("bob"), 1);
+        assert_eq!(c.add("dave"), 3);
+        assert_eq!(c.uids, vec!["alice", "bob", "carol", "dave"]);
+    }
+}
@@ -1,147 +1,322 @@
-use std::{net::SocketAddr, path::Path, sync::Arc};
+use std::{
+    fs,
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, MutexGuard, PoisonError,
+    },
+    time::Duration,
+};
 
-use colored::*;
 use core::{
     codec::{command::Command, message::Message, msg_codec::MsgCodec},
     config::ClientConfig,
-    error::{ClientError, GlobalResult},
+    device::{self, DeviceInfo, DEFAULT_DEVICE},
+    error::{ClientError, GlobalError, GlobalResult},
+    presence::Presence,
     traits::encrypt::Encrypt,
 };
 use futures::SinkExt;
-use tokio::{
-    io::{self, AsyncBufReadExt},
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpStream,
-    },
+use rsa::RsaPublicKey;
+use tokio::net::{
+    tcp::{OwnedReadHalf, OwnedWriteHalf},
+    TcpStream,
 };
 use tokio_util::codec::{FramedRead, FramedWrite};
 
 use tokio_stream::StreamExt;
 
-use crate::init::Encryptor;
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use crate::{
+    history::{History, SharedHistory, Verification},
+    init::{self, Encryptor},
+    peers::Peers,
+};
+use tokio::sync::{
+    mpsc::{UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+
+pub type Reader = FramedRead<OwnedReadHalf, MsgCodec>;
+pub type Writer = FramedWrite<OwnedWriteHalf, MsgCodec>;
 
-type Reader = FramedRead<OwnedReadHalf, MsgCodec>;
-type Writer = FramedWrite<OwnedWriteHalf, MsgCodec>;
+/// what the connection reports, in the order it happened
+#[derive(Debug)]
+pub enum Event {
+    // decrypted message from `uid/device`, already recorded in the history
+    Received { sender: String, text: String },
+    // a key or the device list of `uid` arrived, so its keys may verify differently
+    KeyReceived(String),
+    // a frame that could not be handled, the connection goes on
+    Error(GlobalError),
+    OnlineList(String),
+    ServerShutdown(String),
+    Kicked(String),
+    Announcement(String),
+    // `id` is the failed request, 0 if the server did not name one
+    RemoteError { id: u64, error: GlobalError },
+    Presence { uid: String, presence: Presence },
+    Typing(String),
+    StoppedTyping(String),
+    Viewing(String),
+    Unknown(Message),
+    Disconnected,
+    // a message of the outbox was handed to the server for `devices` devices
+    // `id` is the request id `ChatClient::queue` returned for it
+    Sent { id: u64, receiver: String, devices: usize },
+    // a message of the outbox waits for a device of `receiver` to come online
+    Waiting { id: u64, receiver: String },
+    // a message of the outbox was given up on
+    Undelivered { id: u64, receiver: String, error: GlobalError },
+}
 
-pub async fn authenticate(wt: &mut Writer, config: Arc<ClientConfig>) -> GlobalResult<()> {
-    wt.send(Message::login(&config.uid)).await?;
+/// id attached to each request, echoed by the server in `RemoteError`
+pub fn next_id() -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// log in as `uid/device`, presenting the device's public key and signing the server's challenge
+/// with its private key
+/// the server registers the key of the first device of a uid, further devices wait until
+/// another one approves them, which is reported as `AwaitingApproval` once the connection closes
+pub async fn authenticate(
+    rd: &mut Reader,
+    wt: &mut Writer,
+    config: &ClientConfig,
+) -> GlobalResult<()> {
+    let pub_key =
+        config.encryption.rsa_self_pub_key.as_ref().ok_or(
+            ClientError::EncryptKeyPersistence.info("user's public key does not exist"),
+        )?;
+    let priv_key =
+        config.encryption.rsa_self_priv_key.as_ref().ok_or(
+            ClientError::EncryptKeyPersistence.info("user's private key does not exist"),
+        )?;
+    let content = Encryptor::export_pub_key(pub_key)?;
+    let address = device::address(&config.uid, &config.device);
+    wt.send(Message::login(&address).set_content(&content)).await?;
+    let nonce = match rd.next().await {
+        Some(Ok(msg)) if msg.command == Command::Challenge => msg.content,
+        Some(Ok(msg)) if msg.command == Command::RemoteError => {
+            return Err(GlobalError::from(String::from_utf8_lossy(&msg.content).to_string()))
+        }
+        _ => return Err(ClientError::ServerDisconnected.info("no answer to Login")),
+    };
+    let proof = device::login_proof(&address, &String::from_utf8_lossy(&nonce));
+    let signature = Encryptor::sign(proof.as_bytes(), priv_key)?;
+    wt.send(Message::answer_challenge(&signature)).await?;
+    if !config.contacts.is_empty() {
+        wt.send(Message::subscribe(&config.contacts).set_id(next_id()))
+            .await?;
+    }
     Ok(())
 }
 
+/// send `request` and wait at most `timeout` for the frame or `RemoteError` with its id
+pub async fn request(
+    tx: &UnboundedSender<Message>,
+    peers: &Peers,
+    request: Message,
+    timeout: Duration,
+) -> GlobalResult<Message> {
+    let id = request.id;
+    let command = request.command.clone();
+    let answer = peers.expect(id);
+    tx.send(request)?;
+    match tokio::time::timeout(timeout, answer).await {
+        Ok(Ok(answer)) => answer,
+        // `read_stream` has ended
+        Ok(Err(_)) => Err(ClientError::ServerDisconnected.info(command.as_ref())),
+        Err(_) => {
+            peers.cancel(id);
+            Err(ClientError::RequestTimeout.info(&format!(
+                "no answer to {} within {}s",
+                command.as_ref(),
+                timeout.as_secs()
+            )))
+        }
+    }
+}
+
+pub fn lock(history: &SharedHistory) -> MutexGuard<'_, History> {
+    history.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// a message is verified only if every device key used matches the one in `safe_key_dir`
+pub fn verification(config: &ClientConfig, uid: &str, devices: &[DeviceInfo]) -> Verification {
+    let verified = devices.iter().all(|DeviceInfo { name, .. }| {
+        let safe = Path::new(&config.encryption.safe_key_dir).join(uid).join(name);
+        let unsafe_ = Path::new(&config.encryption.unsafe_key_dir).join(uid).join(name);
+        match (fs::read(safe), fs::read(unsafe_)) {
+            (Ok(safe), Ok(unsafe_)) => safe == unsafe_,
+            _ => false,
+        }
+    });
+    match verified {
+        true => Verification::Verified,
+        false => Verification::Unverified,
+    }
+}
+
+/// save a key delivered by the server to `unsafe_key_dir`
+async fn persist_key(
+    config: &ClientConfig,
+    address: &str,
+    key: &RsaPublicKey,
+) -> GlobalResult<()> {
+    let (uid, device) = device::parse_address(address);
+    let path = init::device_key_path(
+        &config.encryption.unsafe_key_dir,
+        uid,
+        device.unwrap_or(DEFAULT_DEVICE),
+    )?;
+    Encryptor::async_persist_pub_key(path, key).await
+}
+
 pub fn read_stream(
-    mut rd: Reader,
-    tx: UnboundedSender<Message>,
+    rd: Reader,
+    events: UnboundedSender<Event>,
     config: Arc<ClientConfig>,
+    peers: Peers,
+    history: SharedHistory,
 ) -> tokio::task::JoinHandle<GlobalResult<()>> {
     tokio::spawn(async move {
-        // read private key from local disk
-        let priv_key =
-            config.encryption.rsa_self_priv_key.as_ref().ok_or(
-                ClientError::EncryptKeyPersistence.info("user's private key does not exist"),
-            )?;
-        let pub_key =
-            config.encryption.rsa_self_pub_key.as_ref().ok_or(
-                ClientError::EncryptKeyPersistence.info("user's public key does not exist"),
-            )?;
-
-        println!("{}", "polling the read stream".green());
-        // poll read stream, deserialize message, then respond to command
-        while let Some(Ok(msg)) = rd.next().await {
-            match msg.command {
-                // someone sends message to me -> decrypt & display
-                Command::SendMsg => {
-                    let message = Encryptor::decrypt_to_string(&msg.content, priv_key)?;
-                    println!("{} {}: {}", "from".green(), &msg.sender.green(), message.green());
+        let result = poll_stream(rd, &events, &config, &peers, &history).await;
+        // also after an error, so the ui knows the connection is gone
+        let _ = events.send(Event::Disconnected);
+        result
+    })
+}
+
+async fn poll_stream(
+    mut rd: Reader,
+    events: &UnboundedSender<Event>,
+    config: &ClientConfig,
+    peers: &Peers,
+    history: &SharedHistory,
+) -> GlobalResult<()> {
+    // read private key from local disk
+    let priv_key =
+        config.encryption.rsa_self_priv_key.as_ref().ok_or(
+            ClientError::EncryptKeyPersistence.info("user's private key does not exist"),
+        )?;
+
+    // poll read stream, deserialize message, then respond to command
+    while let Some(Ok(msg)) = rd.next().await {
+        let event = match msg.command {
+            // someone sends message to me -> decrypt & record
+            Command::SendMsg => match Encryptor::decrypt_to_string(&msg.content, priv_key) {
+                Ok(text) => {
+                    // shown even if it cannot be kept
+                    if let Err(e) = lock(history).record_received(&msg.sender, &text, msg.id) {
+                        let _ = events.send(Event::Error(e));
+                    }
+                    Event::Received {
+                        sender: msg.sender,
+                        text,
+                    }
                 }
-                // someone requests for my public key -> notify write_stream
-                Command::GetPubKey => {
-                    println!("{}", "get pub key command received".yellow());
-                    let content = Encryptor::export_pub_key(pub_key)?;
-                    tx.send(Message::send_pub_key(&msg.sender, &content))?;
+                Err(e) => Event::Error(e),
+            },
+            // receive the public key of a device -> save to local disk
+            Command::SendPubKey => {
+                let (uid, device) = device::parse_address(&msg.sender);
+                let address = device::address(uid, device.unwrap_or(DEFAULT_DEVICE));
+                match Encryptor::import_pub_key(&msg.content) {
+                    Ok(pub_key) => {
+                        // used for this session even if it cannot be saved
+                        if let Err(e) = persist_key(config, &address, &pub_key).await {
+                            let _ = events.send(Event::Error(e));
+                        }
+                        peers.resolve_key(&address, pub_key);
+                        Event::KeyReceived(uid.into())
+                    }
+                    Err(e) => Event::Error(e),
                 }
-                // receive someone's public key -> save to local disk & notify write_stream
-                Command::SendPubKey => {
-                    let pub_key_path =
-                        Path::new(&config.encryption.unsafe_key_dir).join(&msg.sender);
-                    let pub_key = Encryptor::import_pub_key(&msg.content)?;
-                    Encryptor::async_persist_pub_key(pub_key_path, &pub_key).await?;
+            }
+            // devices of a uid -> notify whoever waits for them
+            Command::DeviceList => {
+                peers.store_devices(&msg.sender, DeviceInfo::from_content(&msg.content));
+                let uid = msg.sender.clone();
+                peers.answer(msg);
+                Event::KeyReceived(uid)
+            }
+            // answers `ChatClient::list_online`, or shown if nobody asked
+            Command::OnlineList => match peers.answer(msg) {
+                Some(msg) => Event::OnlineList(String::from_utf8_lossy(&msg.content).to_string()),
+                None => continue,
+            },
+            Command::ServerShutdown => {
+                Event::ServerShutdown(String::from_utf8_lossy(&msg.content).to_string())
+            }
+            Command::Kicked => Event::Kicked(String::from_utf8_lossy(&msg.content).to_string()),
+            Command::Announcement => {
+                Event::Announcement(String::from_utf8_lossy(&msg.content).to_string())
+            }
+            Command::RemoteError => {
+                let error = GlobalError::from(String::from_utf8_lossy(&msg.content).to_string());
+                // the answer to a pending request is reported by whoever made it
+                if msg.id != 0 && peers.reject(msg.id, error.clone()) {
+                    continue;
                 }
-                Command::OnlineList => {
-                    println!("{}", String::from_utf8_lossy(&msg.content).to_string());
+                if msg.id != 0 {
+                    if let Err(e) = lock(history).mark_failed(msg.id) {
+                        let _ = events.send(Event::Error(e));
+                    }
                 }
-                _ => println!("{:?}", msg),
+                Event::RemoteError { id: msg.id, error }
             }
+            Command::PresenceChanged => match Presence::from_content(&msg.content) {
+                // the devices of a uid change as it goes online or offline
+                Some(presence) => {
+                    peers.forget_devices(&msg.sender);
+                    Event::Presence {
+                        uid: msg.sender,
+                        presence,
+                    }
+                }
+                None => Event::Unknown(msg),
+            },
+            Command::Typing => Event::Typing(msg.sender),
+            Command::StoppedTyping => Event::StoppedTyping(msg.sender),
+            Command::Viewing => Event::Viewing(msg.sender),
+            _ => Event::Unknown(msg),
+        };
+        // nobody is listening once the user has exited
+        if events.send(event).is_err() {
+            break;
         }
-        Ok(())
-    })
+    }
+    Ok(())
 }
 
+/// `flushed` senders are answered once every frame queued before them is written
 pub fn write_stream(
     mut wt: Writer,
     mut rx: UnboundedReceiver<Message>,
+    mut flushed: UnboundedReceiver<oneshot::Sender<()>>,
 ) -> tokio::task::JoinHandle<GlobalResult<()>> {
     tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            wt.send(msg).await?;
-        }
-        Ok(())
-    })
-}
-
-pub fn read_stdin(
-    tx: UnboundedSender<Message>,
-    config: Arc<ClientConfig>,
-) -> tokio::task::JoinHandle<GlobalResult<()>> {
-    tokio::spawn(async move {
-        let stdin = io::stdin();
-        let mut reader = io::BufReader::new(stdin);
-        let mut line = String::new();
-
         loop {
-            line.clear();
-            reader.read_line(&mut line).await?;
-            let tokens: Vec<&str> = line.split_whitespace().collect();
-            match tokens[0] {
-                "list" => tx.send(Message::online_list(""))?,
-                "send" => {
-                    let receiver = tokens[1];
-                    let receiver_key_path =
-                        Path::new(&config.encryption.unsafe_key_dir).join(receiver);
-                    tx.send(Message::get_pub_key(receiver))?;
-                    if !receiver_key_path.is_file() {
-                        println!(
-                            "{}",
-                            "receiver's key does not exist, requesting...".yellow()
-                        );
-                    }
-                    while !receiver_key_path.is_file() {
-                        tokio::task::yield_now().await;
-                    }
-                    println!(
-                        "{} {:?}",
-                        "receiver's key is saved at".green(),
-                        receiver_key_path
-                    );
-                    let receiver_key = Encryptor::async_read_pub_key(receiver_key_path).await?;
-                    let ciphertext = {
-                        let mut rng = rand::thread_rng();
-                        Encryptor::encrypt_from_str(tokens[2], &receiver_key, &mut rng)?
-                    };
-                    tx.send(Message::send_text(receiver, &ciphertext))?;
+            tokio::select! {
+                // frames first, so a flush is answered only when none is left
+                biased;
+                msg = rx.recv() => match msg {
+                    Some(msg) => wt.send(msg).await?,
+                    None => break,
+                },
+                Some(done) = flushed.recv() => {
+                    let _ = done.send(());
                 }
-                "exit" => break Ok(()),
-                _ => (),
             }
         }
+        Ok(())
     })
 }
 
 pub async fn connect(addr: SocketAddr) -> GlobalResult<(Reader, Writer)> {
-    println!("{} {}", "connecting to".green(), addr.to_string().yellow());
     let stream = TcpStream::connect(addr).await?;
-    println!("{}", "connection established".green());
     let (rd, wt) = stream.into_split();
 
     Ok((
@@ -0,0 +1,5 @@
+// print every error code as JSON
+// cargo run -p core --example error_catalogue > errors.json
+fn main() {
+    println!("{}", core::error::catalogue_json());
+}
@@ -0,0 +1,641 @@
+use std::{
+    ffi::OsString,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    iter,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex, OnceLock, PoisonError,
+    },
+    thread,
+};
+
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{ExternalError, GlobalError, GlobalResult};
+
+/// events queued for the writer thread, `record` drops any further ones until it catches up
+const QUEUE: usize = 4096;
+
+/// `prev` of the first entry
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// something security relevant that happened on the server
+/// uids and devices appear as they are, unlike in the tracing output, message content never does
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event")]
+pub enum Event {
+    // a device proved it holds its key and got a session
+    Login {
+        address: String,
+        ip: String,
+    },
+    // a connection that got no session, `sender` is empty if it never sent `Login`
+    AuthFailed {
+        sender: String,
+        ip: String,
+        reason: String,
+    },
+    // the first device of a uid never seen before registered its key
+    KeyRegistered {
+        address: String,
+        fingerprint: String,
+    },
+    // a further device asked to log in with a key, a new request replaces the key of the last one
+    KeyRequested {
+        address: String,
+        fingerprint: String,
+    },
+    // `by` is the approving `uid/device`, or `admin`
+    KeyApproved {
+        address: String,
+        by: String,
+    },
+    DeviceRevoked {
+        address: String,
+        by: String,
+    },
+    Kicked {
+        address: String,
+        reason: String,
+    },
+    // a command of the admin console with its arguments, `outcome` is `ok` or the error
+    Admin {
+        peer: String,
+        command: String,
+        outcome: String,
+    },
+    AdminAuthFailed {
+        peer: String,
+    },
+    // a frame over the limit, and how many the uid sent over it without a pause
+    // only the first of a command is recorded, and the one that gets the uid kicked
+    RateLimited {
+        address: String,
+        command: String,
+        violations: usize,
+    },
+    // a connection refused before authentication, such as one over the cap of its ip
+    // refused again before a connection of the ip closes, it is not recorded again
+    Refused {
+        ip: String,
+        reason: String,
+    },
+    // events that were not recorded because the writer fell behind
+    Dropped {
+        events: u64,
+    },
+}
+
+/// one line of the log is `<hash> <entry as JSON>`, where hash is the SHA-256 of the JSON
+/// and `prev` is the hash of the line before, so editing or removing a line breaks the chain
+/// the hashes are unkeyed: whoever can write the log directory can rewrite the whole chain
+/// and its head file, only a head kept off the host, see `verify_anchored`, shows that
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    // 1 for the first line
+    pub seq: u64,
+    // RFC 3339
+    pub time: String,
+    pub prev: String,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// the last entry of a chain, written as `<seq> <hash>` like in the head file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Head {
+    pub seq: u64,
+    pub hash: String,
+}
+
+impl fmt::Display for Head {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.seq, self.hash)
+    }
+}
+
+impl FromStr for Head {
+    type Err = GlobalError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.trim()
+            .split_once(' ')
+            .and_then(|(seq, hash)| {
+                Some(Head {
+                    seq: seq.parse().ok()?,
+                    hash: hash.trim().into(),
+                })
+            })
+            .ok_or_else(|| {
+                ExternalError::AuditLog.info(&format!("{:?} is not `<seq> <hash>`", s.trim()))
+            })
+    }
+}
+
+impl Default for Head {
+    fn default() -> Self {
+        Self {
+            seq: 0,
+            hash: GENESIS.into(),
+        }
+    }
+}
+
+/// `<file>.head`, rewritten after every write so that removing lines from the end shows
+fn head_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".head");
+    PathBuf::from(name)
+}
+
+fn broken(path: &Path, line: u64, reason: &str) -> GlobalError {
+    ExternalError::AuditLog.info(&format!("{} line {}: {}", path.display(), line, reason))
+}
+
+/// walk the chain of the log at `path` and compare its end with `<file>.head`
+/// a missing log is an empty chain, the head may lag behind the last write after a crash
+/// `AuditLog` error naming the first line that is edited, removed or out of place
+pub fn verify(path: &Path) -> GlobalResult<Head> {
+    walk(path, None)
+}
+
+/// `verify`, and that entry `anchor.seq` still has the hash `anchor.hash`
+/// with a head recorded elsewhere this also catches a chain rewritten from scratch
+pub fn verify_anchored(path: &Path, anchor: &Head) -> GlobalResult<Head> {
+    walk(path, Some(anchor))
+}
+
+fn walk(path: &Path, anchor: Option<&Head>) -> GlobalResult<Head> {
+    let head_path = head_path(path);
+    let recorded = match head_path.try_exists()? {
+        true => Some(fs::read_to_string(&head_path)?),
+        false => None,
+    };
+    let recorded: Option<Head> = recorded.map(|r| r.parse()).transpose().map_err(|_| {
+        ExternalError::AuditLog.info(&format!("{} is not `<seq> <hash>`", head_path.display()))
+    })?;
+    let mut head = Head::default();
+    // the hash of the entry the head file names, which is the last one unless the server
+    // stopped between writing entries and the head
+    let mut at_recorded = None;
+    if path.try_exists()? {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 0 {
+            let seq = head.seq + 1;
+            let Some(text) = line.strip_suffix('\n') else {
+                return Err(broken(
+                    path,
+                    seq,
+                    "ends without a newline, the write was cut off",
+                ));
+            };
+            let Some((hash, json)) = text.split_once(' ') else {
+                return Err(broken(path, seq, "is not `<hash> <entry>`"));
+            };
+            if sha256::digest(json) != hash {
+                return Err(broken(path, seq, "was edited, its hash does not match"));
+            }
+            let entry: Entry = serde_json::from_str(json)
+                .map_err(|e| broken(path, seq, &format!("is no entry: {}", e)))?;
+            if entry.prev != head.hash || entry.seq != seq {
+                return Err(broken(
+                    path,
+                    seq,
+                    "does not follow the line before, lines were removed or moved",
+                ));
+            }
+            if anchor.is_some_and(|a| a.seq == seq && a.hash != hash) {
+                return Err(broken(
+                    path,
+                    seq,
+                    "does not match the expected head, the chain was rewritten",
+                ));
+            }
+            if recorded.as_ref().is_some_and(|r| r.seq == seq) {
+                at_recorded = Some(hash.to_string());
+            }
+            head = Head {
+                seq,
+                hash: hash.into(),
+            };
+            line.clear();
+        }
+    }
+    if let Some(anchor) = anchor.filter(|a| a.seq > head.seq) {
+        return Err(ExternalError::AuditLog.info(&format!(
+            "{} ends at entry {} but the expected head is entry {}, it was truncated",
+            path.display(),
+            head.seq,
+            anchor.seq
+        )));
+    }
+    let recorded = match recorded {
+        Some(recorded) => recorded,
+        None if head.seq == 0 => return Ok(head),
+        None => {
+            return Err(ExternalError::AuditLog.info(&format!("{} is missing", head_path.display())))
+        }
+    };
+    if recorded.seq > head.seq {
+        return Err(ExternalError::AuditLog.info(&format!(
+            "{} ends at entry {} but {} records {}, it was truncated",
+            path.display(),
+            head.seq,
+            head_path.display(),
+            recorded.seq
+        )));
+    }
+    let expected = match recorded.seq {
+        0 => Some(GENESIS.to_string()),
+        _ => at_recorded,
+    };
+    match expected == Some(recorded.hash) {
+        true => Ok(head),
+        false => Err(ExternalError::AuditLog.info(&format!(
+            "{} does not match entry {} of {}",
+            head_path.display(),
+            recorded.seq,
+            path.display()
+        ))),
+    }
+}
+
+/// append-only, hash-chained log of `Event`, see `Entry`
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    tail: Mutex<(File, Head)>,
+}
+
+impl AuditLog {
+    /// continue the log at `path`, which must pass `verify`, a missing file starts a new chain
+    /// so that nothing is appended to a log that was tampered with
+    pub fn open(path: PathBuf) -> GlobalResult<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let head = verify(&path)?;
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            path,
+            tail: Mutex::new((file, head)),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// the last entry written
+    pub fn head(&self) -> Head {
+        self.tail
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .1
+            .clone()
+    }
+
+    /// write `event` as the next entry, then its hash to the head file
+    pub fn append(&self, event: Event) -> GlobalResult<()> {
+        self.append_all(vec![event])
+    }
+
+    /// write `events` as the next entries with a single sync, then the hash of the last
+    /// to the head file, which so never names an entry that is not on disk
+    pub fn append_all(&self, events: Vec<Event>) -> GlobalResult<()> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        let mut tail = self.tail.lock().unwrap_or_else(PoisonError::into_inner);
+        let (file, head) = &mut *tail;
+        let mut next = head.clone();
+        let mut lines = String::new();
+        for event in events {
+            let entry = Entry {
+                seq: next.seq + 1,
+                time: Local::now().to_rfc3339(),
+                prev: next.hash,
+                event,
+            };
+            let json = serde_json::to_string(&entry)
+                .map_err(|e| ExternalError::AuditLog.info(&e.to_string()))?;
+            let hash = sha256::digest(json.as_str());
+            lines.push_str(&format!("{} {}\n", hash, json));
+            next = Head {
+                seq: entry.seq,
+                hash,
+            };
+        }
+        file.write_all(lines.as_bytes())?;
+        file.sync_data()?;
+        *head = next;
+        let head_path = head_path(&self.path);
+        let temp = head_path.with_extension("head.tmp");
+        fs::write(&temp, format!("{}\n", head))?;
+        fs::rename(&temp, &head_path)?;
+        Ok(())
+    }
+}
+
+enum Message {
+    Event(Event),
+    // answered once every event queued before is written
+    Flush(mpsc::Sender<()>),
+}
+
+/// the queue of the writer thread, which owns the installed log
+struct Writer {
+    queue: SyncSender<Message>,
+    // events `record` could not queue since the writer last wrote
+    dropped: Arc<AtomicU64>,
+}
+
+static WRITER: OnceLock<Writer> = OnceLock::new();
+
+/// `record` queues events for `log` from now on, events before are not recorded
+/// a thread of its own writes them, so no request waits for the disk
+pub fn install(log: AuditLog) -> GlobalResult<()> {
+    let (queue, rx) = mpsc::sync_channel(QUEUE);
+    let dropped = Arc::new(AtomicU64::new(0));
+    let writer = Writer {
+        queue,
+        dropped: Arc::clone(&dropped),
+    };
+    thread::Builder::new()
+        .name("audit".into())
+        .spawn(move || write(log, rx, dropped))?;
+    // a second log is dropped with its queue, which ends its thread
+    let _ = WRITER.set(writer);
+    Ok(())
+}
+
+/// queue `event` for the installed log, a failure to write it is only logged
+/// while the writer is `QUEUE` events behind it is dropped, the next write counts it
+pub fn record(event: Event) {
+    let Some(writer) = WRITER.get() else {
+        return;
+    };
+    if let Err(TrySendError::Full(_)) = writer.queue.try_send(Message::Event(event)) {
+        if writer.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+            tracing::error!("the audit log falls behind, events are dropped");
+        }
+    }
+}
+
+/// wait until every event recorded before is written, such as before the server exits
+pub fn flush() {
+    let Some(writer) = WRITER.get() else {
+        return;
+    };
+    let (done, written) = mpsc::channel();
+    if writer.queue.send(Message::Flush(done)).is_ok() {
+        let _ = written.recv();
+    }
+}
+
+/// the writer thread, whatever is queued when it wakes up is written with one sync
+fn write(log: AuditLog, queue: Receiver<Message>, dropped: Arc<AtomicU64>) {
+    while let Ok(first) = queue.recv() {
+        let mut events = Vec::new();
+        let mut flushed = Vec::new();
+        for message in iter::once(first).chain(queue.try_iter().take(QUEUE)) {
+            match message {
+                Message::Event(event) => events.push(event),
+                Message::Flush(done) => flushed.push(done),
+            }
+        }
+        let events_dropped = dropped.swap(0, Ordering::Relaxed);
+        if events_dropped > 0 {
+            events.push(Event::Dropped {
+                events: events_dropped,
+            });
+        }
+        if let Err(e) = log.append_all(events) {
+            tracing::error!("cannot write the audit log {}: {}", log.path().display(), e);
+        }
+        for done in flushed {
+            let _ = done.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kicked(n: usize) -> Event {
+        Event::Kicked {
+            address: format!("alice/{}", n),
+            reason: "test".into(),
+        }
+    }
+
+    fn written(n: usize) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let log = AuditLog::open(path.clone()).unwrap();
+        for n in 0..n {
+            log.append(kicked(n)).unwrap();
+        }
+        (dir, path)
+    }
+
+    fn lines(path: &Path) -> Vec<String> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    fn write_lines(path: &Path, lines: &[String]) {
+        fs::write(
+            path,
+            lines.iter().map(|l| format!("{}\n", l)).collect::<String>(),
+        )
+        .unwrap();
+    }
+
+    fn code(result: GlobalResult<Head>) -> u16 {
+        result.unwrap_err().code()
+    }
+
+    #[test]
+    fn chain_continues_after_reopening() {
+        let (_dir, path) = written(3);
+        let log = AuditLog::open(path.clone()).unwrap();
+        assert_eq!(log.head().seq, 3);
+        log.append(kicked(3)).unwrap();
+        let head = verify(&path).unwrap();
+        assert_eq!(head, log.head());
+        assert_eq!(head.seq, 4);
+        let last = lines(&path).pop().unwrap();
+        let (_, json) = last.split_once(' ').unwrap();
+        let entry: Entry = serde_json::from_str(json).unwrap();
+        assert_eq!(entry.event, kicked(3));
+    }
+
+    #[test]
+    fn edited_entries_are_detected() {
+        let (_dir, path) = written(3);
+        let mut lines = lines(&path);
+        lines[1] = lines[1].replace("alice/1", "mallory/1");
+        write_lines(&path, &lines);
+        assert_eq!(code(verify(&path)), ExternalError::AuditLog as u16);
+        assert!(AuditLog::open(path).is_err());
+    }
+
+    #[test]
+    fn removed_or_moved_entries_are_detected() {
+        let (_dir, path) = written(3);
+        let all = lines(&path);
+        write_lines(&path, &[all[0].clone(), all[2].clone()]);
+        assert_eq!(code(verify(&path)), ExternalError::AuditLog as u16);
+        write_lines(&path, &[all[1].clone(), all[0].clone(), all[2].clone()]);
+        assert_eq!(code(verify(&path)), ExternalError::AuditLog as u16);
+    }
+
+    #[test]
+    fn truncation_is_detected() {
+        let (_dir, path) = written(3);
+        let all = lines(&path);
+        write_lines(&path, &all[..1]);
+        let e = verify(&path).unwrap_err();
+        assert!(e.info.unwrap().contains("truncated"));
+
+        // cut off in the middle of the last line
+        let mut text = fs::read_to_string(&path).unwrap();
+        text.push_str(&all[1][..10]);
+        fs::write(&path, text).unwrap();
+        assert_eq!(code(verify(&path)), ExternalError::AuditLog as u16);
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(code(verify(&path)), ExternalError::AuditLog as u16);
+    }
+
+    #[test]
+    fn head_may_lag_behind() {
+        let (_dir, path) = written(2);
+        let head = fs::read_to_string(head_path(&path)).unwrap();
+        let log = AuditLog::open(path.clone()).unwrap();
+        log.append_all(vec![kicked(2), kicked(3)]).unwrap();
+        assert_eq!(log.head().seq, 4);
+        // as if the server stopped between writing the entries and the head
+        fs::write(head_path(&path), &head).unwrap();
+        assert_eq!(verify(&path).unwrap().seq, 4);
+
+        fs::write(head_path(&path), "1 ".to_string() + GENESIS).unwrap();
+        assert_eq!(code(verify(&path)), ExternalError::AuditLog as u16);
+        fs::write(head_path(&path), "0 ".to_string() + GENESIS).unwrap();
+        assert_eq!(verify(&path).unwrap().seq, 4);
+    }
+
+    #[test]
+    fn entries_written_at_once_form_one_chain() {
+        let (_dir, path) = written(1);
+        let log = AuditLog::open(path.clone()).unwrap();
+        log.append_all(Vec::new()).unwrap();
+        log.append_all((1..4).map(kicked).collect()).unwrap();
+        let head = verify(&path).unwrap();
+        assert_eq!(head, log.head());
+        assert_eq!(head.seq, 4);
+        assert_eq!(
+            fs::read_to_string(head_path(&path)).unwrap(),
+            format!("{}\n", head)
+        );
+    }
+
+    #[test]
+    fn writer_counts_dropped_events_and_answers_flushes() {
+        let (_dir, path) = written(0);
+        let log = AuditLog::open(path.clone()).unwrap();
+        let (queue, rx) = mpsc::sync_channel(QUEUE);
+        let (done, written) = mpsc::channel();
+        queue.send(Message::Event(kicked(0))).unwrap();
+        queue.send(Message::Flush(done)).unwrap();
+        queue.send(Message::Event(kicked(1))).unwrap();
+        drop(queue);
+        // the writer returns once the queue is closed and empty
+        write(log, rx, Arc::new(AtomicU64::new(2)));
+        assert!(written.try_recv().is_ok());
+
+        let events: Vec<Event> = lines(&path)
+            .iter()
+            .map(|line| {
+                let (_, json) = line.split_once(' ').unwrap();
+                serde_json::from_str::<Entry>(json).unwrap().event
+            })
+            .collect();
+        assert_eq!(events, [kicked(0), kicked(1), Event::Dropped { events: 2 }]);
+        assert_eq!(verify(&path).unwrap().seq, 3);
+    }
+
+    #[test]
+    fn recorded_events_are_written_by_the_installed_log() {
+        let (_dir, path) = written(0);
+        // other tests record too, these events are told apart by their peer
+        let admin = |n: usize| Event::Admin {
+            peer: "audit test".into(),
+            command: format!("command {}", n),
+            outcome: "ok".into(),
+        };
+        record(admin(0));
+        install(AuditLog::open(path.clone()).unwrap()).unwrap();
+        for n in 1..4 {
+            record(admin(n));
+        }
+        flush();
+        verify(&path).unwrap();
+        let events: Vec<Event> = lines(&path)
+            .iter()
+            .map(|line| {
+                let (_, json) = line.split_once(' ').unwrap();
+                serde_json::from_str::<Entry>(json).unwrap().event
+            })
+            .filter(|event| matches!(event, Event::Admin { peer, .. } if peer == "audit test"))
+            .collect();
+        assert_eq!(events, [admin(1), admin(2), admin(3)]);
+    }
+
+    #[test]
+    fn rewritten_chains_fail_against_an_anchored_head() {
+        let (_dir, path) = written(3);
+        let anchor: Head = fs::read_to_string(head_path(&path))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(anchor.to_string().parse::<Head>().unwrap(), anchor);
+        AuditLog::open(path.clone())
+            .unwrap()
+            .append(kicked(3))
+            .unwrap();
+        assert_eq!(verify_anchored(&path, &anchor).unwrap().seq, 4);
+
+        // rewritten from scratch with its own head file, which plain `verify` cannot tell
+        let mut lines = lines(&path);
+        lines[1] = lines[1].replace("alice/1", "mallory/1");
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(head_path(&path)).unwrap();
+        let log = AuditLog::open(path.clone()).unwrap();
+        for line in &lines {
+            let (_, json) = line.split_once(' ').unwrap();
+            let entry: Entry = serde_json::from_str(json).unwrap();
+            log.append(entry.event).unwrap();
+        }
+        assert_eq!(verify(&path).unwrap().seq, 4);
+        let e = verify_anchored(&path, &anchor).unwrap_err();
+        assert!(e.info.unwrap().contains("rewritten"));
+
+        let ahead = Head { seq: 5, ..anchor };
+        let e = verify_anchored(&path, &ahead).unwrap_err();
+        assert!(e.info.unwrap().contains("truncated"));
+        assert!("3".parse::<Head>().is_err());
+    }
+}
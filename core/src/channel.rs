@@ -0,0 +1,275 @@
+use crate::codec::message::Message;
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+};
+use tokio::sync::Notify;
+
+/// state shared by both ends of a bounded queue
+#[derive(Debug)]
+struct Shared {
+    queue: Mutex<VecDeque<Message>>,
+    capacity: usize,
+    closed: AtomicBool,
+    notify: Notify,
+}
+
+impl Shared {
+    fn queue(&self) -> MutexGuard<'_, VecDeque<Message>> {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.notify.notify_one();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+}
+
+/// reason a `Message` is handed back by `Tx::try_send`
+#[derive(Debug)]
+pub enum TrySendError {
+    Full(Message),
+    Closed(Message),
+}
+
+/// sending half, owned by `OnlineUsers`
+#[derive(Debug)]
+pub struct Tx {
+    shared: Arc<Shared>,
+}
+
+/// receiving half, owned by the write task of a connection
+#[derive(Debug)]
+pub struct Rx {
+    shared: Arc<Shared>,
+}
+
+/// create a single-producer single-consumer queue holding at most `capacity` messages
+/// unlike `tokio::sync::mpsc`, the producer may evict the oldest queued message
+pub fn bounded(capacity: usize) -> (Tx, Rx) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(VecDeque::with_capacity(capacity)),
+        capacity: capacity.max(1),
+        closed: AtomicBool::new(false),
+        notify: Notify::new(),
+    });
+    (
+        Tx {
+            shared: Arc::clone(&shared),
+        },
+        Rx { shared },
+    )
+}
+
+impl Tx {
+    /// push to the back of the queue
+    /// the `Message` is handed back if the queue is full or closed
+    pub fn try_send(&self, msg: Message) -> Result<(), TrySendError> {
+        if self.shared.is_closed() {
+            return Err(TrySendError::Closed(msg));
+        }
+        let mut queue = self.shared.queue();
+        if queue.len() >= self.shared.capacity {
+            return Err(TrySendError::Full(msg));
+        }
+        queue.push_back(msg);
+        drop(queue);
+        self.shared.notify.notify_one();
+        Ok(())
+    }
+
+    /// push to the back of the queue, evicting the oldest `Message` if it is full
+    /// Ok(Some(evicted)) if a `Message` has been dropped to make room
+    pub fn force_send(&self, msg: Message) -> Result<Option<Message>, TrySendError> {
+        if self.shared.is_closed() {
+            return Err(TrySendError::Closed(msg));
+        }
+        let mut queue = self.shared.queue();
+        let evicted = if queue.len() >= self.shared.capacity {
+            queue.pop_front()
+        } else {
+            None
+        };
+        queue.push_back(msg);
+        drop(queue);
+        self.shared.notify.notify_one();
+        Ok(evicted)
+    }
+
+    /// number of messages waiting to be written to the connection
+    pub fn len(&self) -> usize {
+        self.shared.queue().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.shared.capacity
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.is_closed()
+    }
+
+    /// stop accepting messages, `Rx` still drains whatever is queued
+    pub fn close(&self) {
+        self.shared.close();
+    }
+
+    /// stop accepting messages and discard whatever is queued
+    pub fn abort(&self) {
+        self.shared.queue().clear();
+        self.shared.close();
+    }
+}
+
+impl Drop for Tx {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+impl Rx {
+    /// wait for the next `Message`
+    /// None once the queue is closed and drained
+    pub async fn recv(&mut self) -> Option<Message> {
+        loop {
+            if let Some(msg) = self.shared.queue().pop_front() {
+                return Some(msg);
+            }
+            if self.shared.is_closed() {
+                return None;
+            }
+            // `notify_one` stores a permit, so a push between the check above and
+            // this await is not lost
+            self.shared.notify.notified().await;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.queue().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Drop for Rx {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(n: usize) -> Message {
+        Message::kicked(&n.to_string())
+    }
+
+    fn content(msg: &Message) -> String {
+        String::from_utf8_lossy(&msg.content).to_string()
+    }
+
+    #[tokio::test]
+    async fn try_send_refuses_beyond_capacity() {
+        let (tx, mut rx) = bounded(2);
+        assert_eq!(tx.capacity(), 2);
+        tx.try_send(msg(0)).unwrap();
+        tx.try_send(msg(1)).unwrap();
+        assert!(tx.is_full());
+        assert!(matches!(tx.try_send(msg(2)), Err(TrySendError::Full(m)) if content(&m) == "2"));
+        assert_eq!(content(&rx.recv().await.unwrap()), "0");
+        assert_eq!((tx.len(), rx.len()), (1, 1));
+        tx.try_send(msg(3)).unwrap();
+        assert_eq!(content(&rx.recv().await.unwrap()), "1");
+        assert_eq!(content(&rx.recv().await.unwrap()), "3");
+        assert!(tx.is_empty() && rx.is_empty());
+    }
+
+    #[test]
+    fn zero_capacity_holds_one() {
+        let (tx, _rx) = bounded(0);
+        assert_eq!(tx.capacity(), 1);
+        tx.try_send(msg(0)).unwrap();
+        assert!(tx.is_full());
+    }
+
+    #[tokio::test]
+    async fn force_send_evicts_the_oldest() {
+        let (tx, mut rx) = bounded(2);
+        assert!(tx.force_send(msg(0)).unwrap().is_none());
+        assert!(tx.force_send(msg(1)).unwrap().is_none());
+        let evicted = tx.force_send(msg(2)).unwrap().unwrap();
+        assert_eq!(content(&evicted), "0");
+        let evicted = tx.force_send(msg(3)).unwrap().unwrap();
+        assert_eq!(content(&evicted), "1");
+        assert_eq!(tx.len(), 2);
+        assert_eq!(content(&rx.recv().await.unwrap()), "2");
+        assert_eq!(content(&rx.recv().await.unwrap()), "3");
+    }
+
+    #[tokio::test]
+    async fn close_drains_abort_discards() {
+        let (tx, mut rx) = bounded(4);
+        tx.try_send(msg(0)).unwrap();
+        tx.try_send(msg(1)).unwrap();
+        tx.close();
+        assert!(tx.is_closed());
+        assert!(matches!(tx.try_send(msg(2)), Err(TrySendError::Closed(_))));
+        assert!(matches!(tx.force_send(msg(2)), Err(TrySendError::Closed(_))));
+        assert_eq!(content(&rx.recv().await.unwrap()), "0");
+        assert_eq!(content(&rx.recv().await.unwrap()), "1");
+        assert!(rx.recv().await.is_none());
+
+        let (tx, mut rx) = bounded(4);
+        tx.try_send(msg(0)).unwrap();
+        tx.abort();
+        assert!(rx.is_empty());
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn dropping_either_end_closes() {
+        let (tx, mut rx) = bounded(4);
+        tx.try_send(msg(0)).unwrap();
+        drop(tx);
+        assert_eq!(content(&rx.recv().await.unwrap()), "0");
+        assert!(rx.recv().await.is_none());
+
+        let (tx, rx) = bounded(4);
+        drop(rx);
+        assert!(matches!(tx.try_send(msg(0)), Err(TrySendError::Closed(_))));
+    }
+
+    #[tokio::test]
+    async fn waiting_receiver_wakes_on_send_and_close() {
+        let (tx, mut rx) = bounded(4);
+        let reader = tokio::spawn(async move {
+            let first = rx.recv().await.map(|m| content(&m));
+            let second = rx.recv().await.map(|m| content(&m));
+            (first, second)
+        });
+        tokio::task::yield_now().await;
+        tx.try_send(msg(0)).unwrap();
+        tokio::task::yield_now().await;
+        tx.close();
+        assert_eq!(reader.await.unwrap(), (Some("0".into()), None));
+    }
+}
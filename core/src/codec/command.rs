@@ -7,11 +7,26 @@ use crate::codec::message::Message;
 pub enum Command {
     Help,
     Login,
+    Challenge,
     OnlineList,
     SendMsg,
     GetPubKey,
     SendPubKey,
     RemoteError,
+    ServerShutdown,
+    Kicked,
+    Announcement,
+    Admin,
+    DeviceList,
+    RevokeDevice,
+    ApproveDevice,
+    Subscribe,
+    SetStatus,
+    SetHidden,
+    PresenceChanged,
+    Typing,
+    StoppedTyping,
+    Viewing,
 }
 
 impl From<BytesMut> for Command {
@@ -27,7 +42,20 @@ impl From<Command> for BytesMut {
 }
 
 impl Command {
+    /// commands that are only ever sent as ephemeral frames
+    pub fn is_signal(&self) -> bool {
+        matches!(self, Command::Typing | Command::StoppedTyping | Command::Viewing)
+    }
+
+    /// response to a malformed frame
     pub fn help() -> Message {
-        Message::send_text("", b"").set_sender("Server")
+        Message {
+            sender: "Server".into(),
+            receiver: "".into(),
+            command: Command::Help,
+            content: "".into(),
+            id: 0,
+            ephemeral: false,
+        }
     }
 }
@@ -1,6 +1,10 @@
 use std::fmt::Display;
 
-use crate::codec::command::Command;
+use crate::{
+    codec::command::Command,
+    error::GlobalError,
+    presence::{Presence, PresenceState},
+};
 use bytes::{BufMut, BytesMut};
 use colored::*;
 
@@ -10,6 +14,13 @@ pub struct Message {
     pub receiver: String,
     pub command: Command,
     pub content: Vec<u8>,
+    // chosen by the client to match a `RemoteError` with the frame that caused it
+    // 0 means none, and is not serialized
+    pub id: u64,
+    // relayed only if the receiver can take it right away, never queued offline nor answered with errors
+    // only `SendMsg` and the signals can be ephemeral, the server refuses the flag on other commands
+    // serialized as a trailing `e` argument
+    pub ephemeral: bool,
 }
 
 /// serialize `Message` into bytes
@@ -48,12 +59,23 @@ impl Display for Message {
 
 impl Message {
     fn args_string(&self) -> String {
-        format!(
-            "{},{},{}",
-            self.content.len(),
-            self.sender,
-            self.receiver,
-        )
+        match (self.id, self.ephemeral) {
+            (0, false) => format!("{},{},{}", self.content.len(), self.sender, self.receiver),
+            (id, false) => format!(
+                "{},{},{},{}",
+                self.content.len(),
+                self.sender,
+                self.receiver,
+                id
+            ),
+            (id, true) => format!(
+                "{},{},{},{},e",
+                self.content.len(),
+                self.sender,
+                self.receiver,
+                id
+            ),
+        }
     }
 
     pub fn login(uid: &str) -> Self {
@@ -62,6 +84,33 @@ impl Message {
             receiver: "Server".into(),
             command: Command::Login,
             content: "".into(),
+            id: 0,
+            ephemeral: false,
+        }
+    }
+
+    /// sent by the server in answer to `Login`, the client answers with `Challenge`
+    /// carrying its signature of `device::login_proof`
+    pub fn challenge(nonce: &str) -> Self {
+        Self {
+            sender: "Server".into(),
+            receiver: "".into(),
+            command: Command::Challenge,
+            content: nonce.into(),
+            id: 0,
+            ephemeral: false,
+        }
+    }
+
+    /// the client's answer to `challenge`
+    pub fn answer_challenge(signature: &[u8]) -> Self {
+        Self {
+            sender: "".into(),
+            receiver: "Server".into(),
+            command: Command::Challenge,
+            content: signature.to_vec(),
+            id: 0,
+            ephemeral: false,
         }
     }
 
@@ -71,6 +120,8 @@ impl Message {
             receiver: to.into(),
             command: Command::GetPubKey,
             content: "".into(),
+            id: 0,
+            ephemeral: false,
         }
     }
 
@@ -80,6 +131,8 @@ impl Message {
             receiver: to.into(),
             command: Command::SendPubKey,
             content: rsa.to_vec(),
+            id: 0,
+            ephemeral: false,
         }
     }
 
@@ -89,6 +142,8 @@ impl Message {
             receiver: to.into(),
             command: Command::SendMsg,
             content: content.to_vec(),
+            id: 0,
+            ephemeral: false,
         }
     }
 
@@ -98,6 +153,163 @@ impl Message {
             receiver: "".into(),
             command: Command::OnlineList,
             content: content.into(),
+            id: 0,
+            ephemeral: false,
+        }
+    }
+
+    pub fn server_shutdown(reason: &str) -> Self {
+        Self {
+            sender: "Server".into(),
+            receiver: "".into(),
+            command: Command::ServerShutdown,
+            content: reason.into(),
+            id: 0,
+            ephemeral: false,
+        }
+    }
+
+    pub fn kicked(reason: &str) -> Self {
+        Self {
+            sender: "Server".into(),
+            receiver: "".into(),
+            command: Command::Kicked,
+            content: reason.into(),
+            id: 0,
+            ephemeral: false,
+        }
+    }
+
+    pub fn announcement(text: &str) -> Self {
+        Self {
+            sender: "Server".into(),
+            receiver: "".into(),
+            command: Command::Announcement,
+            content: text.into(),
+            id: 0,
+            ephemeral: false,
+        }
+    }
+
+    pub fn admin(content: &str) -> Self {
+        Self {
+            sender: "".into(),
+            receiver: "Server".into(),
+            command: Command::Admin,
+            content: content.into(),
+            id: 0,
+            ephemeral: false,
+        }
+    }
+
+    /// ask for the devices of `uid`, empty for the sender's own
+    pub fn device_list(uid: &str) -> Self {
+        Self {
+            sender: "".into(),
+            receiver: uid.into(),
+            command: Command::DeviceList,
+            content: "".into(),
+            id: 0,
+            ephemeral: false,
+        }
+    }
+
+    /// revoke `device` of the sender, its sessions are kicked and its key is no longer served
+    pub fn revoke_device(device: &str) -> Self {
+        Self {
+            sender: "".into(),
+            receiver: "Server".into(),
+            command: Command::RevokeDevice,
+            content: device.into(),
+            id: 0,
+            ephemeral: false,
+        }
+    }
+
+    /// let `device` of the sender log in, `fingerprint` is that of the key it asked with
+    pub fn approve_device(device: &str, fingerprint: &str) -> Self {
+        Self {
+            sender: "".into(),
+            receiver: "Server".into(),
+            command: Command::ApproveDevice,
+            content: format!("{} {}", device, fingerprint).into(),
+            id: 0,
+            ephemeral: false,
+        }
+    }
+
+    /// replace the sender's contacts with `uids`
+    pub fn subscribe(uids: &[String]) -> Self {
+        Self {
+            sender: "".into(),
+            receiver: "Server".into(),
+            command: Command::Subscribe,
+            content: uids.join("\n").into(),
+            id: 0,
+            ephemeral: false,
+        }
+    }
+
+    /// `state` is `Online` or `Away`
+    pub fn set_status(state: PresenceState, text: &str) -> Self {
+        let presence = Presence {
+            state,
+            text: text.into(),
+        };
+        Self {
+            sender: "".into(),
+            receiver: "Server".into(),
+            command: Command::SetStatus,
+            content: presence.to_content().into(),
+            id: 0,
+            ephemeral: false,
+        }
+    }
+
+    /// a hidden uid is left out of `OnlineList`, its contacts see it offline
+    pub fn set_hidden(hidden: bool) -> Self {
+        Self {
+            sender: "".into(),
+            receiver: "Server".into(),
+            command: Command::SetHidden,
+            content: hidden.to_string().into(),
+            id: 0,
+            ephemeral: false,
+        }
+    }
+
+    pub fn presence_changed(uid: &str, presence: &Presence) -> Self {
+        Self {
+            sender: uid.into(),
+            receiver: "".into(),
+            command: Command::PresenceChanged,
+            content: presence.to_content().into(),
+            id: 0,
+            ephemeral: false,
+        }
+    }
+
+    /// ephemeral signal to `to`, one of `Typing`, `StoppedTyping` or `Viewing`
+    /// signals carry no content, the server sees when they are sent either way
+    pub fn signal(to: &str, command: Command) -> Self {
+        Self {
+            sender: "".into(),
+            receiver: to.into(),
+            command,
+            content: "".into(),
+            id: 0,
+            ephemeral: true,
+        }
+    }
+
+    pub fn remote_error(err: GlobalError) -> Self {
+        Self {
+            sender: "Server".into(),
+            receiver: "".into(),
+            command: Command::RemoteError,
+            content: String::from(err).into(),
+            id: 0,
+            ephemeral: false,
         }
     }
 
@@ -111,6 +323,16 @@ impl Message {
         self
     }
 
+    pub fn set_id(mut self, id: u64) -> Self {
+        self.id = id;
+        self
+    }
+
+    pub fn set_content(mut self, content: &[u8]) -> Self {
+        self.content = content.to_vec();
+        self
+    }
+
     pub fn get_receiver(&self) -> String {
         self.receiver.clone()
     }
@@ -1,6 +1,6 @@
 use bytes::{Buf, BufMut, BytesMut};
 use std::io;
-use std::{cmp, usize};
+use std::cmp;
 use tokio_util::codec::{Decoder, Encoder};
 
 use crate::codec::{command::Command, message::Message};
@@ -23,7 +23,7 @@ pub struct MsgCodec {
     // Command in `Message`
     command: Option<Command>,
 
-    // [content-length, sender, receiver]
+    // [content-length, sender, receiver, (id, (flags))]
     args: Option<Vec<String>>,
 
     // max length of `Command` and `Args`
@@ -34,6 +34,9 @@ pub struct MsgCodec {
 
     // triggered when byte stream is in wrong form -> discard to end
     is_discarding: bool,
+
+    // frames answered with `Command::help()` or of an unknown command since `take_malformed`
+    malformed: usize,
 }
 
 impl MsgCodec {
@@ -44,9 +47,16 @@ impl MsgCodec {
             max_before_delimiter: 512,
             content_len: 0,
             is_discarding: false,
+            malformed: 0,
         }
     }
 
+    /// number of malformed frames decoded since the last call
+    /// a `Help` frame the peer sent on purpose is not counted
+    pub fn take_malformed(&mut self) -> usize {
+        std::mem::take(&mut self.malformed)
+    }
+
     /// for either `Discarding` or complete
     pub fn reset(&mut self) {
         self.command = None;
@@ -83,10 +93,7 @@ impl Default for MsgCodec {
 /// if `buf.first()` returns None, i.e buffer is empty, default false breaks the loop
 /// otherwise, this while loop continues until first byte is not ascii white space
 fn trim_front(buf: &mut BytesMut) {
-    while buf
-        .first()
-        .map_or(false, |&byte| byte.is_ascii_whitespace())
-    {
+    while buf.first().is_some_and(|byte| byte.is_ascii_whitespace()) {
         buf.advance(1);
     }
 }
@@ -139,7 +146,15 @@ fn read_command(codec: &mut MsgCodec, buf: &mut BytesMut) -> Result<Option<Comma
             buf.advance(1);
 
             trim_front(&mut command_bytes);
-            Ok(Some(Command::from(command_bytes)))
+            let command = String::from_utf8_lossy(&command_bytes);
+            match command.parse() {
+                Ok(command) => Ok(Some(command)),
+                // decoded as `Help`, like `Command::from`
+                Err(_) => {
+                    codec.malformed += 1;
+                    Ok(Some(Command::Help))
+                }
+            }
         }
     }
 }
@@ -156,7 +171,14 @@ fn read_args(codec: &mut MsgCodec, buf: &mut BytesMut) -> Result<Option<Vec<Stri
 
             let args_string = String::from_utf8_lossy(&args_bytes).to_string();
             let args_vec: Vec<String> = args_string.split(',').map(|s| s.into()).collect();
-            if args_vec.len() != 3 {
+            if !(3..=5).contains(&args_vec.len()) {
+                return Err(());
+            }
+            if let Some(id) = args_vec.get(3) {
+                id.parse::<u64>().map_err(|_| ())?;
+            }
+            // `e` marks an ephemeral frame, no other flag is defined
+            if args_vec.get(4).is_some_and(|flags| flags != "e") {
                 return Err(());
             }
 
@@ -173,8 +195,8 @@ impl Decoder for MsgCodec {
     /// MessageDecoder is a state machine with four states
     /// `Message` can be serialized into three sections
     /// [Command, Arguments, Content]
-    /// where Arguments = `content-length,sender,receiver`
-    /// bytes format: `command#length,sender,receiver|content$`
+    /// where Arguments = `content-length,sender,receiver` optionally followed by `,id` and `,e`
+    /// bytes format: `command#length,sender,receiver[,id[,e]]|content$`
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         loop {
             match self.status() {
@@ -182,6 +204,7 @@ impl Decoder for MsgCodec {
                 MsgCodecStatus::Command => match read_command(self, buf) {
                     Err(_) => {
                         self.is_discarding = true;
+                        self.malformed += 1;
                         return Ok(Some(Command::help()));
                     }
                     Ok(None) => return Ok(None),
@@ -192,6 +215,7 @@ impl Decoder for MsgCodec {
                 MsgCodecStatus::Args => match read_args(self, buf) {
                     Err(_) => {
                         self.is_discarding = true;
+                        self.malformed += 1;
                         return Ok(Some(Command::help()));
                     }
                     Ok(None) => return Ok(None),
@@ -205,6 +229,7 @@ impl Decoder for MsgCodec {
                     }
                     if buf[self.content_len] != b'$' {
                         self.is_discarding = true;
+                        self.malformed += 1;
                         return Ok(Some(Command::help()));
                     }
 
@@ -213,6 +238,8 @@ impl Decoder for MsgCodec {
 
                     let sender = args[1].clone();
                     let receiver = args[2].clone();
+                    let id = args.get(3).and_then(|id| id.parse().ok()).unwrap_or(0);
+                    let ephemeral = args.get(4).is_some();
 
                     let content_bytes = buf.split_to(self.content_len);
 
@@ -224,6 +251,8 @@ impl Decoder for MsgCodec {
                         receiver,
                         command,
                         content: content_bytes.to_vec(),
+                        id,
+                        ephemeral,
                     }));
                 }
                 MsgCodecStatus::Discarding => {
@@ -245,3 +274,81 @@ impl Decoder for MsgCodec {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// every frame `bytes` decodes to
+    fn decode_all(codec: &mut MsgCodec, bytes: &[u8]) -> Vec<Message> {
+        let mut buf = BytesMut::from(bytes);
+        let mut frames = Vec::new();
+        while !buf.is_empty() {
+            let before = buf.len();
+            if let Some(msg) = codec.decode(&mut buf).unwrap() {
+                frames.push(msg);
+            } else if buf.len() == before {
+                break;
+            }
+        }
+        frames
+    }
+
+    #[test]
+    fn malformed_frames_are_counted_help_is_not() {
+        let mut codec = MsgCodec::new();
+        let frames = decode_all(&mut codec, b"Help#0,alice,|$");
+        assert_eq!(frames[0].command, Command::Help);
+        assert_eq!(codec.take_malformed(), 0);
+
+        let frames = decode_all(&mut codec, b"SendMsg#x,alice,bob|hi$Frobnicate#0,alice,|$");
+        assert_eq!(frames.len(), 2);
+        assert!(frames.iter().all(|msg| msg.command == Command::Help));
+        assert_eq!(codec.take_malformed(), 2);
+        assert_eq!(codec.take_malformed(), 0);
+    }
+
+    /// `msg` encoded, then decoded again
+    fn round_trip(msg: Message) -> (BytesMut, Message) {
+        let mut codec = MsgCodec::new();
+        let mut bytes = BytesMut::new();
+        codec.encode(msg, &mut bytes).unwrap();
+        let mut frames = decode_all(&mut codec, &bytes);
+        assert_eq!(frames.len(), 1);
+        (bytes, frames.remove(0))
+    }
+
+    #[test]
+    fn id_and_ephemeral_flag_round_trip() {
+        let (bytes, msg) = round_trip(Message::send_text("bob", b"hi").set_sender("alice"));
+        assert_eq!(&bytes[..], b"SendMsg#2,alice,bob|hi$");
+        assert_eq!((msg.id, msg.ephemeral), (0, false));
+
+        let (bytes, msg) = round_trip(Message::send_text("bob", b"hi").set_id(7));
+        assert_eq!(&bytes[..], b"SendMsg#2,,bob,7|hi$");
+        assert_eq!((msg.id, msg.ephemeral), (7, false));
+
+        let signal = Message::signal("bob/phone", Command::Typing).set_id(7);
+        let (bytes, msg) = round_trip(signal);
+        assert_eq!(&bytes[..], b"Typing#0,,bob/phone,7,e|$");
+        assert_eq!(msg.command, Command::Typing);
+        assert_eq!(msg.receiver, "bob/phone");
+        assert_eq!((msg.id, msg.ephemeral), (7, true));
+
+        // without an id the flag still needs its position
+        let (bytes, msg) = round_trip(Message::signal("bob", Command::Viewing));
+        assert_eq!(&bytes[..], b"Viewing#0,,bob,0,e|$");
+        assert_eq!((msg.id, msg.ephemeral), (0, true));
+    }
+
+    #[test]
+    fn unknown_flags_are_malformed() {
+        let mut codec = MsgCodec::new();
+        let frames = decode_all(
+            &mut codec,
+            b"SendMsg#2,alice,bob,7,x|hi$SendMsg#2,alice,bob,7,e,e|hi$",
+        );
+        assert!(frames.iter().all(|msg| msg.command == Command::Help));
+        assert_eq!(codec.take_malformed(), 2);
+    }
+}
@@ -0,0 +1,287 @@
+use std::{fs, path::Path};
+
+use super::{get, notice, set, Config};
+use crate::{
+    error::{ExternalError, GlobalResult},
+    layout::{self, Layout},
+};
+
+/// bring a file of an older `config_version` up to `VERSION`: run the migrations it misses,
+/// then fill in the keys added since with their defaults
+/// the file is rewritten, the old one is kept as `<file>.v<version>`
+/// returns whether it was rewritten
+pub(super) fn upgrade<C: Config>(
+    path: &Path,
+    value: &mut toml::Value,
+    default: &toml::Value,
+) -> GlobalResult<bool> {
+    let Some(version) = in_memory::<C>(path, value, default)? else {
+        return Ok(false);
+    };
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let backup = path.with_file_name(format!("{}.v{}", name, version));
+    fs::copy(path, &backup)?;
+    fs::write(path, toml::to_string_pretty(value)?)?;
+    notice(format!(
+        "upgraded {} to config_version {}, the old file is kept as {}",
+        path.display(),
+        C::VERSION,
+        backup.display()
+    ));
+    Ok(true)
+}
+
+/// the part of `upgrade` done in memory, returns the version `value` had if it was older
+pub(super) fn in_memory<C: Config>(
+    path: &Path,
+    value: &mut toml::Value,
+    default: &toml::Value,
+) -> GlobalResult<Option<i64>> {
+    let version = get(value, "config_version")
+        .and_then(toml::Value::as_integer)
+        .unwrap_or(0);
+    if version > i64::from(C::VERSION) {
+        return Err(ExternalError::InvalidConfig.info(&format!(
+            "{}: config_version {} is newer than this build understands ({})",
+            path.display(),
+            version,
+            C::VERSION
+        )));
+    }
+    if version == i64::from(C::VERSION) {
+        return Ok(None);
+    }
+    for migration in C::MIGRATIONS.iter().skip(version.max(0) as usize) {
+        migration(value);
+    }
+    fill(value, default, "", C::MAPS);
+    set(
+        value,
+        "config_version",
+        toml::Value::Integer(C::VERSION.into()),
+    )?;
+
+    Ok(Some(version))
+}
+
+/// move an install of the portable layout to the current layout: config.toml next to the
+/// executable and every file or directory of `PATHS` inside the executable's directory
+/// nothing happens once the config of the current layout exists, or if config.toml belongs
+/// to another program of jhchat
+/// config.toml is left as config.toml.migrated, so the portable layout is not detected again
+/// the file keeps its `config_version`, an older one is upgraded once it is loaded
+/// paths set in the file become absolute, the client resolves relative ones against its
+/// working directory, not the data directory
+pub(super) fn from_portable<C: Config>() -> GlobalResult<()> {
+    let from = Layout::Portable.config_path("");
+    let to = C::config_path()?;
+    move_install::<C>(
+        &from,
+        &to,
+        &layout::exe_dir(),
+        &Layout::current().data_dir(),
+    )
+}
+
+/// `from_portable` from the config file `from` and the files of `exe_dir`
+/// to the config file `to` and the files of `data_dir`
+fn move_install<C: Config>(
+    from: &Path,
+    to: &Path,
+    exe_dir: &Path,
+    data_dir: &Path,
+) -> GlobalResult<()> {
+    if from == to || to.try_exists()? || !from.is_file() {
+        return Ok(());
+    }
+    let mut value: toml::Value = toml::from_str(&fs::read_to_string(from)?)?;
+    if value.clone().try_into::<C::This>().is_err() {
+        return Ok(());
+    }
+    let default = toml::Value::try_from(C::associated_default())?;
+    for key in C::PATHS {
+        // relative paths of the portable layout are relative to the executable's directory,
+        // a key missing from the file is the portable default, in the same place as the new one
+        let (old, in_file) = match (get(&value, key), get(&default, key)) {
+            (Some(toml::Value::String(old)), _) => (exe_dir.join(old), true),
+            (None, Some(toml::Value::String(new))) => match Path::new(new).strip_prefix(data_dir) {
+                Ok(relative) => (exe_dir.join(relative), false),
+                Err(_) => continue,
+            },
+            _ => continue,
+        };
+        let Ok(relative) = old.strip_prefix(exe_dir) else {
+            continue;
+        };
+        let new = data_dir.join(relative);
+        if old.exists() {
+            layout::move_path(&old, &new)?;
+        }
+        if in_file {
+            set(
+                &mut value,
+                key,
+                toml::Value::String(new.to_string_lossy().into()),
+            )?;
+        }
+    }
+    if let Some(dir) = to.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(to, toml::to_string_pretty(&value)?)?;
+    fs::rename(from, from.with_file_name("config.toml.migrated"))?;
+    notice(format!(
+        "moved {:?} to {:?}, and its files to {:?}",
+        from, to, data_dir
+    ));
+    Ok(())
+}
+
+/// add every key of `default` missing from `value`, tables in `maps` are taken as a whole
+fn fill(value: &mut toml::Value, default: &toml::Value, prefix: &str, maps: &[&str]) {
+    let (Some(table), Some(defaults)) = (value.as_table_mut(), default.as_table()) else {
+        return;
+    };
+    for (name, default) in defaults {
+        let key = match prefix.is_empty() {
+            true => name.clone(),
+            false => format!("{}.{}", prefix, name),
+        };
+        match table.get_mut(name) {
+            Some(value) if !maps.contains(&key.as_str()) => fill(value, default, &key, maps),
+            Some(_) => (),
+            None => {
+                table.insert(name.clone(), default.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ClientConfig, ServerConfig};
+
+    fn default() -> toml::Value {
+        toml::Value::try_from(ServerConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn versionless_portable_install_is_moved() {
+        let dir = tempfile::tempdir().unwrap();
+        let (exe_dir, data_dir) = (dir.path().join("exe"), dir.path().join("data"));
+        fs::create_dir_all(&exe_dir).unwrap();
+        let from = exe_dir.join("config.toml");
+        let to = dir.path().join("config").join("server.toml");
+        fs::write(
+            &from,
+            "ip = \"0.0.0.0\"\nport = \"2333\"\n[login]\ndevices_file = \"keys/devices.toml\"\n",
+        )
+        .unwrap();
+        fs::create_dir_all(exe_dir.join("keys")).unwrap();
+        fs::write(exe_dir.join("keys").join("devices.toml"), "").unwrap();
+
+        move_install::<ServerConfig>(&from, &to, &exe_dir, &data_dir).unwrap();
+        assert!(!from.exists());
+        assert!(exe_dir.join("config.toml.migrated").is_file());
+        assert!(data_dir.join("keys").join("devices.toml").is_file());
+        let mut value: toml::Value = toml::from_str(&fs::read_to_string(&to).unwrap()).unwrap();
+        let devices_file = data_dir.join("keys").join("devices.toml");
+        assert_eq!(
+            get(&value, "login.devices_file").and_then(toml::Value::as_str),
+            Some(devices_file.to_str().unwrap())
+        );
+        // still version 0 until it is loaded
+        let upgraded = in_memory::<ServerConfig>(&to, &mut value, &default()).unwrap();
+        assert_eq!(upgraded, Some(0));
+        let config: ServerConfig = value.try_into().unwrap();
+        assert_eq!(config.config_version, ServerConfig::VERSION);
+    }
+
+    #[test]
+    fn relative_client_paths_become_absolute() {
+        let dir = tempfile::tempdir().unwrap();
+        let (exe_dir, data_dir) = (dir.path().join("exe"), dir.path().join("data"));
+        let from = exe_dir.join("config.toml");
+        let to = dir.path().join("config").join("client.toml");
+        let mut value = toml::Value::try_from(ClientConfig::default()).unwrap();
+        for (key, dir) in [
+            ("history_dir", "history"),
+            ("encryption.self_key_dir", "keys/self"),
+            ("encryption.unsafe_key_dir", "keys/unsafe"),
+        ] {
+            set(&mut value, key, toml::Value::String(dir.into())).unwrap();
+            fs::create_dir_all(exe_dir.join(dir)).unwrap();
+            fs::write(exe_dir.join(dir).join("alice"), dir).unwrap();
+        }
+        let absolute = dir.path().join("elsewhere").to_string_lossy().to_string();
+        set(
+            &mut value,
+            "encryption.safe_key_dir",
+            toml::Value::String(absolute.clone()),
+        )
+        .unwrap();
+        fs::write(&from, toml::to_string_pretty(&value).unwrap()).unwrap();
+
+        move_install::<ClientConfig>(&from, &to, &exe_dir, &data_dir).unwrap();
+        let config: ClientConfig = toml::from_str(&fs::read_to_string(&to).unwrap()).unwrap();
+        // the client would look for them in its working directory otherwise
+        for (path, dir) in [
+            (&config.history_dir, "history"),
+            (&config.encryption.self_key_dir, "keys/self"),
+            (&config.encryption.unsafe_key_dir, "keys/unsafe"),
+        ] {
+            assert_eq!(Path::new(path), data_dir.join(dir));
+            assert_eq!(
+                fs::read_to_string(Path::new(path).join("alice")).unwrap(),
+                dir
+            );
+            assert!(!exe_dir.join(dir).exists());
+        }
+        // outside the executable's directory, nothing moves
+        assert_eq!(config.encryption.safe_key_dir, absolute);
+    }
+
+    #[test]
+    fn config_of_another_program_is_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("config.toml");
+        let to = dir.path().join("server.toml");
+        let client = toml::to_string_pretty(&ClientConfig::default()).unwrap();
+        fs::write(&from, client).unwrap();
+        move_install::<ServerConfig>(&from, &to, dir.path(), &dir.path().join("data")).unwrap();
+        assert!(from.is_file());
+        assert!(!to.exists());
+    }
+
+    #[test]
+    fn newer_config_version_is_refused() {
+        let path = Path::new("server.toml");
+        let newer = ServerConfig::VERSION + 1;
+        let mut value: toml::Value =
+            toml::from_str(&format!("config_version = {}", newer)).unwrap();
+        let e = in_memory::<ServerConfig>(path, &mut value, &default()).unwrap_err();
+        assert!(e
+            .info
+            .unwrap()
+            .contains("is newer than this build understands"));
+        // the current version needs nothing
+        let mut value = default();
+        let upgraded = in_memory::<ServerConfig>(path, &mut value, &default()).unwrap();
+        assert_eq!(upgraded, None);
+    }
+
+    #[test]
+    fn fill_leaves_maps_alone() {
+        let mut value: toml::Value =
+            toml::from_str("[rate_limit.commands.SendMsg]\nrate = 1.0\nburst = 2\n").unwrap();
+        in_memory::<ServerConfig>(Path::new("server.toml"), &mut value, &default()).unwrap();
+        let commands = get(&value, "rate_limit.commands")
+            .and_then(toml::Value::as_table)
+            .unwrap();
+        // the defaults for other commands would undo their removal
+        assert_eq!(commands.keys().collect::<Vec<_>>(), vec!["SendMsg"]);
+        assert!(get(&value, "rate_limit.default.rate").is_some());
+        assert!(get(&value, "queue.capacity").is_some());
+    }
+}
@@ -0,0 +1,943 @@
+use rsa::{RsaPublicKey, RsaPrivateKey};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    fmt::Debug,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+};
+
+use crate::{
+    codec::command::Command,
+    error::{ExternalError, GlobalResult},
+    layout::Layout,
+};
+
+mod migrate;
+mod overrides;
+mod validate;
+
+use overrides::leaves;
+pub use overrides::Overrides;
+
+// type AnyResult<T> = Result<T, Box<dyn Error>>;
+
+/// associate type should always be `Self`, so that init() can return the specific config struct
+pub trait Config: DeserializeOwned + Serialize {
+    type This: DeserializeOwned + Serialize;
+
+    /// the default value of configuration
+    /// `Default` trait is not used here because it returns `Self`
+    /// while the associate type `This` is required
+    fn associated_default() -> Self::This;
+
+    fn config_path() -> io::Result<PathBuf>;
+
+    /// keys of files and directories, moved along with config.toml when an install of the
+    /// portable layout is migrated
+    const PATHS: &'static [&'static str] = &[];
+
+    /// tables whose keys are chosen by the user, e.g. one per command
+    const MAPS: &'static [&'static str] = &[];
+
+    /// `config_version` written by this build
+    /// bump it along with a new entry of `MIGRATIONS` when a key is renamed, moved or changes
+    /// meaning, keys that are only added need no entry
+    const VERSION: u32 = 1;
+
+    /// `MIGRATIONS[n]` turns a file of version n into version n + 1,
+    /// files without `config_version` are version 0
+    const MIGRATIONS: &'static [fn(&mut toml::Value)] = &[];
+
+    /// problems of a deserialized config as key and reason, e.g. a port out of range
+    fn validate(_config: &Self::This) -> Vec<(String, String)> {
+        Vec::new()
+    }
+
+    /// if `config_path` does exist, use it
+    /// if not, create a default config file
+    fn init() -> GlobalResult<Self::This> {
+        Self::load(&Overrides::default())
+    }
+
+    /// read the config file named by `overrides`, or the defaults with `--defaults`,
+    /// then apply its settings
+    /// a missing config file is created with the defaults, like `init` does
+    fn load(overrides: &Overrides) -> GlobalResult<Self::This> {
+        let layout = overrides.layout.unwrap_or_else(Layout::detect).install();
+        if layout != Layout::Portable && overrides.path.is_none() && !overrides.defaults {
+            migrate::from_portable::<Self>()?;
+        }
+        let default = toml::Value::try_from(Self::associated_default())?;
+        // the path and text of the file, to name the line of a bad key
+        let (value, file) = match overrides.defaults {
+            true => (default.clone(), None),
+            false => {
+                let path = match &overrides.path {
+                    Some(path) => path.clone(),
+                    None => Self::config_path()?,
+                };
+                if !path.try_exists()? {
+                    if let Some(dir) = path.parent() {
+                        fs::create_dir_all(dir)?;
+                    }
+                    fs::write(&path, toml::to_string_pretty(&default)?)?;
+                    return Err(ExternalError::Initialize.info(&format!(
+                        "a default configuration has been generated at {:?}, please restart after modification, or start with --defaults",
+                        path
+                    )));
+                }
+                let text = fs::read_to_string(&path)?;
+                let mut value = toml::from_str(&text).map_err(|e| {
+                    ExternalError::InvalidConfig.info(&format!("{}: {}", path.display(), e))
+                })?;
+                // lines are reported in the file as it is now
+                let text = match migrate::upgrade::<Self>(&path, &mut value, &default)? {
+                    true => fs::read_to_string(&path)?,
+                    false => text,
+                };
+                (value, Some((path, text)))
+            }
+        };
+
+        let mut problems = Vec::new();
+        validate::unknown_keys(&value, &default, "", Self::MAPS, &mut problems);
+        let settings = overrides.resolve(&default)?;
+        let mut value = value;
+        for (key, setting) in &settings {
+            set(&mut value, key, setting.clone())?;
+        }
+        let config = match value.try_into::<Self::This>() {
+            Ok(config) => {
+                problems.extend(Self::validate(&config));
+                Some(config)
+            }
+            Err(e) => {
+                // `invalid type: string "x", expected usize\nin `encryption.key_len``
+                let message = e.to_string();
+                let key = message.rsplit('`').nth(1).unwrap_or_default().to_string();
+                let reason = message.lines().next().unwrap_or_default().to_string();
+                problems.push((key, reason));
+                None
+            }
+        };
+        match (config, problems.is_empty()) {
+            (Some(config), true) => Ok(config),
+            _ => Err(validate::invalid(&problems, file.as_ref(), &settings)),
+        }
+    }
+
+    /// read the config file without ever writing, for programs that only look at another one's
+    /// config, e.g. jhchat-admin at the server's
+    /// an older `config_version` is migrated in memory only
+    /// `None` if there is no config file
+    fn read() -> GlobalResult<Option<Self::This>> {
+        let path = Self::config_path()?;
+        if !path.try_exists()? {
+            return Ok(None);
+        }
+        Self::read_file(&path).map(Some)
+    }
+
+    /// `read` for the config file at `path`, neither validated nor written
+    fn read_file(path: &Path) -> GlobalResult<Self::This> {
+        let default = toml::Value::try_from(Self::associated_default())?;
+        let mut value = toml::from_str(&fs::read_to_string(path)?).map_err(|e| {
+            ExternalError::InvalidConfig.info(&format!("{}: {}", path.display(), e))
+        })?;
+        migrate::in_memory::<Self>(path, &mut value, &default)?;
+        value.try_into::<Self::This>().map_err(|e| {
+            ExternalError::InvalidConfig.info(&format!("{}: {}", path.display(), e))
+        })
+    }
+
+    fn write_string(content: &str) -> io::Result<()> {
+        Self::config_file().and_then(|mut f| f.write_all(content.as_bytes()))?;
+        Ok(())
+    }
+
+    fn read_string() -> io::Result<String> {
+        let mut buf = String::new();
+        Self::config_file().and_then(|mut f| f.read_to_string(&mut buf))?;
+        Ok(buf)
+    }
+
+    fn config_file() -> io::Result<File> {
+        let path = Self::config_path()?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(file)
+    }
+
+    fn exist() -> io::Result<bool> {
+        let exist = Self::config_path().and_then(|path| path.try_exists())?;
+        Ok(exist)
+    }
+
+    fn cur_exe() -> io::Result<PathBuf> {
+        env::current_exe()
+    }
+}
+
+fn get<'a>(root: &'a toml::Value, key: &str) -> Option<&'a toml::Value> {
+    key.split('.').try_fold(root, |value, segment| value.get(segment))
+}
+
+/// set the dotted `key` of `root`, creating the tables on the way
+fn set(root: &mut toml::Value, key: &str, value: toml::Value) -> GlobalResult<()> {
+    let mut segments: Vec<&str> = key.split('.').collect();
+    let last = segments.pop().unwrap_or_default();
+    let mut table = root.as_table_mut();
+    for segment in segments {
+        table = table.and_then(|t| {
+            t.entry(segment)
+                .or_insert_with(|| toml::Value::Table(Default::default()))
+                .as_table_mut()
+        });
+    }
+    let table = table.ok_or_else(|| {
+        ExternalError::Initialize.info(&format!("{} is not a table in the config file", key))
+    })?;
+    table.insert(last.into(), value);
+    Ok(())
+}
+
+/// a key with its old and new value written as TOML, `None` where the key is missing
+pub type Change = (String, Option<String>, Option<String>);
+
+/// every key whose value differs between `old` and `new`
+pub fn diff<T: Serialize>(old: &T, new: &T) -> GlobalResult<Vec<Change>> {
+    let (old, new) = (toml::Value::try_from(old)?, toml::Value::try_from(new)?);
+    let (mut old_keys, mut new_keys) = (Vec::new(), Vec::new());
+    leaves(&old, "", &mut old_keys);
+    leaves(&new, "", &mut new_keys);
+    let old_keys: BTreeMap<_, _> = old_keys.into_iter().collect();
+    let new_keys: BTreeMap<_, _> = new_keys.into_iter().collect();
+    let keys: BTreeSet<&String> = old_keys.keys().chain(new_keys.keys()).collect();
+    Ok(keys
+        .into_iter()
+        .filter_map(|key| {
+            let (old, new) = (old_keys.get(key), new_keys.get(key));
+            match old == new {
+                true => None,
+                false => Some((
+                    key.clone(),
+                    old.map(ToString::to_string),
+                    new.map(ToString::to_string),
+                )),
+            }
+        })
+        .collect())
+}
+
+/// what loading a config did to the files besides reading them, e.g. a migration,
+/// for the program to report once it can
+static NOTICES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+fn notice(text: String) {
+    NOTICES.lock().unwrap_or_else(PoisonError::into_inner).push(text);
+}
+
+/// notices of every config loaded so far, each returned once
+pub fn take_notices() -> Vec<String> {
+    std::mem::take(&mut *NOTICES.lock().unwrap_or_else(PoisonError::into_inner))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    // schema of the file, older files are upgraded when loaded, a file without it is version 0
+    #[serde(default)]
+    pub config_version: u32,
+    pub ip: String,
+    pub port: String,
+    #[serde(default)]
+    pub queue: QueueConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub reload: ReloadConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub login: LoginConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub audit: AuditConfig,
+}
+
+impl Config for ServerConfig {
+    type This = Self;
+
+    const PATHS: &'static [&'static str] = &["login.devices_file", "audit.file"];
+
+    const MAPS: &'static [&'static str] = &["rate_limit.commands"];
+
+    /// config.toml next to the executable, or server.toml in the XDG config directory
+    fn config_path() -> io::Result<PathBuf> {
+        Ok(Layout::current().config_path("server"))
+    }
+
+    fn associated_default() -> Self::This {
+        Self::default()
+    }
+
+    fn validate(config: &Self) -> Vec<(String, String)> {
+        validate::server(config)
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            config_version: <Self as Config>::VERSION,
+            ip: "0.0.0.0".into(),
+            port: "2333".into(),
+            queue: QueueConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            reload: ReloadConfig::default(),
+            admin: AdminConfig::default(),
+            metrics: MetricsConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            login: LoginConfig::default(),
+            log: LogConfig::default(),
+            audit: AuditConfig::default(),
+        }
+    }
+}
+
+/// outgoing queue of each connection
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct QueueConfig {
+    // max number of frames waiting to be written to one connection
+    pub capacity: usize,
+
+    // what happens to a new frame when the receiver's queue is full
+    pub policy: FullQueuePolicy,
+
+    // max number of frames held for one user by `FullQueuePolicy::SpillOffline`
+    pub offline_capacity: usize,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 256,
+            policy: FullQueuePolicy::DropOldest,
+            offline_capacity: 1024,
+        }
+    }
+}
+
+/// graceful shutdown on SIGINT / SIGTERM
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    // sent to every connected user as `ServerShutdown`, followed by the signal name
+    pub notice: String,
+
+    // seconds to wait for write tasks to flush pending frames before exiting
+    pub drain_timeout_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            notice: "server is shutting down".into(),
+            drain_timeout_secs: 10,
+        }
+    }
+}
+
+/// config.toml is re-read on SIGHUP, on `reload` from the admin console and when it changes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ReloadConfig {
+    // seconds between checks of the file for changes, 0 to reload on SIGHUP and `reload` only
+    pub watch_secs: u64,
+}
+
+impl Default for ReloadConfig {
+    fn default() -> Self {
+        Self { watch_secs: 2 }
+    }
+}
+
+/// admin console used by `jhchat-admin`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct AdminConfig {
+    // must be a loopback address, connections from other hosts are refused
+    pub addr: String,
+
+    // shared secret sent by `jhchat-admin` on login
+    // the console is disabled while this is empty
+    pub token: String,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1:2334".into(),
+            token: "".into(),
+        }
+    }
+}
+
+/// tracing output of the server, written to stdout and to files in `dir`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct LogConfig {
+    // `local` for the time zone of the host, or an offset from UTC such as `+09:00` or `-05:30`
+    pub time_zone: String,
+
+    // a level for every target, then `target=level` where it differs, such as
+    // `info,server::admin=debug,core=warn`, levels are off, error, warn, info, debug and trace
+    // applied on reload, like `uids` and `debug_frames`
+    pub level: String,
+
+    pub format: LogFormat,
+
+    // relative to the state directory unless absolute
+    pub dir: String,
+
+    // how often a new file is started, files are named `chat.<date>`
+    pub rotation: LogRotation,
+
+    // files kept on rotation, the oldest are deleted, 0 keeps every file
+    pub max_files: usize,
+
+    // how uids appear in the log, errors naming a uid are logged without their info unless Plain
+    pub uids: LogUids,
+
+    // every frame is logged at debug level with its command, addresses and length
+    // this logs it whole, content included, and is meant for debugging only
+    pub debug_frames: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            time_zone: "+09:00".into(),
+            level: "info".into(),
+            format: LogFormat::Plain,
+            dir: "server_log".into(),
+            rotation: LogRotation::Daily,
+            max_files: 0,
+            uids: LogUids::Plain,
+            debug_frames: false,
+        }
+    }
+}
+
+impl LogConfig {
+    /// `None` for `local`
+    pub fn offset(&self) -> GlobalResult<Option<chrono::FixedOffset>> {
+        let invalid = || {
+            ExternalError::InvalidConfig.info(&format!(
+                "{} is not local or an offset such as +09:00",
+                self.time_zone
+            ))
+        };
+        if self.time_zone.eq_ignore_ascii_case("local") {
+            return Ok(None);
+        }
+        let zone = &self.time_zone;
+        let (sign, rest) = match (zone.strip_prefix('+'), zone.strip_prefix('-')) {
+            (Some(rest), _) => (1, rest),
+            (_, Some(rest)) => (-1, rest),
+            _ => return Err(invalid()),
+        };
+        let (hours, minutes) = rest.split_once(':').unwrap_or((rest, "0"));
+        // `parse` alone would take a second sign, as in `+-09:00`
+        let digits = |s: &str| (1..=2).contains(&s.len()) && s.bytes().all(|b| b.is_ascii_digit());
+        if !digits(hours) || !digits(minutes) {
+            return Err(invalid());
+        }
+        let (Ok(hours), Ok(minutes)) = (hours.parse::<i32>(), minutes.parse::<i32>()) else {
+            return Err(invalid());
+        };
+        if !(0..60).contains(&minutes) {
+            return Err(invalid());
+        }
+        chrono::FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+            .map(Some)
+            .ok_or_else(invalid)
+    }
+
+    /// the directory of the log files, the state directory joined with `dir`
+    pub fn dir(&self) -> PathBuf {
+        Layout::current().state_dir().join(&self.dir)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    // one line of text per event, colored on a terminal
+    Plain,
+    // one JSON object per event, for log aggregators
+    Json,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogUids {
+    Plain,
+    // the first 12 hex digits of the SHA-256 of the uid, the same on every start,
+    // so the digest of a known uid can be searched for
+    Hash,
+    // like `Hash` with a salt chosen on every start, a uid cannot be recognized across restarts
+    Pseudonym,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    Daily,
+    // a single file named `chat`
+    Never,
+}
+
+/// hash-chained record of logins, key changes, kicks, admin commands and rate limit violations,
+/// checked by `jhchat-admin verify-audit`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct AuditConfig {
+    // relative to the data directory unless absolute, the audit log is disabled while this is empty
+    // `<file>.head` next to it holds the hash of the last entry
+    pub file: String,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            file: "audit.log".into(),
+        }
+    }
+}
+
+impl AuditConfig {
+    /// the data directory joined with `file`, `None` while disabled
+    pub fn path(&self) -> Option<PathBuf> {
+        match self.file.is_empty() {
+            true => None,
+            false => Some(Layout::current().data_dir().join(&self.file)),
+        }
+    }
+}
+
+/// HTTP endpoint serving counters in the Prometheus text format at `/metrics`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct MetricsConfig {
+    // the endpoint is disabled while this is empty, and meant for a loopback address
+    // since it has no authentication, any other address is warned about on start
+    pub addr: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1:9333".into(),
+        }
+    }
+}
+
+/// token buckets per (uid, command) and connection cap per ip
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    // 0 means unlimited
+    pub max_connections_per_ip: usize,
+
+    // rejected frames of a uid tolerated before it is kicked, 0 means never kick
+    pub max_violations: usize,
+
+    // one violation is forgiven every this many seconds, 0 means never
+    pub forgive_secs: u64,
+
+    // bucket of every command not listed in `commands`
+    pub default: BucketConfig,
+
+    // keyed by `Command` name, e.g. `SendMsg`
+    pub commands: BTreeMap<String, BucketConfig>,
+}
+
+impl RateLimitConfig {
+    pub fn bucket(&self, command: &Command) -> &BucketConfig {
+        self.commands.get(command.as_ref()).unwrap_or(&self.default)
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        let commands = [
+            ("SendMsg", BucketConfig { rate: 5.0, burst: 20 }),
+            ("GetPubKey", BucketConfig { rate: 1.0, burst: 5 }),
+            ("SendPubKey", BucketConfig { rate: 1.0, burst: 5 }),
+            ("Typing", BucketConfig { rate: 1.0, burst: 5 }),
+            ("StoppedTyping", BucketConfig { rate: 1.0, burst: 5 }),
+            ("Viewing", BucketConfig { rate: 0.5, burst: 3 }),
+        ];
+        Self {
+            max_connections_per_ip: 16,
+            max_violations: 50,
+            forgive_secs: 60,
+            default: BucketConfig { rate: 10.0, burst: 20 },
+            commands: commands.into_iter().map(|(k, v)| (k.into(), v)).collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BucketConfig {
+    // tokens refilled per second
+    pub rate: f64,
+
+    // bucket size, i.e. how many frames may arrive at once
+    pub burst: usize,
+}
+
+impl BucketConfig {
+    /// slower buckets are refused by validation, they would never refill in practice
+    pub const MIN_RATE: f64 = 1.0 / 3600.0;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FullQueuePolicy {
+    // evict the oldest queued frame to make room
+    DropOldest,
+    // drop the slow connection, it may log in again
+    Disconnect,
+    // move the frame to the receiver's offline queue, delivered once the queue drains
+    SpillOffline,
+}
+
+/// authentication of a uid that is already online
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct LoginConfig {
+    // what happens when a device logs in while it already has a session
+    pub duplicate: DuplicateLoginPolicy,
+
+    // registered device keys, relative paths are resolved against the data directory of the layout
+    pub devices_file: String,
+}
+
+impl Default for LoginConfig {
+    fn default() -> Self {
+        Self {
+            duplicate: DuplicateLoginPolicy::KickOld,
+            devices_file: "devices.toml".into(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateLoginPolicy {
+    // refuse the new login with `DuplicatedAuth`, the old session goes on
+    Reject,
+    // send `Kicked` to the old session and close it, the new one takes over
+    KickOld,
+    // keep every session, frames to the device are delivered to all of them
+    Multi,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientConfig {
+    // schema of the file, older files are upgraded when loaded, a file without it is version 0
+    #[serde(default)]
+    pub config_version: u32,
+    pub server_host: String,
+    pub uid: String,
+    // name of this device, each device of a uid has its own key pair
+    #[serde(default = "default_device")]
+    pub device: String,
+    // uids whose presence is pushed to this client, as far as they list this uid too
+    #[serde(default)]
+    pub contacts: Vec<String>,
+    // encrypted message history, one file per uid and device
+    #[serde(default = "default_history_dir")]
+    pub history_dir: String,
+    // `Line` reads commands from stdin, `Tui` takes over the terminal, `Json` is for scripts
+    #[serde(default)]
+    pub ui: UiMode,
+    #[serde(default)]
+    pub fetch: FetchConfig,
+    pub encryption: Encryption,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct FetchConfig {
+    // how long to wait for a device list or public key before giving up on a message
+    pub timeout_secs: u64,
+
+    // device lists younger than this are used without asking the server again
+    // keys are kept for the whole session and read from `unsafe_key_dir` when known
+    pub devices_cache_secs: u64,
+
+    // a message to a uid without an online device waits this long for one to come online
+    pub offline_wait_secs: u64,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 10,
+            devices_cache_secs: 30,
+            offline_wait_secs: 300,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UiMode {
+    // one command per line, suitable for scripting
+    #[default]
+    Line,
+    // full-screen conversations, started with `--tui` as well
+    Tui,
+    // JSON commands on stdin, one JSON event per line on stdout, started with `--json` as well
+    Json,
+}
+
+fn default_device() -> String {
+    crate::device::DEFAULT_DEVICE.into()
+}
+
+fn default_history_dir() -> String {
+    let data_dir = Layout::current().data_dir();
+    data_dir.join("history").to_string_lossy().into()
+}
+
+impl Config for ClientConfig {
+    type This = Self;
+
+    const PATHS: &'static [&'static str] = &[
+        "history_dir",
+        "encryption.self_key_dir",
+        "encryption.unsafe_key_dir",
+        "encryption.safe_key_dir",
+    ];
+
+    /// config.toml next to the executable, or client.toml in the XDG config directory
+    fn config_path() -> io::Result<PathBuf> {
+        Ok(Layout::current().config_path("client"))
+    }
+
+    fn associated_default() -> Self::This {
+        Self::default()
+    }
+
+    fn validate(config: &Self) -> Vec<(String, String)> {
+        validate::client(config)
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            config_version: <Self as Config>::VERSION,
+            server_host: "0.0.0.0:2333".into(),
+            uid: "user".into(),
+            device: default_device(),
+            contacts: Vec::new(),
+            history_dir: default_history_dir(),
+            ui: UiMode::Line,
+            fetch: FetchConfig::default(),
+            encryption: Encryption::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Encryption {
+    pub key_len: usize,
+
+    // public key and private key of current user
+    pub self_key_dir: String,
+
+    // public keys from server
+    // keys exchanged via chat server
+    pub unsafe_key_dir: String,
+
+    // public keys from third party communication
+    // keys exchanged via email, offline, etc.
+    pub safe_key_dir: String,
+
+    // when the public key provided is not identical to the one in rsa_safe_dir
+    // original message will be cancelled (with warning to user)
+    // this message will be encrypted by server public key and sent to server
+    // therefore the server will not be aware of the fact client has already detected its malicious behavior
+    pub dummy_msg: String,
+
+    // by default the client never sends original message if the public key from server mismatches
+    // the one in rsa_safe_dir
+    // if this value is set to `true`, only the first original message will be cancelled
+    pub send_on_unsafe: bool,
+
+    // public key and private key
+    #[serde(skip)]
+    pub rsa_self_pub_key: Option<RsaPublicKey>,
+    #[serde(skip)]
+    pub rsa_self_priv_key: Option<RsaPrivateKey>,
+}
+
+impl Default for Encryption {
+    fn default() -> Self {
+        let data_dir = Layout::current().data_dir();
+        let self_key_dir = data_dir.join("self_key").to_string_lossy().into();
+        let unsafe_key_dir = data_dir.join("unsafe_key").to_string_lossy().into();
+        let safe_key_dir = data_dir.join("safe_key").to_string_lossy().into();
+        Self {
+            key_len: 4096,
+            self_key_dir,
+            unsafe_key_dir,
+            safe_key_dir,
+            rsa_self_pub_key: None,
+            rsa_self_priv_key: None,
+            dummy_msg: "hello?".into(),
+            send_on_unsafe: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// load `text` as the server's config.toml with the arguments `args`
+    fn load(text: &str, args: &[&str]) -> GlobalResult<ServerConfig> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, text).unwrap();
+        let path = path.to_string_lossy().to_string();
+        let args = ["--layout", "portable", "--config", &path]
+            .into_iter()
+            .chain(args.iter().copied())
+            .map(String::from);
+        ServerConfig::load(&Overrides::parse(std::iter::empty(), args, &[])?)
+    }
+
+    /// the lines of the error of `load`
+    fn problems(text: &str, args: &[&str]) -> Vec<String> {
+        let info = load(text, args).unwrap_err().info.unwrap();
+        info.lines().skip(1).map(String::from).collect()
+    }
+
+    const VALID: &str = "config_version = 1\nip = \"127.0.0.1\"\nport = \"2333\"\n";
+
+    #[test]
+    fn valid_file_loads() {
+        let config = load(VALID, &["--queue.capacity", "8"]).unwrap();
+        assert_eq!(config.ip, "127.0.0.1");
+        assert_eq!(config.queue.capacity, 8);
+    }
+
+    #[test]
+    fn misspelled_key_is_named_with_its_line() {
+        let text = format!("{}\n[queue]\ncapacty = 8\n", VALID);
+        assert_eq!(
+            problems(&text, &[]),
+            vec!["  line 6, queue.capacty: unknown setting, is it misspelled?"]
+        );
+    }
+
+    #[test]
+    fn type_error_in_a_table_is_named_with_its_line() {
+        let text = format!("{}\n[shutdown]\ndrain_timeout_secs = \"soon\"\n", VALID);
+        let problems = problems(&text, &[]);
+        assert_eq!(problems.len(), 1);
+        assert!(
+            problems[0].starts_with(
+                "  line 6, shutdown.drain_timeout_secs: invalid type: string \"soon\""
+            ),
+            "{}",
+            problems[0]
+        );
+    }
+
+    #[test]
+    fn overridden_key_is_named_as_an_argument() {
+        assert_eq!(
+            problems(VALID, &["--port", "0"]),
+            vec!["  port (given as an argument or variable): 0 is not a port between 1 and 65535"]
+        );
+    }
+
+    #[test]
+    fn diff_lists_changed_added_and_removed_keys() {
+        let old = ServerConfig::default();
+        let mut new = old.clone();
+        assert!(diff(&old, &new).unwrap().is_empty());
+        new.port = "2334".into();
+        new.admin.token = "secret".into();
+        new.rate_limit.commands.remove("Viewing");
+        new.rate_limit.commands.insert(
+            "Help".into(),
+            BucketConfig {
+                rate: 1.0,
+                burst: 3,
+            },
+        );
+        let keys: Vec<Change> = diff(&old, &new).unwrap();
+        let some = |value: &str| Some(value.to_string());
+        assert_eq!(
+            keys,
+            vec![
+                ("admin.token".into(), some("\"\""), some("\"secret\"")),
+                ("port".into(), some("\"2333\""), some("\"2334\"")),
+                ("rate_limit.commands.Help.burst".into(), None, some("3")),
+                ("rate_limit.commands.Help.rate".into(), None, some("1.0")),
+                ("rate_limit.commands.Viewing.burst".into(), some("3"), None),
+                ("rate_limit.commands.Viewing.rate".into(), some("0.5"), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn time_zones_are_local_or_an_offset() {
+        let offset = |zone: &str| {
+            LogConfig {
+                time_zone: zone.into(),
+                ..Default::default()
+            }
+            .offset()
+        };
+        let east = |seconds| Some(chrono::FixedOffset::east_opt(seconds).unwrap());
+        assert_eq!(offset("+09:00").unwrap(), east(9 * 3600));
+        assert_eq!(offset("-05:30").unwrap(), east(-(5 * 3600 + 30 * 60)));
+        assert_eq!(offset("+9").unwrap(), east(9 * 3600));
+        assert_eq!(offset("+00:00").unwrap(), east(0));
+        assert_eq!(offset("-00:45").unwrap(), east(-45 * 60));
+        assert_eq!(offset("local").unwrap(), None);
+        assert_eq!(offset("Local").unwrap(), None);
+        for zone in [
+            "+25:00", "+24:00", "+09:60", "09:00", "9", "", "+", "+:30", "+09:", "+-09:00",
+            "++09", "+09:+30", "+09:00:00", "+009:00", "UTC", "Asia/Tokyo",
+        ] {
+            let info = offset(zone).unwrap_err().info.unwrap();
+            assert!(info.contains("not local or an offset"), "{:?}: {}", zone, info);
+        }
+    }
+
+    #[test]
+    fn newer_config_version_is_refused() {
+        let text = VALID.replace("config_version = 1", "config_version = 99");
+        let info = load(&text, &[]).unwrap_err().info.unwrap();
+        assert!(info.contains("config_version 99 is newer"), "{}", info);
+    }
+}
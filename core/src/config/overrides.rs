@@ -0,0 +1,295 @@
+use std::{env, path::PathBuf};
+
+use super::set;
+use crate::{
+    error::{ExternalError, GlobalResult},
+    layout::Layout,
+};
+
+/// settings given as `JHCHAT_*` variables or command line arguments, the latter win
+/// keys are those of config.toml, e.g. `--uid bob`, `--fetch.timeout_secs 3`
+/// or `JHCHAT_FETCH_TIMEOUT_SECS=3`
+#[derive(Debug, Default, Clone)]
+pub struct Overrides {
+    // `--config <path>` or `JHCHAT_CONFIG`, instead of config.toml next to the executable
+    pub path: Option<PathBuf>,
+    // `--defaults`, start from the default configuration without reading or writing a file
+    pub defaults: bool,
+    // `--layout` or `JHCHAT_LAYOUT`, detected when absent
+    pub layout: Option<Layout>,
+    // `JHCHAT_*` variables without the prefix, matched against the keys once they are known
+    // variables naming no key are ignored, they may be meant for another program of jhchat
+    env: Vec<(String, String)>,
+    // dotted key and raw value of each argument
+    args: Vec<(String, String)>,
+}
+
+impl Overrides {
+    const ENV_PREFIX: &'static str = "JHCHAT_";
+
+    /// `JHCHAT_*` variables and the arguments of this process
+    /// `flags` are switches taking no value, each sets a key, e.g. `("--tui", "ui", "Tui")`
+    pub fn from_env(flags: &[(&str, &str, &str)]) -> GlobalResult<Self> {
+        Self::parse(env::vars(), env::args().skip(1), flags)
+    }
+
+    pub fn parse(
+        vars: impl IntoIterator<Item = (String, String)>,
+        args: impl IntoIterator<Item = String>,
+        flags: &[(&str, &str, &str)],
+    ) -> GlobalResult<Self> {
+        let mut overrides = Self::default();
+        for (name, value) in vars {
+            match name.strip_prefix(Self::ENV_PREFIX) {
+                Some("CONFIG") => overrides.path = Some(value.into()),
+                Some("LAYOUT") => overrides.layout = Some(Layout::parse(&value)?),
+                Some(name) => overrides.env.push((name.into(), value)),
+                None => (),
+            }
+        }
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if let Some((_, key, value)) = flags.iter().find(|(flag, ..)| *flag == arg) {
+                overrides.args.push((key.to_string(), value.to_string()));
+                continue;
+            }
+            let Some(key) = arg.strip_prefix("--") else {
+                return Err(ExternalError::Initialize.info(&format!("unexpected argument {}", arg)));
+            };
+            // `--key=value` or `--key value`
+            let (key, value) = match key.split_once('=') {
+                Some((key, value)) => (key.to_string(), Some(value.to_string())),
+                None => (key.to_string(), None),
+            };
+            if key == "defaults" && value.is_none() {
+                overrides.defaults = true;
+                continue;
+            }
+            let value = value.or_else(|| args.next()).ok_or_else(|| {
+                ExternalError::Initialize.info(&format!("--{} needs a value", key))
+            })?;
+            match key.as_str() {
+                "config" => overrides.path = Some(value.into()),
+                "layout" => overrides.layout = Some(Layout::parse(&value)?),
+                _ => overrides.args.push((key.replace('-', "_"), value)),
+            }
+        }
+        Ok(overrides)
+    }
+
+    /// set every key of `value` given by a variable or an argument
+    /// `default` is the default configuration, it tells the keys and the type of their values
+    pub fn apply(
+        &self,
+        default: &toml::Value,
+        mut value: toml::Value,
+    ) -> GlobalResult<toml::Value> {
+        for (key, setting) in self.resolve(default)? {
+            set(&mut value, &key, setting)?;
+        }
+        Ok(value)
+    }
+
+    /// every key given by a variable or an argument with its value, in the order to set them
+    pub fn resolve(&self, default: &toml::Value) -> GlobalResult<Vec<(String, toml::Value)>> {
+        let mut keys = Vec::new();
+        leaves(default, "", &mut keys);
+        let mut settings = Vec::new();
+        let from_env = self.env.iter().filter_map(|(name, raw)| {
+            keys.iter()
+                .find(|(key, _)| key.replace('.', "_").to_uppercase() == *name)
+                .map(|(key, default)| (key, *default, raw))
+        });
+        for (key, default, raw) in from_env {
+            settings.push((key.clone(), parse_value(key, default, raw)?));
+        }
+        for (key, raw) in &self.args {
+            let Some((_, default)) = keys.iter().find(|(k, _)| k == key) else {
+                return Err(ExternalError::Initialize.info(&format!("unknown setting {}", key)));
+            };
+            settings.push((key.clone(), parse_value(key, default, raw)?));
+        }
+        Ok(settings)
+    }
+}
+
+/// dotted keys of every value of `value` that is not a table
+pub(super) fn leaves<'a>(value: &'a toml::Value, prefix: &str, keys: &mut Vec<(String, &'a toml::Value)>) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, value) in table {
+                let key = match prefix.is_empty() {
+                    true => key.clone(),
+                    false => format!("{}.{}", prefix, key),
+                };
+                leaves(value, &key, keys);
+            }
+        }
+        value => keys.push((prefix.into(), value)),
+    }
+}
+
+/// `raw` as a value of the type of `default`
+/// strings are taken as they are, arrays may be given as `a,b,c`, anything else is TOML
+fn parse_value(key: &str, default: &toml::Value, raw: &str) -> GlobalResult<toml::Value> {
+    let value = match default {
+        toml::Value::String(_) => toml::Value::String(raw.into()),
+        toml::Value::Array(_) if !raw.trim_start().starts_with('[') => toml::Value::Array(
+            raw.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| toml::Value::String(item.into()))
+                .collect(),
+        ),
+        _ => toml::from_str::<toml::Table>(&format!("value = {}", raw))
+            .ok()
+            .and_then(|mut table| table.remove("value"))
+            .map(|value| match (default, value) {
+                (toml::Value::Float(_), toml::Value::Integer(i)) => toml::Value::Float(i as f64),
+                (_, value) => value,
+            })
+            .filter(|value| value.same_type(default))
+            .ok_or_else(|| {
+                ExternalError::Initialize.info(&format!(
+                    "{} is not a valid {} for {}",
+                    raw,
+                    default.type_str(),
+                    key
+                ))
+            })?,
+    };
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLAGS: &[(&str, &str, &str)] = &[("--tui", "ui", "Tui")];
+
+    fn default() -> toml::Value {
+        toml::from_str(
+            r#"
+            uid = "user"
+            ui = "Line"
+            contacts = ["alice"]
+            [fetch]
+            timeout_secs = 10
+            [limit]
+            burst = 1.5
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn parse(vars: &[(&str, &str)], args: &[&str]) -> GlobalResult<Overrides> {
+        Overrides::parse(
+            vars.iter().map(|(k, v)| (k.to_string(), v.to_string())),
+            args.iter().map(|arg| arg.to_string()),
+            FLAGS,
+        )
+    }
+
+    /// the settings of `vars` and `args`, in the order they are set
+    fn settings(vars: &[(&str, &str)], args: &[&str]) -> GlobalResult<Vec<(String, toml::Value)>> {
+        parse(vars, args)?.resolve(&default())
+    }
+
+    fn setting(key: &str, value: toml::Value) -> (String, toml::Value) {
+        (key.into(), value)
+    }
+
+    #[test]
+    fn both_argument_forms_are_read() {
+        let expected = vec![
+            setting("uid", "bob".into()),
+            setting("fetch.timeout_secs", 3.into()),
+        ];
+        let joined = settings(&[], &["--uid=bob", "--fetch.timeout_secs=3"]).unwrap();
+        let split = settings(&[], &["--uid", "bob", "--fetch.timeout_secs", "3"]).unwrap();
+        assert_eq!(joined, expected);
+        assert_eq!(split, expected);
+        // a value may contain `=`
+        let value = settings(&[], &["--uid=a=b"]).unwrap();
+        assert_eq!(value, vec![setting("uid", "a=b".into())]);
+        assert!(parse(&[], &["--uid"]).is_err());
+        assert!(parse(&[], &["uid"]).is_err());
+    }
+
+    #[test]
+    fn dashes_stand_for_underscores() {
+        let settings = settings(&[], &["--fetch.timeout-secs", "3"]).unwrap();
+        assert_eq!(settings, vec![setting("fetch.timeout_secs", 3.into())]);
+    }
+
+    #[test]
+    fn arguments_win_over_variables() {
+        let vars = [("JHCHAT_UID", "carol"), ("JHCHAT_FETCH_TIMEOUT_SECS", "7")];
+        let settings = settings(&vars, &["--uid", "bob"]).unwrap();
+        // set in order, so the argument overwrites the variable
+        assert_eq!(
+            settings,
+            vec![
+                setting("uid", "carol".into()),
+                setting("fetch.timeout_secs", 7.into()),
+                setting("uid", "bob".into()),
+            ]
+        );
+        let value = parse(&vars, &["--uid", "bob"])
+            .unwrap()
+            .apply(&default(), default())
+            .unwrap();
+        assert_eq!(value["uid"].as_str(), Some("bob"));
+        assert_eq!(value["fetch"]["timeout_secs"].as_integer(), Some(7));
+    }
+
+    #[test]
+    fn unknown_keys() {
+        // a variable may be meant for another program, an argument is a mistake
+        assert!(settings(&[("JHCHAT_NOPE", "1"), ("HOME", "/root")], &[])
+            .unwrap()
+            .is_empty());
+        let e = settings(&[], &["--fetch.timeout", "3"]).unwrap_err();
+        assert!(e.to_string().contains("unknown setting fetch.timeout"));
+    }
+
+    #[test]
+    fn values_take_the_type_of_the_default() {
+        let parsed = settings(
+            &[],
+            &["--contacts", "bob, carol,", "--limit.burst", "2", "--tui"],
+        )
+        .unwrap();
+        assert_eq!(
+            parsed,
+            vec![
+                setting("contacts", vec!["bob", "carol"].into()),
+                // an integer is good for a float
+                setting("limit.burst", 2.0.into()),
+                setting("ui", "Tui".into()),
+            ]
+        );
+        // arrays may be TOML too
+        let parsed = settings(&[], &["--contacts", r#"["bob"]"#]).unwrap();
+        assert_eq!(parsed, vec![setting("contacts", vec!["bob"].into())]);
+        assert!(settings(&[], &["--fetch.timeout_secs", "soon"]).is_err());
+        assert!(settings(&[], &["--limit.burst", "1.5.1"]).is_err());
+    }
+
+    #[test]
+    fn defaults_and_paths() {
+        let overrides = parse(
+            &[("JHCHAT_CONFIG", "/etc/jhchat.toml")],
+            &["--defaults", "--layout", "xdg"],
+        )
+        .unwrap();
+        assert!(overrides.defaults);
+        assert_eq!(overrides.path, Some(PathBuf::from("/etc/jhchat.toml")));
+        assert_eq!(overrides.layout, Some(Layout::Xdg));
+        assert!(overrides.resolve(&default()).unwrap().is_empty());
+        // the argument wins over the variable
+        let overrides = parse(&[("JHCHAT_CONFIG", "a.toml")], &["--config=b.toml"]).unwrap();
+        assert_eq!(overrides.path, Some(PathBuf::from("b.toml")));
+        assert!(!overrides.defaults);
+    }
+}
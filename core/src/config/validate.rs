@@ -0,0 +1,382 @@
+use std::{
+    fs::{self, OpenOptions},
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::{BucketConfig, ClientConfig, ServerConfig};
+use crate::{
+    codec::command::Command,
+    device,
+    encryption::rsa_impl::RsaEncryption,
+    error::{ExternalError, GlobalError},
+    layout::Layout,
+    traits::encrypt::Encrypt,
+};
+
+pub(super) fn server(config: &ServerConfig) -> Vec<(String, String)> {
+    let mut problems = Vec::new();
+    let mut problem = |key: &str, reason: String| problems.push((key.to_string(), reason));
+    if config.ip.parse::<IpAddr>().is_err() {
+        problem("ip", format!("{} is not an IP address", config.ip));
+    }
+    if !matches!(config.port.parse::<u16>(), Ok(port) if port > 0) {
+        problem(
+            "port",
+            format!("{} is not a port between 1 and 65535", config.port),
+        );
+    }
+    if config.queue.capacity == 0 {
+        problem("queue.capacity", "must be at least 1".into());
+    }
+    match config.admin.addr.parse::<SocketAddr>() {
+        Ok(addr) if addr.ip().is_loopback() => (),
+        Ok(_) => problem("admin.addr", "must be a loopback address".into()),
+        Err(_) if config.admin.token.is_empty() => (),
+        Err(_) => problem(
+            "admin.addr",
+            format!("{} is not an ip:port address", config.admin.addr),
+        ),
+    }
+    if !config.metrics.addr.is_empty() && config.metrics.addr.parse::<SocketAddr>().is_err() {
+        problem(
+            "metrics.addr",
+            format!(
+                "{} is not an ip:port address, leave it empty to disable metrics",
+                config.metrics.addr
+            ),
+        );
+    }
+    let buckets = config
+        .rate_limit
+        .commands
+        .iter()
+        .map(|(command, bucket)| (format!("rate_limit.commands.{}", command), bucket))
+        .chain([("rate_limit.default".to_string(), &config.rate_limit.default)]);
+    for (key, bucket) in buckets {
+        if !(bucket.rate.is_finite() && bucket.rate >= BucketConfig::MIN_RATE) {
+            problem(
+                &format!("{}.rate", key),
+                "must allow at least one frame an hour, i.e. 1/3600".into(),
+            );
+        }
+        if bucket.burst == 0 {
+            problem(&format!("{}.burst", key), "must be at least 1".into());
+        }
+    }
+    for command in config.rate_limit.commands.keys() {
+        if command.parse::<Command>().is_err() {
+            problem(
+                &format!("rate_limit.commands.{}", command),
+                "is not a command".into(),
+            );
+        }
+    }
+    if let Err(e) = config.log.offset() {
+        problem("log.time_zone", e.info.unwrap_or_default());
+    }
+    if !valid_level(&config.log.level) {
+        problem(
+            "log.level",
+            format!(
+                "{} is not a level filter such as info,server=debug",
+                config.log.level
+            ),
+        );
+    }
+    if !writable(&config.log.dir()) {
+        problem(
+            "log.dir",
+            format!("{} is not writable", config.log.dir().display()),
+        );
+    }
+    let devices_file = Layout::current()
+        .data_dir()
+        .join(&config.login.devices_file);
+    if !writable(devices_file.parent().unwrap_or(Path::new("./"))) {
+        problem(
+            "login.devices_file",
+            format!(
+                "the directory of {} is not writable",
+                devices_file.display()
+            ),
+        );
+    }
+    problems
+}
+
+pub(super) fn client(config: &ClientConfig) -> Vec<(String, String)> {
+    let mut problems = Vec::new();
+    let mut problem = |key: &str, reason: String| problems.push((key.to_string(), reason));
+    match config
+        .server_host
+        .to_socket_addrs()
+        .map(|mut addrs| addrs.next())
+    {
+        Ok(Some(_)) => (),
+        _ => problem(
+            "server_host",
+            format!(
+                "{} cannot be resolved to a host:port address",
+                config.server_host
+            ),
+        ),
+    }
+    let name = "letters, digits, '-', '_' and '.' only";
+    if !device::valid_name(&config.uid) {
+        problem(
+            "uid",
+            format!("{} is not a valid uid, {}", config.uid, name),
+        );
+    }
+    if !device::valid_name(&config.device) {
+        problem(
+            "device",
+            format!("{} is not a valid device, {}", config.device, name),
+        );
+    }
+    if let Some(uid) = config.contacts.iter().find(|uid| !device::valid_name(uid)) {
+        problem("contacts", format!("{} is not a valid uid, {}", uid, name));
+    }
+    if config.fetch.timeout_secs == 0 {
+        problem("fetch.timeout_secs", "must be at least 1".into());
+    }
+    if !RsaEncryption::valid_key_len(config.encryption.key_len) {
+        problem(
+            "encryption.key_len",
+            format!(
+                "{} bits cannot be used for RSA, try 2048, 3072 or 4096",
+                config.encryption.key_len
+            ),
+        );
+    }
+    let dirs = [
+        ("history_dir", &config.history_dir),
+        ("encryption.self_key_dir", &config.encryption.self_key_dir),
+        (
+            "encryption.unsafe_key_dir",
+            &config.encryption.unsafe_key_dir,
+        ),
+        ("encryption.safe_key_dir", &config.encryption.safe_key_dir),
+    ];
+    for (key, dir) in dirs {
+        if !writable(Path::new(dir)) {
+            problem(key, format!("{} is not writable", dir));
+        }
+    }
+    problems
+}
+
+/// `level` of `LogConfig`, every directive is `level` or `target=level`
+fn valid_level(level: &str) -> bool {
+    let levels = ["off", "error", "warn", "info", "debug", "trace"];
+    level.split(',').all(|directive| {
+        let level = directive.rsplit('=').next().unwrap_or_default().trim();
+        let target = directive.split_once('=').map(|(target, _)| target.trim());
+        levels.iter().any(|l| l.eq_ignore_ascii_case(level)) && target != Some("")
+    })
+}
+
+/// every problem on a line of its own, with the line of the key in the file
+/// unless the key was set by a variable or an argument
+pub(super) fn invalid(
+    problems: &[(String, String)],
+    file: Option<&(PathBuf, String)>,
+    settings: &[(String, toml::Value)],
+) -> GlobalError {
+    let mut info = match file {
+        Some((path, _)) => format!("invalid configuration in {}", path.display()),
+        None => "invalid configuration".into(),
+    };
+    for (key, reason) in problems {
+        let overridden = settings.iter().any(|(k, _)| k == key);
+        let line = match (file, overridden) {
+            (Some((_, text)), false) => line_of(text, key),
+            _ => None,
+        };
+        info.push_str(&match (line, overridden) {
+            (Some(line), _) => format!("\n  line {}, {}: {}", line, key, reason),
+            (None, true) => format!("\n  {} (given as an argument or variable): {}", key, reason),
+            (None, false) => format!("\n  {}: {}", key, reason),
+        });
+    }
+    ExternalError::InvalidConfig.info(&info)
+}
+
+/// line of the dotted `key` in `text`, either `key = ...` in its table or the header of a table
+fn line_of(text: &str, key: &str) -> Option<usize> {
+    let mut table = String::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let name = match line.strip_prefix('[') {
+            Some(header) => {
+                table = header
+                    .trim_matches(|c| c == '[' || c == ']')
+                    .trim()
+                    .to_string();
+                table.clone()
+            }
+            None => match line.split_once('=') {
+                Some((name, _)) if table.is_empty() => name.trim().trim_matches('"').to_string(),
+                Some((name, _)) => format!("{}.{}", table, name.trim().trim_matches('"')),
+                None => continue,
+            },
+        };
+        if name == key {
+            return Some(n + 1);
+        }
+    }
+    None
+}
+
+/// keys of `value` that `default` does not have, except inside `maps`
+pub(super) fn unknown_keys(
+    value: &toml::Value,
+    default: &toml::Value,
+    prefix: &str,
+    maps: &[&str],
+    problems: &mut Vec<(String, String)>,
+) {
+    let (Some(table), Some(defaults)) = (value.as_table(), default.as_table()) else {
+        return;
+    };
+    if maps.contains(&prefix) {
+        return;
+    }
+    for (name, value) in table {
+        let key = match prefix.is_empty() {
+            true => name.clone(),
+            false => format!("{}.{}", prefix, name),
+        };
+        match defaults.get(name) {
+            Some(default) => unknown_keys(value, default, &key, maps, problems),
+            None => problems.push((key, "unknown setting, is it misspelled?".into())),
+        }
+    }
+}
+
+/// `dir` or the first of its parents that exists is a directory a file can be created in
+/// permission bits do not tell, e.g. for root, ACLs or a read-only mount,
+/// so a probe file is created and removed right away
+fn writable(dir: &Path) -> bool {
+    let Some(existing) = dir.ancestors().find(|dir| dir.exists()) else {
+        return false;
+    };
+    if !existing.is_dir() {
+        return false;
+    }
+    static PROBES: AtomicUsize = AtomicUsize::new(0);
+    let n = PROBES.fetch_add(1, Ordering::Relaxed);
+    let probe = existing.join(format!(".jhchat-probe-{}-{}", process::id(), n));
+    match OpenOptions::new().write(true).create_new(true).open(&probe) {
+        Ok(_) => fs::remove_file(&probe).is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = r#"port = "2333"
+prot = "2333"
+
+[queue]
+capacity = 0
+"policy" = "DropNewest"
+
+[rate_limit.commands.Frobnicate]
+rate = 1.0
+"#;
+
+    fn default() -> toml::Value {
+        toml::Value::try_from(ServerConfig::default()).unwrap()
+    }
+
+    fn problems(text: &str) -> Vec<(String, String)> {
+        let mut problems = Vec::new();
+        let value = toml::from_str(text).unwrap();
+        unknown_keys(
+            &value,
+            &default(),
+            "",
+            &["rate_limit.commands"],
+            &mut problems,
+        );
+        problems
+    }
+
+    #[test]
+    fn keys_are_found_in_their_table() {
+        assert_eq!(line_of(TEXT, "port"), Some(1));
+        assert_eq!(line_of(TEXT, "queue.capacity"), Some(5));
+        assert_eq!(line_of(TEXT, "queue.policy"), Some(6));
+        // a table named in a header
+        assert_eq!(line_of(TEXT, "rate_limit.commands.Frobnicate"), Some(8));
+        assert_eq!(
+            line_of(TEXT, "rate_limit.commands.Frobnicate.rate"),
+            Some(9)
+        );
+        // `capacity` at the top level is another key
+        assert_eq!(line_of(TEXT, "capacity"), None);
+        assert_eq!(line_of(TEXT, "ip"), None);
+    }
+
+    #[test]
+    fn misspelled_keys_are_unknown() {
+        let found = problems(TEXT);
+        let keys: Vec<&str> = found.iter().map(|(key, _)| key.as_str()).collect();
+        // keys of `rate_limit.commands` are chosen by the user, see `validate::server`
+        assert_eq!(keys, vec!["prot"]);
+        let found = problems("[queue]\ncapacty = 1\n[logg]\nlevel = \"info\"\n");
+        let keys: Vec<&str> = found.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, vec!["logg", "queue.capacty"]);
+    }
+
+    #[test]
+    fn problems_name_their_line_or_origin() {
+        let file = (PathBuf::from("server.toml"), TEXT.to_string());
+        let problems = vec![
+            (
+                "queue.capacity".to_string(),
+                "must be at least 1".to_string(),
+            ),
+            ("port".to_string(), "0 is not a port".to_string()),
+            ("ip".to_string(), "x is not an IP address".to_string()),
+        ];
+        let settings = vec![("port".to_string(), toml::Value::String("0".into()))];
+        let info = invalid(&problems, Some(&file), &settings).info.unwrap();
+        let lines: Vec<&str> = info.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "invalid configuration in server.toml",
+                "  line 5, queue.capacity: must be at least 1",
+                // the line in the file is not what was used
+                "  port (given as an argument or variable): 0 is not a port",
+                "  ip: x is not an IP address",
+            ]
+        );
+    }
+
+    #[test]
+    fn level_filters() {
+        assert!(valid_level("info"));
+        assert!(valid_level("warn,server=DEBUG"));
+        assert!(!valid_level("loud"));
+        assert!(!valid_level("=info"));
+    }
+
+    #[test]
+    fn writable_looks_at_the_first_existing_parent() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(writable(&dir.path().join("not").join("yet")));
+        let file = dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        assert!(!writable(&file.join("below")));
+        // the probe is gone
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
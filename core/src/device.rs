@@ -0,0 +1,490 @@
+use crate::error::{ClientError, GlobalResult};
+
+use chrono::{Local, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::PathBuf};
+use tokio::sync::Mutex;
+
+/// device of a uid that does not name one, i.e. clients from before multi-device support
+pub const DEFAULT_DEVICE: &str = "default";
+
+/// split `uid/device` into its parts
+/// a bare `uid` addresses every device of the uid
+pub fn parse_address(address: &str) -> (&str, Option<&str>) {
+    match address.split_once('/') {
+        Some((uid, device)) => (uid, Some(device)),
+        None => (address, None),
+    }
+}
+
+pub fn address(uid: &str, device: &str) -> String {
+    format!("{}/{}", uid, device)
+}
+
+/// random text for `Challenge`, new for every login
+pub fn nonce() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// what a device signs to answer `Challenge` with `nonce`, bound to its address
+/// so the signature is of no use for another device or another login
+pub fn login_proof(address: &str, nonce: &str) -> String {
+    format!("jhchat login {} {}", address, nonce)
+}
+
+/// short digest of a public key in PEM, compared by the user to approve a new device
+pub fn fingerprint(key: &str) -> String {
+    sha256::digest(key)[..16].to_string()
+}
+
+/// uid and device names end up in frame arguments and file names
+/// `Server` is the sender and receiver of frames to and from the server, never a uid or device
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        && name != "."
+        && name != ".."
+        && name != "Server"
+}
+
+/// one line of a `DeviceList` frame: `device\tonline|offline|pending\tregistered\tfingerprint`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub online: bool,
+    // waits for approval, it is never online and receives nothing
+    pub pending: bool,
+    pub registered: String,
+    // of its public key, see `fingerprint`
+    pub fingerprint: String,
+}
+
+impl DeviceInfo {
+    pub fn to_line(&self) -> String {
+        let state = match (self.pending, self.online) {
+            (true, _) => "pending",
+            (false, true) => "online",
+            (false, false) => "offline",
+        };
+        format!(
+            "{}\t{}\t{}\t{}",
+            self.name, state, self.registered, self.fingerprint
+        )
+    }
+
+    pub fn from_line(line: &str) -> Option<Self> {
+        let mut fields = line.split('\t');
+        let name = fields.next()?.to_string();
+        let state = fields.next()?;
+        let registered = fields.next().unwrap_or_default().to_string();
+        let fingerprint = fields.next().unwrap_or_default().to_string();
+        Some(Self {
+            name,
+            online: state == "online",
+            pending: state == "pending",
+            registered,
+            fingerprint,
+        })
+    }
+
+    /// parse the content of a `DeviceList` frame
+    pub fn from_content(content: &[u8]) -> Vec<Self> {
+        String::from_utf8_lossy(content)
+            .lines()
+            .filter_map(Self::from_line)
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceRecord {
+    // public key sent with the first login of the device, PEM encoded
+    // empty for clients that do not send one
+    pub key: String,
+    // when the key was registered, or requested for a pending device, see `TIME_FORMAT`
+    pub registered: String,
+    // a revoked device cannot log in again under the same name
+    pub revoked: bool,
+    // asked to log in with `key` while the uid had other devices, waits for `approve`
+    #[serde(default)]
+    pub pending: bool,
+}
+
+/// what `admit` made of a device that proved it holds the private half of its key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    // the key was registered before
+    Known,
+    // the first device of a uid never seen before, its key is now registered
+    Registered,
+    // the uid has or had other devices, one of them or the admin has to approve the key
+    Pending,
+}
+
+/// devices of a uid that may wait for approval at once, so requests cannot pile up
+const MAX_PENDING: usize = 8;
+
+/// a request nobody approved within this many hours is dropped, which frees its place
+const PENDING_HOURS: i64 = 24;
+
+const TIME_FORMAT: &str = "%Y/%m/%d-%H:%M:%S";
+
+type Devices = BTreeMap<String, BTreeMap<String, DeviceRecord>>;
+
+/// whether `record` waits for approval for longer than `PENDING_HOURS`
+fn expired(record: &DeviceRecord, now: NaiveDateTime) -> bool {
+    record.pending
+        && NaiveDateTime::parse_from_str(&record.registered, TIME_FORMAT)
+            .map_or(true, |requested| {
+                (now - requested).num_hours() >= PENDING_HOURS
+            })
+}
+
+/// public keys of every device, keyed by uid then device
+/// the first device of a uid registers its key on login, further devices, also those of a uid
+/// whose devices are all revoked, wait until an existing one or the admin approves them,
+/// later logins must present the same key
+/// persisted as toml after every change when created by `load`, a change is only kept once saved
+#[derive(Debug, Default)]
+pub struct DeviceRegistry {
+    path: Option<PathBuf>,
+    devices: Mutex<Devices>,
+}
+
+impl DeviceRegistry {
+    /// read the registry at `path`, an absent file is an empty registry
+    pub fn load(path: PathBuf) -> GlobalResult<Self> {
+        let devices = match path.try_exists()? {
+            true => toml::from_str(&fs::read_to_string(&path)?)?,
+            false => BTreeMap::new(),
+        };
+        Ok(Self {
+            path: Some(path),
+            devices: Mutex::new(devices),
+        })
+    }
+
+    /// write `devices` next to the registry, then rename it over the registry,
+    /// so a failed or interrupted write leaves the previous one intact
+    async fn save(&self, devices: &Devices) -> GlobalResult<()> {
+        if let Some(path) = &self.path {
+            let temp = path.with_extension("toml.tmp");
+            tokio::fs::write(&temp, toml::to_string_pretty(devices)?).await?;
+            tokio::fs::rename(&temp, path).await?;
+        }
+        Ok(())
+    }
+
+    /// admit `uid/device` presenting `key`, once the device has proven it holds the private half
+    /// the key of the first device of a new uid is registered, that of any other waits for `approve`
+    /// `AuthenticationFailed` error if the device is revoked or presents another key,
+    /// or if too many devices of the uid wait for approval
+    pub async fn admit(&self, uid: &str, device: &str, key: &[u8]) -> GlobalResult<Admission> {
+        let key = String::from_utf8_lossy(key).to_string();
+        let address = address(uid, device);
+        if key.is_empty() {
+            return Err(ClientError::AuthenticationFailed
+                .info(&format!("{} presented no public key", address)));
+        }
+        let mut devices = self.devices.lock().await;
+        let now = Local::now().naive_local();
+        let mut records = devices.get(uid).cloned().unwrap_or_default();
+        records.retain(|_, record| !expired(record, now));
+        match records.get(device) {
+            Some(record) if record.revoked => {
+                return Err(ClientError::AuthenticationFailed
+                    .info(&format!("{} has been revoked", address)))
+            }
+            Some(record) if !record.pending && record.key == key => return Ok(Admission::Known),
+            // clients that never sent a key are registered again like a new device
+            Some(record) if !record.pending && !record.key.is_empty() => {
+                return Err(ClientError::AuthenticationFailed.info(&format!(
+                    "key of {} does not match the registered one",
+                    address
+                )))
+            }
+            _ => (),
+        }
+        // revoked devices count too, whoever logs in next must not take over such a uid
+        let admission = match records.keys().any(|name| name != device) {
+            true => Admission::Pending,
+            false => Admission::Registered,
+        };
+        let pending = records
+            .iter()
+            .filter(|(name, record)| *name != device && record.pending && !record.revoked)
+            .count();
+        if admission == Admission::Pending && pending >= MAX_PENDING {
+            return Err(ClientError::AuthenticationFailed.info(&format!(
+                "{} devices of {} already await approval",
+                pending, uid
+            )));
+        }
+        // a new request of a pending device replaces the key it asked with before
+        let record = DeviceRecord {
+            key,
+            registered: Local::now().format(TIME_FORMAT).to_string(),
+            revoked: false,
+            pending: admission == Admission::Pending,
+        };
+        records.insert(device.into(), record);
+        self.update(&mut devices, uid, records).await?;
+        Ok(admission)
+    }
+
+    /// let the pending `uid/device` log in, `fingerprint` must be that of the key it asked with
+    /// so a later request under the same name cannot slip in
+    /// `ReceiverNotExist` error if the device does not wait for approval or its request expired,
+    /// `AuthenticationFailed` if the fingerprint does not match
+    pub async fn approve(&self, uid: &str, device: &str, fingerprint: &str) -> GlobalResult<()> {
+        let mut devices = self.devices.lock().await;
+        let now = Local::now().naive_local();
+        let mut records = devices.get(uid).cloned().unwrap_or_default();
+        match records.get_mut(device) {
+            Some(record) if record.pending && !record.revoked && !expired(record, now) => {
+                if self::fingerprint(&record.key) != fingerprint.to_lowercase() {
+                    return Err(ClientError::AuthenticationFailed.info(&format!(
+                        "{} asked with another key than {}",
+                        address(uid, device),
+                        fingerprint
+                    )));
+                }
+                record.pending = false;
+            }
+            _ => {
+                return Err(ClientError::ReceiverNotExist
+                    .info(&format!("{} does not await approval", address(uid, device))))
+            }
+        }
+        self.update(&mut devices, uid, records).await
+    }
+
+    /// devices of `uid` that are not revoked, pending ones included, with their records
+    pub async fn devices(&self, uid: &str) -> Vec<(String, DeviceRecord)> {
+        let now = Local::now().naive_local();
+        self.devices
+            .lock()
+            .await
+            .get(uid)
+            .map(|records| {
+                records
+                    .iter()
+                    .filter(|(_, record)| !record.revoked && !expired(record, now))
+                    .map(|(device, record)| (device.clone(), record.clone()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// `ReceiverNotExist` error if `uid/device` is not registered or already revoked
+    pub async fn revoke(&self, uid: &str, device: &str) -> GlobalResult<()> {
+        let mut devices = self.devices.lock().await;
+        let mut records = devices.get(uid).cloned().unwrap_or_default();
+        match records.get_mut(device) {
+            Some(record) if !record.revoked => record.revoked = true,
+            _ => return Err(ClientError::ReceiverNotExist.info(&address(uid, device))),
+        }
+        self.update(&mut devices, uid, records).await
+    }
+
+    /// save `devices` with `records` as those of `uid`, which are kept only once saved
+    async fn update(
+        &self,
+        devices: &mut Devices,
+        uid: &str,
+        records: BTreeMap<String, DeviceRecord>,
+    ) -> GlobalResult<()> {
+        let mut changed = devices.clone();
+        changed.insert(uid.into(), records);
+        self.save(&changed).await?;
+        *devices = changed;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn record(registry: &DeviceRegistry, uid: &str, device: &str) -> DeviceRecord {
+        registry.devices.lock().await[uid][device].clone()
+    }
+
+    #[test]
+    fn names_that_are_paths_or_reserved_are_invalid() {
+        for name in ["alice", "phone-2", "a.b_c", "server", "Servers"] {
+            assert!(valid_name(name), "{}", name);
+        }
+        let long = "a".repeat(65);
+        for name in ["", ".", "..", "a/b", "a b", "Server", long.as_str()] {
+            assert!(!valid_name(name), "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn first_device_is_registered_then_known() {
+        let registry = DeviceRegistry::default();
+        assert_eq!(
+            registry.admit("alice", "phone", b"key-1").await.unwrap(),
+            Admission::Registered
+        );
+        assert_eq!(
+            registry.admit("alice", "phone", b"key-1").await.unwrap(),
+            Admission::Known
+        );
+        let e = registry
+            .admit("alice", "phone", b"key-2")
+            .await
+            .unwrap_err();
+        assert_eq!(e.code(), ClientError::AuthenticationFailed as u16);
+        let e = registry.admit("alice", "phone", b"").await.unwrap_err();
+        assert_eq!(e.code(), ClientError::AuthenticationFailed as u16);
+    }
+
+    #[tokio::test]
+    async fn further_devices_wait_for_approval() {
+        let registry = DeviceRegistry::default();
+        registry.admit("alice", "phone", b"key-1").await.unwrap();
+        assert_eq!(
+            registry.admit("alice", "laptop", b"key-2").await.unwrap(),
+            Admission::Pending
+        );
+        // asking again keeps it pending, it is not known before approval
+        assert_eq!(
+            registry.admit("alice", "laptop", b"key-2").await.unwrap(),
+            Admission::Pending
+        );
+        assert!(record(&registry, "alice", "laptop").await.pending);
+
+        let e = registry
+            .approve("alice", "laptop", "0000000000000000")
+            .await
+            .unwrap_err();
+        assert_eq!(e.code(), ClientError::AuthenticationFailed as u16);
+        registry
+            .approve("alice", "laptop", &fingerprint("key-2"))
+            .await
+            .unwrap();
+        assert_eq!(
+            registry.admit("alice", "laptop", b"key-2").await.unwrap(),
+            Admission::Known
+        );
+        // approved once is enough
+        let e = registry
+            .approve("alice", "laptop", &fingerprint("key-2"))
+            .await
+            .unwrap_err();
+        assert_eq!(e.code(), ClientError::ReceiverNotExist as u16);
+    }
+
+    #[tokio::test]
+    async fn approval_names_the_key() {
+        let registry = DeviceRegistry::default();
+        registry.admit("alice", "phone", b"key-1").await.unwrap();
+        registry.admit("alice", "laptop", b"key-2").await.unwrap();
+        // a later request under the same name replaces the key, the old fingerprint no longer fits
+        registry.admit("alice", "laptop", b"key-3").await.unwrap();
+        assert!(registry
+            .approve("alice", "laptop", &fingerprint("key-2"))
+            .await
+            .is_err());
+        registry
+            .approve("alice", "laptop", &fingerprint("key-3"))
+            .await
+            .unwrap();
+        let e = registry
+            .admit("alice", "laptop", b"key-2")
+            .await
+            .unwrap_err();
+        assert_eq!(e.code(), ClientError::AuthenticationFailed as u16);
+    }
+
+    #[tokio::test]
+    async fn revoked_devices_stay_out() {
+        let registry = DeviceRegistry::default();
+        registry.admit("alice", "phone", b"key-1").await.unwrap();
+        registry.admit("alice", "laptop", b"key-2").await.unwrap();
+        // turning down a pending device
+        registry.revoke("alice", "laptop").await.unwrap();
+        assert!(registry.admit("alice", "laptop", b"key-2").await.is_err());
+        assert!(registry
+            .approve("alice", "laptop", &fingerprint("key-2"))
+            .await
+            .is_err());
+        // with every device revoked, a new one still needs the admin's approval
+        registry.revoke("alice", "phone").await.unwrap();
+        assert!(registry.admit("alice", "phone", b"key-1").await.is_err());
+        assert_eq!(
+            registry.admit("alice", "tablet", b"key-4").await.unwrap(),
+            Admission::Pending
+        );
+        registry
+            .approve("alice", "tablet", &fingerprint("key-4"))
+            .await
+            .unwrap();
+        assert_eq!(
+            registry.admit("alice", "tablet", b"key-4").await.unwrap(),
+            Admission::Known
+        );
+    }
+
+    #[tokio::test]
+    async fn pending_requests_are_bounded() {
+        let registry = DeviceRegistry::default();
+        registry.admit("alice", "phone", b"key").await.unwrap();
+        for n in 0..MAX_PENDING {
+            let device = format!("device-{}", n);
+            assert_eq!(
+                registry.admit("alice", &device, b"key").await.unwrap(),
+                Admission::Pending
+            );
+        }
+        assert!(registry.admit("alice", "one-more", b"key").await.is_err());
+        // a pending device may still ask again
+        assert_eq!(
+            registry.admit("alice", "device-0", b"key").await.unwrap(),
+            Admission::Pending
+        );
+    }
+
+    #[tokio::test]
+    async fn pending_requests_expire() {
+        let registry = DeviceRegistry::default();
+        registry.admit("alice", "phone", b"key").await.unwrap();
+        for n in 0..MAX_PENDING {
+            let device = format!("device-{}", n);
+            registry.admit("alice", &device, b"key").await.unwrap();
+        }
+        // requested long ago
+        let old = Local::now() - chrono::Duration::hours(PENDING_HOURS);
+        if let Some(records) = registry.devices.lock().await.get_mut("alice") {
+            let record = records.get_mut("device-0").unwrap();
+            record.registered = old.format(TIME_FORMAT).to_string();
+        }
+        let e = registry
+            .approve("alice", "device-0", &fingerprint("key"))
+            .await
+            .unwrap_err();
+        assert_eq!(e.code(), ClientError::ReceiverNotExist as u16);
+        assert_eq!(registry.devices("alice").await.len(), MAX_PENDING);
+        // its place is free again
+        assert_eq!(
+            registry.admit("alice", "one-more", b"key").await.unwrap(),
+            Admission::Pending
+        );
+        assert!(!registry.devices.lock().await["alice"].contains_key("device-0"));
+    }
+
+    #[tokio::test]
+    async fn failed_save_changes_nothing() {
+        let registry = DeviceRegistry {
+            path: Some(PathBuf::from("/nonexistent/jhchat/devices.toml")),
+            ..DeviceRegistry::default()
+        };
+        assert!(registry.admit("alice", "phone", b"key").await.is_err());
+        assert!(registry.devices("alice").await.is_empty());
+    }
+}
@@ -2,8 +2,9 @@ use crate::error::{ClientError, GlobalResult};
 use rand::rngs::ThreadRng;
 use rsa::{
     pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding},
-    Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey,
+    Pkcs1v15Encrypt, Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey,
 };
+use sha2::{Digest, Sha256};
 
 use crate::traits::encrypt::Encrypt;
 use async_trait::async_trait;
@@ -33,6 +34,23 @@ impl Encrypt for RsaEncryption {
         Ok(raw)
     }
 
+    fn sign(data: &[u8], priv_key: &Self::PrivateKey) -> GlobalResult<Vec<u8>> {
+        let signature = priv_key
+            .sign(Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(data))
+            .map_err(|_| ClientError::Encryption.info("cannot sign"))?;
+        Ok(signature)
+    }
+
+    fn verify(data: &[u8], signature: &[u8], pub_key: &Self::PublicKey) -> GlobalResult<()> {
+        pub_key
+            .verify(
+                Pkcs1v15Sign::new::<Sha256>(),
+                &Sha256::digest(data),
+                signature,
+            )
+            .map_err(|_| ClientError::AuthenticationFailed.info("signature does not match the key"))
+    }
+
     fn import_pub_key(bytes: &[u8]) -> GlobalResult<Self::PublicKey> {
         let pem_str = String::from_utf8(bytes.to_vec())
             .map_err(|_| ClientError::EncryptKeyPersistence.info("pem not utf8"))?;
@@ -73,4 +91,9 @@ impl Encrypt for RsaEncryption {
         let pub_key = RsaPublicKey::from(&priv_key);
         Ok((pub_key, priv_key))
     }
+
+    /// 2048 to 16384 bits in steps of 256, shorter keys are no longer considered safe
+    fn valid_key_len(len: usize) -> bool {
+        (2048..=16384).step_by(256).any(|n| n == len)
+    }
 }
@@ -1,28 +1,110 @@
 use colored::*;
+use serde::Serialize;
 use std::{fmt::Display, str::FromStr};
+use strum::{EnumMessage, IntoEnumIterator};
 
 pub type GlobalResult<T> = std::result::Result<T, GlobalError>;
 pub type ClientResult<T> = std::result::Result<T, ClientError>;
-impl std::error::Error for GlobalError {}
+
+type Source = Box<dyn std::error::Error + Send + Sync + 'static>;
 
 #[derive(Debug)]
 pub struct GlobalError {
     pub err: ErrorType,
     pub info: Option<String>,
+    // the error this one was converted from, exposed through `Error::source`
+    pub source: Option<Source>,
 }
 
-#[derive(Debug)]
+/// the source is not carried over to the clone
+impl Clone for GlobalError {
+    fn clone(&self) -> Self {
+        Self {
+            err: self.err,
+            info: self.info.clone(),
+            source: None,
+        }
+    }
+}
+
+impl std::error::Error for GlobalError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|e| e as &(dyn std::error::Error + 'static))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub enum ErrorType {
     Client(ClientError),
     Server(ServerError),
     External(ExternalError),
 }
 
+impl ErrorType {
+    pub fn category(&self) -> &'static str {
+        match self {
+            ErrorType::Client(_) => "Client",
+            ErrorType::Server(_) => "Server",
+            ErrorType::External(_) => "External",
+        }
+    }
+
+    /// stable numeric code, see `catalogue`
+    pub fn code(&self) -> u16 {
+        match self {
+            ErrorType::Client(e) => *e as u16,
+            ErrorType::Server(e) => *e as u16,
+            ErrorType::External(e) => *e as u16,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorType::Client(e) => e.into(),
+            ErrorType::Server(e) => e.into(),
+            ErrorType::External(e) => e.into(),
+        }
+    }
+
+    /// look up a variant by its stable code
+    pub fn from_code(code: u16) -> Option<Self> {
+        ClientError::from_repr(code)
+            .map(ErrorType::Client)
+            .or_else(|| ServerError::from_repr(code).map(ErrorType::Server))
+            .or_else(|| ExternalError::from_repr(code).map(ErrorType::External))
+    }
+
+    /// look up a variant by category and name, for peers that do not send codes
+    fn from_name(category: &str, name: &str) -> Option<Self> {
+        match category {
+            "Client" => ClientError::from_str(name).ok().map(ErrorType::Client),
+            "Server" => ServerError::from_str(name).ok().map(ErrorType::Server),
+            "External" => ExternalError::from_str(name).ok().map(ErrorType::External),
+            _ => None,
+        }
+    }
+}
+
+impl GlobalError {
+    pub fn code(&self) -> u16 {
+        self.err.code()
+    }
+
+    /// keep `source` as the cause of this error
+    pub fn with_source(mut self, source: impl std::error::Error + Send + Sync + 'static) -> Self {
+        self.source = Some(Box::new(source));
+        self
+    }
+}
+
 impl ClientError {
     pub fn info(self, i: &str) -> GlobalError {
         GlobalError {
             err: ErrorType::Client(self),
             info: Some(i.into()),
+            source: None,
         }
     }
 }
@@ -32,6 +114,7 @@ impl ServerError {
         GlobalError {
             err: ErrorType::Server(self),
             info: Some(i.into()),
+            source: None,
         }
     }
 }
@@ -41,129 +124,267 @@ impl ExternalError {
         GlobalError {
             err: ErrorType::External(self),
             info: Some(i.into()),
+            source: None,
         }
     }
 }
 
-impl From<ClientError> for GlobalError {
-    fn from(value: ClientError) -> Self {
+impl From<ErrorType> for GlobalError {
+    fn from(value: ErrorType) -> Self {
         Self {
-            err: ErrorType::Client(value),
+            err: value,
             info: None,
+            source: None,
         }
     }
 }
 
+impl From<ClientError> for GlobalError {
+    fn from(value: ClientError) -> Self {
+        ErrorType::Client(value).into()
+    }
+}
+
 impl From<ServerError> for GlobalError {
     fn from(value: ServerError) -> Self {
-        Self {
-            err: ErrorType::Server(value),
-            info: None,
-        }
+        ErrorType::Server(value).into()
     }
 }
 
 impl From<ExternalError> for GlobalError {
     fn from(value: ExternalError) -> Self {
-        Self {
-            err: ErrorType::External(value),
-            info: None,
+        ErrorType::External(value).into()
+    }
+}
+
+/// parse `Category-code-Name: info`
+/// the code wins over the name, so renaming a variant does not break older peers
+/// `Category-Name: info` without a code is accepted as well
+impl FromStr for GlobalError {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (head, info) = match s.split_once(": ") {
+            Some((head, info)) => (head, Some(info.to_string())),
+            None => (s, None),
+        };
+        let parts: Vec<&str> = head.splitn(3, '-').collect();
+        let err = match parts[..] {
+            [category, code, name] => code
+                .parse()
+                .ok()
+                .and_then(ErrorType::from_code)
+                .filter(|err| err.category() == category)
+                .or_else(|| ErrorType::from_name(category, name)),
+            [category, name] => ErrorType::from_name(category, name),
+            _ => None,
         }
+        .ok_or(())?;
+        Ok(Self {
+            err,
+            info,
+            source: None,
+        })
     }
 }
 
+/// lenient version of `FromStr`
+/// a string that cannot be parsed becomes `Client-Unknown` with the original string as info
 impl From<String> for GlobalError {
     fn from(value: String) -> Self {
-        use ErrorType::*;
-        let (source, error_t) = value
-            .split_once('-')
-            .unwrap_or(("Client", "Unknown: cannot deserialize this error"));
-        let (error, info) = match error_t.split_once(": ") {
-            Some((e, i)) => (e, Some(i.into())),
-            None => (error_t, None),
-        };
-        match source {
-            "Server" => Self {
-                err: Server(ServerError::from_str(error).unwrap_or(ServerError::Unknown)),
-                info,
-            },
-            "External" => Self {
-                err: External(ExternalError::from_str(error).unwrap_or(ExternalError::Unknown)),
-                info,
-            },
-            _ => Self {
-                err: Client(ClientError::from_str(error).unwrap_or(ClientError::Unknown)),
-                info,
-            },
-        }
+        value.parse().unwrap_or_else(|_| {
+            ClientError::Unknown.info(&format!("cannot deserialize this error: {}", value))
+        })
     }
 }
 
 impl From<GlobalError> for String {
     fn from(value: GlobalError) -> Self {
-        use ErrorType::*;
         let info = if let Some(i) = value.info {
             format!(": {}", i)
         } else {
             String::new()
         };
-        match value.err {
-            Client(e) => format!("Client-{}{}", e.as_ref(), info),
-            Server(e) => format!("Server-{}{}", e.as_ref(), info),
-            External(e) => format!("External-{}{}", e.as_ref(), info),
-        }
+        format!(
+            "{}-{}-{}{}",
+            value.err.category(),
+            value.err.code(),
+            value.err.name(),
+            info
+        )
     }
 }
 
 impl Display for GlobalError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        use ErrorType::*;
         let info = if let Some(i) = &self.info {
             format!(": {}", i.yellow())
         } else {
             String::new()
         };
-        match &self.err {
-            Client(e) => write!(f, "Client-{}{}", e.as_ref().red(), info),
-            Server(e) => write!(f, "Server-{}{}", e.as_ref().red(), info),
-            External(e) => write!(f, "External-{}{}", e.as_ref().red(), info),
-        }
+        write!(
+            f,
+            "{}-{}-{}{}",
+            self.err.category(),
+            self.err.code(),
+            self.err.name().red(),
+            info
+        )
     }
 }
 
-#[derive(Debug, strum::AsRefStr, strum::EnumString)]
+/// one entry of the error catalogue
+#[derive(Debug, Serialize)]
+pub struct CatalogueEntry {
+    pub code: u16,
+    pub category: &'static str,
+    pub name: &'static str,
+    pub description: &'static str,
+}
+
+/// every error variant with its stable code, ordered by code
+pub fn catalogue() -> Vec<CatalogueEntry> {
+    let client = ClientError::iter().map(ErrorType::Client);
+    let server = ServerError::iter().map(ErrorType::Server);
+    let external = ExternalError::iter().map(ErrorType::External);
+    let mut entries: Vec<CatalogueEntry> = client
+        .chain(server)
+        .chain(external)
+        .map(|err| CatalogueEntry {
+            code: err.code(),
+            category: err.category(),
+            name: err.name(),
+            description: match &err {
+                ErrorType::Client(e) => e.get_message(),
+                ErrorType::Server(e) => e.get_message(),
+                ErrorType::External(e) => e.get_message(),
+            }
+            .unwrap_or_default(),
+        })
+        .collect();
+    entries.sort_by_key(|entry| entry.code);
+    entries
+}
+
+/// `catalogue` as pretty printed JSON, for bots branching on codes
+pub fn catalogue_json() -> String {
+    serde_json::to_string_pretty(&catalogue()).unwrap_or_default()
+}
+
+// codes are part of the protocol: never renumber or reuse one,
+// give a new variant the next free code of its category
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    strum::AsRefStr,
+    strum::IntoStaticStr,
+    strum::EnumString,
+    strum::EnumIter,
+    strum::EnumMessage,
+    strum::FromRepr,
+)]
+#[repr(u16)]
 pub enum ClientError {
-    ReceiverNotExist,
-    EncryptKeyGeneration,
-    EncryptKeyPersistence,
-    Encryption,
-    Decryption,
-    CannotEstablishConnection,
-    AuthenticationFailed,
-    ServerDisconnected,
-    Unknown,
-}
-
-#[derive(Debug, strum::AsRefStr, strum::EnumString)]
+    #[strum(message = "the receiver is not online")]
+    ReceiverNotExist = 1000,
+    #[strum(message = "cannot generate a key pair")]
+    EncryptKeyGeneration = 1001,
+    #[strum(message = "cannot read, write or convert a key")]
+    EncryptKeyPersistence = 1002,
+    #[strum(message = "cannot encrypt the message")]
+    Encryption = 1003,
+    #[strum(message = "cannot decrypt the message")]
+    Decryption = 1004,
+    #[strum(message = "cannot connect to the server")]
+    CannotEstablishConnection = 1005,
+    #[strum(message = "credentials were rejected")]
+    AuthenticationFailed = 1006,
+    #[strum(message = "the server closed the connection")]
+    ServerDisconnected = 1007,
+    #[strum(message = "the device waits for approval by another device of the uid")]
+    AwaitingApproval = 1008,
+    #[strum(message = "the chat history cannot be read or written")]
+    History = 1009,
+    #[strum(message = "the server did not answer in time")]
+    RequestTimeout = 1010,
+    #[strum(message = "the command cannot be parsed")]
+    InvalidCommand = 1011,
+    #[strum(message = "unclassified client error")]
+    Unknown = 1999,
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    strum::AsRefStr,
+    strum::IntoStaticStr,
+    strum::EnumString,
+    strum::EnumIter,
+    strum::EnumMessage,
+    strum::FromRepr,
+)]
+#[repr(u16)]
 pub enum ServerError {
-    UserDisconnect,
-    DuplicatedAuth,
-    UnexpectedFrame,
-    Unknown,
+    #[strum(message = "the user closed the connection")]
+    UserDisconnect = 2000,
+    #[strum(message = "the uid is already logged in")]
+    DuplicatedAuth = 2001,
+    #[strum(message = "the frame is not valid at this point")]
+    UnexpectedFrame = 2002,
+    #[strum(message = "too many frames or connections, retry later")]
+    RateLimited = 2003,
+    #[strum(message = "the server is shutting down")]
+    ShuttingDown = 2004,
+    #[strum(message = "unclassified server error")]
+    Unknown = 2999,
 }
 
-#[derive(Debug, strum::AsRefStr, strum::EnumString)]
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    strum::AsRefStr,
+    strum::IntoStaticStr,
+    strum::EnumString,
+    strum::EnumIter,
+    strum::EnumMessage,
+    strum::FromRepr,
+)]
+#[repr(u16)]
 pub enum ExternalError {
-    Initialize,
-    ListenPort,
-    IO,
-    Concurrent,
-    DeserializeToml,
-    SerializeToml,
-    DeserializeFrame,
-    SerializeFrame,
-    TokioChannel,
-    Unknown,
+    #[strum(message = "startup could not complete")]
+    Initialize = 3000,
+    #[strum(message = "cannot listen on the address")]
+    ListenPort = 3001,
+    #[strum(message = "file or socket IO failed")]
+    IO = 3002,
+    #[strum(message = "a concurrency primitive failed")]
+    Concurrent = 3003,
+    #[strum(message = "invalid TOML")]
+    DeserializeToml = 3004,
+    #[strum(message = "cannot write TOML")]
+    SerializeToml = 3005,
+    #[strum(message = "malformed frame")]
+    DeserializeFrame = 3006,
+    #[strum(message = "cannot encode frame")]
+    SerializeFrame = 3007,
+    #[strum(message = "an internal channel is closed")]
+    TokioChannel = 3008,
+    #[strum(message = "the configuration is invalid")]
+    InvalidConfig = 3009,
+    #[strum(message = "the audit log was edited, truncated or cannot be read")]
+    AuditLog = 3010,
+    #[strum(message = "unclassified external error")]
+    Unknown = 3999,
 }
 
 // ? implicitly invokes `into()`, `From<T>` gives T.into() for free
@@ -222,10 +443,95 @@ impl From<rsa::pkcs8::Error> for GlobalError {
     }
 }
 
-fn wrap_c(e: ClientError, v: impl std::error::Error) -> GlobalError {
-    e.info(&format!("{}", v))
+fn wrap_c(e: ClientError, v: impl std::error::Error + Send + Sync + 'static) -> GlobalError {
+    e.info(&format!("{}", v)).with_source(v)
+}
+
+fn wrap_e(e: ExternalError, v: impl std::error::Error + Send + Sync + 'static) -> GlobalError {
+    e.info(&format!("{}", v)).with_source(v)
 }
 
-fn wrap_e(e: ExternalError, v: impl std::error::Error) -> GlobalError {
-    e.info(&format!("{}", v))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn all() -> Vec<ErrorType> {
+        ClientError::iter()
+            .map(ErrorType::Client)
+            .chain(ServerError::iter().map(ErrorType::Server))
+            .chain(ExternalError::iter().map(ErrorType::External))
+            .collect()
+    }
+
+    #[test]
+    fn codes_are_unique_and_in_their_category_range() {
+        let mut codes = HashSet::new();
+        for err in all() {
+            assert!(codes.insert(err.code()), "{} is used twice", err.code());
+            let range = match err.category() {
+                "Client" => 1000..2000,
+                "Server" => 2000..3000,
+                _ => 3000..4000,
+            };
+            assert!(range.contains(&err.code()), "{}", err.name());
+            assert_eq!(ErrorType::from_code(err.code()).map(|e| e.name()), Some(err.name()));
+        }
+    }
+
+    #[test]
+    fn codes_are_pinned() {
+        assert_eq!(ClientError::ReceiverNotExist as u16, 1000);
+        assert_eq!(ClientError::AuthenticationFailed as u16, 1006);
+        assert_eq!(ClientError::Unknown as u16, 1999);
+        assert_eq!(ServerError::UserDisconnect as u16, 2000);
+        assert_eq!(ServerError::RateLimited as u16, 2003);
+        assert_eq!(ServerError::Unknown as u16, 2999);
+        assert_eq!(ExternalError::Initialize as u16, 3000);
+        assert_eq!(ExternalError::InvalidConfig as u16, 3009);
+        assert_eq!(ExternalError::Unknown as u16, 3999);
+    }
+
+    #[test]
+    fn parses_code_and_legacy_forms() {
+        let e: GlobalError = "Client-1000-ReceiverNotExist: bob".parse().unwrap();
+        assert_eq!(e.code(), ClientError::ReceiverNotExist as u16);
+        assert_eq!(e.info.as_deref(), Some("bob"));
+        assert_eq!(String::from(e), "Client-1000-ReceiverNotExist: bob");
+
+        // the code wins over a renamed variant
+        let e: GlobalError = "Server-2003-Throttled".parse().unwrap();
+        assert_eq!(e.code(), ServerError::RateLimited as u16);
+        assert!(e.info.is_none());
+
+        let e: GlobalError = "Server-RateLimited: retry in 2s".parse().unwrap();
+        assert_eq!(e.code(), ServerError::RateLimited as u16);
+        assert_eq!(e.info.as_deref(), Some("retry in 2s"));
+
+        // a code of another category is ignored, and the name must then be of this one
+        assert!("Client-2003-RateLimited".parse::<GlobalError>().is_err());
+        assert!("Nowhere-Unknown".parse::<GlobalError>().is_err());
+        assert!("Client-Frobnicated".parse::<GlobalError>().is_err());
+    }
+
+    #[test]
+    fn unparsable_strings_keep_their_text() {
+        let e = GlobalError::from("something broke".to_string());
+        assert_eq!(e.code(), ClientError::Unknown as u16);
+        assert!(e.info.unwrap().contains("something broke"));
+    }
+
+    #[test]
+    fn catalogue_lists_every_variant_once() {
+        let entries = catalogue();
+        assert_eq!(entries.len(), all().len());
+        let codes: Vec<u16> = entries.iter().map(|entry| entry.code).collect();
+        let mut sorted = codes.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(codes, sorted);
+        assert!(entries.iter().all(|entry| !entry.description.is_empty()));
+        let json: serde_json::Value = serde_json::from_str(&catalogue_json()).unwrap();
+        assert_eq!(json.as_array().unwrap().len(), entries.len());
+    }
 }
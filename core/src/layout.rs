@@ -0,0 +1,128 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+};
+
+use crate::error::{ExternalError, GlobalResult};
+
+const APP: &str = "jhchat";
+
+static CURRENT: OnceLock<Layout> = OnceLock::new();
+
+/// where config, keys, history and logs are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::AsRefStr, strum::EnumString)]
+#[strum(ascii_case_insensitive)]
+pub enum Layout {
+    // everything next to the executable, config.toml included
+    // what a build directory or an unpacked archive expects
+    Portable,
+    // config under $XDG_CONFIG_HOME/jhchat, keys, history and the device registry
+    // under $XDG_DATA_HOME/jhchat, logs under $XDG_STATE_HOME/jhchat
+    Xdg,
+}
+
+impl Layout {
+    /// `portable` or `xdg`, in any case
+    pub fn parse(value: &str) -> GlobalResult<Self> {
+        Self::from_str(value).map_err(|_| {
+            ExternalError::Initialize.info(&format!("unknown layout {}, try portable or xdg", value))
+        })
+    }
+
+    /// the layout set by `install`, otherwise `detect`
+    pub fn current() -> Self {
+        *CURRENT.get_or_init(Self::detect)
+    }
+
+    /// use `self` for the rest of the process, ignored once the layout is in use
+    pub fn install(self) -> Self {
+        *CURRENT.get_or_init(|| self)
+    }
+
+    /// `Xdg` once $XDG_CONFIG_HOME/jhchat exists and no config.toml is next to the executable,
+    /// `Portable` otherwise, so existing installs and unpacked archives keep working
+    pub fn detect() -> Self {
+        let portable = exe_dir().join("config.toml").is_file();
+        let xdg = xdg_home("XDG_CONFIG_HOME", ".config").is_some_and(|dir| dir.is_dir());
+        match !portable && xdg {
+            true => Self::Xdg,
+            false => Self::Portable,
+        }
+    }
+
+    /// config file of `program`, `client` or `server`
+    /// the portable layout has room for one config.toml only
+    pub fn config_path(&self, program: &str) -> PathBuf {
+        match self {
+            Self::Portable => exe_dir().join("config.toml"),
+            Self::Xdg => xdg_dir("XDG_CONFIG_HOME", ".config").join(format!("{}.toml", program)),
+        }
+    }
+
+    /// keys, history and the device registry
+    pub fn data_dir(&self) -> PathBuf {
+        match self {
+            Self::Portable => exe_dir(),
+            Self::Xdg => xdg_dir("XDG_DATA_HOME", ".local/share"),
+        }
+    }
+
+    /// logs
+    pub fn state_dir(&self) -> PathBuf {
+        match self {
+            Self::Portable => exe_dir(),
+            Self::Xdg => xdg_dir("XDG_STATE_HOME", ".local/state"),
+        }
+    }
+}
+
+/// the directory of the executable, or the working directory if it cannot be told
+pub fn exe_dir() -> PathBuf {
+    env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf))
+        .unwrap_or_else(|| PathBuf::from("./"))
+}
+
+/// `$var/jhchat`, or `$HOME/fallback/jhchat` if `var` is unset or relative as the spec asks
+/// without a home, the portable directory
+fn xdg_dir(var: &str, fallback: &str) -> PathBuf {
+    xdg_home(var, fallback).unwrap_or_else(exe_dir)
+}
+
+fn xdg_home(var: &str, fallback: &str) -> Option<PathBuf> {
+    let base = env::var_os(var)
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(fallback)));
+    base.map(|base| base.join(APP))
+}
+
+/// move a file or directory, copying it when `from` and `to` are on different file systems
+pub fn move_path(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    copy_path(from, to)?;
+    match from.is_dir() {
+        true => fs::remove_dir_all(from),
+        false => fs::remove_file(from),
+    }
+}
+
+fn copy_path(from: &Path, to: &Path) -> io::Result<()> {
+    if !from.is_dir() {
+        return fs::copy(from, to).map(|_| ());
+    }
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        copy_path(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    Ok(())
+}
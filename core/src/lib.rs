@@ -1,7 +1,13 @@
 pub mod config;
+pub mod layout;
 pub mod server_state;
 pub mod error;
 pub mod codec;
 pub mod traits;
 pub mod encryption;
+pub mod channel;
 
+pub mod device;
+pub mod presence;
+pub mod redact;
+pub mod audit;
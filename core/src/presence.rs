@@ -0,0 +1,48 @@
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::AsRefStr, strum::EnumString)]
+#[strum(ascii_case_insensitive)]
+pub enum PresenceState {
+    Online,
+    Away,
+    Offline,
+}
+
+/// what contacts see of a uid, carried by `PresenceChanged` as `state\ttext`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Presence {
+    pub state: PresenceState,
+    // custom status text, may be empty
+    pub text: String,
+}
+
+impl Presence {
+    pub fn online() -> Self {
+        Self {
+            state: PresenceState::Online,
+            text: "".into(),
+        }
+    }
+
+    pub fn offline() -> Self {
+        Self {
+            state: PresenceState::Offline,
+            text: "".into(),
+        }
+    }
+
+    pub fn to_content(&self) -> String {
+        format!("{}\t{}", self.state.as_ref(), self.text)
+    }
+
+    /// parse the content of `PresenceChanged` or `SetStatus`
+    /// the state is case insensitive, the text is optional
+    pub fn from_content(content: &[u8]) -> Option<Self> {
+        let content = String::from_utf8_lossy(content);
+        let (state, text) = content.split_once('\t').unwrap_or((&content, ""));
+        Some(Self {
+            state: PresenceState::from_str(state.trim()).ok()?,
+            text: text.trim().into(),
+        })
+    }
+}
@@ -0,0 +1,207 @@
+use std::{
+    fmt::{self, Display},
+    sync::{PoisonError, RwLock},
+};
+
+use crate::{
+    codec::message::Message,
+    config::{LogConfig, LogUids},
+    device,
+    error::GlobalError,
+};
+
+/// how uids, frames and errors appear in the log, see `LogConfig`
+struct Policy {
+    uids: LogUids,
+    debug_frames: bool,
+    // mixed into every digest of `LogUids::Pseudonym`, new on every start
+    salt: String,
+}
+
+static POLICY: RwLock<Policy> = RwLock::new(Policy {
+    uids: LogUids::Plain,
+    debug_frames: false,
+    salt: String::new(),
+});
+
+/// follow `uids` and `debug_frames` of `config` from now on
+pub fn install(config: &LogConfig) {
+    let mut policy = POLICY.write().unwrap_or_else(PoisonError::into_inner);
+    policy.uids = config.uids;
+    policy.debug_frames = config.debug_frames;
+    if policy.salt.is_empty() {
+        policy.salt = format!("{:032x}", rand::random::<u128>());
+    }
+}
+
+fn digest(salt: &str, name: &str) -> String {
+    sha256::digest(format!("{}{}", salt, name))[..12].to_string()
+}
+
+impl Policy {
+    fn uid(&self, address: &str, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let salt = match self.uids {
+            // frames to and from the server name it `Server`, which is no uid
+            _ if address == "Server" => return f.write_str(address),
+            LogUids::Plain => return f.write_str(address),
+            LogUids::Hash => "",
+            LogUids::Pseudonym => &self.salt,
+        };
+        let (uid, device) = device::parse_address(address);
+        f.write_str(&digest(salt, uid))?;
+        match device {
+            Some(device) => write!(f, "/{}", digest(salt, device)),
+            None => Ok(()),
+        }
+    }
+
+    fn frame(&self, msg: &Message, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.debug_frames {
+            return write!(f, "{:?}", msg);
+        }
+        write!(f, "{} id {}", msg.command.as_ref(), msg.id)?;
+        if !msg.sender.is_empty() {
+            f.write_str(" from ")?;
+            self.uid(&msg.sender, f)?;
+        }
+        if !msg.receiver.is_empty() {
+            f.write_str(" to ")?;
+            self.uid(&msg.receiver, f)?;
+        }
+        write!(f, ", {} bytes of content", msg.content.len())
+    }
+
+    fn error(&self, e: &GlobalError, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.uids {
+            LogUids::Plain => write!(f, "{}", e),
+            _ => write!(f, "{}-{}-{}", e.err.category(), e.err.code(), e.err.name()),
+        }
+    }
+}
+
+/// `uid` or `uid/device` as the log shows it, e.g. `9f86d081884c/2c26b46b68ff` when hashed
+pub fn uid(address: &str) -> Uid<'_> {
+    Uid(address)
+}
+
+pub struct Uid<'a>(&'a str);
+
+impl Display for Uid<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let policy = POLICY.read().unwrap_or_else(PoisonError::into_inner);
+        policy.uid(self.0, f)
+    }
+}
+
+/// command, id, sender, receiver and the length of the content, never the content itself
+/// unless `debug_frames` is set
+pub fn frame(msg: &Message) -> Frame<'_> {
+    Frame(msg)
+}
+
+pub struct Frame<'a>(&'a Message);
+
+impl Display for Frame<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let policy = POLICY.read().unwrap_or_else(PoisonError::into_inner);
+        policy.frame(self.0, f)
+    }
+}
+
+/// `e` with its info, which may name a uid, only while uids are logged as they are
+pub fn error(e: &GlobalError) -> Error<'_> {
+    Error(e)
+}
+
+pub struct Error<'a>(&'a GlobalError);
+
+impl Display for Error<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let policy = POLICY.read().unwrap_or_else(PoisonError::into_inner);
+        policy.error(self.0, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ClientError;
+
+    const PAYLOAD: &[u8] = b"meet me at the docks";
+
+    // formats with `policy` instead of the global one, which other tests may change
+    struct With<'a>(&'a Policy, &'a dyn Fn(&Policy, &mut fmt::Formatter<'_>) -> fmt::Result);
+
+    impl Display for With<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            (self.1)(self.0, f)
+        }
+    }
+
+    fn policy(uids: LogUids, debug_frames: bool) -> Policy {
+        Policy {
+            uids,
+            debug_frames,
+            salt: "salt".into(),
+        }
+    }
+
+    fn show_frame(policy: &Policy, msg: &Message) -> String {
+        With(policy, &|p, f| p.frame(msg, f)).to_string()
+    }
+
+    fn show_uid(policy: &Policy, address: &str) -> String {
+        With(policy, &|p, f| p.uid(address, f)).to_string()
+    }
+
+    fn text() -> Message {
+        Message::send_text("bob/laptop", PAYLOAD).set_sender("alice/phone")
+    }
+
+    #[test]
+    fn frames_never_show_their_content() {
+        let msg = text();
+        let debug = format!("{:?}", msg.content);
+        for uids in [LogUids::Plain, LogUids::Hash, LogUids::Pseudonym] {
+            let line = show_frame(&policy(uids, false), &msg);
+            assert!(!line.contains("meet me"), "{}", line);
+            assert!(!line.contains(&debug), "{}", line);
+            assert!(line.contains(&format!("{} bytes of content", PAYLOAD.len())));
+        }
+        let line = show_frame(&policy(LogUids::Plain, false), &msg);
+        assert!(line.contains("from alice/phone to bob/laptop"), "{}", line);
+    }
+
+    #[test]
+    fn debug_frames_show_the_whole_frame() {
+        let line = show_frame(&policy(LogUids::Plain, true), &text());
+        assert!(line.contains(&format!("{:?}", PAYLOAD)), "{}", line);
+    }
+
+    #[test]
+    fn hashed_uids_hide_the_names() {
+        let hash = policy(LogUids::Hash, false);
+        let line = show_frame(&hash, &text());
+        for name in ["alice", "phone", "bob", "laptop"] {
+            assert!(!line.contains(name), "{}", line);
+        }
+        let hashed = show_uid(&hash, "alice/phone");
+        assert_eq!(hashed, format!("{}/{}", digest("", "alice"), digest("", "phone")));
+        assert_eq!(show_uid(&hash, "alice"), digest("", "alice"));
+        assert_eq!(show_uid(&hash, "Server"), "Server");
+
+        let pseudonym = show_uid(&policy(LogUids::Pseudonym, false), "alice/phone");
+        assert_ne!(pseudonym, hashed);
+        assert!(!pseudonym.contains("alice"));
+    }
+
+    #[test]
+    fn errors_lose_their_info_unless_uids_are_plain() {
+        let e = ClientError::ReceiverNotExist.info("bob");
+        let show = |uids| With(&policy(uids, false), &|p, f| p.error(&e, f)).to_string();
+        assert!(show(LogUids::Plain).contains("bob"));
+        let line = show(LogUids::Hash);
+        assert!(!line.contains("bob"), "{}", line);
+        assert!(line.contains(&e.err.code().to_string()));
+    }
+}
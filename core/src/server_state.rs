@@ -1,61 +1,1096 @@
 use crate::{
+    audit::{self, Event},
+    channel::{self, Rx, TrySendError, Tx},
     codec::message::Message,
-    error::{GlobalResult, ClientError},
+    config::{DuplicateLoginPolicy, FullQueuePolicy, LoginConfig, QueueConfig},
+    device::{self, DeviceInfo, DeviceRegistry},
+    error::{ClientError, GlobalError, GlobalResult, ServerError},
+    presence::{Presence, PresenceState},
+    redact,
 };
 
-use std::collections::HashMap;
-use tokio::sync::{mpsc, Mutex, RwLock};
+use chrono::{DateTime, Local};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        PoisonError, RwLock as StdRwLock,
+    },
+};
+use tokio::sync::{Mutex, RwLock};
 
-type Tx = mpsc::UnboundedSender<Message>;
 type Uid = String;
 
+/// identifies one session among those of the same uid
+pub type SessionId = u64;
+
+/// a logged in connection
+#[derive(Debug)]
+pub struct Session {
+    pub id: SessionId,
+    pub device: String,
+    pub tx: Tx,
+    pub addr: SocketAddr,
+    pub since: DateTime<Local>,
+}
+
+/// status of an online uid
+#[derive(Debug, Clone)]
+struct Status {
+    presence: Presence,
+    // left out of `OnlineList` and `Offline` to contacts
+    hidden: bool,
+}
+
+impl Status {
+    /// what contacts see
+    fn visible(&self) -> Presence {
+        match self.hidden {
+            true => Presence::offline(),
+            false => self.presence.clone(),
+        }
+    }
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Self {
+            presence: Presence::online(),
+            hidden: false,
+        }
+    }
+}
+
+/// presence subscriptions, declared again by each client after login
+/// a uid sees the presence of a contact only if that contact lists it too
+#[derive(Debug, Default)]
+struct Presences {
+    // present while the uid has at least one session
+    status: HashMap<Uid, Status>,
+    // uid -> uids that receive its `PresenceChanged`
+    subscribers: HashMap<Uid, HashSet<Uid>>,
+    // uid -> uids it subscribed to, the reverse of `subscribers`
+    contacts: HashMap<Uid, HashSet<Uid>>,
+}
+
+impl Presences {
+    /// whether `a` and `b` list each other
+    fn mutual(&self, a: &str, b: &str) -> bool {
+        let lists = |owner: &str, contact: &str| {
+            self.contacts
+                .get(owner)
+                .is_some_and(|contacts| contacts.contains(contact))
+        };
+        lists(a, b) && lists(b, a)
+    }
+
+    /// subscribers of `uid` that it lists as well, i.e. those who see its presence
+    fn watchers(&self, uid: &str) -> Vec<Uid> {
+        self.subscribers
+            .get(uid)
+            .map(|subscribers| {
+                subscribers
+                    .iter()
+                    .filter(|subscriber| self.mutual(uid, subscriber))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
 /// `OnlineUsers` holds a read write locked map
-/// each entry is a pair of (unique_id, sender)
+/// each entry is a pair of (unique_id, sessions)
+/// a uid has one session per device, more only under `DuplicateLoginPolicy::Multi`
+/// frames spilled by `FullQueuePolicy::SpillOffline` wait in `offline`
 #[derive(Debug)]
 pub struct OnlineUsers {
-    pub list: RwLock<HashMap<Uid, Mutex<Tx>>>,
+    pub list: RwLock<HashMap<Uid, Vec<Session>>>,
+    pub offline: Mutex<HashMap<Uid, VecDeque<Message>>>,
+    pub devices: DeviceRegistry,
+    presences: Mutex<Presences>,
+    queue: StdRwLock<QueueConfig>,
+    login: StdRwLock<LoginConfig>,
+    next_session: AtomicU64,
+    // set by `shutdown`, no session is added afterwards
+    shutting_down: AtomicBool,
 }
 
 impl OnlineUsers {
-    pub fn new() -> Self {
+    pub fn new(queue: QueueConfig, login: LoginConfig, devices: DeviceRegistry) -> Self {
         let list = RwLock::new(HashMap::new());
-        Self { list }
+        let offline = Mutex::new(HashMap::new());
+        Self {
+            list,
+            offline,
+            devices,
+            presences: Mutex::new(Presences::default()),
+            queue: StdRwLock::new(queue),
+            login: StdRwLock::new(login),
+            next_session: AtomicU64::new(1),
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    pub fn queue_config(&self) -> QueueConfig {
+        self.queue.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// takes effect immediately for the policy, and for new sessions for the capacity
+    pub fn set_queue_config(&self, queue: QueueConfig) {
+        *self.queue.write().unwrap_or_else(PoisonError::into_inner) = queue;
+    }
+
+    pub fn login_config(&self) -> LoginConfig {
+        self.login.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// takes effect from the next login, existing sessions are kept
+    pub fn set_login_config(&self, login: LoginConfig) {
+        *self.login.write().unwrap_or_else(PoisonError::into_inner) = login;
     }
 
     /// generate a `Message` that contains current list of online unique_id
     /// the `Message` will have `Text` content tyle
+    /// hidden uids are left out
     pub async fn to_msg(&self) -> Message {
         let list_map = self.list.read().await;
-        let list_keys: Vec<String> = list_map.keys().map(|key| key.into()).collect();
+        let presences = self.presences.lock().await;
+        let list_keys: Vec<String> = list_map
+            .keys()
+            .filter(|key| !presences.status.get(*key).is_some_and(|s| s.hidden))
+            .map(|key| key.into())
+            .collect();
         Message::online_list(&list_keys.join("\n"))
     }
 
-    /// insert an entry (unique_id, sender) to the map
-    pub async fn add_user(&self, uid: &str, tx: Tx) {
+    /// add a session of `uid/device` to the map
+    /// an existing session of the same device is handled by `LoginConfig::duplicate`
+    /// contacts of `uid` receive `PresenceChanged` if it was offline
+    /// returns the id of the new session and the receiving half of its outgoing queue
+    /// `DuplicatedAuth` error if `uid/device` is online and the policy is `Reject`,
+    /// `ShuttingDown` once `shutdown` has begun
+    pub async fn add_user(
+        &self,
+        uid: &str,
+        device: &str,
+        addr: SocketAddr,
+    ) -> GlobalResult<(SessionId, Rx)> {
+        let (tx, rx) = channel::bounded(self.queue_config().capacity);
+        let session = Session {
+            id: self.next_session.fetch_add(1, Ordering::Relaxed),
+            device: device.into(),
+            tx,
+            addr,
+            since: Local::now(),
+        };
+        let id = session.id;
         let mut list = self.list.write().await;
-        list.insert(uid.into(), Mutex::new(tx));
+        // set under the same lock, a session is either drained by `shutdown` or refused
+        if self.shutting_down.load(Ordering::Acquire) {
+            return Err(ServerError::ShuttingDown.info(uid));
+        }
+        let sessions = list.entry(uid.into()).or_default();
+        let address = device::address(uid, device);
+        let newly_online = sessions.is_empty();
+        let mut kicked = Vec::new();
+        if sessions.iter().any(|s| s.device == device) {
+            match self.login_config().duplicate {
+                DuplicateLoginPolicy::Reject => {
+                    return Err(ServerError::DuplicatedAuth
+                        .info(&format!("{} is already online", address)));
+                }
+                DuplicateLoginPolicy::KickOld => {
+                    let reason = format!("{} logged in from {}", address, addr);
+                    sessions.retain(|old| {
+                        if old.device != device {
+                            return true;
+                        }
+                        let _ = old.tx.force_send(Message::kicked(&reason).set_receiver(&address));
+                        old.tx.close();
+                        kicked.push(Event::Kicked {
+                            address: address.clone(),
+                            reason: reason.clone(),
+                        });
+                        false
+                    });
+                }
+                DuplicateLoginPolicy::Multi => (),
+            }
+        }
+        sessions.push(session);
+        drop(list);
+        kicked.into_iter().for_each(audit::record);
+        if newly_online {
+            self.presences
+                .lock()
+                .await
+                .status
+                .insert(uid.into(), Status::default());
+            self.notify(uid, &Presence::online()).await;
+        }
+        self.restore_spilled(uid).await;
+        Ok((id, rx))
     }
 
-    /// remove an entry (unique_id, sender) from the map
-    pub async fn remove_user(&self, uid: &str) {
+    /// remove the session `id` of `uid` from the map
+    /// a session that was already replaced or kicked is left alone
+    /// returns true if `uid` has no session left, its contacts then receive `PresenceChanged`
+    pub async fn remove_user(&self, uid: &str, id: SessionId) -> bool {
         let mut list = self.list.write().await;
-        list.remove(uid);
+        let gone = match list.get_mut(uid) {
+            Some(sessions) => {
+                sessions.retain(|s| s.id != id);
+                sessions.is_empty()
+            }
+            // kicked or disconnected as a slow receiver
+            None => true,
+        };
+        if gone {
+            list.remove(uid);
+        }
+        drop(list);
+        if gone {
+            self.went_offline(uid).await;
+        }
+        gone
+    }
+
+    /// forget the status and subscriptions of `uid`, then tell its contacts
+    /// only the first call after `uid` goes offline notifies anyone
+    async fn went_offline(&self, uid: &str) {
+        let mut presences = self.presences.lock().await;
+        // contacts of a hidden uid already see it offline
+        let watchers = match presences.status.remove(uid) {
+            Some(status) if !status.hidden => presences.watchers(uid),
+            _ => Vec::new(),
+        };
+        for contact in presences.contacts.remove(uid).unwrap_or_default() {
+            if let Some(subscribers) = presences.subscribers.get_mut(&contact) {
+                subscribers.remove(uid);
+                if subscribers.is_empty() {
+                    presences.subscribers.remove(&contact);
+                }
+            }
+        }
+        drop(presences);
+        self.tell(uid, &Presence::offline(), watchers).await;
+    }
+
+    /// send `PresenceChanged` of `uid` to those who see its presence
+    async fn notify(&self, uid: &str, presence: &Presence) {
+        let watchers = self.presences.lock().await.watchers(uid);
+        self.tell(uid, presence, watchers).await;
+    }
+
+    async fn tell(&self, uid: &str, presence: &Presence, watchers: Vec<Uid>) {
+        for watcher in watchers {
+            let _ = self
+                .send(&watcher, Message::presence_changed(uid, presence))
+                .await;
+        }
+    }
+
+    /// replace the contacts of `uid` with `contacts`
+    /// contacts that list `uid` as well start or stop seeing its presence
+    /// returns the current presence of each contact, `Offline` for those that do not list `uid`
+    pub async fn subscribe(&self, uid: &str, contacts: HashSet<Uid>) -> Vec<(Uid, Presence)> {
+        let mut presences = self.presences.lock().await;
+        let before = presences.watchers(uid);
+        for old in presences.contacts.remove(uid).unwrap_or_default() {
+            if let Some(subscribers) = presences.subscribers.get_mut(&old) {
+                subscribers.remove(uid);
+                if subscribers.is_empty() {
+                    presences.subscribers.remove(&old);
+                }
+            }
+        }
+        for contact in &contacts {
+            presences
+                .subscribers
+                .entry(contact.clone())
+                .or_default()
+                .insert(uid.into());
+        }
+        presences.contacts.insert(uid.into(), contacts.clone());
+        let mut current: Vec<(Uid, Presence)> = contacts
+            .into_iter()
+            .map(|contact| {
+                let presence = match presences.status.get(&contact) {
+                    Some(status) if presences.mutual(uid, &contact) => status.visible(),
+                    _ => Presence::offline(),
+                };
+                (contact, presence)
+            })
+            .collect();
+        current.sort_by(|a, b| a.0.cmp(&b.0));
+        let after = presences.watchers(uid);
+        let presence = presences
+            .status
+            .get(uid)
+            .map_or(Presence::offline(), Status::visible);
+        drop(presences);
+        let gained = after.iter().filter(|w| !before.contains(w)).cloned();
+        let lost = before.iter().filter(|w| !after.contains(w)).cloned();
+        self.tell(uid, &presence, gained.collect()).await;
+        self.tell(uid, &Presence::offline(), lost.collect()).await;
+        current
     }
 
-    /// send a `Message` to `receiver`
-    /// `Offline` error if `receiver` is not a key in the map
-    /// `Channel` error if the sender fails
+    /// set the status of `uid` and tell its contacts, unless it is hidden
+    /// `Offline` cannot be set, it follows from the sessions of `uid`
+    pub async fn set_status(&self, uid: &str, presence: Presence) -> GlobalResult<()> {
+        if presence.state == PresenceState::Offline {
+            return Err(ServerError::UnexpectedFrame.info("status must be Online or Away"));
+        }
+        let mut presences = self.presences.lock().await;
+        let status = presences
+            .status
+            .get_mut(uid)
+            .ok_or(ClientError::ReceiverNotExist.info(uid))?;
+        if status.presence == presence {
+            return Ok(());
+        }
+        status.presence = presence.clone();
+        let hidden = status.hidden;
+        drop(presences);
+        if !hidden {
+            self.notify(uid, &presence).await;
+        }
+        Ok(())
+    }
+
+    /// a hidden uid is left out of `OnlineList` and its contacts see it `Offline`,
+    /// those who message it or ask for its devices can still tell it is online
+    pub async fn set_hidden(&self, uid: &str, hidden: bool) -> GlobalResult<()> {
+        let mut presences = self.presences.lock().await;
+        let status = presences
+            .status
+            .get_mut(uid)
+            .ok_or(ClientError::ReceiverNotExist.info(uid))?;
+        if status.hidden == hidden {
+            return Ok(());
+        }
+        status.hidden = hidden;
+        let presence = status.visible();
+        drop(presences);
+        self.notify(uid, &presence).await;
+        Ok(())
+    }
+
+    /// send a `Message` to every session of `receiver`, which is either `uid` or `uid/device`
+    /// `ReceiverNotExist` error if no session matches `receiver`
+    /// a full queue is handled according to `QueueConfig::policy`
     pub async fn send(&self, receiver: &str, msg: Message) -> GlobalResult<()> {
+        let msg = msg.set_receiver(receiver);
+        let (uid, device) = device::parse_address(receiver);
+        let queue = self.queue_config();
+        let list = self.list.read().await;
+        let sessions: Vec<&Session> = list
+            .get(uid)
+            .map(|sessions| sessions.iter().filter(|s| s.matches(device)).collect())
+            .unwrap_or_default();
+        if sessions.is_empty() {
+            return Err(ClientError::ReceiverNotExist.info(receiver));
+        }
+        match queue.policy {
+            FullQueuePolicy::DropOldest => {
+                let mut delivered = false;
+                for Session { tx, .. } in sessions.iter() {
+                    match tx.force_send(msg.clone()) {
+                        Ok(Some(evicted)) => tracing::warn!(
+                            "queue of {} is full, dropped oldest {} frame",
+                            redact::uid(receiver),
+                            evicted.command.as_ref()
+                        ),
+                        Ok(None) => (),
+                        Err(_) => continue,
+                    }
+                    delivered = true;
+                }
+                if !delivered {
+                    return Err(closed(receiver));
+                }
+            }
+            FullQueuePolicy::Disconnect => {
+                let mut delivered = false;
+                let mut slow = false;
+                for Session { tx, .. } in sessions.iter() {
+                    match tx.try_send(msg.clone()) {
+                        Ok(()) => delivered = true,
+                        Err(TrySendError::Closed(_)) => (),
+                        Err(TrySendError::Full(_)) => {
+                            tracing::warn!(
+                                "queue of {} is full, disconnecting",
+                                redact::uid(receiver)
+                            );
+                            tx.abort();
+                            slow = true;
+                        }
+                    }
+                }
+                if slow {
+                    drop(list);
+                    let mut list = self.list.write().await;
+                    if let Some(sessions) = list.get_mut(uid) {
+                        sessions.retain(|s| !s.tx.is_closed());
+                        if sessions.is_empty() {
+                            list.remove(uid);
+                        }
+                    }
+                } else if !delivered {
+                    return Err(closed(receiver));
+                }
+            }
+            FullQueuePolicy::SpillOffline => {
+                // frames spilled earlier for these sessions go first to keep the order
+                // a frame is spilled only if no session of `receiver` has room for it
+                let mut offline = self.offline.lock().await;
+                if !waits_for(offline.get(uid), &sessions) {
+                    let mut delivered = false;
+                    let mut open = false;
+                    for Session { tx, .. } in sessions.iter() {
+                        match tx.try_send(msg.clone()) {
+                            Ok(()) => delivered = true,
+                            Err(TrySendError::Closed(_)) => continue,
+                            Err(TrySendError::Full(_)) => (),
+                        }
+                        open = true;
+                    }
+                    if !open {
+                        return Err(closed(receiver));
+                    }
+                    if delivered {
+                        return Ok(());
+                    }
+                }
+                let spilled = offline.entry(uid.into()).or_default();
+                if spilled.len() >= queue.offline_capacity {
+                    spilled.pop_front();
+                    tracing::warn!(
+                        "offline queue of {} is full, dropped oldest frame",
+                        redact::uid(receiver)
+                    );
+                }
+                spilled.push_back(msg);
+            }
+        }
+        Ok(())
+    }
+
+    /// deliver an ephemeral frame to the sessions of `receiver` that have room for it right now
+    /// unlike `send`, nothing is evicted, spilled or reported, the frame is simply dropped
+    pub async fn relay(&self, receiver: &str, msg: Message) {
+        let msg = msg.set_receiver(receiver);
+        let (uid, device) = device::parse_address(receiver);
+        let list = self.list.read().await;
+        let Some(sessions) = list.get(uid) else {
+            return;
+        };
+        for Session { tx, .. } in sessions.iter().filter(|s| s.matches(device)) {
+            let _ = tx.try_send(msg.clone());
+        }
+    }
+
+    /// move frames spilled by `FullQueuePolicy::SpillOffline` back to the live queues of `uid`
+    /// called whenever a queue of `uid` has drained
+    pub async fn restore_spilled(&self, uid: &str) {
+        // same lock order as `send`: list, then offline
         let list = self.list.read().await;
-        let tx = list.get(receiver).ok_or(ClientError::ReceiverNotExist.info(&msg.receiver))?;
-        let tx = tx.lock().await;
-        tx.send(msg.set_receiver(receiver))?;
+        let Some(sessions) = list.get(uid) else {
+            return;
+        };
+        let mut offline = self.offline.lock().await;
+        let Some(spilled) = offline.get_mut(uid) else {
+            return;
+        };
+        // a frame that cannot be delivered, e.g. for a device without a session, stays and holds
+        // back the later frames of its device only, one for every device holds back all of them
+        let mut blocked = HashSet::new();
+        let mut all_blocked = false;
+        let mut kept = VecDeque::new();
+        while let Some(msg) = spilled.pop_front() {
+            let (_, device) = device::parse_address(&msg.receiver);
+            let mut delivered = false;
+            if !all_blocked {
+                for Session { tx, .. } in sessions
+                    .iter()
+                    .filter(|s| s.matches(device) && !blocked.contains(s.device.as_str()))
+                {
+                    delivered |= tx.try_send(msg.clone()).is_ok();
+                }
+            }
+            if !delivered {
+                match device {
+                    Some(device) => {
+                        blocked.insert(device.to_string());
+                    }
+                    None => all_blocked = true,
+                }
+                kept.push_back(msg);
+            }
+        }
+        *spilled = kept;
+        if spilled.is_empty() {
+            offline.remove(uid);
+        }
+    }
+
+    /// queue `ServerShutdown` for every session, then close all queues
+    /// write tasks flush what is queued and end, later `send` fails with `ReceiverNotExist`
+    /// and `add_user` with `ShuttingDown`
+    pub async fn shutdown(&self, reason: &str) {
+        let mut list = self.list.write().await;
+        self.shutting_down.store(true, Ordering::Release);
+        for (uid, sessions) in list.drain() {
+            for Session { tx, device, .. } in sessions {
+                let address = device::address(&uid, &device);
+                if tx.force_send(Message::server_shutdown(reason).set_receiver(&address)).is_err() {
+                    tracing::warn!("cannot notify {} of shutdown", redact::uid(&address));
+                }
+                tx.close();
+            }
+        }
+    }
+
+    /// queue `Kicked` for every session matching `target`, which is either `uid` or `uid/device`,
+    /// then close their queues and remove them from the map
+    pub async fn kick(&self, target: &str, reason: &str) -> GlobalResult<()> {
+        let (uid, device) = device::parse_address(target);
+        let mut list = self.list.write().await;
+        let sessions = list
+            .get_mut(uid)
+            .ok_or(ClientError::ReceiverNotExist.info(target))?;
+        let (kicked, kept): (Vec<Session>, Vec<Session>) =
+            sessions.drain(..).partition(|s| s.matches(device));
+        *sessions = kept;
+        if sessions.is_empty() {
+            list.remove(uid);
+        }
+        if kicked.is_empty() {
+            return Err(ClientError::ReceiverNotExist.info(target));
+        }
+        drop(list);
+        for session in kicked {
+            let address = device::address(uid, &session.device);
+            let _ = session.tx.force_send(Message::kicked(reason).set_receiver(&address));
+            session.tx.close();
+            audit::record(Event::Kicked {
+                address,
+                reason: reason.into(),
+            });
+        }
         Ok(())
     }
+
+    /// registered devices of `uid` and whether each has a live session
+    pub async fn device_list(&self, uid: &str) -> Vec<DeviceInfo> {
+        let devices = self.devices.devices(uid).await;
+        let list = self.list.read().await;
+        let sessions = list.get(uid);
+        devices
+            .into_iter()
+            .map(|(name, record)| DeviceInfo {
+                online: sessions.is_some_and(|s| s.iter().any(|s| s.device == name)),
+                name,
+                pending: record.pending,
+                registered: record.registered,
+                fingerprint: match record.key.is_empty() {
+                    true => String::new(),
+                    false => device::fingerprint(&record.key),
+                },
+            })
+            .collect()
+    }
+
+    /// send a copy of `msg` to every online user
+    /// returns the number of users reached
+    pub async fn broadcast(&self, msg: Message) -> usize {
+        let uids: Vec<Uid> = self.list.read().await.keys().cloned().collect();
+        let mut reached = 0;
+        for uid in uids {
+            if self.send(&uid, msg.clone()).await.is_ok() {
+                reached += 1;
+            }
+        }
+        reached
+    }
+
+    /// number of frames waiting to be written to each `uid/device`, deepest first
+    pub async fn queue_depths(&self) -> Vec<(Uid, usize)> {
+        let list = self.list.read().await;
+        let mut depths: Vec<(Uid, usize)> = list
+            .iter()
+            .flat_map(|(uid, sessions)| {
+                sessions
+                    .iter()
+                    .map(|s| (device::address(uid, &s.device), s.tx.len()))
+            })
+            .collect();
+        depths.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        depths
+    }
+}
+
+impl Session {
+    /// whether the session belongs to `device`, `None` matches every device
+    fn matches(&self, device: Option<&str>) -> bool {
+        match device {
+            Some(device) => self.device == device,
+            None => true,
+        }
+    }
+}
+
+/// whether a frame spilled for the uid of `sessions` is for one of them,
+/// a new frame for them has to wait behind it
+fn waits_for(spilled: Option<&VecDeque<Message>>, sessions: &[&Session]) -> bool {
+    spilled.is_some_and(|spilled| {
+        spilled.iter().any(|msg| {
+            let (_, device) = device::parse_address(&msg.receiver);
+            sessions.iter().any(|s| s.matches(device))
+        })
+    })
+}
+
+fn closed(receiver: &str) -> GlobalError {
+    ClientError::ReceiverNotExist.info(&format!("{} is disconnecting", receiver))
 }
 
 impl Default for OnlineUsers {
     fn default() -> Self {
-        Self::new()
+        Self::new(
+            QueueConfig::default(),
+            LoginConfig::default(),
+            DeviceRegistry::default(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::command::Command;
+
+    fn online_users(duplicate: DuplicateLoginPolicy) -> OnlineUsers {
+        OnlineUsers::new(
+            QueueConfig::default(),
+            LoginConfig {
+                duplicate,
+                ..LoginConfig::default()
+            },
+            DeviceRegistry::default(),
+        )
+    }
+
+    /// queues of a single frame, later frames are spilled
+    fn spilling() -> OnlineUsers {
+        let queue = QueueConfig {
+            capacity: 1,
+            policy: FullQueuePolicy::SpillOffline,
+            ..QueueConfig::default()
+        };
+        OnlineUsers::new(queue, LoginConfig::default(), DeviceRegistry::default())
+    }
+
+    async fn spilled(online_users: &OnlineUsers, uid: &str) -> Vec<String> {
+        let offline = online_users.offline.lock().await;
+        offline
+            .get(uid)
+            .map(|spilled| spilled.iter().map(|msg| msg.receiver.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    async fn sessions(online_users: &OnlineUsers, uid: &str) -> Vec<SessionId> {
+        let list = online_users.list.read().await;
+        list.get(uid)
+            .map(|sessions| sessions.iter().map(|s| s.id).collect())
+            .unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn reject_keeps_the_old_session() {
+        let online_users = online_users(DuplicateLoginPolicy::Reject);
+        let (old, _rx) = online_users
+            .add_user("alice", "phone", addr(1))
+            .await
+            .unwrap();
+        let e = online_users
+            .add_user("alice", "phone", addr(2))
+            .await
+            .unwrap_err();
+        assert_eq!(e.code(), ServerError::DuplicatedAuth as u16);
+        assert_eq!(sessions(&online_users, "alice").await, vec![old]);
+        // other devices of the uid are no duplicates
+        let (laptop, _rx) = online_users
+            .add_user("alice", "laptop", addr(3))
+            .await
+            .unwrap();
+        assert_eq!(sessions(&online_users, "alice").await, vec![old, laptop]);
+    }
+
+    #[tokio::test]
+    async fn kick_old_replaces_the_session() {
+        let online_users = online_users(DuplicateLoginPolicy::KickOld);
+        let (old, mut old_rx) = online_users
+            .add_user("alice", "phone", addr(1))
+            .await
+            .unwrap();
+        let (new, _rx) = online_users
+            .add_user("alice", "phone", addr(2))
+            .await
+            .unwrap();
+        assert_ne!(old, new);
+        assert_eq!(sessions(&online_users, "alice").await, vec![new]);
+        // the old session is told why, then its queue ends
+        let kicked = old_rx.recv().await.unwrap();
+        assert_eq!(kicked.command, Command::Kicked);
+        assert!(old_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn multi_delivers_to_every_session() {
+        let online_users = online_users(DuplicateLoginPolicy::Multi);
+        let (first, mut first_rx) = online_users
+            .add_user("alice", "phone", addr(1))
+            .await
+            .unwrap();
+        let (second, mut second_rx) = online_users
+            .add_user("alice", "phone", addr(2))
+            .await
+            .unwrap();
+        assert_eq!(sessions(&online_users, "alice").await, vec![first, second]);
+        online_users
+            .send("alice/phone", Message::kicked("test"))
+            .await
+            .unwrap();
+        assert_eq!(
+            first_rx.recv().await.unwrap().content,
+            second_rx.recv().await.unwrap().content
+        );
+    }
+
+    #[tokio::test]
+    async fn remove_user_leaves_newer_sessions_alone() {
+        let online_users = online_users(DuplicateLoginPolicy::KickOld);
+        let (old, _old_rx) = online_users
+            .add_user("alice", "phone", addr(1))
+            .await
+            .unwrap();
+        let (new, _rx) = online_users
+            .add_user("alice", "phone", addr(2))
+            .await
+            .unwrap();
+        // the kicked connection closing late must not take the new session with it
+        assert!(!online_users.remove_user("alice", old).await);
+        assert_eq!(sessions(&online_users, "alice").await, vec![new]);
+        assert!(online_users.remove_user("alice", new).await);
+        assert!(sessions(&online_users, "alice").await.is_empty());
+        // a session removed twice, e.g. after a kick, is harmless
+        assert!(online_users.remove_user("alice", new).await);
+    }
+
+    #[tokio::test]
+    async fn remove_user_keeps_the_uid_while_sessions_are_left() {
+        let online_users = online_users(DuplicateLoginPolicy::Multi);
+        let (first, _first_rx) = online_users
+            .add_user("alice", "phone", addr(1))
+            .await
+            .unwrap();
+        let (second, _second_rx) = online_users
+            .add_user("alice", "laptop", addr(2))
+            .await
+            .unwrap();
+        assert!(!online_users.remove_user("alice", first).await);
+        assert_eq!(sessions(&online_users, "alice").await, vec![second]);
+    }
+
+    #[tokio::test]
+    async fn shutdown_notifies_closes_and_refuses_new_sessions() {
+        let online_users = online_users(DuplicateLoginPolicy::Multi);
+        let (_, mut rx) = online_users
+            .add_user("alice", "phone", addr(1))
+            .await
+            .unwrap();
+        online_users.shutdown("maintenance").await;
+        let notice = rx.recv().await.unwrap();
+        assert_eq!(notice.command, Command::ServerShutdown);
+        assert_eq!(notice.receiver, "alice/phone");
+        assert_eq!(notice.content, b"maintenance");
+        // the queue ends after the notice
+        assert!(rx.recv().await.is_none());
+        assert!(sessions(&online_users, "alice").await.is_empty());
+        let e = online_users
+            .add_user("bob", "laptop", addr(2))
+            .await
+            .unwrap_err();
+        assert_eq!(e.code(), ServerError::ShuttingDown as u16);
+        assert!(sessions(&online_users, "bob").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn spilled_frames_of_absent_devices_hold_back_nothing() {
+        let online_users = spilling();
+        let (laptop, _laptop_rx) = online_users
+            .add_user("alice", "laptop", addr(1))
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            online_users
+                .send("alice/laptop", Message::kicked("test"))
+                .await
+                .unwrap();
+        }
+        online_users.remove_user("alice", laptop).await;
+        assert_eq!(spilled(&online_users, "alice").await, vec!["alice/laptop"]);
+
+        // the frame of the laptop neither blocks the phone nor makes its frames spill
+        let (_, mut phone_rx) = online_users
+            .add_user("alice", "phone", addr(2))
+            .await
+            .unwrap();
+        online_users
+            .send("alice/phone", Message::kicked("first"))
+            .await
+            .unwrap();
+        online_users
+            .send("alice/phone", Message::kicked("second"))
+            .await
+            .unwrap();
+        assert_eq!(
+            spilled(&online_users, "alice").await,
+            vec!["alice/laptop", "alice/phone"]
+        );
+        assert_eq!(phone_rx.recv().await.unwrap().content, b"first");
+        online_users.restore_spilled("alice").await;
+        assert_eq!(phone_rx.recv().await.unwrap().content, b"second");
+        assert_eq!(spilled(&online_users, "alice").await, vec!["alice/laptop"]);
+    }
+
+    #[tokio::test]
+    async fn spilled_frames_keep_their_order_per_device() {
+        let online_users = spilling();
+        let (_, mut phone_rx) = online_users
+            .add_user("alice", "phone", addr(1))
+            .await
+            .unwrap();
+        let (_, mut laptop_rx) = online_users
+            .add_user("alice", "laptop", addr(2))
+            .await
+            .unwrap();
+        for n in 0..3 {
+            for device in ["alice/phone", "alice/laptop"] {
+                let msg = Message::kicked(&n.to_string());
+                online_users.send(device, msg).await.unwrap();
+            }
+        }
+        // the laptop drains first, the phone's frames wait for the phone
+        for n in 0..3 {
+            assert_eq!(
+                laptop_rx.recv().await.unwrap().content,
+                n.to_string().as_bytes()
+            );
+            online_users.restore_spilled("alice").await;
+        }
+        for n in 0..3 {
+            assert_eq!(
+                phone_rx.recv().await.unwrap().content,
+                n.to_string().as_bytes()
+            );
+            online_users.restore_spilled("alice").await;
+        }
+        assert!(spilled(&online_users, "alice").await.is_empty());
+    }
+
+    /// `PresenceChanged` frames waiting in `rx`, as states
+    async fn presences(rx: &mut Rx) -> Vec<PresenceState> {
+        let mut states = Vec::new();
+        while !rx.is_empty() {
+            let msg = rx.recv().await.unwrap();
+            states.push(Presence::from_content(&msg.content).unwrap().state);
+        }
+        states
+    }
+
+    #[tokio::test]
+    async fn hidden_uids_appear_offline_to_contacts() {
+        use PresenceState::*;
+        let online_users = online_users(DuplicateLoginPolicy::Reject);
+        let (_, mut bob) = online_users
+            .add_user("bob", "phone", addr(1))
+            .await
+            .unwrap();
+        online_users
+            .subscribe("bob", HashSet::from(["alice".to_string()]))
+            .await;
+        let (alice, _rx) = online_users
+            .add_user("alice", "phone", addr(2))
+            .await
+            .unwrap();
+        online_users
+            .subscribe("alice", HashSet::from(["bob".to_string()]))
+            .await;
+        assert_eq!(presences(&mut bob).await, vec![Online]);
+
+        online_users.set_hidden("alice", true).await.unwrap();
+        assert_eq!(presences(&mut bob).await, vec![Offline]);
+        assert!(!String::from_utf8_lossy(&online_users.to_msg().await.content).contains("alice"));
+        let current = online_users
+            .subscribe("bob", HashSet::from(["alice".to_string()]))
+            .await;
+        assert_eq!(current[0].1.state, Offline);
+        // changes made while hidden are not told
+        let away = Presence {
+            state: Away,
+            text: "lunch".into(),
+        };
+        online_users.set_status("alice", away).await.unwrap();
+        assert!(presences(&mut bob).await.is_empty());
+
+        online_users.set_hidden("alice", false).await.unwrap();
+        assert_eq!(presences(&mut bob).await, vec![Away]);
+        online_users.set_hidden("alice", true).await.unwrap();
+        assert_eq!(presences(&mut bob).await, vec![Offline]);
+        // already offline to bob
+        online_users.remove_user("alice", alice).await;
+        assert!(presences(&mut bob).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn presence_is_shown_to_mutual_contacts_only() {
+        use PresenceState::*;
+        let online_users = online_users(DuplicateLoginPolicy::Reject);
+        let (_, mut alice) = online_users
+            .add_user("alice", "phone", addr(1))
+            .await
+            .unwrap();
+        let (_, mut eve) = online_users
+            .add_user("eve", "phone", addr(2))
+            .await
+            .unwrap();
+        // eve lists alice, who does not list her
+        let current = online_users
+            .subscribe("eve", HashSet::from(["alice".to_string()]))
+            .await;
+        assert_eq!(current[0].1.state, Offline);
+        let away = Presence {
+            state: Away,
+            text: "lunch".into(),
+        };
+        online_users.set_status("alice", away).await.unwrap();
+        assert!(presences(&mut eve).await.is_empty());
+
+        // once alice lists eve too, both see each other
+        let current = online_users
+            .subscribe("alice", HashSet::from(["eve".to_string()]))
+            .await;
+        assert_eq!(current[0].1.state, Online);
+        assert_eq!(presences(&mut eve).await, vec![Away]);
+        assert!(presences(&mut alice).await.is_empty());
+
+        // and dropping her tells eve that alice is gone
+        online_users.subscribe("alice", HashSet::new()).await;
+        assert_eq!(presences(&mut eve).await, vec![Offline]);
+        online_users.set_hidden("alice", true).await.unwrap();
+        assert!(presences(&mut eve).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn relay_drops_what_cannot_be_delivered_right_away() {
+        let online_users = spilling();
+        let (_, mut rx) = online_users
+            .add_user("alice", "phone", addr(1))
+            .await
+            .unwrap();
+        let ephemeral = || Message {
+            ephemeral: true,
+            ..Message::send_text("alice", b"gone")
+        };
+        // the queue has room for one frame, the second is neither spilled nor evicting the first
+        online_users.relay("alice", ephemeral()).await;
+        online_users.relay("alice/phone", ephemeral()).await;
+        assert_eq!(rx.len(), 1);
+        assert!(spilled(&online_users, "alice").await.is_empty());
+        // nobody to relay to, nothing is kept for later
+        online_users.relay("alice/laptop", ephemeral()).await;
+        online_users.relay("bob", ephemeral()).await;
+        assert!(spilled(&online_users, "alice").await.is_empty());
+        assert!(spilled(&online_users, "bob").await.is_empty());
+        assert!(rx.recv().await.unwrap().ephemeral);
+        assert!(rx.is_empty());
+    }
+
+    #[tokio::test]
+    async fn log_lines_name_neither_uids_nor_content() {
+        use crate::config::{LogConfig, LogUids};
+        use std::{
+            io,
+            sync::{Arc, Mutex},
+        };
+
+        #[derive(Clone, Default)]
+        struct Capture(Arc<Mutex<Vec<u8>>>);
+
+        impl io::Write for Capture {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _default = tracing::subscriber::set_default(subscriber);
+        redact::install(&LogConfig {
+            uids: LogUids::Hash,
+            ..LogConfig::default()
+        });
+
+        let payload = "meet me at the docks";
+        let policies = [
+            FullQueuePolicy::DropOldest,
+            FullQueuePolicy::SpillOffline,
+            FullQueuePolicy::Disconnect,
+        ];
+        for policy in policies {
+            let queue = QueueConfig {
+                capacity: 1,
+                policy,
+                offline_capacity: 1,
+            };
+            let online_users =
+                OnlineUsers::new(queue, LoginConfig::default(), DeviceRegistry::default());
+            let (_, _rx) = online_users
+                .add_user("bob", "laptop", addr(1))
+                .await
+                .unwrap();
+            // the queue fills up, then the offline queue, then the session is dropped
+            for _ in 0..3 {
+                let msg = Message::send_text("bob", payload.as_bytes()).set_sender("alice/phone");
+                let _ = online_users.send("bob/laptop", msg.clone()).await;
+                online_users.relay("bob", msg).await;
+            }
+            online_users.shutdown(payload).await;
+        }
+
+        let log = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        assert!(log.contains("is full"), "{}", log);
+        for secret in [payload, "alice", "bob", "laptop"] {
+            assert!(!log.contains(secret), "{} in {}", secret, log);
+        }
     }
 }
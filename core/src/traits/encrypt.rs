@@ -18,11 +18,21 @@ pub trait Encrypt {
     ) -> GlobalResult<Vec<u8>>;
     fn decrypt(ciphertext: &[u8], priv_key: &Self::PrivateKey) -> GlobalResult<Vec<u8>>;
 
+    /// signature of `data`, proves to the server that the device holds `priv_key`
+    fn sign(data: &[u8], priv_key: &Self::PrivateKey) -> GlobalResult<Vec<u8>>;
+    /// `AuthenticationFailed` error unless `signature` is `sign` of `data` by the key of `pub_key`
+    fn verify(data: &[u8], signature: &[u8], pub_key: &Self::PublicKey) -> GlobalResult<()>;
+
     fn generate_key_pair(
         rand: &mut ThreadRng,
         len: usize,
     ) -> GlobalResult<(Self::PublicKey, Self::PrivateKey)>;
 
+    /// whether `generate_key_pair` accepts `len`, checked when the config is loaded
+    fn valid_key_len(len: usize) -> bool {
+        len > 0
+    }
+
     fn export_pub_key(key: &Self::PublicKey) -> GlobalResult<Vec<u8>>;
     fn export_priv_key(key: &Self::PrivateKey) -> GlobalResult<Vec<u8>>;
 
@@ -0,0 +1,482 @@
+use crate::{limit::RateLimiter, reload};
+use core::{
+    audit::{self, Event},
+    codec::{command::Command, message::Message, msg_codec::MsgCodec},
+    config::{AdminConfig, ServerConfig},
+    device::{self, DeviceInfo},
+    error::{ClientError, ExternalError, GlobalResult, ServerError},
+    redact,
+    server_state::OnlineUsers,
+};
+use futures::SinkExt;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_stream::StreamExt;
+use tokio_util::codec::Framed;
+
+type Console = Framed<TcpStream, MsgCodec>;
+
+const USAGE: &str = "\
+list                      sessions with uid/device, address, connect time and queue depth
+kick <uid>[/device] [reason]
+                          disconnect every session of a user, or of one device
+devices <uid>             registered devices of a user
+revoke <uid>/<device>     revoke a device and disconnect it, or turn down a pending one
+approve <uid>/<device> <fingerprint>
+                          let a pending device log in, the fingerprint is shown by devices
+broadcast <text>          send an announcement to every connected user
+queues                    queue depth of every connection, deepest first
+reload                    re-read config.toml, the same as SIGHUP
+help                      this message";
+
+/// accept admin connections on `config.addr` until the server stops
+/// the console speaks `MsgCodec`: one `Login` frame carrying the token, then `Admin` frames
+pub async fn serve(
+    config: AdminConfig,
+    server_config: Arc<RwLock<ServerConfig>>,
+    online_users: Arc<OnlineUsers>,
+    limiter: Arc<RateLimiter>,
+) -> GlobalResult<()> {
+    let addr: SocketAddr = config
+        .addr
+        .parse()
+        .map_err(|_| ExternalError::ListenPort.info(&config.addr))?;
+    if !addr.ip().is_loopback() {
+        return Err(ExternalError::ListenPort.info("admin console must listen on a loopback address"));
+    }
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|_| ExternalError::ListenPort.info(&config.addr))?;
+    tracing::info!("admin console running on {}", addr);
+
+    let token = Arc::new(config.token);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        if !peer.ip().is_loopback() {
+            tracing::warn!("refused admin connection from {}", peer);
+            continue;
+        }
+        let token = Arc::clone(&token);
+        let server_config = Arc::clone(&server_config);
+        let online_users = Arc::clone(&online_users);
+        let limiter = Arc::clone(&limiter);
+        tokio::spawn(async move {
+            let mut console = Framed::new(stream, MsgCodec::new());
+            let result = match authenticate(&mut console, &token).await {
+                Ok(()) => {
+                    session(&mut console, peer, &server_config, &online_users, &limiter).await
+                }
+                Err(e) => {
+                    tracing::warn!("admin authentication from {} failed", peer);
+                    audit::record(Event::AdminAuthFailed {
+                        peer: peer.to_string(),
+                    });
+                    let _ = console.send(Message::remote_error(e)).await;
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                tracing::warn!("admin console {} closed: {}", peer, redact::error(&e));
+            }
+        });
+    }
+}
+
+/// the first frame must be `Login` with the token as content
+async fn authenticate(console: &mut Console, token: &str) -> GlobalResult<()> {
+    match console.next().await {
+        Some(Ok(msg)) if msg.command == Command::Login => {
+            // compare digests so the time taken does not depend on a common prefix
+            match sha256::digest(msg.content.as_slice()) == sha256::digest(token.as_bytes()) {
+                true => {
+                    console.send(Message::admin("ok").set_sender("Server")).await?;
+                    Ok(())
+                }
+                false => Err(ClientError::AuthenticationFailed.info("wrong admin token")),
+            }
+        }
+        Some(Ok(_)) => Err(ServerError::UnexpectedFrame.info("admin console expects Login")),
+        _ => Err(ExternalError::DeserializeFrame.into()),
+    }
+}
+
+async fn session(
+    console: &mut Console,
+    peer: SocketAddr,
+    server_config: &RwLock<ServerConfig>,
+    online_users: &OnlineUsers,
+    limiter: &RateLimiter,
+) -> GlobalResult<()> {
+    while let Some(Ok(msg)) = console.next().await {
+        if msg.command != Command::Admin {
+            let e = ServerError::UnexpectedFrame.info(msg.command.as_ref());
+            console.send(Message::remote_error(e)).await?;
+            continue;
+        }
+        let line = String::from_utf8_lossy(&msg.content).to_string();
+        // arguments may be uids or the text of an announcement
+        let command = line.split_whitespace().next().unwrap_or_default();
+        tracing::info!("admin command: {}", command);
+        let result = execute(&line, server_config, online_users, limiter).await;
+        audit::record(Event::Admin {
+            peer: peer.to_string(),
+            command: line.clone(),
+            outcome: match &result {
+                Ok(_) => "ok".into(),
+                Err(e) => e.clone().into(),
+            },
+        });
+        let reply = match result {
+            Ok(text) => Message::admin(&text).set_sender("Server"),
+            Err(e) => Message::remote_error(e),
+        };
+        console.send(reply).await?;
+    }
+    Ok(())
+}
+
+async fn execute(
+    line: &str,
+    server_config: &RwLock<ServerConfig>,
+    online_users: &OnlineUsers,
+    limiter: &RateLimiter,
+) -> GlobalResult<String> {
+    let line = line.trim();
+    let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let rest = rest.trim();
+    match command {
+        "list" => {
+            let list = online_users.list.read().await;
+            let mut rows: Vec<String> = list
+                .iter()
+                .flat_map(|(uid, sessions)| {
+                    sessions.iter().map(move |session| {
+                        format!(
+                            "{}\t{}\t{}\t{}",
+                            device::address(uid, &session.device),
+                            session.addr,
+                            session.since.format("%Y/%m/%d-%H:%M:%S"),
+                            session.tx.len()
+                        )
+                    })
+                })
+                .collect();
+            rows.sort();
+            rows.insert(0, format!("{} online, {} sessions", list.len(), rows.len()));
+            Ok(rows.join("\n"))
+        }
+        "kick" if !rest.is_empty() => {
+            let (uid, reason) = rest.split_once(' ').unwrap_or((rest, "kicked by admin"));
+            online_users.kick(uid, reason.trim()).await?;
+            tracing::info!("admin kicked {}", redact::uid(uid));
+            Ok(format!("{} kicked", uid))
+        }
+        "devices" if !rest.is_empty() => {
+            let devices = online_users.device_list(rest).await;
+            if devices.is_empty() {
+                return Err(ClientError::ReceiverNotExist.info(rest));
+            }
+            let rows: Vec<String> = devices.iter().map(DeviceInfo::to_line).collect();
+            Ok(rows.join("\n"))
+        }
+        "revoke" if !rest.is_empty() => {
+            let (uid, Some(name)) = device::parse_address(rest) else {
+                return Err(ClientError::ReceiverNotExist.info("expected <uid>/<device>"));
+            };
+            online_users.devices.revoke(uid, name).await?;
+            audit::record(Event::DeviceRevoked {
+                address: rest.into(),
+                by: "admin".into(),
+            });
+            let _ = online_users.kick(rest, "device revoked by admin").await;
+            tracing::info!("admin revoked {}", redact::uid(rest));
+            Ok(format!("{} revoked", rest))
+        }
+        "approve" if !rest.is_empty() => {
+            let (address, fingerprint) = rest.split_once(' ').unwrap_or((rest, ""));
+            let (uid, Some(name)) = device::parse_address(address) else {
+                return Err(ClientError::ReceiverNotExist.info("expected <uid>/<device>"));
+            };
+            online_users.devices.approve(uid, name, fingerprint.trim()).await?;
+            tracing::info!("admin approved {}", redact::uid(address));
+            audit::record(Event::KeyApproved {
+                address: address.into(),
+                by: "admin".into(),
+            });
+            Ok(format!("{} approved", address))
+        }
+        "broadcast" if !rest.is_empty() => {
+            let reached = online_users.broadcast(Message::announcement(rest)).await;
+            Ok(format!("announcement sent to {} users", reached))
+        }
+        "queues" => {
+            let capacity = online_users.queue_config().capacity;
+            let rows: Vec<String> = online_users
+                .queue_depths()
+                .await
+                .into_iter()
+                .map(|(uid, depth)| format!("{}\t{}/{}", uid, depth, capacity))
+                .collect();
+            Ok(rows.join("\n"))
+        }
+        "reload" => reload::reload("admin reload", server_config, online_users, limiter),
+        "help" | "" => Ok(USAGE.into()),
+        _ => Err(ServerError::UnexpectedFrame.info(&format!("unknown admin command\n{}", USAGE))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::{channel::Rx, config::RateLimitConfig, device::Admission};
+
+    /// `#[tokio::test]` cannot name std's `core` in this workspace, whose own crate takes the name
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    struct Server {
+        config: RwLock<ServerConfig>,
+        online_users: OnlineUsers,
+        limiter: RateLimiter,
+    }
+
+    impl Server {
+        fn new() -> Self {
+            let config = ServerConfig::default();
+            Self {
+                online_users: OnlineUsers::new(
+                    config.queue.clone(),
+                    config.login.clone(),
+                    Default::default(),
+                ),
+                limiter: RateLimiter::new(RateLimitConfig::default()),
+                config: RwLock::new(config),
+            }
+        }
+
+        async fn login(&self, address: &str, port: u16) -> Rx {
+            let (uid, device) = device::parse_address(address);
+            let addr = SocketAddr::from(([127, 0, 0, 1], port));
+            let (_, rx) = self
+                .online_users
+                .add_user(uid, device.unwrap(), addr)
+                .await
+                .unwrap();
+            rx
+        }
+
+        async fn execute(&self, line: &str) -> GlobalResult<String> {
+            execute(line, &self.config, &self.online_users, &self.limiter).await
+        }
+    }
+
+    fn code(result: GlobalResult<String>) -> u16 {
+        result.unwrap_err().code()
+    }
+
+    #[test]
+    fn list_and_queues_show_every_session() {
+        block_on(async {
+            let server = Server::new();
+            let _alice = server.login("alice/phone", 1).await;
+            let _bob = server.login("bob/laptop", 2).await;
+            let list = server.execute("list").await.unwrap();
+            let rows: Vec<&str> = list.lines().collect();
+            assert_eq!(rows[0], "2 online, 2 sessions");
+            assert!(rows[1].starts_with("alice/phone\t127.0.0.1:1\t"));
+            assert!(rows[1].ends_with("\t0"));
+            assert!(rows[2].starts_with("bob/laptop\t127.0.0.1:2\t"));
+
+            server
+                .online_users
+                .send("bob", Message::announcement("hi"))
+                .await
+                .unwrap();
+            let capacity = server.online_users.queue_config().capacity;
+            assert_eq!(
+                server.execute("queues").await.unwrap(),
+                format!("bob/laptop\t1/{}\nalice/phone\t0/{}", capacity, capacity)
+            );
+        })
+    }
+
+    #[test]
+    fn kick_disconnects_a_uid_or_one_device() {
+        block_on(async {
+            let server = Server::new();
+            let mut phone = server.login("alice/phone", 1).await;
+            let mut laptop = server.login("alice/laptop", 2).await;
+            assert_eq!(
+                server
+                    .execute("kick alice/phone too many requests")
+                    .await
+                    .unwrap(),
+                "alice/phone kicked"
+            );
+            let kicked = phone.recv().await.unwrap();
+            assert_eq!(kicked.command, Command::Kicked);
+            assert_eq!(kicked.content, b"too many requests");
+            assert!(phone.recv().await.is_none());
+
+            server.execute("kick alice").await.unwrap();
+            assert_eq!(laptop.recv().await.unwrap().content, b"kicked by admin");
+            let e = server.execute("kick alice").await;
+            assert_eq!(code(e), ClientError::ReceiverNotExist as u16);
+        })
+    }
+
+    #[test]
+    fn devices_approve_and_revoke() {
+        block_on(async {
+            let server = Server::new();
+            let devices = &server.online_users.devices;
+            let admitted = devices.admit("alice", "phone", b"phone key").await.unwrap();
+            assert_eq!(admitted, Admission::Registered);
+            let admitted = devices
+                .admit("alice", "laptop", b"laptop key")
+                .await
+                .unwrap();
+            assert_eq!(admitted, Admission::Pending);
+            let listed = server.execute("devices alice").await.unwrap();
+            assert_eq!(listed.lines().count(), 2);
+            let e = server.execute("devices bob").await;
+            assert_eq!(code(e), ClientError::ReceiverNotExist as u16);
+
+            let e = server.execute("approve alice/laptop 00ff").await;
+            assert_eq!(code(e), ClientError::AuthenticationFailed as u16);
+            let e = server.execute("approve alice").await;
+            assert_eq!(code(e), ClientError::ReceiverNotExist as u16);
+            let approve = format!("approve alice/laptop {}", device::fingerprint("laptop key"));
+            assert_eq!(
+                server.execute(&approve).await.unwrap(),
+                "alice/laptop approved"
+            );
+            assert!(devices
+                .devices("alice")
+                .await
+                .iter()
+                .all(|(_, r)| !r.pending));
+
+            let mut laptop = server.login("alice/laptop", 2).await;
+            assert_eq!(
+                server.execute("revoke alice/laptop").await.unwrap(),
+                "alice/laptop revoked"
+            );
+            assert_eq!(laptop.recv().await.unwrap().command, Command::Kicked);
+            assert_eq!(devices.devices("alice").await.len(), 1);
+            let e = server.execute("revoke alice/laptop").await;
+            assert_eq!(code(e), ClientError::ReceiverNotExist as u16);
+            let e = server.execute("revoke alice").await;
+            assert_eq!(code(e), ClientError::ReceiverNotExist as u16);
+        })
+    }
+
+    #[test]
+    fn broadcast_reaches_every_uid() {
+        block_on(async {
+            let server = Server::new();
+            let mut alice = server.login("alice/phone", 1).await;
+            let mut bob = server.login("bob/laptop", 2).await;
+            assert_eq!(
+                server.execute("broadcast back in five").await.unwrap(),
+                "announcement sent to 2 users"
+            );
+            for rx in [&mut alice, &mut bob] {
+                let msg = rx.recv().await.unwrap();
+                assert_eq!(msg.command, Command::Announcement);
+                assert_eq!(msg.content, b"back in five");
+            }
+        })
+    }
+
+    #[test]
+    fn help_missing_arguments_and_unknown_commands() {
+        block_on(async {
+            let server = Server::new();
+            assert_eq!(server.execute("help").await.unwrap(), USAGE);
+            assert_eq!(server.execute("  ").await.unwrap(), USAGE);
+            for line in ["kick", "devices ", "broadcast", "shutdown"] {
+                let e = server.execute(line).await;
+                assert_eq!(code(e), ServerError::UnexpectedFrame as u16, "{}", line);
+            }
+        })
+    }
+
+    #[test]
+    fn reload_keeps_the_config_when_the_file_is_gone() {
+        let dir = tempfile::tempdir().unwrap();
+        // the only test of this crate that reads the environment
+        std::env::set_var("JHCHAT_CONFIG", dir.path().join("config.toml"));
+        block_on(async {
+            let server = Server::new();
+            server.config.write().unwrap().port = "2555".into();
+            assert!(server.execute("reload").await.is_err());
+            assert_eq!(server.config.read().unwrap().port, "2555");
+        })
+    }
+
+    /// the server and the client end of a console over loopback
+    async fn consoles() -> (Console, Console) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap());
+        let (client, accepted) = tokio::join!(client, listener.accept());
+        let server = Framed::new(accepted.unwrap().0, MsgCodec::new());
+        (server, Framed::new(client.unwrap(), MsgCodec::new()))
+    }
+
+    #[test]
+    fn only_the_token_opens_the_console() {
+        block_on(async {
+            let (mut server, mut client) = consoles().await;
+            client
+                .send(Message::login("admin").set_content(b"secret"))
+                .await
+                .unwrap();
+            authenticate(&mut server, "secret").await.unwrap();
+            let ok = client.next().await.unwrap().unwrap();
+            assert_eq!((ok.command, ok.content), (Command::Admin, b"ok".to_vec()));
+
+            let (mut server, mut client) = consoles().await;
+            client
+                .send(Message::login("admin").set_content(b"secre"))
+                .await
+                .unwrap();
+            let e = authenticate(&mut server, "secret").await.unwrap_err();
+            assert_eq!(e.code(), ClientError::AuthenticationFailed as u16);
+
+            let (mut server, mut client) = consoles().await;
+            client.send(Message::admin("list")).await.unwrap();
+            let e = authenticate(&mut server, "secret").await.unwrap_err();
+            assert_eq!(e.code(), ServerError::UnexpectedFrame as u16);
+        })
+    }
+
+    #[test]
+    fn the_console_listens_on_loopback_only() {
+        block_on(async {
+            for addr in ["0.0.0.0:0", "192.0.2.1:2334", "localhost"] {
+                let config = AdminConfig {
+                    addr: addr.into(),
+                    token: "secret".into(),
+                };
+                let e = serve(
+                    config,
+                    Arc::new(RwLock::new(ServerConfig::default())),
+                    Arc::new(Server::new().online_users),
+                    Arc::new(RateLimiter::new(RateLimitConfig::default())),
+                )
+                .await
+                .unwrap_err();
+                assert_eq!(e.code(), ExternalError::ListenPort as u16, "{}", addr);
+            }
+        })
+    }
+}
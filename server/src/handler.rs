@@ -1,24 +1,53 @@
 use core::{
+    audit::{self, Event},
+    channel::Rx,
     codec::{
         command::Command,
         message::Message,
         msg_codec::MsgCodec,
     },
-    error::{ErrorType, ExternalError, GlobalResult, ServerError},
-    server_state::OnlineUsers,
+    device::{self, Admission, DeviceInfo, DEFAULT_DEVICE},
+    encryption::rsa_impl::RsaEncryption,
+    error::{ClientError, ErrorType, ExternalError, GlobalError, GlobalResult, ServerError},
+    redact,
+    server_state::{OnlineUsers, SessionId},
+    traits::encrypt::Encrypt,
+};
+use crate::metrics::{Metered, METRICS};
+use futures::SinkExt;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+};
+use tokio::net::{
+    tcp::{OwnedReadHalf, OwnedWriteHalf},
+    TcpStream,
 };
-use std::{net::SocketAddr, sync::Arc};
-use tokio::{net::tcp::OwnedReadHalf, sync::mpsc};
 use tokio_stream::StreamExt;
-use tokio_util::codec::FramedRead;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+/// a logged in connection as seen by its tasks
+#[derive(Debug)]
+pub struct Login {
+    pub uid: String,
+    // `uid/device`, the sender of every frame relayed from this connection
+    pub address: String,
+    pub session: SessionId,
+}
 
-type Rx = mpsc::UnboundedReceiver<Message>;
+/// the algorithm of the clients' keys, the server only checks signatures with them
+type Keys = RsaEncryption;
 
+/// the sender of `Login` is `uid` or `uid/device`, its content is the device's public key
+/// the device proves it holds the private half by signing a `Challenge`, then `DeviceRegistry::admit`
+/// decides whether it may log in
 pub async fn authenticate(
     online_users: Arc<OnlineUsers>,
-    rd_frame: &mut FramedRead<OwnedReadHalf, MsgCodec>,
+    rd_frame: &mut FramedRead<Metered<OwnedReadHalf>, MsgCodec>,
+    wt_frame: &mut FramedWrite<Metered<OwnedWriteHalf>, MsgCodec>,
     addr: SocketAddr,
-) -> GlobalResult<(String, Rx)> {
+) -> GlobalResult<(Login, Rx)> {
     // 1. get next frame
     match rd_frame.next().await {
         // 1.1 a frame is deserialized
@@ -26,28 +55,343 @@ pub async fn authenticate(
         Some(Ok(msg)) => match msg.command {
             // 2.1 `Command` is `Login`
             Command::Login => {
-                let uid = msg.sender;
-                let (tx, rx) = mpsc::unbounded_channel();
-                online_users.add_user(&uid, tx).await;
-                tracing::info!("{} has joined server", uid);
-                Ok((uid, rx))
+                METRICS.frame(msg.command.as_ref());
+                let result = login(&online_users, &msg, rd_frame, wt_frame, addr).await;
+                if let Err(e) = &result {
+                    audit_failure(&msg.sender, addr, e);
+                }
+                result
             }
             // 2.2 `Command` is NOT `Login`
             _ => {
                 tracing::warn!("{} command is not login during authentication", addr);
-                Err(ServerError::UnexpectedFrame.into())
+                let e = ServerError::UnexpectedFrame.into();
+                audit_failure("", addr, &e);
+                Err(e)
             }
         },
         // 1.2 deserialization failed
         _ => {
             tracing::warn!("cannot deserialize tokens received from {}", addr);
-            Err(ExternalError::DeserializeFrame.into())
+            let e = ExternalError::DeserializeFrame.into();
+            audit_failure("", addr, &e);
+            Err(e)
+        }
+    }
+}
+
+/// a device waiting for approval did not fail, `login` records its request
+fn audit_failure(sender: &str, addr: SocketAddr, e: &GlobalError) {
+    if !matches!(e.err, ErrorType::Client(ClientError::AwaitingApproval)) {
+        audit::record(Event::AuthFailed {
+            sender: sender.into(),
+            ip: addr.ip().to_string(),
+            reason: e.clone().into(),
+        });
+    }
+}
+
+/// check the name of the device and its key, then add its session
+async fn login(
+    online_users: &OnlineUsers,
+    msg: &Message,
+    rd_frame: &mut FramedRead<Metered<OwnedReadHalf>, MsgCodec>,
+    wt_frame: &mut FramedWrite<Metered<OwnedWriteHalf>, MsgCodec>,
+    addr: SocketAddr,
+) -> GlobalResult<(Login, Rx)> {
+    let (uid, device) = device::parse_address(&msg.sender);
+    let device = device.unwrap_or(DEFAULT_DEVICE);
+    if !device::valid_name(uid) || !device::valid_name(device) {
+        return Err(ClientError::AuthenticationFailed
+            .info(&format!("invalid uid or device name {}", msg.sender)));
+    }
+    let address = device::address(uid, device);
+    prove(rd_frame, wt_frame, &address, &msg.content).await?;
+    let admission = online_users
+        .devices
+        .admit(uid, device, &msg.content)
+        .await?;
+    let fingerprint = device::fingerprint(&String::from_utf8_lossy(&msg.content));
+    match admission {
+        Admission::Known => (),
+        Admission::Registered => audit::record(Event::KeyRegistered {
+            address: address.clone(),
+            fingerprint: fingerprint.clone(),
+        }),
+        Admission::Pending => audit::record(Event::KeyRequested {
+            address: address.clone(),
+            fingerprint: fingerprint.clone(),
+        }),
+    }
+    if admission == Admission::Pending {
+        tracing::info!("{} awaits approval", redact::uid(&address));
+        return Err(ClientError::AwaitingApproval.info(&format!(
+            "run `/approve {} {}` on another device of {}, or ask the admin to",
+            device, fingerprint, uid
+        )));
+    }
+    let (session, rx) = online_users.add_user(uid, device, addr).await?;
+    tracing::info!("{} has joined server", redact::uid(&address));
+    audit::record(Event::Login {
+        address: address.clone(),
+        ip: addr.ip().to_string(),
+    });
+    let login = Login {
+        uid: uid.into(),
+        address,
+        session,
+    };
+    Ok((login, rx))
+}
+
+/// send a `Challenge` and check that the answer is its `device::login_proof` signed by `key`
+async fn prove(
+    rd_frame: &mut FramedRead<Metered<OwnedReadHalf>, MsgCodec>,
+    wt_frame: &mut FramedWrite<Metered<OwnedWriteHalf>, MsgCodec>,
+    address: &str,
+    key: &[u8],
+) -> GlobalResult<()> {
+    let pub_key = Keys::import_pub_key(key).map_err(|_| {
+        ClientError::AuthenticationFailed
+            .info(&format!("{} presented no valid public key", address))
+    })?;
+    let nonce = device::nonce();
+    wt_frame.send(Message::challenge(&nonce)).await?;
+    match rd_frame.next().await {
+        Some(Ok(msg)) if msg.command == Command::Challenge => {
+            let proof = device::login_proof(address, &nonce);
+            Keys::verify(proof.as_bytes(), &msg.content, &pub_key)
+        }
+        Some(Ok(msg)) => Err(ServerError::UnexpectedFrame
+            .info(&format!("{} instead of Challenge", msg.command.as_ref()))),
+        _ => Err(ExternalError::DeserializeFrame.into()),
+    }
+}
+
+/// tell a connection why its authentication failed before dropping it
+/// only `Client` and `Server` errors are worth telling, e.g. `DuplicatedAuth`
+pub async fn reject(
+    wt_frame: &mut FramedWrite<Metered<OwnedWriteHalf>, MsgCodec>,
+    e: GlobalError,
+) -> GlobalResult<()> {
+    if matches!(e.err, ErrorType::Client(_) | ErrorType::Server(_)) {
+        let _ = wt_frame.send(Message::remote_error(e.clone())).await;
+    }
+    Err(e)
+}
+
+/// answer `GetPubKey` for `target`, either `uid` or `uid/device`
+/// the registered key of every online device goes back as `SendPubKey` from `uid/device`,
+/// devices without a registered key are left out, the server never asks a device for its key
+/// `DeviceList` of the uid follows, so the requester knows which devices to encrypt for
+pub async fn pub_keys(
+    online_users: &OnlineUsers,
+    requester: &str,
+    target: &str,
+    request_id: u64,
+) -> GlobalResult<()> {
+    let (uid, device) = device::parse_address(target);
+    let devices = online_users.device_list(uid).await;
+    if devices.is_empty() {
+        return Err(ClientError::ReceiverNotExist.info(target));
+    }
+    let keys: HashMap<String, String> = online_users
+        .devices
+        .devices(uid)
+        .await
+        .into_iter()
+        .filter(|(_, record)| !record.pending)
+        .map(|(name, record)| (name, record.key))
+        .collect();
+    let wanted = devices.iter().filter(|d| match device {
+        Some(device) => d.online && d.name == device,
+        None => d.online,
+    });
+    for DeviceInfo { name, .. } in wanted {
+        let address = device::address(uid, name);
+        if let Some(key) = keys.get(name).filter(|key| !key.is_empty()) {
+            let reply = Message::send_pub_key(requester, key.as_bytes())
+                .set_sender(&address)
+                .set_id(request_id);
+            online_users.send(requester, reply).await?;
+        }
+    }
+    device_list(online_users, requester, uid, request_id).await
+}
+
+/// send the devices of `uid` to `requester` as `DeviceList` from `uid`
+pub async fn device_list(
+    online_users: &OnlineUsers,
+    requester: &str,
+    uid: &str,
+    request_id: u64,
+) -> GlobalResult<()> {
+    let devices = online_users.device_list(uid).await;
+    if devices.is_empty() {
+        return Err(ClientError::ReceiverNotExist.info(uid));
+    }
+    let lines: Vec<String> = devices.iter().map(DeviceInfo::to_line).collect();
+    let reply = Message::device_list(requester)
+        .set_sender(uid)
+        .set_content(lines.join("\n").as_bytes())
+        .set_id(request_id);
+    online_users.send(requester, reply).await
+}
+
+/// the device named by `target`, either `device` or `uid/device` of the requester's own `uid`
+/// `UnexpectedFrame` error for a device of another uid
+fn own_device<'a>(uid: &str, target: &'a str) -> GlobalResult<&'a str> {
+    match device::parse_address(target) {
+        (device, None) => Ok(device),
+        (owner, Some(device)) if owner == uid => Ok(device),
+        _ => {
+            Err(ServerError::UnexpectedFrame
+                .info(&format!("{} is not a device of {}", target, uid)))
         }
     }
 }
 
+/// revoke the `target` device of the uid behind `requester`, see `own_device`,
+/// confirm with the remaining devices, then kick it
+pub async fn revoke_device(
+    online_users: &OnlineUsers,
+    requester: &str,
+    uid: &str,
+    target: &str,
+    request_id: u64,
+) -> GlobalResult<()> {
+    let device = own_device(uid, target)?;
+    online_users.devices.revoke(uid, device).await?;
+    let address = device::address(uid, device);
+    tracing::info!("{} revoked by {}", redact::uid(&address), redact::uid(requester));
+    audit::record(Event::DeviceRevoked {
+        address: address.clone(),
+        by: requester.into(),
+    });
+    device_list(online_users, requester, uid, request_id).await?;
+    let _ = online_users.kick(&address, "device revoked").await;
+    Ok(())
+}
+
+/// approve the pending `target` device of the uid behind `requester`, see `own_device`,
+/// then confirm with the devices
+pub async fn approve_device(
+    online_users: &OnlineUsers,
+    requester: &str,
+    uid: &str,
+    target: &str,
+    fingerprint: &str,
+    request_id: u64,
+) -> GlobalResult<()> {
+    let device = own_device(uid, target)?;
+    online_users
+        .devices
+        .approve(uid, device, fingerprint)
+        .await?;
+    let address = device::address(uid, device);
+    tracing::info!("{} approved by {}", redact::uid(&address), redact::uid(requester));
+    audit::record(Event::KeyApproved {
+        address,
+        by: requester.into(),
+    });
+    device_list(online_users, requester, uid, request_id).await
+}
+
+/// replace the contacts of `uid` with the uids listed in `content`, one per line
+/// the current presence of each contact goes back to `address` as `PresenceChanged`
+pub async fn subscribe(
+    online_users: &OnlineUsers,
+    uid: &str,
+    address: &str,
+    content: &[u8],
+    request_id: u64,
+) -> GlobalResult<()> {
+    let contacts: HashSet<String> = String::from_utf8_lossy(content)
+        .lines()
+        .map(str::trim)
+        .filter(|contact| device::valid_name(contact) && *contact != uid)
+        .map(String::from)
+        .collect();
+    tracing::info!("{} subscribed to {} contacts", redact::uid(address), contacts.len());
+    for (contact, presence) in online_users.subscribe(uid, contacts).await {
+        let event = Message::presence_changed(&contact, &presence).set_id(request_id);
+        online_users.send(address, event).await?;
+    }
+    Ok(())
+}
+
+/// answer a frame rejected by `RateLimiter` with `RemoteError`, an ephemeral one is dropped silently
+/// once `violations` exceeds `max`, the device is kicked and the error is propagated
+pub async fn throttle(
+    e: GlobalError,
+    address: &str,
+    request_id: u64,
+    ephemeral: bool,
+    violations: usize,
+    max: usize,
+    online_users: &OnlineUsers,
+) -> GlobalResult<()> {
+    if max > 0 && violations > max {
+        tracing::warn!(
+            "{} kicked after {} rate limit violations",
+            redact::uid(address),
+            violations
+        );
+        let _ = online_users.kick(address, "rate limit exceeded repeatedly").await;
+        return Err(e);
+    }
+    if ephemeral {
+        return Ok(());
+    }
+    online_users
+        .send(address, Message::remote_error(e).set_id(request_id))
+        .await
+}
+
+/// a `Client` or `Server` error is caused by a single frame,
+/// so it is sent back to `address` as `RemoteError` and the connection goes on
+/// an `External` error means the connection itself is broken and is propagated
+pub async fn report(
+    result: GlobalResult<()>,
+    address: &str,
+    request_id: u64,
+    online_users: &OnlineUsers,
+) -> GlobalResult<()> {
+    match result {
+        Err(e) if matches!(e.err, ErrorType::Client(_) | ErrorType::Server(_)) => {
+            tracing::info!(
+                "request {} of {} failed: {}",
+                request_id,
+                redact::uid(address),
+                redact::error(&e)
+            );
+            online_users
+                .send(address, Message::remote_error(e).set_id(request_id))
+                .await
+        }
+        result => result,
+    }
+}
+
+/// tell a connection why it is refused before dropping it
+/// only the `first` of the connections an ip has refused in a row is audited
+pub async fn refuse(stream: TcpStream, addr: SocketAddr, e: GlobalError, first: bool) {
+    tracing::warn!("refused connection from {}: {}", addr, redact::error(&e));
+    if first {
+        audit::record(Event::Refused {
+            ip: addr.ip().to_string(),
+            reason: e.clone().into(),
+        });
+    }
+    let mut wt_frame = FramedWrite::new(stream, MsgCodec::new());
+    let _ = wt_frame.send(Message::remote_error(e)).await;
+}
+
 pub fn record(result: GlobalResult<()>) {
     use ErrorType::*;
+    if let Err(e) = &result {
+        METRICS.error(e);
+    }
     match result {
         Ok(()) => (),
         Err(e) => match e.err {
@@ -67,3 +411,16 @@ pub fn record(result: GlobalResult<()>) {
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn own_device_strips_the_own_uid_only() {
+        assert_eq!(own_device("alice", "phone").unwrap(), "phone");
+        assert_eq!(own_device("alice", "alice/phone").unwrap(), "phone");
+        let e = own_device("alice", "bob/phone").unwrap_err();
+        assert_eq!(e.code(), ServerError::UnexpectedFrame as u16);
+    }
+}
@@ -1,13 +1,35 @@
+use chrono::{FixedOffset, Local, Utc};
 use core::{
-    config::{Config, ServerConfig},
-    error::{GlobalResult, ExternalError},
+    audit::{self, AuditLog},
+    config::{
+        self, AuditConfig, Config, LogConfig, LogFormat, LogRotation, LoginConfig, Overrides,
+        ServerConfig,
+    },
+    device::DeviceRegistry,
+    error::{ExternalError, GlobalResult},
+    layout::Layout,
+    redact,
 };
-use time::macros::{offset, format_description};
+use std::{path::PathBuf, sync::OnceLock};
 use tokio::net::TcpListener;
+use tracing::Subscriber;
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
 use tracing_subscriber::{
-    fmt::{self, format::FmtSpan, time::OffsetTime},
+    filter::Targets,
+    fmt::{
+        self,
+        format::{FmtSpan, Writer},
+        time::FormatTime,
+        MakeWriter,
+    },
     prelude::__tracing_subscriber_SubscriberExt,
-    util::SubscriberInitExt, Layer, filter::LevelFilter,
+    registry::LookupSpan,
+    reload,
+    util::SubscriberInitExt,
+    Layer, Registry,
 };
 
 #[tracing::instrument]
@@ -20,46 +42,253 @@ pub async fn listen(ip: &str, port: &str) -> GlobalResult<TcpListener> {
     Ok(listener)
 }
 
+/// resolves with the name of the first shutdown signal received
+pub async fn shutdown_signal() -> GlobalResult<&'static str> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        let name = tokio::select! {
+            result = tokio::signal::ctrl_c() => result.map(|_| "SIGINT")?,
+            _ = terminate.recv() => "SIGTERM",
+        };
+        Ok(name)
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await?;
+        Ok("Ctrl-C")
+    }
+}
+
 #[tracing::instrument]
 pub fn config() -> GlobalResult<ServerConfig> {
-    let config = ServerConfig::init()?;
+    // `--port 2444`, `JHCHAT_PORT=2444` and so on override config.toml
+    let config = ServerConfig::load(&Overrides::from_env(&[])?)?;
     Ok(config)
 }
 
-/// print log -> std out & files "`exe_dir`/server_log/"
-pub fn trace() -> tracing_appender::non_blocking::WorkerGuard {
-    let mut log_dir = std::env::current_exe().expect("failed to read cur exe");
-    log_dir.pop();
-    log_dir.push("server_log");
-    let file_appender = tracing_appender::rolling::daily(log_dir, "chat");
-    let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
+/// the file `config` reads, `None` if the defaults are used
+pub fn config_file() -> GlobalResult<Option<PathBuf>> {
+    let overrides = Overrides::from_env(&[])?;
+    match (overrides.defaults, overrides.path) {
+        (true, _) => Ok(None),
+        (false, Some(path)) => Ok(Some(path)),
+        (false, None) => Ok(Some(ServerConfig::config_path()?)),
+    }
+}
 
-    let time_offset = offset!(+9);
-    let time_description = format_description!("[year]/[month]/[day]-[hour]:[minute]:[second]");
-    let timer = OffsetTime::new(time_offset, time_description);
+/// load the device registry, `devices_file` is relative to the data directory unless absolute
+pub fn devices(login: &LoginConfig) -> GlobalResult<DeviceRegistry> {
+    let data_dir = Layout::current().data_dir();
+    std::fs::create_dir_all(&data_dir)?;
+    let path = data_dir.join(&login.devices_file);
+    let registry = DeviceRegistry::load(path.clone())?;
+    tracing::info!("device registry at {:?}", path);
+    Ok(registry)
+}
 
-    let file_layer = fmt::layer()
-        .with_timer(timer.clone())
-        .with_line_number(true)
-        .with_thread_ids(true)
-        .with_span_events(FmtSpan::ACTIVE)
-        .with_file(true)
-        .with_writer(non_blocking)
-        .with_ansi(false)
-        .with_filter(LevelFilter::INFO);
+/// start recording `audit::Event`s, the log must be intact to be continued
+pub fn audit(config: &AuditConfig) -> GlobalResult<()> {
+    let Some(path) = config.path() else {
+        tracing::info!("audit log disabled");
+        return Ok(());
+    };
+    let log = AuditLog::open(path.clone()).map_err(|e| {
+        ExternalError::AuditLog.info(&format!(
+            "{}, move the log and its head aside to start a new one",
+            e.info.unwrap_or_default()
+        ))
+    })?;
+    let head = log.head();
+    tracing::info!(
+        "audit log at {:?}, entry {} is {}",
+        path,
+        head.seq,
+        head.hash
+    );
+    audit::install(log)?;
+    Ok(())
+}
+
+/// changes the level filter of `trace` while the server runs
+static LEVEL: OnceLock<reload::Handle<Targets, Registry>> = OnceLock::new();
+
+/// timestamps in the time zone of `LogConfig`, `None` for the local one
+#[derive(Clone)]
+struct Timer {
+    offset: Option<FixedOffset>,
+    format: &'static str,
+}
+
+impl FormatTime for Timer {
+    fn format_time(&self, w: &mut Writer<'_>) -> std::fmt::Result {
+        match self.offset {
+            Some(offset) => write!(
+                w,
+                "{}",
+                Utc::now().with_timezone(&offset).format(self.format)
+            ),
+            None => write!(w, "{}", Local::now().format(self.format)),
+        }
+    }
+}
+
+fn level(level: &str) -> GlobalResult<Targets> {
+    level
+        .parse()
+        .map_err(|_| ExternalError::InvalidConfig.info(&format!("{} is not a level filter", level)))
+}
 
-    let std_layer = fmt::layer()
-        .with_timer(timer)
+fn layer<S, W>(
+    config: &LogConfig,
+    writer: W,
+    ansi: bool,
+) -> GlobalResult<Box<dyn Layer<S> + Send + Sync>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = fmt::layer()
         .with_line_number(true)
         .with_thread_ids(true)
         .with_file(true)
         .with_span_events(FmtSpan::ACTIVE)
-        .with_filter(LevelFilter::INFO);
+        .with_writer(writer);
+    let offset = config.offset()?;
+    Ok(match config.format {
+        LogFormat::Plain => layer
+            .with_timer(Timer {
+                offset,
+                format: "%Y/%m/%d-%H:%M:%S",
+            })
+            .with_ansi(ansi)
+            .boxed(),
+        // RFC 3339, which aggregators parse without being told the format
+        LogFormat::Json => layer
+            .with_timer(Timer {
+                offset,
+                format: "%Y-%m-%dT%H:%M:%S%.3f%:z",
+            })
+            .json()
+            .boxed(),
+    })
+}
+
+/// print log -> std out & files in `config.dir`, `server_log` in the state directory by default
+pub fn trace(config: &LogConfig) -> GlobalResult<WorkerGuard> {
+    redact::install(config);
+    let rotation = match config.rotation {
+        LogRotation::Minutely => Rotation::MINUTELY,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Never => Rotation::NEVER,
+    };
+    // old files are looked up in it before the first one is written
+    std::fs::create_dir_all(config.dir())?;
+    let mut builder = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix("chat");
+    if config.max_files > 0 {
+        builder = builder.max_log_files(config.max_files);
+    }
+    let file_appender = builder.build(config.dir()).map_err(|e| {
+        ExternalError::Initialize.info(&format!("{}: {}", config.dir().display(), e))
+    })?;
+    let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
+
+    let (level, handle) = reload::Layer::new(level(&config.level)?);
+    let _ = LEVEL.set(handle);
+
+    let file_layer = layer(config, non_blocking, false)?;
+    let std_layer = layer(config, std::io::stdout, true)?;
 
     tracing_subscriber::registry()
+        .with(level)
         .with(file_layer)
         .with(std_layer)
         .init();
 
-    guard
+    // the config is loaded before logging is set up
+    for notice in config::take_notices() {
+        tracing::info!("{}", notice);
+    }
+    Ok(guard)
+}
+
+/// replace the level filter of `trace`
+pub fn set_level(config: &LogConfig) -> GlobalResult<()> {
+    let level = level(&config.level)?;
+    if let Some(handle) = LEVEL.get() {
+        handle
+            .reload(level)
+            .map_err(|e| ExternalError::Initialize.info(&e.to_string()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+    use tracing::Level;
+
+    fn time(offset: Option<FixedOffset>, format: &'static str) -> String {
+        let mut text = String::new();
+        Timer { offset, format }
+            .format_time(&mut Writer::new(&mut text))
+            .unwrap();
+        text
+    }
+
+    #[test]
+    fn timestamps_are_in_the_configured_zone() {
+        let tokyo = FixedOffset::east_opt(9 * 3600);
+        assert_eq!(time(tokyo, "%:z"), "+09:00");
+        let new_delhi = FixedOffset::east_opt(5 * 3600 + 30 * 60);
+        assert_eq!(time(new_delhi, "%:z"), "+05:30");
+        assert_eq!(time(None, "%:z"), Local::now().format("%:z").to_string());
+
+        // the json format is RFC 3339 and names the same instant whatever the zone
+        let json = "%Y-%m-%dT%H:%M:%S%.3f%:z";
+        let tokyo = DateTime::parse_from_rfc3339(&time(tokyo, json)).unwrap();
+        let utc = DateTime::parse_from_rfc3339(&time(FixedOffset::east_opt(0), json)).unwrap();
+        assert_eq!(tokyo.offset().local_minus_utc(), 9 * 3600);
+        assert!((utc - tokyo).num_seconds().abs() < 5);
+    }
+
+    #[test]
+    fn level_applies_to_every_target_unless_named() {
+        let targets = level("info,server::admin=debug,core=warn").unwrap();
+        assert!(targets.would_enable("server::process", &Level::INFO));
+        assert!(!targets.would_enable("server::process", &Level::DEBUG));
+        assert!(targets.would_enable("server::admin", &Level::DEBUG));
+        assert!(!targets.would_enable("server::admin", &Level::TRACE));
+        assert!(targets.would_enable("core::server_state", &Level::WARN));
+        assert!(!targets.would_enable("core::server_state", &Level::INFO));
+
+        let targets = level("OFF,server=trace").unwrap();
+        assert!(!targets.would_enable("core", &Level::ERROR));
+        assert!(targets.would_enable("server::init", &Level::TRACE));
+
+        for wrong in ["core=loud", "info=server", "server=info=debug"] {
+            let info = level(wrong).unwrap_err().info.unwrap();
+            assert!(
+                info.contains("is not a level filter"),
+                "{}: {}",
+                wrong,
+                info
+            );
+        }
+    }
+
+    #[test]
+    fn set_level_refuses_a_bad_filter() {
+        let config = |level: &str| LogConfig {
+            level: level.into(),
+            ..Default::default()
+        };
+        assert!(set_level(&config("debug,core=warn")).is_ok());
+        assert!(set_level(&config("core=loud")).is_err());
+    }
 }
@@ -0,0 +1,395 @@
+use core::{
+    codec::command::Command,
+    config::{BucketConfig, RateLimitConfig},
+    error::{GlobalError, GlobalResult, ServerError},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock},
+    time::{Duration, Instant},
+};
+
+/// token bucket, refilled continuously at `BucketConfig::rate` tokens per second
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(config: &BucketConfig) -> Self {
+        Self {
+            tokens: config.burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// take one token, or report how long until one is available
+    fn take(&mut self, config: &BucketConfig) -> Result<(), Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * config.rate).min(config.burst as f64);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        // a tiny rate makes the wait too long for a `Duration`, which is as good as never
+        Err(Duration::try_from_secs_f64((1.0 - self.tokens) / config.rate).unwrap_or(Duration::MAX))
+    }
+
+    /// whether the bucket is full again, i.e. the same as a new one
+    fn refilled(&self, config: &BucketConfig, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens + elapsed * config.rate >= config.burst as f64
+    }
+}
+
+/// rejected frames of a uid, one is forgiven every `RateLimitConfig::forgive_secs`
+#[derive(Debug)]
+struct Violations {
+    count: usize,
+    // when the last one was forgiven, or counted if none was since
+    last: Instant,
+    // commands rejected since all were last forgiven
+    commands: HashSet<Command>,
+}
+
+impl Violations {
+    fn forgive(&mut self, forgive_secs: u64, now: Instant) {
+        if forgive_secs == 0 {
+            self.last = now;
+            return;
+        }
+        let elapsed = now.duration_since(self.last).as_secs();
+        let forgiven = (elapsed / forgive_secs).min(self.count as u64);
+        self.count -= forgiven as usize;
+        self.last = match self.count {
+            0 => now,
+            _ => self.last + Duration::from_secs(forgiven * forgive_secs),
+        };
+        if self.count == 0 {
+            self.commands.clear();
+        }
+    }
+}
+
+/// a rejected frame as counted by `RateLimiter::violation`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Violation {
+    // violations of the uid after forgiving the old ones, this one included
+    pub count: usize,
+    // no frame of the command was rejected since the violations of the uid were all forgiven
+    pub first: bool,
+}
+
+/// open connections of an ip
+#[derive(Debug, Default)]
+struct Connections {
+    open: usize,
+    // whether one was refused since one of them last closed
+    refused: bool,
+}
+
+/// a connection refused by `RateLimiter::connect`
+#[derive(Debug)]
+pub struct Refused {
+    pub error: GlobalError,
+    // no connection of the ip was refused since one of its connections last closed
+    pub first: bool,
+}
+
+/// per (uid, command) token buckets, violations per uid and connection counts per ip
+/// buckets and violations outlive connections, so logging in again does not reset them
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RwLock<RateLimitConfig>,
+    buckets: Mutex<HashMap<(String, Command), Bucket>>,
+    violations: Mutex<HashMap<String, Violations>>,
+    connections: Arc<Mutex<HashMap<IpAddr, Connections>>>,
+}
+
+/// holds one connection slot of an ip, released on drop
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    ip: IpAddr,
+    connections: Arc<Mutex<HashMap<IpAddr, Connections>>>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut connections = lock(&self.connections);
+        if let Some(ip) = connections.get_mut(&self.ip) {
+            ip.open -= 1;
+            ip.refused = false;
+            if ip.open == 0 {
+                connections.remove(&self.ip);
+            }
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config: RwLock::new(config),
+            buckets: Mutex::new(HashMap::new()),
+            violations: Mutex::new(HashMap::new()),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn config(&self) -> RateLimitConfig {
+        self.config.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// existing buckets keep their tokens and follow the new rates from now on
+    pub fn set_config(&self, config: RateLimitConfig) {
+        *self.config.write().unwrap_or_else(PoisonError::into_inner) = config;
+    }
+
+    /// reserve a connection slot for `ip`
+    /// `RateLimited` error if `ip` already holds `max_connections_per_ip` connections
+    pub fn connect(&self, ip: IpAddr) -> Result<ConnectionSlot, Refused> {
+        let max = self.config().max_connections_per_ip;
+        let mut connections = lock(&self.connections);
+        let open = connections.entry(ip).or_default();
+        if max > 0 && open.open >= max {
+            return Err(Refused {
+                error: ServerError::RateLimited
+                    .info(&format!("at most {} connections per ip", max)),
+                first: !std::mem::replace(&mut open.refused, true),
+            });
+        }
+        open.open += 1;
+        Ok(ConnectionSlot {
+            ip,
+            connections: Arc::clone(&self.connections),
+        })
+    }
+
+    /// take a token from the bucket of (`uid`, `command`)
+    /// `RateLimited` error telling when to retry if the bucket is empty
+    pub fn check(&self, uid: &str, command: &Command) -> GlobalResult<()> {
+        let config = self.config();
+        let bucket_config = config.bucket(command);
+        let mut buckets = lock(&self.buckets);
+        let bucket = buckets
+            .entry((uid.into(), command.clone()))
+            .or_insert_with(|| Bucket::new(bucket_config));
+        bucket.take(bucket_config).map_err(|wait| {
+            ServerError::RateLimited.info(&format!(
+                "{} limited to {}/s, retry in {:.1}s",
+                command.as_ref(),
+                bucket_config.rate,
+                wait.as_secs_f64().min(3600.0)
+            ))
+        })
+    }
+
+    /// count a rejected `command` of `uid`
+    pub fn violation(&self, uid: &str, command: &Command) -> Violation {
+        let forgive_secs = self.config().forgive_secs;
+        let now = Instant::now();
+        let mut violations = lock(&self.violations);
+        let entry = violations.entry(uid.into()).or_insert(Violations {
+            count: 0,
+            last: now,
+            commands: HashSet::new(),
+        });
+        entry.forgive(forgive_secs, now);
+        entry.count += 1;
+        Violation {
+            count: entry.count,
+            first: entry.commands.insert(command.clone()),
+        }
+    }
+
+    /// drop buckets that have refilled and uids whose violations are all forgiven,
+    /// they are no different from new ones
+    pub fn prune(&self) {
+        let config = self.config();
+        let now = Instant::now();
+        lock(&self.buckets)
+            .retain(|(_, command), bucket| !bucket.refilled(config.bucket(command), now));
+        lock(&self.violations).retain(|_, violations| {
+            violations.forgive(config.forgive_secs, now);
+            violations.count > 0
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::error::ErrorType;
+    use std::collections::BTreeMap;
+
+    fn limiter(default: BucketConfig, commands: &[(&str, BucketConfig)]) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            default,
+            commands: commands
+                .iter()
+                .map(|(command, bucket)| (command.to_string(), bucket.clone()))
+                .collect::<BTreeMap<_, _>>(),
+            ..RateLimitConfig::default()
+        })
+    }
+
+    fn bucket(rate: f64, burst: usize) -> BucketConfig {
+        BucketConfig { rate, burst }
+    }
+
+    /// move the buckets of `uid` `secs` into the past, as if that much time had passed
+    fn age(limiter: &RateLimiter, uid: &str, secs: u64) {
+        for ((owner, _), bucket) in lock(&limiter.buckets).iter_mut() {
+            if owner == uid {
+                bucket.last -= Duration::from_secs(secs);
+            }
+        }
+    }
+
+    #[test]
+    fn burst_then_limited() {
+        let limiter = limiter(bucket(0.001, 3), &[]);
+        for _ in 0..3 {
+            assert!(limiter.check("alice", &Command::Help).is_ok());
+        }
+        let e = limiter.check("alice", &Command::Help).unwrap_err();
+        assert!(matches!(e.err, ErrorType::Server(ServerError::RateLimited)));
+        // buckets are per uid
+        assert!(limiter.check("bob", &Command::Help).is_ok());
+    }
+
+    #[test]
+    fn tiny_rate_does_not_overflow() {
+        for rate in [0.0, 1e-300, f64::MIN_POSITIVE] {
+            let limiter = limiter(bucket(rate, 0), &[]);
+            let e = limiter.check("alice", &Command::Help).unwrap_err();
+            assert!(matches!(e.err, ErrorType::Server(ServerError::RateLimited)));
+        }
+    }
+
+    #[test]
+    fn refill_at_rate_up_to_burst() {
+        let limiter = limiter(bucket(2.0, 3), &[]);
+        for _ in 0..3 {
+            assert!(limiter.check("alice", &Command::Help).is_ok());
+        }
+        assert!(limiter.check("alice", &Command::Help).is_err());
+        age(&limiter, "alice", 1);
+        assert!(limiter.check("alice", &Command::Help).is_ok());
+        assert!(limiter.check("alice", &Command::Help).is_ok());
+        assert!(limiter.check("alice", &Command::Help).is_err());
+        // a long pause refills no more than the burst
+        age(&limiter, "alice", 3600);
+        for _ in 0..3 {
+            assert!(limiter.check("alice", &Command::Help).is_ok());
+        }
+        assert!(limiter.check("alice", &Command::Help).is_err());
+    }
+
+    #[test]
+    fn commands_override_the_default() {
+        let limiter = limiter(bucket(0.001, 5), &[("SendMsg", bucket(0.001, 1))]);
+        assert!(limiter.check("alice", &Command::SendMsg).is_ok());
+        assert!(limiter.check("alice", &Command::SendMsg).is_err());
+        // other commands have buckets of their own with the default size
+        for _ in 0..5 {
+            assert!(limiter.check("alice", &Command::Help).is_ok());
+        }
+        assert!(limiter.check("alice", &Command::Help).is_err());
+    }
+
+    #[test]
+    fn prune_keeps_buckets_until_refilled() {
+        let limiter = limiter(bucket(1.0, 2), &[]);
+        assert!(limiter.check("alice", &Command::Help).is_ok());
+        assert!(limiter.check("alice", &Command::Help).is_ok());
+        // leaving and logging in again does not reset the bucket
+        limiter.prune();
+        assert!(limiter.check("alice", &Command::Help).is_err());
+        age(&limiter, "alice", 10);
+        limiter.prune();
+        assert!(lock(&limiter.buckets).is_empty());
+    }
+
+    #[test]
+    fn violations_are_forgiven_over_time() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            forgive_secs: 60,
+            ..RateLimitConfig::default()
+        });
+        assert_eq!(limiter.violation("alice", &Command::Help).count, 1);
+        assert_eq!(limiter.violation("alice", &Command::Help).count, 2);
+        assert_eq!(limiter.violation("alice", &Command::Help).count, 3);
+        assert_eq!(limiter.violation("bob", &Command::Help).count, 1);
+        // two periods later two of alice's three are forgiven
+        lock(&limiter.violations).get_mut("alice").unwrap().last -= Duration::from_secs(130);
+        assert_eq!(limiter.violation("alice", &Command::Help).count, 2);
+        lock(&limiter.violations).get_mut("alice").unwrap().last -= Duration::from_secs(3600);
+        limiter.prune();
+        assert!(!lock(&limiter.violations).contains_key("alice"));
+        assert!(lock(&limiter.violations).contains_key("bob"));
+    }
+
+    #[test]
+    fn first_violation_of_each_command_until_all_are_forgiven() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            forgive_secs: 60,
+            ..RateLimitConfig::default()
+        });
+        let violation = |uid: &str, command: &Command| limiter.violation(uid, command).first;
+        assert!(violation("alice", &Command::Help));
+        assert!(!violation("alice", &Command::Help));
+        assert!(violation("alice", &Command::SendMsg));
+        assert!(!violation("alice", &Command::SendMsg));
+        assert!(violation("bob", &Command::Help));
+        // one of three forgiven, help is still being throttled
+        lock(&limiter.violations).get_mut("alice").unwrap().last -= Duration::from_secs(70);
+        assert!(!violation("alice", &Command::Help));
+        lock(&limiter.violations).get_mut("alice").unwrap().last -= Duration::from_secs(3600);
+        assert!(violation("alice", &Command::Help));
+    }
+
+    #[test]
+    fn connections_are_capped_per_ip() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            max_connections_per_ip: 2,
+            ..RateLimitConfig::default()
+        });
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let first = limiter.connect(ip).unwrap();
+        let _second = limiter.connect(ip).unwrap();
+        let refused = limiter.connect(ip).unwrap_err();
+        assert!(matches!(
+            refused.error.err,
+            ErrorType::Server(ServerError::RateLimited)
+        ));
+        assert!(refused.first);
+        // refused again while the ip holds its connections, only the first one is new
+        assert!(!limiter.connect(ip).unwrap_err().first);
+        assert!(limiter.connect("10.0.0.2".parse().unwrap()).is_ok());
+
+        drop(first);
+        let _third = limiter.connect(ip).unwrap();
+        assert!(limiter.connect(ip).unwrap_err().first);
+        assert!(!limiter.connect(ip).unwrap_err().first);
+    }
+
+    #[test]
+    fn violations_never_forgiven_with_zero() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            forgive_secs: 0,
+            ..RateLimitConfig::default()
+        });
+        limiter.violation("alice", &Command::Help);
+        lock(&limiter.violations).get_mut("alice").unwrap().last -= Duration::from_secs(3600);
+        assert_eq!(limiter.violation("alice", &Command::Help).count, 2);
+    }
+}
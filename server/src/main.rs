@@ -1,26 +1,149 @@
 mod init;
 mod process; mod handler;
-use std::{error::Error, sync::Arc};
+mod admin;
+mod metrics;
+mod limit;
+mod reload;
+use std::{
+    error::Error,
+    sync::{Arc, PoisonError, RwLock},
+    time::Duration,
+};
 
+use limit::RateLimiter;
 use process::process;
-use core::server_state::OnlineUsers;
+use core::{audit, config, server_state::OnlineUsers};
+use tokio::task::JoinSet;
+
+/// pause after a failed accept, so that a full fd table is not polled in a loop
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let _guard = init::trace();
+    // the config decides the layout, and with it where the logs go
+    // a bad config is reported with one line per problem
+    let config = init::config().unwrap_or_else(|e| {
+        // there is no log yet for what loading did to the files
+        for notice in config::take_notices() {
+            eprintln!("{}", notice);
+        }
+        eprintln!("{}", e);
+        std::process::exit(1)
+    });
+    let _guard = init::trace(&config.log)?;
 
-    let config = init::config()?;
-    let online_users = Arc::new(OnlineUsers::new());
+    let devices = init::devices(&config.login)?;
+    init::audit(&config.audit)?;
+    let online_users = Arc::new(OnlineUsers::new(
+        config.queue.clone(),
+        config.login.clone(),
+        devices,
+    ));
+    let limiter = Arc::new(RateLimiter::new(config.rate_limit.clone()));
     let listener = init::listen(&config.ip, &config.port).await?;
+    let shared_config = Arc::new(RwLock::new(config.clone()));
+
+    if config.admin.token.is_empty() {
+        tracing::info!("admin console disabled, set admin.token to enable it");
+    } else {
+        let admin_task = admin::serve(
+            config.admin.clone(),
+            Arc::clone(&shared_config),
+            Arc::clone(&online_users),
+            Arc::clone(&limiter),
+        );
+        tokio::spawn(async move {
+            if let Err(e) = admin_task.await {
+                tracing::warn!("admin console stopped: {}", e);
+            }
+        });
+    }
 
-    loop {
-        let online_users = Arc::clone(&online_users);
-        let (stream, addr) = listener.accept().await?;
+    let reload_task = reload::watch(
+        Arc::clone(&shared_config),
+        Arc::clone(&online_users),
+        Arc::clone(&limiter),
+    );
+    tokio::spawn(async move {
+        if let Err(e) = reload_task.await {
+            tracing::warn!("config reload on SIGHUP and file change stopped: {}", e);
+        }
+    });
 
+    if config.metrics.addr.is_empty() {
+        tracing::info!("metrics endpoint disabled");
+    } else {
+        let metrics_task = metrics::serve(config.metrics.clone(), Arc::clone(&online_users));
         tokio::spawn(async move {
-            let result = process(stream, addr, online_users).await;
-            handler::record(result);
+            if let Err(e) = metrics_task.await {
+                tracing::warn!("metrics endpoint stopped: {}", e);
+            }
         });
     }
 
+    let mut connections = JoinSet::new();
+    let shutdown = init::shutdown_signal();
+    tokio::pin!(shutdown);
+
+    let signal = loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let online_users = Arc::clone(&online_users);
+                let limiter = Arc::clone(&limiter);
+                let (stream, addr) = match accepted {
+                    Ok(accepted) => accepted,
+                    // such as too many open files, which passes as connections close
+                    Err(e) => {
+                        tracing::warn!("cannot accept a connection: {}", e);
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                };
+                metrics::METRICS.connection_accepted();
+
+                connections.spawn(async move {
+                    let _slot = match limiter.connect(addr.ip()) {
+                        Ok(slot) => slot,
+                        Err(refused) => {
+                            return handler::refuse(stream, addr, refused.error, refused.first).await
+                        }
+                    };
+                    let result = process(stream, addr, online_users, limiter).await;
+                    handler::record(result);
+                });
+            }
+            // reap finished connections so the set does not grow without bound
+            Some(_) = connections.join_next(), if !connections.is_empty() => (),
+            signal = &mut shutdown => break signal?,
+        }
+    };
+
+    // stop accepting, notify users, then give write tasks time to flush
+    drop(listener);
+    let config = shared_config
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    tracing::info!("{} received, shutting down", signal);
+    let reason = format!("{} ({})", config.shutdown.notice, signal);
+    online_users.shutdown(&reason).await;
+
+    let drain_timeout = Duration::from_secs(config.shutdown.drain_timeout_secs);
+    let drained = tokio::time::timeout(drain_timeout, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        tracing::warn!(
+            "{} connections still open after {:?}, aborting",
+            connections.len(),
+            drain_timeout
+        );
+        connections.shutdown().await;
+    }
+    // the writer thread would be cut off with events still queued
+    audit::flush();
+    tracing::info!("server stopped");
+
+    Ok(())
 }
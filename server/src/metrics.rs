@@ -0,0 +1,342 @@
+use core::{
+    config::MetricsConfig,
+    error::{ErrorType, ExternalError, GlobalError, GlobalResult},
+    server_state::OnlineUsers,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpListener,
+};
+
+/// process wide counters, rendered in the Prometheus text exposition format
+#[derive(Debug)]
+pub struct Metrics {
+    connections_accepted: AtomicU64,
+    decode_errors: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    // keyed by `Command`
+    frames: Mutex<BTreeMap<String, u64>>,
+    // keyed by (category, error) of errors passed to `handler::record`
+    errors: Mutex<BTreeMap<(&'static str, String), u64>>,
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            connections_accepted: AtomicU64::new(0),
+            decode_errors: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            frames: Mutex::new(BTreeMap::new()),
+            errors: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn connection_accepted(&self) {
+        self.connections_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `MsgCodec::decode` answers malformed input with a `Help` frame, see `take_malformed`
+    pub fn decode_errors(&self, count: usize) {
+        self.decode_errors.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn frame(&self, command: &str) {
+        let mut frames = self.frames.lock().unwrap_or_else(PoisonError::into_inner);
+        *frames.entry(command.into()).or_default() += 1;
+    }
+
+    pub fn error(&self, e: &GlobalError) {
+        use ErrorType::*;
+        let key = match &e.err {
+            Client(e) => ("Client", e.as_ref().to_string()),
+            Server(e) => ("Server", e.as_ref().to_string()),
+            External(e) => ("External", e.as_ref().to_string()),
+        };
+        let mut errors = self.errors.lock().unwrap_or_else(PoisonError::into_inner);
+        *errors.entry(key).or_default() += 1;
+    }
+
+    pub async fn render(&self, online_users: &OnlineUsers) -> String {
+        let online = online_users.list.read().await.len();
+        let mut out = String::new();
+        let mut single = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+        single(
+            "jhchat_connections_accepted_total",
+            "counter",
+            "Connections accepted by the listener.",
+            self.connections_accepted.load(Ordering::Relaxed),
+        );
+        single(
+            "jhchat_online_users",
+            "gauge",
+            "Users currently logged in.",
+            online as u64,
+        );
+        single(
+            "jhchat_decode_errors_total",
+            "counter",
+            "Malformed frames answered with Help.",
+            self.decode_errors.load(Ordering::Relaxed),
+        );
+        single(
+            "jhchat_received_bytes_total",
+            "counter",
+            "Bytes read from client connections.",
+            self.bytes_in.load(Ordering::Relaxed),
+        );
+        single(
+            "jhchat_sent_bytes_total",
+            "counter",
+            "Bytes written to client connections.",
+            self.bytes_out.load(Ordering::Relaxed),
+        );
+
+        let _ = writeln!(out, "# HELP jhchat_frames_total Frames received per command.");
+        let _ = writeln!(out, "# TYPE jhchat_frames_total counter");
+        for (command, count) in self.frames.lock().unwrap_or_else(PoisonError::into_inner).iter() {
+            let _ = writeln!(out, "jhchat_frames_total{{command=\"{}\"}} {}", command, count);
+        }
+
+        let _ = writeln!(out, "# HELP jhchat_connection_errors_total Errors that ended a connection.");
+        let _ = writeln!(out, "# TYPE jhchat_connection_errors_total counter");
+        for ((category, error), count) in
+            self.errors.lock().unwrap_or_else(PoisonError::into_inner).iter()
+        {
+            let _ = writeln!(
+                out,
+                "jhchat_connection_errors_total{{category=\"{}\",error=\"{}\"}} {}",
+                category, error, count
+            );
+        }
+        out
+    }
+}
+
+/// how long a scrape connection may take to send its request line
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// serve `GET /metrics` on `config.addr` until the server stops
+/// the endpoint has no authentication, an address other hosts can reach is warned about
+pub async fn serve(config: MetricsConfig, online_users: Arc<OnlineUsers>) -> GlobalResult<()> {
+    let listener = TcpListener::bind(&config.addr)
+        .await
+        .map_err(|_| ExternalError::ListenPort.info(&config.addr))?;
+    tracing::info!("metrics available on http://{}/metrics", config.addr);
+    if !listener.local_addr()?.ip().is_loopback() {
+        tracing::warn!(
+            "metrics on {} are not limited to this host, anyone who can reach it can read them",
+            config.addr
+        );
+    }
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let online_users = Arc::clone(&online_users);
+        tokio::spawn(async move {
+            // only the request line matters, the rest of the request is ignored
+            let mut buf = [0; 1024];
+            let n = match tokio::time::timeout(REQUEST_TIMEOUT, stream.read(&mut buf)).await {
+                Ok(read) => read.unwrap_or(0),
+                // a connection that sends nothing is dropped without an answer
+                Err(_) => return,
+            };
+            let request = String::from_utf8_lossy(&buf[..n]);
+            let response = match request.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+                ["GET", "/metrics"] => {
+                    let body = METRICS.render(&online_users).await;
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                }
+                _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .into(),
+            };
+            let _ = stream.write_all(response.as_bytes()).await;
+            let _ = stream.shutdown().await;
+        });
+    }
+}
+
+/// counts bytes passing through a connection half into `METRICS`
+#[derive(Debug)]
+pub struct Metered<T> {
+    inner: T,
+}
+
+impl<T> Metered<T> {
+    pub fn new(inner: T) -> Self {
+        Self { inner }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Metered<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - before;
+        METRICS.bytes_in.fetch_add(read as u64, Ordering::Relaxed);
+        poll
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Metered<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            METRICS.bytes_out.fetch_add(written as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::{
+        config::{LoginConfig, QueueConfig},
+        device::DeviceRegistry,
+        error::{ClientError, ServerError},
+    };
+    use std::net::SocketAddr;
+    use tokio::net::TcpStream;
+
+    /// `#[tokio::test]` cannot name std's `core` in this workspace, whose own crate takes the name
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn online_users() -> OnlineUsers {
+        OnlineUsers::new(
+            QueueConfig::default(),
+            LoginConfig::default(),
+            DeviceRegistry::default(),
+        )
+    }
+
+    #[test]
+    fn render_writes_every_counter_with_its_labels() {
+        block_on(async {
+            let metrics = Metrics::new();
+            let online_users = online_users();
+            let addr = SocketAddr::from(([127, 0, 0, 1], 1));
+            let _rx = online_users.add_user("alice", "phone", addr).await.unwrap();
+            metrics.connection_accepted();
+            metrics.decode_errors(2);
+            metrics.frame("SendMsg");
+            metrics.frame("SendMsg");
+            metrics.frame("Login");
+            metrics.error(&ClientError::AuthenticationFailed.into());
+            metrics.error(&ServerError::UserDisconnect.into());
+            let out = metrics.render(&online_users).await;
+            for line in [
+                "# TYPE jhchat_connections_accepted_total counter",
+                "jhchat_connections_accepted_total 1",
+                "# TYPE jhchat_online_users gauge",
+                "jhchat_online_users 1",
+                "jhchat_decode_errors_total 2",
+                "jhchat_received_bytes_total 0",
+                "jhchat_frames_total{command=\"Login\"} 1",
+                "jhchat_frames_total{command=\"SendMsg\"} 2",
+                "jhchat_connection_errors_total{category=\"Client\",error=\"AuthenticationFailed\"} 1",
+                "jhchat_connection_errors_total{category=\"Server\",error=\"UserDisconnect\"} 1",
+            ] {
+                assert!(out.lines().any(|l| l == line), "{} missing from\n{}", line, out);
+            }
+        })
+    }
+
+    #[test]
+    fn metered_counts_the_bytes_both_ways() {
+        block_on(async {
+            let (client, server) = tokio::io::duplex(64);
+            let mut metered = Metered::new(server);
+            let mut client = client;
+            let read = METRICS.bytes_in.load(Ordering::Relaxed);
+            let written = METRICS.bytes_out.load(Ordering::Relaxed);
+
+            client.write_all(b"hello").await.unwrap();
+            let mut buf = [0; 5];
+            metered.read_exact(&mut buf).await.unwrap();
+            metered.write_all(b"hi there").await.unwrap();
+            let mut buf = [0; 8];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hi there");
+
+            assert_eq!(METRICS.bytes_in.load(Ordering::Relaxed) - read, 5);
+            assert_eq!(METRICS.bytes_out.load(Ordering::Relaxed) - written, 8);
+        })
+    }
+
+    #[test]
+    fn serve_answers_metrics_and_nothing_else() {
+        block_on(async {
+            // a port that was free a moment ago
+            let addr = TcpListener::bind("127.0.0.1:0")
+                .await
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            let config = MetricsConfig {
+                addr: addr.to_string(),
+            };
+            tokio::spawn(serve(config, Arc::new(online_users())));
+            // until the endpoint listens, the idle connections just time out
+            while TcpStream::connect(addr).await.is_err() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            let get = |path: &'static str| async move {
+                let mut stream = TcpStream::connect(addr).await.unwrap();
+                let request = format!("GET {} HTTP/1.1\r\nHost: x\r\n\r\n", path);
+                stream.write_all(request.as_bytes()).await.unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).await.unwrap();
+                response
+            };
+            let response = get("/metrics").await;
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(response.contains("jhchat_online_users 0"));
+            assert!(get("/").await.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        })
+    }
+}
@@ -1,108 +1,257 @@
-use crate::handler;
+use crate::handler::{self, Login};
+use crate::limit::RateLimiter;
+use crate::metrics::{Metered, METRICS};
 use futures::SinkExt;
 use core::error::{GlobalResult, ServerError, ExternalError};
 use core::{
+    audit::{self, Event},
     codec::{command::Command, message::Message, msg_codec::MsgCodec},
+    device,
+    presence::Presence,
+    redact,
     server_state::OnlineUsers,
 };
 use tokio::sync::mpsc::unbounded_channel;
 use std::{net::SocketAddr, sync::Arc};
-use tokio::net::TcpStream;
+use tokio::{net::TcpStream, task::AbortHandle};
 use tokio_stream::StreamExt;
 use tokio_util::codec::{FramedRead, FramedWrite};
 
+/// aborts the tasks of a connection once it is over, also when `process` itself is aborted
+struct Tasks(Vec<AbortHandle>);
+
+impl Drop for Tasks {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
+
 pub async fn process(
     stream: TcpStream,
     addr: SocketAddr,
     online_users: Arc<OnlineUsers>,
+    limiter: Arc<RateLimiter>,
 ) -> GlobalResult<()> {
     let (rd, wt) = stream.into_split();
-    let mut rd_frame = FramedRead::new(rd, MsgCodec::new());
-    let mut wt_frame = FramedWrite::new(wt, MsgCodec::new());
+    let mut rd_frame = FramedRead::new(Metered::new(rd), MsgCodec::new());
+    let mut wt_frame = FramedWrite::new(Metered::new(wt), MsgCodec::new());
 
-    let (uid, mut rx) =
-        handler::authenticate(Arc::clone(&online_users), &mut rd_frame, addr).await?;
-    let uid_shared_1 = Arc::new(uid);
-    let uid_shared_2 = Arc::clone(&uid_shared_1);
+    let login = handler::authenticate(
+        Arc::clone(&online_users),
+        &mut rd_frame,
+        &mut wt_frame,
+        addr,
+    )
+    .await;
+    METRICS.decode_errors(rd_frame.decoder_mut().take_malformed());
+    let (login, mut rx) = match login {
+        Ok(auth) => auth,
+        Err(e) => return handler::reject(&mut wt_frame, e).await,
+    };
+    let login_shared_1 = Arc::new(login);
+    let login_shared_2 = Arc::clone(&login_shared_1);
+    let login_shared_3 = Arc::clone(&login_shared_1);
+    let online_users_2 = Arc::clone(&online_users);
+    let online_users_3 = Arc::clone(&online_users);
+    let limiter_2 = Arc::clone(&limiter);
 
     let (e_tx, mut e_rx) = unbounded_channel();
     let e_tx_1 = e_tx.clone();
     let e_tx_2 = e_tx.clone();
 
     // task 1: peek the stream and handle frames
-    tokio::spawn(async move {
+    let read = tokio::spawn(async move {
         loop {
-            let uid = Arc::clone(&uid_shared_1);
-            let result = match rd_frame.next().await {
-                Some(Ok(msg)) => handle_incoming_msg(msg, &uid, Arc::clone(&online_users)).await,
+            let login = Arc::clone(&login_shared_1);
+            let Login { uid, address, .. } = login.as_ref();
+            let frame = rd_frame.next().await;
+            METRICS.decode_errors(rd_frame.decoder_mut().take_malformed());
+            let result = match frame {
+                Some(Ok(msg)) => {
+                    // throttled frames count too, a flood shows here first
+                    METRICS.frame(msg.command.as_ref());
+                    let request_id = msg.id;
+                    let ephemeral = msg.ephemeral || msg.command.is_signal();
+                    match limiter.check(uid, &msg.command) {
+                        Ok(()) => {
+                            let result =
+                                handle_incoming_msg(msg, &login, Arc::clone(&online_users)).await;
+                            handler::report(result, address, request_id, &online_users).await
+                        }
+                        Err(e) => {
+                            let violation = limiter.violation(uid, &msg.command);
+                            let max = limiter.config().max_violations;
+                            // a flood is recorded as it starts and as it gets the uid kicked
+                            if violation.first || (max > 0 && violation.count == max + 1) {
+                                audit::record(Event::RateLimited {
+                                    address: address.clone(),
+                                    command: msg.command.as_ref().into(),
+                                    violations: violation.count,
+                                });
+                            }
+                            handler::throttle(
+                                e,
+                                address,
+                                request_id,
+                                ephemeral,
+                                violation.count,
+                                max,
+                                &online_users,
+                            )
+                            .await
+                        }
+                    }
+                }
                 _ => {
-                    tracing::info!("user {} with ip {} has left the server", &uid, addr);
-                    online_users.remove_user(&uid).await;
-                    Err(ServerError::UserDisconnect.info(&uid))
+                    tracing::info!(
+                        "user {} with ip {} has left the server",
+                        redact::uid(address),
+                        addr
+                    );
+                    Err(ServerError::UserDisconnect.info(address))
                 }
             };
             if let Err(e) = result {
-                let _ = e_tx_1.send(e);
+                let _ = e_tx_1.send(Err(e));
                 break;
             }
         }
     });
 
     // task 2: send frames to client
-    tokio::spawn(async move {
+    let write = tokio::spawn(async move {
         loop {
-            let uid = Arc::clone(&uid_shared_2);
+            let login = Arc::clone(&login_shared_2);
+            if rx.is_empty() {
+                online_users_2.restore_spilled(&login.uid).await;
+            }
             let result =
                 match rx.recv().await {
                     Some(msg) => wt_frame
                         .send(msg)
                         .await
                         .map_err(|e| ExternalError::TokioChannel.info(&format!("{}", e))),
-                    None => Err(ExternalError::TokioChannel
-                        .info(&format!("{}'s transmitter is dropped", &uid))),
+                    // queue closed by `OnlineUsers`, everything queued has been written
+                    None => {
+                        let closed = wt_frame
+                            .close()
+                            .await
+                            .map_err(|e| ExternalError::TokioChannel.info(&format!("{}", e)));
+                        tracing::info!("queue of {} is closed", redact::uid(&login.address));
+                        let _ = e_tx_2.send(closed);
+                        break;
+                    }
                 };
             if let Err(e) = result {
-                let _ = e_tx_2.send(e);
+                let _ = e_tx_2.send(Err(e));
                 break;
             }
         }
     });
-    if let Some(error) = e_rx.recv().await {
-        return Err(error);
-    }
-    Ok(())
+    let _tasks = Tasks(vec![read.abort_handle(), write.abort_handle()]);
+    let result = e_rx.recv().await.unwrap_or(Ok(()));
+    // frames are no longer read once either side is over, removing the session closes its queue,
+    // the write task still flushes what is queued, such as `ServerShutdown`, then ends
+    // the session may already be gone if it was kicked or replaced by a newer login
+    read.abort();
+    let Login { uid, session, .. } = login_shared_3.as_ref();
+    online_users_3.remove_user(uid, *session).await;
+    limiter_2.prune();
+    let _ = write.await;
+    result
 }
 
 async fn handle_incoming_msg(
     msg: Message,
-    uid: &str,
+    login: &Login,
     online_users: Arc<OnlineUsers>,
 ) -> GlobalResult<()> {
-    tracing::info!("user {} has sent a message to server\n{:?}", uid, msg);
+    let Login { uid, address, .. } = login;
+    tracing::debug!("{} sent {}", redact::uid(address), redact::frame(&msg));
+    if msg.ephemeral && msg.command != Command::SendMsg && !msg.command.is_signal() {
+        return Err(ServerError::UnexpectedFrame
+            .info(&format!("{} cannot be ephemeral", msg.command.as_ref())));
+    }
     match msg.command {
         Command::OnlineList => {
             online_users
-                .send(uid, online_users.to_msg().await.set_sender("Server"))
+                .send(address, online_users.to_msg().await.set_sender("Server").set_id(msg.id))
                 .await
         }
-        Command::SendMsg => {
+        Command::SendMsg if !msg.ephemeral => {
             online_users
-                .send(&msg.get_receiver(), msg.set_sender(uid))
+                .send(&msg.get_receiver(), msg.set_sender(address))
                 .await
         }
-        Command::Help => online_users.send(uid, Command::help()).await,
-        Command::GetPubKey=> {
+        Command::Help => online_users.send(address, Command::help()).await,
+        Command::GetPubKey => {
+            handler::pub_keys(&online_users, address, &msg.receiver, msg.id).await
+        }
+        Command::SendPubKey => {
             online_users
-                .send(&msg.get_receiver(), msg.set_sender(uid))
+                .send(&msg.get_receiver(), msg.set_sender(address))
                 .await
         }
-        Command::SendPubKey=> {
+        Command::DeviceList => {
+            let target = match msg.receiver.as_str() {
+                "" => uid,
+                receiver => device::parse_address(receiver).0,
+            };
+            handler::device_list(&online_users, address, target, msg.id).await
+        }
+        Command::RevokeDevice => {
+            let target = String::from_utf8_lossy(&msg.content).trim().to_string();
+            handler::revoke_device(&online_users, address, uid, &target, msg.id).await
+        }
+        Command::ApproveDevice => {
+            let content = String::from_utf8_lossy(&msg.content);
+            let mut words = content.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some(target), Some(fingerprint)) => {
+                    handler::approve_device(
+                        &online_users,
+                        address,
+                        uid,
+                        target,
+                        fingerprint,
+                        msg.id,
+                    )
+                    .await
+                }
+                _ => Err(ServerError::UnexpectedFrame.info("expected a device and a fingerprint")),
+            }
+        }
+        Command::Subscribe => {
+            handler::subscribe(&online_users, uid, address, &msg.content, msg.id).await
+        }
+        Command::SetStatus => match Presence::from_content(&msg.content) {
+            Some(presence) => online_users.set_status(uid, presence).await,
+            None => Err(ServerError::UnexpectedFrame.info("status must be Online or Away")),
+        },
+        Command::SetHidden => match String::from_utf8_lossy(&msg.content).trim().parse() {
+            Ok(hidden) => online_users.set_hidden(uid, hidden).await,
+            Err(_) => Err(ServerError::UnexpectedFrame.info("hidden must be true or false")),
+        },
+        // ephemeral, so dropped rather than answered with `RemoteError` when they cannot be delivered
+        Command::SendMsg | Command::Typing | Command::StoppedTyping | Command::Viewing => {
+            let receiver = msg.get_receiver();
             online_users
-                .send(&msg.get_receiver(), msg.set_sender(uid))
-                .await
+                .relay(&receiver, msg.set_sender(address).set_id(0))
+                .await;
+            Ok(())
+        }
+        Command::Login | Command::Challenge => Err(ServerError::UnexpectedFrame
+            .info(&format!("{} duplicated authentication request", address))),
+        Command::RemoteError
+        | Command::ServerShutdown
+        | Command::Kicked
+        | Command::Announcement
+        | Command::Admin
+        | Command::PresenceChanged => {
+            Err(ServerError::UnexpectedFrame
+                .info(&format!("{} sent {} to server", address, msg.command.as_ref())))
         }
-        Command::Login => Err(ServerError::UnexpectedFrame
-            .info(&format!("{} duplicated authentication request", &uid))),
-        Command::RemoteError => Err(ServerError::Unknown.into()),
     }
 }
@@ -0,0 +1,247 @@
+use crate::{init, limit::RateLimiter};
+use core::{
+    config::{self, Change, LogConfig, LoginConfig, ServerConfig},
+    error::{ExternalError, GlobalResult},
+    redact,
+    server_state::OnlineUsers,
+};
+use std::{
+    fs,
+    path::Path,
+    sync::{Arc, PoisonError, RwLock},
+    time::{Duration, SystemTime},
+};
+
+// read once at startup, a new value is kept for the next start
+const RESTART: &[&str] = &[
+    "ip",
+    "port",
+    "admin.",
+    "metrics.",
+    "login.devices_file",
+    "audit.",
+    "log.time_zone",
+    "log.format",
+    "log.dir",
+    "log.rotation",
+    "log.max_files",
+];
+
+// logged as changed, without the values
+const SECRET: &[&str] = &["admin.token"];
+
+fn matches(key: &str, patterns: &[&str]) -> bool {
+    patterns
+        .iter()
+        .any(|p| key == *p || (p.ends_with('.') && key.starts_with(p)))
+}
+
+/// `key: old -> new`, or `key: changed` for a secret
+fn describe((key, old, new): &Change) -> String {
+    let show = |value: &Option<String>| value.clone().unwrap_or_else(|| "(none)".into());
+    match matches(key, SECRET) {
+        true => format!("{}: changed", key),
+        false => format!("{}: {} -> {}", key, show(old), show(new)),
+    }
+}
+
+/// re-read config.toml and apply what can change without dropping connections,
+/// `cause` tells what asked for it
+/// every changed key is logged, and so is every failure, the current config is kept then
+pub fn reload(
+    cause: &str,
+    server_config: &RwLock<ServerConfig>,
+    online_users: &OnlineUsers,
+    limiter: &RateLimiter,
+) -> GlobalResult<String> {
+    apply(cause, server_config, online_users, limiter).map_err(|e| {
+        tracing::warn!(
+            "config not reloaded on {}, the current one is kept: {}",
+            cause,
+            e
+        );
+        e
+    })
+}
+
+/// `reload` without logging its failure, nothing is changed before the last one can happen
+fn apply(
+    cause: &str,
+    server_config: &RwLock<ServerConfig>,
+    online_users: &OnlineUsers,
+    limiter: &RateLimiter,
+) -> GlobalResult<String> {
+    let new = init::config_file().and_then(|path| match path {
+        // loading a missing file would write the defaults in its place
+        Some(path) if !path.is_file() => {
+            Err(ExternalError::InvalidConfig.info(&format!("{} is missing", path.display())))
+        }
+        _ => init::config(),
+    })?;
+    let mut current = server_config
+        .write()
+        .unwrap_or_else(PoisonError::into_inner);
+    let changes = config::diff(&*current, &new)?;
+    let capacity_changed = current.queue.capacity != new.queue.capacity;
+
+    if current.log.level != new.log.level {
+        init::set_level(&new.log)?;
+    }
+    redact::install(&new.log);
+    if current.queue != new.queue {
+        online_users.set_queue_config(new.queue.clone());
+    }
+    if current.rate_limit != new.rate_limit {
+        limiter.set_config(new.rate_limit.clone());
+    }
+    if current.login.duplicate != new.login.duplicate {
+        online_users.set_login_config(LoginConfig {
+            devices_file: current.login.devices_file.clone(),
+            ..new.login.clone()
+        });
+    }
+    // shutdown and reload are read from the shared config when needed
+    *current = ServerConfig {
+        ip: current.ip.clone(),
+        port: current.port.clone(),
+        admin: current.admin.clone(),
+        metrics: current.metrics.clone(),
+        audit: current.audit.clone(),
+        login: LoginConfig {
+            devices_file: current.login.devices_file.clone(),
+            ..new.login
+        },
+        log: LogConfig {
+            level: new.log.level.clone(),
+            uids: new.log.uids,
+            debug_frames: new.log.debug_frames,
+            ..current.log.clone()
+        },
+        ..new
+    };
+    drop(current);
+
+    tracing::info!("config reloaded on {}, {} changed", cause, changes.len());
+    let mut report = vec![format!("config reloaded, {} changed", changes.len())];
+    for change in changes {
+        let line = describe(&change);
+        match matches(&change.0, RESTART) {
+            true => {
+                tracing::warn!("{}, needs a restart", line);
+                report.push(format!("{}, needs a restart", line));
+            }
+            false => {
+                tracing::info!("{}", line);
+                report.push(line);
+            }
+        }
+    }
+    if capacity_changed {
+        report.push("queue capacity affects new sessions only".into());
+    }
+    Ok(report.join("\n"))
+}
+
+/// size and modification time, `None` while the file is missing
+fn stamp(path: Option<&Path>) -> Option<(u64, SystemTime)> {
+    let metadata = fs::metadata(path?).ok()?;
+    Some((metadata.len(), metadata.modified().ok()?))
+}
+
+/// reload on SIGHUP, and whenever config.toml changes unless `reload.watch_secs` is 0
+pub async fn watch(
+    server_config: Arc<RwLock<ServerConfig>>,
+    online_users: Arc<OnlineUsers>,
+    limiter: Arc<RateLimiter>,
+) -> GlobalResult<()> {
+    // `None` with `--defaults`, there is no file to watch
+    let path = init::config_file()?;
+    let mut seen = stamp(path.as_deref());
+    let mut hangup = hangup()?;
+    loop {
+        let watch_secs = server_config
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .reload
+            .watch_secs;
+        let cause = tokio::select! {
+            _ = hung_up(&mut hangup) => "SIGHUP",
+            _ = tokio::time::sleep(Duration::from_secs(watch_secs)), if watch_secs > 0 && path.is_some() => {
+                let now = stamp(path.as_deref());
+                // wait for the file to come back instead of reloading the defaults
+                if now == seen || now.is_none() {
+                    continue;
+                }
+                "file change"
+            }
+        };
+        // `reload` has logged the failure, the server goes on with the current config
+        if reload(cause, &server_config, &online_users, &limiter).is_err() {
+            tracing::debug!("waiting for the next change of the config");
+        }
+        // an upgrade of the schema rewrites the file
+        seen = stamp(path.as_deref());
+    }
+}
+
+#[cfg(unix)]
+type Hangup = tokio::signal::unix::Signal;
+
+#[cfg(not(unix))]
+type Hangup = ();
+
+#[cfg(unix)]
+fn hangup() -> GlobalResult<Hangup> {
+    use tokio::signal::unix::{signal, SignalKind};
+    Ok(signal(SignalKind::hangup())?)
+}
+
+#[cfg(not(unix))]
+fn hangup() -> GlobalResult<Hangup> {
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn hung_up(hangup: &mut Hangup) {
+    hangup.recv().await;
+}
+
+/// there is no SIGHUP, reload by file or admin console only
+#[cfg(not(unix))]
+async fn hung_up(_: &mut Hangup) {
+    std::future::pending::<()>().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(key: &str, old: Option<&str>, new: Option<&str>) -> Change {
+        (key.into(), old.map(String::from), new.map(String::from))
+    }
+
+    #[test]
+    fn patterns_match_keys_or_whole_tables() {
+        assert!(matches("port", RESTART));
+        assert!(matches("admin.addr", RESTART));
+        assert!(matches("log.dir", RESTART));
+        // `ip` is no prefix, `admin.` is no key
+        assert!(!matches("ipv6", RESTART));
+        assert!(!matches("admin", RESTART));
+        assert!(!matches("log.level", RESTART));
+        assert!(!matches("login.duplicate", RESTART));
+    }
+
+    #[test]
+    fn secrets_are_reported_without_their_values() {
+        let token = change("admin.token", Some("\"old\""), Some("\"new\""));
+        assert_eq!(describe(&token), "admin.token: changed");
+        let level = change("log.level", Some("\"info\""), Some("\"debug\""));
+        assert_eq!(describe(&level), "log.level: \"info\" -> \"debug\"");
+        let added = change("rate_limit.commands.Help.burst", None, Some("3"));
+        assert_eq!(
+            describe(&added),
+            "rate_limit.commands.Help.burst: (none) -> 3"
+        );
+    }
+}